@@ -0,0 +1,42 @@
+// Decimal-safe amount helpers. Raw on-chain amounts are integers (BN); these convert
+// them to and from display strings without going through floating point, so large
+// fees and rebates never lose digits or get their decimal point misplaced.
+import BN from "bn.js";
+
+// Latin digits keep formatted amounts parseable whatever the locale's own digits are.
+const numberFormat = (locale?: string) =>
+  new Intl.NumberFormat(locale, { numberingSystem: "latn" } as Intl.NumberFormatOptions);
+
+// Separators for `locale`, e.g. "," and "." for en-US, "." and "," for de-DE.
+export function separators(locale?: string): { group: string; decimal: string } {
+  const parts = numberFormat(locale).formatToParts(1234567.5);
+  return {
+    group: parts.find((p) => p.type === "group")?.value ?? ",",
+    decimal: parts.find((p) => p.type === "decimal")?.value ?? ".",
+  };
+}
+
+// `raw / 10^decimals` as a localized string, trailing fractional zeros trimmed. The
+// whole part is grouped by the locale's own rules (12,34,567 in en-IN).
+export function formatAmount(raw: BN, decimals: number, locale?: string): string {
+  const { decimal } = separators(locale);
+  const negative = raw.isNeg();
+  const digits = raw.abs().toString().padStart(decimals + 1, "0");
+  const whole = digits.slice(0, digits.length - decimals);
+  const fraction = digits.slice(digits.length - decimals).replace(/0+$/, "");
+  const grouped = numberFormat(locale).format(BigInt(whole));
+  return (negative ? "-" : "") + grouped + (fraction ? decimal + fraction : "");
+}
+
+// Inverse of `formatAmount`; rejects input with more fractional digits than `decimals`.
+export function parseAmount(text: string, decimals: number, locale?: string): BN {
+  const { group, decimal } = separators(locale);
+  const [whole, fraction = "", extra] = text.trim().split(group).join("").split(decimal);
+  if (extra !== undefined || !/^-?\d+$/.test(whole) || !/^\d*$/.test(fraction)) {
+    throw new Error(`not an amount: ${text}`);
+  }
+  if (fraction.length > decimals) {
+    throw new Error(`${text} has more than ${decimals} decimals`);
+  }
+  return new BN(whole + fraction.padEnd(decimals, "0"));
+}
@@ -1,4 +1,51 @@
+import { formatAmount, parseAmount } from "./amounts";
+
 // Client
 console.log("My address:", pg.wallet.publicKey.toString());
 const balance = await pg.connection.getBalance(pg.wallet.publicKey);
 console.log(`My balance: ${balance / web3.LAMPORTS_PER_SOL} SOL`);
+
+// Markets don't store their mints, so the caller names the mint a market settles in.
+// Decimals are fetched once per mint / market and cached.
+const mintDecimalsCache = new Map<string, number>();
+const priceDecimalsCache = new Map<string, number>();
+
+async function mintDecimals(mint: web3.PublicKey): Promise<number> {
+  const key = mint.toBase58();
+  if (!mintDecimalsCache.has(key)) {
+    const info = await pg.connection.getParsedAccountInfo(mint);
+    const decimals = (info.value?.data as any)?.parsed?.info?.decimals;
+    if (typeof decimals !== "number") {
+      throw new Error(`${key} is not a token mint`);
+    }
+    mintDecimalsCache.set(key, decimals);
+  }
+  return mintDecimalsCache.get(key);
+}
+
+// `price_decimals` of a market: prices are quote units per base unit * 10^price_decimals.
+async function marketPriceDecimals(market: web3.PublicKey): Promise<number> {
+  const key = market.toBase58();
+  if (!priceDecimalsCache.has(key)) {
+    const marketState = await pg.program.account.marketState.fetch(market);
+    priceDecimalsCache.set(key, marketState.priceDecimals);
+  }
+  return priceDecimalsCache.get(key);
+}
+
+// Fees, rebates and rewards are quote-token amounts.
+async function formatQuoteAmount(
+  raw: BN,
+  quoteMint: web3.PublicKey,
+  locale?: string
+): Promise<string> {
+  return formatAmount(raw, await mintDecimals(quoteMint), locale);
+}
+
+async function formatPrice(raw: BN, market: web3.PublicKey, locale?: string): Promise<string> {
+  return formatAmount(raw, await marketPriceDecimals(market), locale);
+}
+
+async function parsePrice(text: string, market: web3.PublicKey, locale?: string): Promise<BN> {
+  return parseAmount(text, await marketPriceDecimals(market), locale);
+}
@@ -1,7 +1,145 @@
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::{system_program, sysvar};
+use anchor_lang::solana_program::instruction::{
+    get_stack_height, Instruction, TRANSACTION_LEVEL_STACK_HEIGHT,
+};
+use anchor_lang::solana_program::program::{get_return_data, invoke, invoke_signed};
+use anchor_lang::solana_program::{hash::hashv, system_instruction, system_program, sysvar};
 
 declare_id!("5CvaXsLiugYKb6nPUqyshDh7vHV12zZGT9t9CC152qgF"); 
+
+// ----------------------------------
+// PDA SEEDS
+// ----------------------------------
+
+/// Seed byte strings and address helpers for every PDA the program owns. Account
+/// constraints use the same constants, so clients and tests derive identical addresses.
+/// `MarketState` itself is a keypair account, not a PDA.
+pub mod pda {
+    use super::*;
+
+    #[constant]
+    pub const GLOBAL_CONFIG_SEED: &[u8] = b"global_config";
+    #[constant]
+    pub const MARKET_STATS_SEED: &[u8] = b"market_stats";
+    #[constant]
+    pub const MARKET_HEALTH_SEED: &[u8] = b"market_health";
+    #[constant]
+    pub const USER_STATE_SEED: &[u8] = b"user_state";
+    #[constant]
+    pub const USER_INDEX_SEED: &[u8] = b"user_index";
+    #[constant]
+    pub const ATTESTATION_SEED: &[u8] = b"attestation";
+    #[constant]
+    pub const STAKE_SEED: &[u8] = b"stake";
+    #[constant]
+    pub const REWARD_PROGRAM_SEED: &[u8] = b"reward_program";
+    #[constant]
+    pub const REWARD_POSITION_SEED: &[u8] = b"reward_position";
+    #[constant]
+    pub const BOOK_CHECKPOINT_SEED: &[u8] = b"book_checkpoint";
+    #[constant]
+    pub const RENT_ESCROW_SEED: &[u8] = b"rent_escrow";
+    #[constant]
+    pub const TOP_OF_BOOK_SEED: &[u8] = b"top_of_book";
+    #[constant]
+    pub const OPEN_ORDER_INDEX_SEED: &[u8] = b"open_order_index";
+    #[constant]
+    pub const EVENT_CURSOR_SEED: &[u8] = b"event_cursor";
+    #[constant]
+    pub const REFERRAL_CHECKPOINT_SEED: &[u8] = b"referral_checkpoint";
+    #[constant]
+    pub const REGISTRATION_SEED: &[u8] = b"registration";
+
+    pub fn global_config_address() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[GLOBAL_CONFIG_SEED], &crate::ID)
+    }
+
+    pub fn market_stats_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[MARKET_STATS_SEED, market.as_ref()], &crate::ID)
+    }
+
+    pub fn market_health_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[MARKET_HEALTH_SEED, market.as_ref()], &crate::ID)
+    }
+
+    pub fn top_of_book_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[TOP_OF_BOOK_SEED, market.as_ref()], &crate::ID)
+    }
+
+    pub fn open_order_index_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[OPEN_ORDER_INDEX_SEED, market.as_ref()], &crate::ID)
+    }
+
+    pub fn event_cursor_address(market: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[EVENT_CURSOR_SEED, market.as_ref()], &crate::ID)
+    }
+
+    pub fn user_state_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[USER_STATE_SEED, market.as_ref(), user.as_ref()], &crate::ID)
+    }
+
+    pub fn user_index_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[USER_INDEX_SEED, market.as_ref(), user.as_ref()], &crate::ID)
+    }
+
+    pub fn attestation_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[ATTESTATION_SEED, market.as_ref(), user.as_ref()],
+            &crate::ID,
+        )
+    }
+
+    pub fn stake_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[STAKE_SEED, market.as_ref(), user.as_ref()], &crate::ID)
+    }
+
+    pub fn reward_program_address(
+        market: &Pubkey,
+        sponsor: &Pubkey,
+        name: &[u8; 32],
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[REWARD_PROGRAM_SEED, market.as_ref(), sponsor.as_ref(), name],
+            &crate::ID,
+        )
+    }
+
+    pub fn reward_position_address(reward_program: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[REWARD_POSITION_SEED, reward_program.as_ref(), user.as_ref()],
+            &crate::ID,
+        )
+    }
+
+    pub fn book_checkpoint_address(market: &Pubkey, epoch: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[BOOK_CHECKPOINT_SEED, market.as_ref(), &epoch.to_le_bytes()],
+            &crate::ID,
+        )
+    }
+
+    pub fn referral_checkpoint_address(market: &Pubkey, epoch: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[REFERRAL_CHECKPOINT_SEED, market.as_ref(), &epoch.to_le_bytes()],
+            &crate::ID,
+        )
+    }
+
+    pub fn rent_escrow_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[RENT_ESCROW_SEED, market.as_ref(), user.as_ref()],
+            &crate::ID,
+        )
+    }
+
+    pub fn registration_address(market: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[REGISTRATION_SEED, market.as_ref(), user.as_ref()],
+            &crate::ID,
+        )
+    }
+}
+
 // ----------------------------------
 // PROGRAM
 // ----------------------------------
@@ -10,664 +148,15092 @@ declare_id!("5CvaXsLiugYKb6nPUqyshDh7vHV12zZGT9t9CC152qgF");
 pub mod fee_rebate {
     use super::*;
 
-    /// Initialize the market with default fee parameters and referral incentives.
+    /// Create the program-wide config PDA. The signer becomes the protocol authority
+    /// that controls the per-instruction kill-switches.
+    pub fn initialize_global_config(ctx: Context<InitializeGlobalConfig>) -> Result<()> {
+        let result = handlers::initialize_global_config(ctx);
+        with_failure_log("initialize_global_config", None, None, String::new, result)
+    }
+
+    /// Allows the protocol authority to enable or disable a single instruction,
+    /// e.g. just `fill_order` during an incident, without pausing everything else.
+    pub fn set_instruction_enabled(
+        ctx: Context<SetInstructionEnabled>,
+        instruction: ProgramInstruction,
+        enabled: bool,
+    ) -> Result<()> {
+        let params = || format!("instruction={:?} enabled={}", instruction, enabled);
+        let result = handlers::set_instruction_enabled(ctx, instruction, enabled);
+        with_failure_log("set_instruction_enabled", None, None, params, result)
+    }
+
+    /// Initialize the market with default fee parameters and referral incentives. The
+    /// rates are in `fee_precision`.
     pub fn initialize_market(
         ctx: Context<InitializeMarket>,
-        maker_rebate_bps: u16,
-        taker_fee_bps: u16,
-        referral_bps: u16,
+        maker_rebate_rate: u16,
+        taker_fee_rate: u16,
+        referral_rate: u16,
+        fee_precision: FeePrecision,
+        go_live_ts: i64, // 0 => live immediately
+        price_decimals: u8,
+        recovery_committee: Option<RecoveryCommittee>, // None => authority is never recoverable
     ) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        
-        // Validate fee config
-        require!(
-            maker_rebate_bps <= taker_fee_bps,
-            FeeError::InvalidFeeConfiguration
-        );
-        require!(
-            referral_bps <= taker_fee_bps,
-            FeeError::InvalidFeeConfiguration
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "maker_rebate_rate={} taker_fee_rate={} referral_rate={} fee_precision={:?}",
+                maker_rebate_rate, taker_fee_rate, referral_rate, fee_precision
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::initialize_market(
+            ctx,
+            maker_rebate_rate,
+            taker_fee_rate,
+            referral_rate,
+            fee_precision,
+            go_live_ts,
+            price_decimals,
+            recovery_committee,
         );
+        with_failure_log("initialize_market", Some(market), Some(status), params, result)
+    }
 
-        market_state.authority = *ctx.accounts.authority.key;
-        market_state.maker_rebate_bps = maker_rebate_bps;
-        market_state.taker_fee_bps = taker_fee_bps;
-        market_state.referral_bps = referral_bps;
-        market_state.total_fees_collected = 0;
-        market_state.total_liquidity_rewards_distributed = 0;
-
-        Ok(())
+    /// Market authority only: bring a `MarketState` created before the current layout up
+    /// to date, in place. Until then no other instruction can read it. The account grows to
+    /// the current size, the authority paying the extra rent, and is rebuilt the way
+    /// `initialize_market` builds a market: the legacy rates, which were bps, and the fee
+    /// and reward totals carry over, everything else takes a new market's defaults, and the
+    /// market's per-market accounts are created alongside.
+    pub fn migrate_market_state(ctx: Context<MigrateMarketState>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let result = handlers::migrate_market_state(ctx);
+        with_failure_log("migrate_market_state", Some(market), None, String::new, result)
     }
 
-    /// Allows the market authority to update fee parameters at any time.
+    /// Allows the market authority to update fee parameters at any time. Rates are in the
+    /// market's `fee_precision`.
     pub fn update_fee_parameters(
         ctx: Context<UpdateFeeParameters>,
-        new_maker_rebate_bps: u16,
-        new_taker_fee_bps: u16,
-        new_referral_bps: u16,
+        new_maker_rebate_rate: u16,
+        new_taker_fee_rate: u16,
+        new_referral_rate: u16,
     ) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        require!(
-            market_state.authority == *ctx.accounts.authority.key,
-            FeeError::Unauthorized
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_maker_rebate_rate={} new_taker_fee_rate={} new_referral_rate={}",
+                new_maker_rebate_rate, new_taker_fee_rate, new_referral_rate
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_fee_parameters(
+            ctx,
+            new_maker_rebate_rate,
+            new_taker_fee_rate,
+            new_referral_rate,
         );
+        with_failure_log("update_fee_parameters", Some(market), Some(status), params, result)
+    }
 
-        require!(
-            new_maker_rebate_bps <= new_taker_fee_bps,
-            FeeError::InvalidFeeConfiguration
-        );
-        require!(
-            new_referral_bps <= new_taker_fee_bps,
-            FeeError::InvalidFeeConfiguration
-        );
+    /// Roll one fee change across several markets at once. Pass each market in `markets`
+    /// (strictly increasing) as a writable `MarketState` in `remaining_accounts`, in the
+    /// same order; the signer must be every market's authority. `params` are in
+    /// `fee_precision` and restated in each market's own precision, so markets of mixed
+    /// precision get the same fees. Any failing market, one the rates can't be restated
+    /// for exactly included, fails the whole batch, so either all markets move or none do.
+    pub fn batch_update_fees<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchUpdateFees<'info>>,
+        markets: Vec<Pubkey>,
+        params: FeeParameters,
+        fee_precision: FeePrecision,
+    ) -> Result<()> {
+        let market_count = markets.len();
+        let log_params = || {
+            format!(
+                "market_count={} params={:?} fee_precision={:?}",
+                market_count, params, fee_precision
+            )
+        };
+        let result = handlers::batch_update_fees(ctx, markets, params, fee_precision);
+        with_failure_log("batch_update_fees", None, None, log_params, result)
+    }
 
-        market_state.maker_rebate_bps = new_maker_rebate_bps;
-        market_state.taker_fee_bps = new_taker_fee_bps;
-        market_state.referral_bps = new_referral_bps;
+    /// Allows the market authority to replace the volume-based fee tiers. The whole table is
+    /// validated up front (strictly increasing thresholds, rates that never get worse with
+    /// volume, bounded bps) so a misconfiguration is rejected here rather than at fill time.
+    pub fn update_fee_tiers(
+        ctx: Context<UpdateFeeParameters>,
+        new_fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let tier_count = new_fee_tiers.len();
+        let params = || format!("tier_count={}", tier_count);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_fee_tiers(ctx, new_fee_tiers);
+        with_failure_log("update_fee_tiers", Some(market), Some(status), params, result)
+    }
 
-        emit!(FeeParametersUpdated {
-            maker_rebate_bps: new_maker_rebate_bps,
-            taker_fee_bps: new_taker_fee_bps,
-            referral_bps: new_referral_bps,
-        });
+    /// View: check a full market parameter bundle, as deployment tooling would pass it to
+    /// `initialize_market` and the setters run right after, without creating any account.
+    /// Every failing parameter is reported with the error code the real call would raise.
+    pub fn validate_market_params(
+        ctx: Context<ValidateMarketParams>,
+        params: MarketParams,
+    ) -> Result<MarketParamsValidation> {
+        let result = handlers::validate_market_params(ctx, params);
+        with_failure_log("validate_market_params", None, None, String::new, result)
+    }
 
-        Ok(())
+    /// View: which tier (and rates) a user with `volume` lifetime volume would get.
+    pub fn simulate_tier_assignment(
+        ctx: Context<SimulateTierAssignment>,
+        volume: u64,
+    ) -> Result<TierAssignment> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("volume={}", volume);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::simulate_tier_assignment(ctx, volume);
+        with_failure_log("simulate_tier_assignment", Some(market), Some(status), params, result)
     }
 
-    /// Register a user in this market, creating a PDA that tracks:
-    ///   - Orders
-    ///   - Maker/taker stats
-    ///   - Referral relationship
-    ///   - Liquidity score
-    pub fn register_user(
-        ctx: Context<RegisterUser>,
-        referrer: Option<Pubkey>,
+    /// Allows the market authority to choose whether `referral_rate` applies to the fill
+    /// size or to the taker fee amount (the industry norm).
+    pub fn update_referral_basis(
+        ctx: Context<UpdateFeeParameters>,
+        new_referral_basis: ReferralBasis,
     ) -> Result<()> {
-        let user_state = &mut ctx.accounts.user_state;
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_referral_basis={:?}", new_referral_basis);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_referral_basis(ctx, new_referral_basis);
+        with_failure_log("update_referral_basis", Some(market), Some(status), params, result)
+    }
 
-        user_state.authority = *ctx.accounts.user_authority.key;
-        user_state.maker_volume = 0;
-        user_state.taker_volume = 0;
-        user_state.maker_rebates_earned = 0;
-        user_state.taker_fees_paid = 0;
-        user_state.liquidity_score = 0;
-        user_state.referrer = referrer;
-        user_state.orders = [Order::default(); MAX_ORDERS];
+    /// Allows the market authority to move the fee schedule between bps and pips (see
+    /// `FeePrecision`). Existing rates and tiers are restated in the new unit so no fee
+    /// changes; later `update_fee_parameters` and `update_fee_tiers` calls use the new unit.
+    pub fn update_fee_precision(
+        ctx: Context<UpdateFeeParameters>,
+        fee_precision: FeePrecision,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("fee_precision={:?}", fee_precision);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_fee_precision(ctx, fee_precision);
+        with_failure_log("update_fee_precision", Some(market), Some(status), params, result)
+    }
 
-        Ok(())
+    /// Allows the market authority to choose where opted-out users' reward shares go.
+    pub fn update_opt_out_policy(
+        ctx: Context<UpdateFeeParameters>,
+        new_opt_out_policy: OptOutPolicy,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_opt_out_policy={:?}", new_opt_out_policy);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_opt_out_policy(ctx, new_opt_out_policy);
+        with_failure_log("update_opt_out_policy", Some(market), Some(status), params, result)
     }
 
-    /// Place an order with details. For simplicity, store a maximum of `MAX_ORDERS` per user.
-    /// This demonstrates partial fills, time-in-force, etc.
-    pub fn place_order(
-        ctx: Context<PlaceOrder>,
-        side: OrderSide,
-        price: u64,
-        size: u64,
-        expiry_timestamp: i64, // if 0, treat as no expiry
+    /// Allows the market authority to set the share of captured price improvement
+    /// that is rebated back to takers. 0 disables the rebate.
+    pub fn update_price_improvement_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        new_price_improvement_rebate_bps: u16,
     ) -> Result<()> {
-        let user_state = &mut ctx.accounts.user_state;
-        require!(
-            user_state.authority == *ctx.accounts.user_authority.key,
-            FeeError::Unauthorized
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_price_improvement_rebate_bps={}",
+                new_price_improvement_rebate_bps
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_price_improvement_rebate(
+            ctx,
+            new_price_improvement_rebate_bps,
         );
+        with_failure_log(
+            "update_price_improvement_rebate",
+            Some(market),
+            Some(status),
+            params,
+            result,
+        )
+    }
 
-        let now = Clock::get()?.unix_timestamp;
-
-        //  Find an empty slot index
-        let mut free_slot_index = None;
-        for (i, order_slot) in user_state.orders.iter().enumerate() {
-            if order_slot.size_remaining == 0 {
-                free_slot_index = Some(i);
-                break;
-            }
-        }
+    /// Allows the market authority to configure the quote-flicker penalty. A user whose
+    /// cancels exceed `max_cancel_to_fill_bps` of their fills in an epoch (with at least
+    /// `min_cancels` cancels) has their maker rebates cut by `rebate_haircut_bps` and pays
+    /// `placement_fee` per order placed during the following epoch. 0 ratio disables it.
+    pub fn update_cancel_penalty(
+        ctx: Context<UpdateFeeParameters>,
+        max_cancel_to_fill_bps: u32,
+        min_cancels: u32,
+        rebate_haircut_bps: u16,
+        placement_fee: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "max_cancel_to_fill_bps={} min_cancels={} rebate_haircut_bps={} placement_fee={}",
+                max_cancel_to_fill_bps, min_cancels, rebate_haircut_bps, placement_fee
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_cancel_penalty(
+            ctx,
+            max_cancel_to_fill_bps,
+            min_cancels,
+            rebate_haircut_bps,
+            placement_fee,
+        );
+        with_failure_log("update_cancel_penalty", Some(market), Some(status), params, result)
+    }
 
-        //  If none found, error out
-        require!(free_slot_index.is_some(), FeeError::NoFreeOrderSlot);
+    /// Allows the market authority to move the market through its lifecycle, e.g. pause
+    /// trading or start a sunset. `Closed` is only reachable through `close_market`.
+    pub fn set_market_status(
+        ctx: Context<UpdateFeeParameters>,
+        status: MarketStatus,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("status={:?}", status);
+        let current_status = ctx.accounts.market_state.status;
+        let result = handlers::set_market_status(ctx, status);
+        with_failure_log("set_market_status", Some(market), Some(current_status), params, result)
+    }
 
-        //  Write the new order data at that slot
-        let idx = free_slot_index.unwrap();
-        user_state.orders[idx] = Order {
-            side,
-            price,
-            size_remaining: size,
-            creation_timestamp: now,
-            expiry_timestamp,
+    /// Allows the market authority to boost liquidity scores on one side of the book until
+    /// `expires_at`, e.g. `multiplier_bps = 20_000` for 2x on asks. 0 clears the incentive.
+    pub fn set_side_incentive(
+        ctx: Context<UpdateFeeParameters>,
+        side: OrderSide,
+        multiplier_bps: u16,
+        expires_at: i64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "side={:?} multiplier_bps={} expires_at={}",
+                side, multiplier_bps, expires_at
+            )
         };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::set_side_incentive(ctx, side, multiplier_bps, expires_at);
+        with_failure_log("set_side_incentive", Some(market), Some(status), params, result)
+    }
 
-        //  Emit an event (no longer holding a mutable reference to the array slot)
-        emit!(OrderPlaced {
-            user: user_state.authority,
-            side,
-            price,
-            size,
-            expiry_timestamp,
-        });
-
-        Ok(())
+    /// Allows the market authority to configure the first-fill bonus for new users and set
+    /// the promotion budget funding it. Raising the budget moves the difference out of the
+    /// treasury (`total_fees_collected`); lowering it moves the unspent difference back.
+    pub fn update_first_fill_bonus(
+        ctx: Context<UpdateFeeParameters>,
+        bonus: FirstFillBonus,
+        promotion_budget: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("bonus={:?} promotion_budget={}", bonus, promotion_budget);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_first_fill_bonus(ctx, bonus, promotion_budget);
+        with_failure_log("update_first_fill_bonus", Some(market), Some(status), params, result)
     }
 
-    /// Cancel a specific order by index. This frees up the slot.
-    ///   also "reward" the user’s liquidity_score based on how long the order was live.
-    pub fn cancel_order(
-        ctx: Context<CancelOrder>,
-        order_index: u8,
+    /// Allows the market authority to only credit liquidity score for time an order rests
+    /// within `liquidity_band_bps` of the reference price. 0 credits all resting time.
+    pub fn update_liquidity_band(
+        ctx: Context<UpdateFeeParameters>,
+        liquidity_band_bps: u16,
     ) -> Result<()> {
-        let user_state = &mut ctx.accounts.user_state;
-        require!(
-            user_state.authority == *ctx.accounts.user_authority.key,
-            FeeError::Unauthorized
-        );
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("liquidity_band_bps={}", liquidity_band_bps);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_liquidity_band(ctx, liquidity_band_bps);
+        with_failure_log("update_liquidity_band", Some(market), Some(status), params, result)
+    }
 
-        let now = Clock::get()?.unix_timestamp;
-        require!(
-            (order_index as usize) < user_state.orders.len(),
-            FeeError::InvalidOrderIndex
-        );
+    /// Allows the market authority to set how many orders each user may have open, up to
+    /// `MAX_ORDERS`. Users registered under a lower limit get the extra slots with
+    /// `resize_order_slots`. Lowering it leaves existing orders alone; users over the new
+    /// limit just cannot place more until they are under.
+    pub fn update_max_open_orders(
+        ctx: Context<UpdateFeeParameters>,
+        max_open_orders: u8,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("max_open_orders={}", max_open_orders);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_max_open_orders(ctx, max_open_orders);
+        with_failure_log("update_max_open_orders", Some(market), Some(status), params, result)
+    }
 
-        // Copy out relevant order data from the slot (and reset it) in a smaller scope
-        let (canceled_size, added_liq) = {
-            let order = &mut user_state.orders[order_index as usize];
-            require!(order.size_remaining > 0, FeeError::NoOpenOrders);
+    /// Allows the market authority to set how long orders placed without an expiry live
+    /// (`default_order_ttl_secs`), and the longest lifetime any order may have
+    /// (`max_order_ttl_secs`). 0 means no default and no cap respectively. Orders already
+    /// resting keep their expiry.
+    pub fn update_order_ttl(
+        ctx: Context<UpdateFeeParameters>,
+        default_order_ttl_secs: i64,
+        max_order_ttl_secs: i64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "default_order_ttl_secs={} max_order_ttl_secs={}",
+                default_order_ttl_secs, max_order_ttl_secs
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_order_ttl(ctx, default_order_ttl_secs, max_order_ttl_secs);
+        with_failure_log("update_order_ttl", Some(market), Some(status), params, result)
+    }
 
-            // how long it was active
-            let active_time = now.checked_sub(order.creation_timestamp).unwrap_or(0);
-            let added_liq = active_time
-                .saturating_mul(order.size_remaining as i64)
-                .max(0) as u64;
+    /// Allows the market authority to require that fee and parameter updates and fee
+    /// withdrawals on this market are top-level transaction instructions, never CPIs made
+    /// by another program.
+    pub fn set_top_level_only(
+        ctx: Context<UpdateFeeParameters>,
+        top_level_only: bool,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("top_level_only={}", top_level_only);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::set_top_level_only(ctx, top_level_only);
+        with_failure_log("set_top_level_only", Some(market), Some(status), params, result)
+    }
 
-            let canceled_size = order.size_remaining;
+    /// Allows the market authority to pay makers an extra `promo_maker_rebate_bps` of each
+    /// fill out of the promotion budget, which can make the net fee negative. Each order
+    /// reserves its promotional rebate when placed, up to the maker's share of the budget,
+    /// and placement fails once the unreserved budget cannot cover it. Orders already
+    /// resting keep their reserve.
+    pub fn update_promo_maker_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        promo_maker_rebate_bps: u16,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("promo_maker_rebate_bps={}", promo_maker_rebate_bps);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_promo_maker_rebate(ctx, promo_maker_rebate_bps);
+        with_failure_log("update_promo_maker_rebate", Some(market), Some(status), params, result)
+    }
 
-            // Mark slot as free
-            *order = Order::default();
+    /// Allows the market authority to cap what promotions (first-fill bonuses and
+    /// promotional maker rebates) may pay out per epoch. Once an epoch's spend reaches
+    /// it, promotions pay nothing more until the next epoch. 0 removes the cap.
+    ///
+    /// Side incentives (`set_side_incentive`) are not counted and stay on at the cap: a
+    /// boost only reweights liquidity scores, so it shifts shares of the separately funded
+    /// `liquidity_reward_pool` between makers and never pays anything out by itself.
+    pub fn update_promotion_epoch_cap(
+        ctx: Context<UpdateFeeParameters>,
+        promotion_epoch_cap: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("promotion_epoch_cap={}", promotion_epoch_cap);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_promotion_epoch_cap(ctx, promotion_epoch_cap);
+        with_failure_log("update_promotion_epoch_cap", Some(market), Some(status), params, result)
+    }
 
-            (canceled_size, added_liq)
-        };
+    /// Allows the market authority to adjust maker rebates for book imbalance: orders on the
+    /// thin side earn up to `max_bonus_bps` more, orders on the heavy side up to
+    /// `max_haircut_bps` less. Both 0 turns it off. Only live fixed-price size within
+    /// `SKEW_BAND_BPS` of the best bid and ask counts toward the imbalance.
+    pub fn update_skew_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        skew_rebate: SkewRebate,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("skew_rebate={:?}", skew_rebate);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_skew_rebate(ctx, skew_rebate);
+        with_failure_log("update_skew_rebate", Some(market), Some(status), params, result)
+    }
 
-        //  Now that it no longer holds a mutable reference to orders[...], 
-        //     can safely mutate other fields or emit events.
-        user_state.liquidity_score = user_state
-            .liquidity_score
-            .saturating_add(added_liq);
+    /// Allows the market authority to cap each referrer's lifetime rewards from a referee at
+    /// `referral_cap_bps` of the taker fees that referee has paid. 10_000 only guarantees
+    /// rewards never outgrow the fees they come from.
+    pub fn update_referral_cap(
+        ctx: Context<UpdateFeeParameters>,
+        referral_cap_bps: u16,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("referral_cap_bps={}", referral_cap_bps);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_referral_cap(ctx, referral_cap_bps);
+        with_failure_log("update_referral_cap", Some(market), Some(status), params, result)
+    }
 
-        emit!(OrderCanceled {
-            user: user_state.authority,
-            order_index,
-            canceled_size,
-        });
+    /// Allows the market authority to set the fill notional (in quote units, see
+    /// `notional_value`) at and above which fills also emit a `LargeFill` event. 0 turns
+    /// the alerts off.
+    pub fn update_large_fill_threshold(
+        ctx: Context<UpdateFeeParameters>,
+        new_large_fill_notional: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_large_fill_notional={}", new_large_fill_notional);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_large_fill_threshold(ctx, new_large_fill_notional);
+        with_failure_log("update_large_fill_threshold", Some(market), Some(status), params, result)
+    }
 
-        Ok(())
+    /// Allows the market authority to plug an external program into liquidity scoring:
+    /// fills and cancels CPI it with the order's context and apply the score delta it
+    /// returns, clamped to `max_score_delta`. Cancels that can't be scored go through
+    /// unscored. `Pubkey::default()` unplugs it.
+    pub fn update_scoring_plugin(
+        ctx: Context<UpdateFeeParameters>,
+        new_scoring_program: Pubkey,
+        new_max_score_delta: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_scoring_program={} new_max_score_delta={}",
+                new_scoring_program, new_max_score_delta
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_scoring_plugin(ctx, new_scoring_program, new_max_score_delta);
+        with_failure_log("update_scoring_plugin", Some(market), Some(status), params, result)
     }
 
-    /// Fill a maker’s order partially or fully. Taker pays fees, maker gets rebates,
-    /// referrer gets a small cut, and update liquidity scores.
-    pub fn fill_order(
-        ctx: Context<FillOrder>,
-        maker_order_index: u8,
-        fill_size: u64,
+    /// Allows the market authority to set the smallest fill notional (in quote units, see
+    /// `notional_value`) the market accepts, so rounding can't make dust fills fee-free.
+    /// Fixed-price orders below it can't be placed, and a fill that takes an order's whole
+    /// remainder is always accepted. 0 accepts any size.
+    pub fn update_min_fill_notional(
+        ctx: Context<UpdateFeeParameters>,
+        new_min_fill_notional: u64,
     ) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        let maker_user = &mut ctx.accounts.maker_user;
-        let taker_user = &mut ctx.accounts.taker_user;
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_min_fill_notional={}", new_min_fill_notional);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_min_fill_notional(ctx, new_min_fill_notional);
+        with_failure_log("update_min_fill_notional", Some(market), Some(status), params, result)
+    }
 
-        // Check signers
-        require!(
-            taker_user.authority == *ctx.accounts.taker_authority.key,
-            FeeError::Unauthorized
-        );
-        require!(
-            (maker_order_index as usize) < maker_user.orders.len(),
-            FeeError::InvalidOrderIndex
+    /// Allows the market authority to set the epoch length and the claim deadline.
+    /// `epoch_duration_secs = 0` stops epochs from rolling; `claim_deadline_secs = 0`
+    /// means claimable balances never expire.
+    pub fn update_epoch_config(
+        ctx: Context<UpdateFeeParameters>,
+        new_epoch_duration_secs: i64,
+        new_claim_deadline_secs: i64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_epoch_duration_secs={} new_claim_deadline_secs={}",
+                new_epoch_duration_secs, new_claim_deadline_secs
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_epoch_config(
+            ctx,
+            new_epoch_duration_secs,
+            new_claim_deadline_secs,
         );
+        with_failure_log("update_epoch_config", Some(market), Some(status), params, result)
+    }
+
+    /// Permissionless crank that advances the market to the next epoch once the
+    /// current one has run for `epoch_duration_secs`.
+    pub fn roll_epoch(ctx: Context<RollEpoch>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::roll_epoch(ctx);
+        with_failure_log("roll_epoch", Some(market), Some(status), String::new, result)
+    }
 
-        let now = Clock::get()?.unix_timestamp;
+    /// Opens the current epoch's `BookCheckpoint`, once per epoch, which commits the
+    /// canonical hash of the market's resting orders so off-chain simulators can reproduce
+    /// it. Markets with more users than fit in one transaction build it across several:
+    /// pass the first batch of `UserState`s in `remaining_accounts`, sorted by address,
+    /// then the rest through `extend_book_checkpoint` and close it with
+    /// `finalize_book_checkpoint`.
+    pub fn checkpoint_order_book<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CheckpointOrderBook<'info>>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::checkpoint_order_book(ctx);
+        with_failure_log("checkpoint_order_book", Some(market), Some(status), String::new, result)
+    }
 
-        //  Access the maker's order in a smaller scope
-        let (trade_size, maker_rebate, taker_fee, referral_reward, net_fee, fully_filled) = {
-            let maker_order = &mut maker_user.orders[maker_order_index as usize];
-            require!(maker_order.size_remaining > 0, FeeError::NoOpenOrders);
+    /// Adds the next batch of `UserState`s, sorted by address and all after the last batch,
+    /// to an unfinished `BookCheckpoint`. If any order was placed, filled, canceled or
+    /// expired, or any user joined or left the market, since the build started, the
+    /// summary so far no longer describes one book: pass `restart` to drop it and begin
+    /// again from this batch.
+    pub fn extend_book_checkpoint<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UpdateBookCheckpoint<'info>>,
+        restart: bool,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("restart={}", restart);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::extend_book_checkpoint(ctx, restart);
+        with_failure_log("extend_book_checkpoint", Some(market), Some(status), params, result)
+    }
 
-            // Check if order expired
-            if maker_order.expiry_timestamp > 0 && now > maker_order.expiry_timestamp {
-                return err!(FeeError::OrderExpired);
-            }
+    /// Commits the hash of a `BookCheckpoint` once it has summarized every user of the
+    /// market: as many as `user_count`, from a book nothing has changed since the build
+    /// started.
+    pub fn finalize_book_checkpoint(ctx: Context<UpdateBookCheckpoint>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::finalize_book_checkpoint(ctx);
+        with_failure_log(
+            "finalize_book_checkpoint",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-            let actual_fill = fill_size.min(maker_order.size_remaining);
+    /// Opens the current epoch's `ReferralCheckpoint`, once per epoch, which commits a
+    /// Merkle root of the market's referral graph so affiliate-payout auditors can prove
+    /// any user's referral (or its absence) at that point. Markets with more users than fit
+    /// in one transaction build the tree across several: pass the first batch of
+    /// `UserState`s in `remaining_accounts`, sorted by address, then the rest through
+    /// `extend_referral_checkpoint` and close it with `finalize_referral_checkpoint`.
+    pub fn checkpoint_referral_graph<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CheckpointReferralGraph<'info>>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::checkpoint_referral_graph(ctx);
+        with_failure_log(
+            "checkpoint_referral_graph",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-            // Fee/Rebate Calculation
-            let taker_fee = (actual_fill as u128)
-                .checked_mul(market_state.taker_fee_bps as u128)
-                .ok_or(FeeError::Overflow)? / 10_000;
+    /// Adds the next batch of `UserState`s, sorted by address and all after the last batch,
+    /// to an unfinished `ReferralCheckpoint`. If users joined or left the market since the
+    /// build started, the leaves so far no longer describe one user set: pass `restart` to
+    /// drop them and begin again from this batch.
+    pub fn extend_referral_checkpoint<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UpdateReferralCheckpoint<'info>>,
+        restart: bool,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("restart={}", restart);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::extend_referral_checkpoint(ctx, restart);
+        with_failure_log("extend_referral_checkpoint", Some(market), Some(status), params, result)
+    }
 
-            let maker_rebate = (actual_fill as u128)
-                .checked_mul(market_state.maker_rebate_bps as u128)
-                .ok_or(FeeError::Overflow)? / 10_000;
+    /// Commits the root of a `ReferralCheckpoint` once it holds a leaf for every user of
+    /// the market: as many leaves as `user_count`, from a user set nobody has joined or
+    /// left since the build started.
+    pub fn finalize_referral_checkpoint(ctx: Context<UpdateReferralCheckpoint>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::finalize_referral_checkpoint(ctx);
+        with_failure_log(
+            "finalize_referral_checkpoint",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-            let net_fee = taker_fee
-                .checked_sub(maker_rebate)
-                .ok_or(FeeError::NegativeFee)?;
+    /// Permissionless crank that brings `MarketHealth` up to date, e.g. before a dashboard
+    /// poll when no trading instruction has touched the market recently.
+    pub fn refresh_market_health(ctx: Context<RefreshMarketHealth>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::refresh_market_health(ctx);
+        with_failure_log("refresh_market_health", Some(market), Some(status), String::new, result)
+    }
 
-            // Referral
-            let mut referral_reward = 0_u128;
-            if let Some(_referrer_pubkey) = taker_user.referrer {
-                if market_state.referral_bps > 0 {
-                    referral_reward = (actual_fill as u128)
-                        .checked_mul(market_state.referral_bps as u128)
-                        .ok_or(FeeError::Overflow)? / 10_000;
-                }
-                // TODO: place credit the referrer account here.
-            }
+    /// Permissionless maintenance check, e.g. after an upgrade or migration: every account
+    /// in `remaining_accounts` must be owned by this program, start with a known account
+    /// discriminator, and be exactly the size of that type's current layout. Emits an
+    /// `AccountsAudited` report listing each account that is not.
+    pub fn audit_accounts(ctx: Context<AuditAccounts>) -> Result<()> {
+        let result = handlers::audit_accounts(ctx);
+        with_failure_log("audit_accounts", None, None, String::new, result)
+    }
 
-            // Reduce maker’s size_remaining
-            maker_order.size_remaining = maker_order
-                .size_remaining
-                .checked_sub(actual_fill)
-                .ok_or(FeeError::Overflow)?;
+    /// Count `count` placements or fills rejected for `reason` into `MarketStats`. A failed
+    /// instruction can't write its own counters, so a keeper watching the market's failed
+    /// transactions (see `with_failure_log`, `RejectionReason::for_error`) reports them
+    /// here, batched. Only the market authority or its `rejection_reporter` may report.
+    pub fn report_rejection(
+        ctx: Context<ReportRejection>,
+        reason: RejectionReason,
+        count: u32,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("reason={:?} count={}", reason, count);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::report_rejection(ctx, reason, count);
+        with_failure_log("report_rejection", Some(market), Some(status), params, result)
+    }
 
-            // Check if fully filled
-            let fully_filled = maker_order.size_remaining == 0;
+    /// View: summarized stats of `count` users of the user index, starting at user `start`
+    /// (`Pubkey::default()` => the newest), returned via return data. Pass each user's
+    /// `UserIndexEntry` followed by its `UserState` in `remaining_accounts`, in index order;
+    /// page through by starting the next call at the page's `next`.
+    pub fn export_user_stats_page<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExportUserStatsPage<'info>>,
+        start: Pubkey,
+        count: u8,
+    ) -> Result<UserStatsPage> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("start={} count={}", start, count);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::export_user_stats_page(ctx, start, count);
+        with_failure_log("export_user_stats_page", Some(market), Some(status), params, result)
+    }
 
-            (
-                actual_fill,        // trade_size
-                maker_rebate,       // maker_rebate
-                taker_fee,          // taker_fee
-                referral_reward,    // referral_reward
-                net_fee,            // net_fee
-                fully_filled,       // fully_filled
+    /// Allows the market authority to choose who publishes the reference price used by
+    /// pegged orders, and how old that price may be at fill time (0 = no staleness check).
+    pub fn update_oracle_config(
+        ctx: Context<UpdateFeeParameters>,
+        new_oracle_authority: Pubkey,
+        new_max_reference_age_secs: i64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_oracle_authority={} new_max_reference_age_secs={}",
+                new_oracle_authority, new_max_reference_age_secs
             )
         };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_oracle_config(
+            ctx,
+            new_oracle_authority,
+            new_max_reference_age_secs,
+        );
+        with_failure_log("update_oracle_config", Some(market), Some(status), params, result)
+    }
 
-        //   Now that it no longer has a reference to maker_order,  can safely
-        //    update the user accounts & global market state:
-        //    - maker/taker volumes, 
-        //    - total_fees_collected, 
-        //    - liquidity_score if fully filled, etc.
-        
-        // Update maker stats
-        maker_user.maker_volume = maker_user
-            .maker_volume
-            .checked_add(trade_size)
-            .ok_or(FeeError::Overflow)?;
-        maker_user.maker_rebates_earned = maker_user
-            .maker_rebates_earned
-            .checked_add(maker_rebate as u64)
-            .ok_or(FeeError::Overflow)?;
+    /// Allows the market authority to choose who, besides itself, may `report_rejection`
+    /// for this market. Default => the authority only.
+    pub fn update_rejection_reporter(
+        ctx: Context<UpdateFeeParameters>,
+        new_rejection_reporter: Pubkey,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_rejection_reporter={}", new_rejection_reporter);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_rejection_reporter(ctx, new_rejection_reporter);
+        with_failure_log("update_rejection_reporter", Some(market), Some(status), params, result)
+    }
 
-        // Update taker stats
-        taker_user.taker_volume = taker_user
-            .taker_volume
-            .checked_add(trade_size)
-            .ok_or(FeeError::Overflow)?;
-        taker_user.taker_fees_paid = taker_user
-            .taker_fees_paid
-            .checked_add(taker_fee as u64)
-            .ok_or(FeeError::Overflow)?;
+    /// Publish the market reference (mid) price. Only the configured oracle authority may push.
+    /// `confidence` is the oracle's uncertainty band around the price, in price units; 0 if
+    /// the source has none.
+    pub fn update_reference_price(
+        ctx: Context<UpdateReferencePrice>,
+        reference_price: u64,
+        confidence: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("reference_price={} confidence={}", reference_price, confidence);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_reference_price(ctx, reference_price, confidence);
+        with_failure_log("update_reference_price", Some(market), Some(status), params, result)
+    }
 
-        // Collect net fees
-        market_state.total_fees_collected = market_state
-            .total_fees_collected
-            .checked_add(net_fee as u64)
-            .ok_or(FeeError::Overflow)?;
+    /// Register a user in this market, creating a PDA that tracks:
+    ///   - Orders
+    ///   - Maker/taker stats
+    ///   - Referral relationship
+    ///   - Liquidity score
+    ///
+    /// The referrer is given either in plain text or, to keep the referral graph out of
+    /// account data, as a `referral_commitment` that the referrer opens at claim time.
+    /// Only a user's first registration in the market starts a first-fill bonus window; a
+    /// `RegistrationRecord` remembers it after the `UserState` is reclaimed.
+    pub fn register_user(
+        ctx: Context<RegisterUser>,
+        referrer: Option<Pubkey>,
+        referrer_commitment: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let confidential = referrer_commitment.is_some();
+        let params = || format!("referrer={:?} confidential={}", referrer, confidential);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::register_user(ctx, referrer, referrer_commitment);
+        with_failure_log("register_user", Some(market), Some(status), params, result)
+    }
 
-        // If the maker's order was fully filled, increment their liquidity_score
-        // based on how long the order was active.  need the original creation time:
-        if fully_filled {
-            // Re-borrow maker_order in a read-only fashion to get creation_timestamp
-            // but do it carefully. it can do the same "copy out" trick or find it previously.
-            let creation_timestamp = maker_user.orders[maker_order_index as usize].creation_timestamp;
-            
-            // (replaced the order's size_remaining with zero, but left creation_timestamp.)
-            // Alternatively, it could store it in a local variable earlier, then zero out the slot.
-            maker_user.orders[maker_order_index as usize] = Order::default();
+    /// Market authority only, and only before the market's `go_live_ts`: create `user`'s
+    /// UserState pre-seeded with their stats from a legacy deployment, so tier status and
+    /// liquidity score carry over. Each user can be imported once, instead of registering.
+    pub fn import_user(
+        ctx: Context<ImportUser>,
+        user: Pubkey,
+        legacy_stats: LegacyUserStats,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("user={}", user);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::import_user(ctx, user, legacy_stats);
+        with_failure_log("import_user", Some(market), Some(status), params, result)
+    }
 
-            let active_time = now.saturating_sub(creation_timestamp);
-            let added_liq = active_time.saturating_mul(trade_size as i64).max(0) as u64;
-            maker_user.liquidity_score = maker_user.liquidity_score.saturating_add(added_liq);
-        }
+    /// Move the signer's `UserState` from the legacy layout into `market_state`. Legacy
+    /// `UserState`s were one per user, at `["user_state", user]`, so they can't be grown in
+    /// place: this registers the user in the market with their lifetime stats and referrer
+    /// carried over, as `import_user` would, and closes the legacy account, returning its
+    /// rent. Legacy orders held no funds and aren't in the market's book; they are dropped
+    /// as if canceled.
+    pub fn migrate_user_state(ctx: Context<MigrateUserState>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::migrate_user_state(ctx);
+        with_failure_log("migrate_user_state", Some(market), Some(status), String::new, result)
+    }
 
-        //  Emit the fill event now that it's done with all references
-        emit!(OrderFilled {
-            maker: maker_user.authority,
-            taker: taker_user.authority,
-            trade_size,
-            maker_rebate: maker_rebate as u64,
-            taker_fee: taker_fee as u64,
-            referral_reward: referral_reward as u64,
-        });
+    /// Resize the signer's `UserState` to the market's current `max_open_orders` order
+    /// slots, paying the extra rent or getting the difference back. Accounts get the limit
+    /// in force when they register; shrinking needs the dropped slots to be empty.
+    pub fn resize_order_slots(ctx: Context<ResizeOrderSlots>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::resize_order_slots(ctx);
+        with_failure_log("resize_order_slots", Some(market), Some(status), String::new, result)
+    }
 
-        Ok(())
+    /// Place an order with details. Each user may have up to the market's `max_open_orders`
+    /// open at once.
+    /// This demonstrates partial fills, time-in-force, etc.
+    ///
+    /// With a pegged `price_mode` the execution price is resolved against the market
+    /// reference price at fill time, and `price` acts as a protective limit (0 = none).
+    /// `strategy_id` tags the order for per-strategy stats (0 = untagged).
+    ///
+    /// `expiry_timestamp = 0` gives the order the market's default TTL, or no expiry if
+    /// the market has neither a default nor a maximum TTL; see `MarketState::order_expiry`.
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        side: OrderSide,
+        price: u64,
+        size: u64,
+        expiry_timestamp: i64, // 0 => the market's default
+        price_mode: PriceMode,
+        strategy_id: u16,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "side={:?} price={} size={} expiry_timestamp={} price_mode={:?}",
+                side, price, size, expiry_timestamp, price_mode
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::place_order(
+            ctx,
+            side,
+            price,
+            size,
+            expiry_timestamp,
+            price_mode,
+            strategy_id,
+        );
+        with_failure_log("place_order", Some(market), Some(status), params, result)
     }
 
-    /// Distribute liquidity rewards to a specific user, proportional to their share
-    /// of the global liquidity score.
-    pub fn distribute_liquidity_rewards(
-        ctx: Context<DistributeLiquidityRewards>,
-        global_liquidity_score: u64,
-        reward_pool: u64, // how many tokens  want to distribute in total
+    /// Cancel a specific order by index. This frees up the slot.
+    ///   also "reward" the user’s liquidity_score based on how long the order was live.
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        order_index: u8,
     ) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        let user_state = &mut ctx.accounts.user_state;
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("order_index={}", order_index);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::cancel_order(ctx, order_index);
+        with_failure_log("cancel_order", Some(market), Some(status), params, result)
+    }
 
-        // If user’s liquidity_score is 0 or global_score is 0, nothing happens.
-        if user_state.liquidity_score == 0 || global_liquidity_score == 0 {
-            return Ok(());
-        }
+    /// Cancel every open order of the user on `side` whose current price is within
+    /// `min_price..=max_price`, e.g. all bids above a level, in one instruction. Pegged
+    /// orders match on their resolved price, and are left alone while it can't be resolved.
+    pub fn cancel_orders_in_range(
+        ctx: Context<CancelOrder>,
+        side: OrderSide,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("side={:?} min_price={} max_price={}", side, min_price, max_price);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::cancel_orders_in_range(ctx, side, min_price, max_price);
+        with_failure_log("cancel_orders_in_range", Some(market), Some(status), params, result)
+    }
 
-        let user_share = (user_state.liquidity_score as u128)
-            .checked_mul(reward_pool as u128)
-            .ok_or(FeeError::Overflow)?
-            / (global_liquidity_score as u128);
+    /// Permissionless crank: cancel every expired order of `user_state`, so the top of
+    /// book and the open order index stop showing them. Anyone can sign as
+    /// `user_authority`. These cancels don't count toward the owner's cancel penalty and
+    /// don't run the scoring plugin.
+    pub fn cancel_expired_orders(ctx: Context<CancelOrder>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::cancel_expired_orders(ctx);
+        with_failure_log("cancel_expired_orders", Some(market), Some(status), String::new, result)
+    }
 
-        //  just "emit" an event for demonstration. In real code, do an SPL token transfer.
-        emit!(LiquidityRewardsDistributed {
-            user: user_state.authority,
-            distributed_amount: user_share as u64,
-        });
+    /// Fill a maker’s order partially or fully. Taker pays fees, maker gets rebates,
+    /// referrer gets a small cut, and update liquidity scores.
+    ///
+    /// `taker_limit_price` is the taker's own limit (0 = no limit). When it crosses
+    /// deeper than the maker's price, the taker earns a share of that price improvement
+    /// as a claimable rebate, paid out of the net fee.
+    ///
+    /// `worst_price` is the taker's slippage bound (0 = none): the fill reverts if the
+    /// execution price with the taker fee applied is worse than it.
+    pub fn fill_order(
+        ctx: Context<FillOrder>,
+        maker_order_index: u8,
+        fill_size: u64,
+        taker_limit_price: u64,
+        worst_price: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "maker_order_index={} fill_size={} taker_limit_price={} worst_price={}",
+                maker_order_index, fill_size, taker_limit_price, worst_price
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::fill_order(
+            ctx,
+            maker_order_index,
+            fill_size,
+            taker_limit_price,
+            worst_price,
+            false,
+        );
+        with_failure_log("fill_order", Some(market), Some(status), params, result)
+    }
 
-        // Optionally track how many total tokens  distributed:
-        market_state.total_liquidity_rewards_distributed = market_state
-            .total_liquidity_rewards_distributed
-            .saturating_add(user_share as u64);
+    /// Take a maker's whole resting order or nothing: `fill_size` must equal the order's
+    /// `size_remaining`, so a taker racing another fill gets `FillSizeMismatch` rather
+    /// than a smaller fill. Otherwise the same as `fill_order` with no taker limit; both
+    /// take the whole-order short path of `execute_fill` when the order fills completely.
+    pub fn fill_order_exact(
+        ctx: Context<FillOrder>,
+        maker_order_index: u8,
+        fill_size: u64,
+        worst_price: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "maker_order_index={} fill_size={} worst_price={}",
+                maker_order_index, fill_size, worst_price
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::fill_order(ctx, maker_order_index, fill_size, 0, worst_price, true);
+        with_failure_log("fill_order_exact", Some(market), Some(status), params, result)
+    }
 
-        // Reset user’s liquidity score if desired
-        user_state.liquidity_score = 0;
+    /// View: what `fill_order` with the same arguments would charge and pay right now if
+    /// `user_state` took `maker_user`'s order, returned via return data. It runs the fill's
+    /// own `quote_fill`, checks and adjustments included, so frontends show exactly what
+    /// the program will charge.
+    pub fn get_effective_fees(
+        ctx: Context<GetEffectiveFees>,
+        maker_order_index: u8,
+        fill_size: u64,
+        taker_limit_price: u64,
+        worst_price: u64,
+    ) -> Result<FillQuote> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "maker_order_index={} fill_size={} taker_limit_price={} worst_price={}",
+                maker_order_index, fill_size, taker_limit_price, worst_price
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::get_effective_fees(
+            ctx,
+            maker_order_index,
+            fill_size,
+            taker_limit_price,
+            worst_price,
+        );
+        with_failure_log("get_effective_fees", Some(market), Some(status), params, result)
+    }
 
-        Ok(())
+    /// Allows the market authority to move `amount` of the retained fees into the liquidity
+    /// reward pool that `distribute_liquidity_rewards` pays out of.
+    pub fn fund_liquidity_rewards(ctx: Context<UpdateFeeParameters>, amount: u64) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("amount={}", amount);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::fund_liquidity_rewards(ctx, amount);
+        with_failure_log("fund_liquidity_rewards", Some(market), Some(status), params, result)
     }
 
-    /// Allows the market authority to withdraw accumulated fees from the program’s treasury.
+    /// Market authority only: distribute liquidity rewards to a specific user, their
+    /// score's share of the market's total liquidity score applied to the reward pool.
+    /// Both totals are kept on-chain, and each distribution takes its share out of both,
+    /// so every user's share is measured against the same pool per unit of score.
+    pub fn distribute_liquidity_rewards(ctx: Context<DistributeLiquidityRewards>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::distribute_liquidity_rewards(ctx);
+        with_failure_log(
+            "distribute_liquidity_rewards",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
+
+    /// Opt the signer in or out of liquidity rewards (some regulated entities cannot receive
+    /// incentive tokens). Liquidity score keeps accruing either way.
+    pub fn set_rewards_opt_out(ctx: Context<SetRewardsOptOut>, opt_out: bool) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let params = || format!("opt_out={}", opt_out);
+        let result = handlers::set_rewards_opt_out(ctx, opt_out);
+        with_failure_log("set_rewards_opt_out", Some(market), None, params, result)
+    }
+
+    /// Snapshot the signer's cumulative volume/rebate stats into a `VolumeAttestation` PDA
+    /// that partner protocols can verify: the account is owned by this program at an address
+    /// derived from (market, user), which stands in as the market's signature, and `digest`
+    /// commits to its contents.
+    pub fn create_attestation(ctx: Context<CreateAttestation>) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let result = handlers::create_attestation(ctx);
+        with_failure_log("create_attestation", Some(market), None, String::new, result)
+    }
+
+    /// Re-snapshot an existing attestation with the user's current stats.
+    pub fn refresh_attestation(ctx: Context<RefreshAttestation>) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let result = handlers::refresh_attestation(ctx);
+        with_failure_log("refresh_attestation", Some(market), None, String::new, result)
+    }
+
+    /// Clear the signer's stats for `strategy_id`, freeing its slot for a new tag. Fills of
+    /// still-open orders with that tag are no longer attributed.
+    pub fn reset_strategy_stats(ctx: Context<ResetStrategyStats>, strategy_id: u16) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let params = || format!("strategy_id={}", strategy_id);
+        let result = handlers::reset_strategy_stats(ctx, strategy_id);
+        with_failure_log("reset_strategy_stats", Some(market), None, params, result)
+    }
+
+    /// Claim the taker price-improvement rebates accrued by the signer.
     /// In real usage, you'd do an SPL token transfer here.
-    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
+    ///
+    /// `idempotency_key` is chosen by the client; a key seen in the user's recent claims is
+    /// rejected, so wallet retries across RPCs can never double-submit.
+    pub fn claim_taker_rebates(
+        ctx: Context<ClaimTakerRebates>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("idempotency_key={}", idempotency_key);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::claim_taker_rebates(ctx, idempotency_key);
+        with_failure_log("claim_taker_rebates", Some(market), Some(status), params, result)
+    }
 
-        // Check authority
-        require!(
-            market_state.authority == *ctx.accounts.authority.key,
-            FeeError::Unauthorized
-        );
+    /// Claim, as the signer, the referral rewards accrued on `user_state` (the referred
+    /// user). A confidential referral is opened here with its `salt`, so the relationship
+    /// becomes public only once the referrer chooses to get paid. `idempotency_key` is
+    /// recorded on the referred user's account, in the same history as their own claims.
+    /// Each fill's share expires on the market's claim deadline, like the user's rebates.
+    pub fn claim_referral_rewards(
+        ctx: Context<ClaimReferralRewards>,
+        salt: Option<[u8; 32]>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("salted={} idempotency_key={}", salt.is_some(), idempotency_key);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::claim_referral_rewards(ctx, salt, idempotency_key);
+        with_failure_log("claim_referral_rewards", Some(market), Some(status), params, result)
+    }
 
-        // Basic check if there are enough fees
-        require!(
-            market_state.total_fees_collected >= amount,
-            FeeError::InsufficientFunds
-        );
+    /// Claim the liquidity rewards credited to the signer by `distribute_liquidity_rewards`.
+    /// In real usage, you'd do an SPL token transfer here. See `claim_taker_rebates` for
+    /// `idempotency_key`.
+    pub fn claim_liquidity_rewards(
+        ctx: Context<ClaimLiquidityRewards>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("idempotency_key={}", idempotency_key);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::claim_liquidity_rewards(ctx, idempotency_key);
+        with_failure_log("claim_liquidity_rewards", Some(market), Some(status), params, result)
+    }
 
-        market_state.total_fees_collected = market_state
-            .total_fees_collected
-            .checked_sub(amount)
-            .ok_or(FeeError::Overflow)?;
+    /// Create the signer's stake position in this market, for `claim_and_stake` to fund.
+    pub fn open_stake_account(ctx: Context<OpenStakeAccount>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::open_stake_account(ctx);
+        with_failure_log("open_stake_account", Some(market), Some(status), String::new, result)
+    }
 
-        emit!(FeesWithdrawn {
-            authority: market_state.authority,
-            amount,
-        });
+    /// Claim all of the signer's rebates and rewards straight into their stake position,
+    /// with no intermediate token account. Same deadline and `idempotency_key` rules as
+    /// the plain claims.
+    pub fn claim_and_stake(ctx: Context<ClaimAndStake>, idempotency_key: u64) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("idempotency_key={}", idempotency_key);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::claim_and_stake(ctx, idempotency_key);
+        with_failure_log("claim_and_stake", Some(market), Some(status), params, result)
+    }
 
-        Ok(())
+    /// Start unstaking `amount` of the signer's stake. It stops counting toward their fee
+    /// tier right away and can be withdrawn after `UNSTAKE_COOLDOWN_SECS`.
+    pub fn request_unstake(ctx: Context<ClaimAndStake>, amount: u64) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("amount={}", amount);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::request_unstake(ctx, amount);
+        with_failure_log("request_unstake", Some(market), Some(status), params, result)
     }
-}
 
-// ----------------------------------
-// ACCOUNTS
-// ----------------------------------
+    /// Withdraw the signer's unstaked funds once their cooldown is over.
+    pub fn withdraw_unstaked(ctx: Context<ClaimAndStake>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::withdraw_unstaked(ctx);
+        with_failure_log("withdraw_unstaked", Some(market), Some(status), String::new, result)
+    }
 
-#[derive(Accounts)]
-#[instruction(maker_rebate_bps: u16, taker_fee_bps: u16, referral_bps: u16)]
-pub struct InitializeMarket<'info> {
-    #[account(init, payer = authority, space = 8 + MarketState::SIZE)]
-    pub market_state: Account<'info, MarketState>,
+    /// Create a named reward program on this market, run by the signer as sponsor. Several
+    /// programs (e.g. the operator's and a third party's token) can run side by side, each
+    /// with its own mint, active window, budget and eligibility rule. Names are per
+    /// sponsor, so nobody can take another sponsor's name first.
+    pub fn create_reward_program(
+        ctx: Context<CreateRewardProgram>,
+        name: [u8; 32],
+        reward_mint: Pubkey,
+        start_ts: i64,
+        end_ts: i64,
+        budget: u64,
+        epoch_reward_pool: u64,
+        eligibility: RewardEligibility,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "reward_mint={} start_ts={} end_ts={} budget={} epoch_reward_pool={} \
+                 eligibility={:?}",
+                reward_mint, start_ts, end_ts, budget, epoch_reward_pool, eligibility
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::create_reward_program(
+            ctx,
+            name,
+            reward_mint,
+            start_ts,
+            end_ts,
+            budget,
+            epoch_reward_pool,
+            eligibility,
+        );
+        with_failure_log("create_reward_program", Some(market), Some(status), params, result)
+    }
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    /// Open the signer's position in a reward program, so distributions can credit it.
+    pub fn open_reward_position(ctx: Context<OpenRewardPosition>) -> Result<()> {
+        let market = ctx.accounts.reward_program.market;
+        let result = handlers::open_reward_position(ctx);
+        with_failure_log("open_reward_position", Some(market), None, String::new, result)
+    }
 
-    /// System Program required for account creation
-    #[account(address = system_program::ID)]
-    pub system_program: Program<'info, System>,
-}
+    /// Close a reward position, refunding its rent to the user. The user can close it once
+    /// everything is claimed; the sponsor once the program has ended, so abandoned
+    /// positions can't keep the program (and the market) open.
+    pub fn close_reward_position(ctx: Context<CloseRewardPosition>) -> Result<()> {
+        let market = ctx.accounts.reward_program.market;
+        let result = handlers::close_reward_position(ctx);
+        with_failure_log("close_reward_position", Some(market), None, String::new, result)
+    }
 
-#[derive(Accounts)]
-pub struct UpdateFeeParameters<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
-    #[account(signer)]
-    pub authority: AccountInfo<'info>,
-}
+    /// Sponsor only: credit one user's share of the program's `epoch_reward_pool` for the
+    /// current epoch, by liquidity score like `distribute_liquidity_rewards` but without
+    /// consuming the score, since other programs share it. Shares are of the market's
+    /// `total_liquidity_score` as of the epoch's first distribution. At most once per user
+    /// per epoch.
+    pub fn distribute_program_rewards(ctx: Context<DistributeProgramRewards>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::distribute_program_rewards(ctx);
+        with_failure_log(
+            "distribute_program_rewards",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-#[derive(Accounts)]
-#[instruction(referrer: Option<Pubkey>)]
-pub struct RegisterUser<'info> {
-    #[account(
-        init,
-        payer = user_authority,
-        space = 8 + UserState::SIZE,
-        seeds = [b"user_state", user_authority.key().as_ref()],
-        bump
-    )]
-    pub user_state: Account<'info, UserState>,
+    /// Claim the signer's rewards from one reward program.
+    /// In real usage, you'd do an SPL token transfer of `reward_mint` here.
+    pub fn claim_program_rewards(ctx: Context<ClaimProgramRewards>) -> Result<()> {
+        let market = ctx.accounts.reward_program.market;
+        let result = handlers::claim_program_rewards(ctx);
+        with_failure_log("claim_program_rewards", Some(market), None, String::new, result)
+    }
 
-    #[account(mut)]
-    pub user_authority: Signer<'info>,
+    /// Permissionless crank: checkpoint the liquidity score of every open order of a user up
+    /// to now. With a liquidity band set, time is judged against the last
+    /// `REFERENCE_HISTORY` reference prices, so an order has to be checkpointed (by a fill,
+    /// a cancel or this crank) at least that often or it loses the oldest time.
+    pub fn accrue_liquidity_score(ctx: Context<AccrueLiquidityScore>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::accrue_liquidity_score(ctx);
+        with_failure_log("accrue_liquidity_score", Some(market), Some(status), String::new, result)
+    }
 
-    #[account(address = system_program::ID)]
-    pub system_program: Program<'info, System>,
-}
+    /// Permissionless crank: once a user's claim deadline has passed, sweep their unclaimed
+    /// rebates and rewards, and the referral rewards their referrer left unclaimed, back
+    /// into the market treasury.
+    pub fn forfeit_expired_claims(ctx: Context<ForfeitExpiredClaims>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::forfeit_expired_claims(ctx);
+        with_failure_log("forfeit_expired_claims", Some(market), Some(status), String::new, result)
+    }
 
-#[derive(Accounts)]
-pub struct PlaceOrder<'info> {
-    #[account(mut)]
-    pub user_state: Account<'info, UserState>,
-    #[account(signer)]
-    pub user_authority: AccountInfo<'info>,
-}
+    /// Mark the signer's `UserState` reclaimable and open the `RentEscrow` its rent will be
+    /// moved into. Once the account has been idle for `RENT_RECLAIM_DELAY_SECS`, anyone
+    /// may crank `reclaim_rent_after_inactivity` on it.
+    pub fn request_rent_reclaim(ctx: Context<RequestRentReclaim>) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let result = handlers::request_rent_reclaim(ctx);
+        with_failure_log("request_rent_reclaim", Some(market), None, String::new, result)
+    }
 
-#[derive(Accounts)]
-pub struct CancelOrder<'info> {
-    #[account(mut)]
-    pub user_state: Account<'info, UserState>,
-    #[account(signer)]
-    pub user_authority: AccountInfo<'info>,
-}
+    /// Undo `request_rent_reclaim` before the account has been reclaimed, closing the
+    /// still-empty `RentEscrow` back to the signer.
+    pub fn cancel_rent_reclaim(ctx: Context<CancelRentReclaim>) -> Result<()> {
+        let market = ctx.accounts.user_state.market;
+        let result = handlers::cancel_rent_reclaim(ctx);
+        with_failure_log("cancel_rent_reclaim", Some(market), None, String::new, result)
+    }
 
-#[derive(Accounts)]
-pub struct FillOrder<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+    /// Permissionless crank: close a `UserState` its owner marked reclaimable and that has
+    /// sat idle and empty for `RENT_RECLAIM_DELAY_SECS`. All of its lamports go to the
+    /// owner's `RentEscrow`, never to the caller, along with those of the user's
+    /// `UserIndexEntry`. Unless the user is the newest in the index, pass the entry linking
+    /// to theirs in `remaining_accounts`.
+    pub fn reclaim_rent_after_inactivity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ReclaimRent<'info>>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::reclaim_rent_after_inactivity(ctx);
+        with_failure_log(
+            "reclaim_rent_after_inactivity",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-    #[account(mut)]
-    pub maker_user: Account<'info, UserState>,
+    /// Withdraw the rent escrowed by `reclaim_rent_after_inactivity`, closing the
+    /// `RentEscrow` so its own rent comes back too.
+    pub fn withdraw_reclaimed_rent(ctx: Context<WithdrawReclaimedRent>) -> Result<()> {
+        let market = ctx.accounts.rent_escrow.market;
+        let result = handlers::withdraw_reclaimed_rent(ctx);
+        with_failure_log("withdraw_reclaimed_rent", Some(market), None, String::new, result)
+    }
 
-    #[account(mut)]
-    pub taker_user: Account<'info, UserState>,
+    /// Propose, as a member of the market's recovery committee, to hand the market to
+    /// `new_authority`. The proposer's approval is counted right away. Replaces a pending
+    /// proposal that lapsed without reaching the threshold.
+    pub fn propose_authority_recovery(
+        ctx: Context<AuthorityRecovery>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("new_authority={}", new_authority);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::propose_authority_recovery(ctx, new_authority);
+        with_failure_log("propose_authority_recovery", Some(market), Some(status), params, result)
+    }
 
-    #[account(signer)]
-    pub taker_authority: AccountInfo<'info>,
-}
+    /// Approve the pending recovery as another committee member. Reaching the threshold
+    /// starts the challenge period.
+    pub fn approve_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::approve_authority_recovery(ctx);
+        with_failure_log(
+            "approve_authority_recovery",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-#[derive(Accounts)]
-pub struct DistributeLiquidityRewards<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+    /// Vote against the pending recovery as a committee member, before it reaches the
+    /// threshold. Once enough members reject that it can't reach the threshold anymore, it
+    /// is dropped, so one rogue member can't hold recovery hostage with a bogus proposal.
+    pub fn reject_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::reject_authority_recovery(ctx);
+        with_failure_log(
+            "reject_authority_recovery",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-    #[account(mut)]
-    pub user_state: Account<'info, UserState>,
-    // Possibly your authority or a governance key that decides on distribution intervals
-    #[account(signer)]
-    pub authority: AccountInfo<'info>,
-}
+    /// Challenge the pending recovery as the current authority, which proves the key is not
+    /// lost. Possible until the recovery is executed.
+    pub fn cancel_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::cancel_authority_recovery(ctx);
+        with_failure_log(
+            "cancel_authority_recovery",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-#[derive(Accounts)]
-pub struct WithdrawFees<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+    /// Hand the market to the proposed authority once the timelock and the challenge
+    /// period have both run out. Anyone may crank this.
+    pub fn execute_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::execute_authority_recovery(ctx);
+        with_failure_log(
+            "execute_authority_recovery",
+            Some(market),
+            Some(status),
+            String::new,
+            result,
+        )
+    }
 
-    #[account(signer)]
-    pub authority: AccountInfo<'info>,
-}
+    /// Close an empty, sunsetting market: no registered users, no outstanding claims, an
+    /// empty treasury and reward pool, and no auxiliary PDAs left (`close_market_accounts`).
+    /// MarketState, MarketStats, MarketHealth, TopOfBook, OpenOrderIndex and EventCursor are
+    /// closed and their rent is refunded to the market authority.
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::close_market(ctx);
+        with_failure_log("close_market", Some(market), Some(status), String::new, result)
+    }
 
-// ----------------------------------
-// ACCOUNT DATA STRUCTS
-// ----------------------------------
+    /// Market authority only, once the market is winding down and every user has left:
+    /// close the market's `RewardProgram` and checkpoint PDAs, which `close_market` waits
+    /// for, and its users' `RegistrationRecord`s. Pass each account in `remaining_accounts`
+    /// followed by where its rent goes: the program's sponsor, the authority for
+    /// checkpoints, or whoever paid for a registration record. A reward program must have
+    /// no positions left (`close_reward_position`).
+    pub fn close_market_accounts<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CloseMarketAccounts<'info>>,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::close_market_accounts(ctx);
+        with_failure_log("close_market_accounts", Some(market), Some(status), String::new, result)
+    }
 
-/// MarketState holds global info like fee rates, fee collection, etc.
-#[account]
-pub struct MarketState {
-    pub authority: Pubkey,
-    pub maker_rebate_bps: u16,       // e.g., 2 bps
-    pub taker_fee_bps: u16,         // e.g., 5 bps
-    pub referral_bps: u16,          // e.g., 1 bps
-    pub total_fees_collected: u64,
-    pub total_liquidity_rewards_distributed: u64,
-}
+    /// Allows the market authority to withdraw accumulated fees from the program’s treasury.
+    /// In real usage, you'd do an SPL token transfer here.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("amount={}", amount);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::withdraw_fees(ctx, amount);
+        with_failure_log("withdraw_fees", Some(market), Some(status), params, result)
+    }
 
-impl MarketState {
-    pub const SIZE: usize = 
-          32 // authority
-        + 2  // maker_rebate_bps
-        + 2  // taker_fee_bps
-        + 2  // referral_bps
-        + 8  // total_fees_collected
-        + 8; // total_liquidity_rewards_distributed
+    /// Sets who must co-sign large withdrawals, and the amount of un-co-signed withdrawals
+    /// per `new_window_secs` above which `withdraw_fees` needs their approval. A threshold
+    /// of 0 turns it off. Signed by the market authority and, while co-signing is on, the
+    /// current cosigner (otherwise the new one, accepting the role), so neither can drop
+    /// or loosen it alone.
+    pub fn update_withdrawal_cosign(
+        ctx: Context<UpdateWithdrawalCosign>,
+        new_cosigner: Pubkey,
+        new_threshold: u64,
+        new_window_secs: i64,
+    ) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || {
+            format!(
+                "new_cosigner={} new_threshold={} new_window_secs={}",
+                new_cosigner, new_threshold, new_window_secs
+            )
+        };
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::update_withdrawal_cosign(
+            ctx,
+            new_cosigner,
+            new_threshold,
+            new_window_secs,
+        );
+        with_failure_log("update_withdrawal_cosign", Some(market), Some(status), params, result)
+    }
+
+    /// The cosigner approves up to `amount` of withdrawals that need co-signing; replaces
+    /// any earlier approval, so 0 revokes it. Used up as `withdraw_fees` draws on it.
+    pub fn approve_large_withdrawal(ctx: Context<ApproveWithdrawal>, amount: u64) -> Result<()> {
+        let market = ctx.accounts.market_state.key();
+        let params = || format!("amount={}", amount);
+        let status = ctx.accounts.market_state.status;
+        let result = handlers::approve_large_withdrawal(ctx, amount);
+        with_failure_log("approve_large_withdrawal", Some(market), Some(status), params, result)
+    }
 }
 
-/// Each user’s state includes:
-///   - maker/taker stats
-///   - referral info
-///   - liquidity score
-///   - active orders array (for demonstration)
-#[account]
+/// Bodies of the program's instructions; each `#[program]` entry point runs its body
+/// through `with_failure_log`.
+mod handlers {
+    use super::*;
+
+    pub fn initialize_global_config(ctx: Context<InitializeGlobalConfig>) -> Result<()> {
+        let global_config = &mut ctx.accounts.global_config;
+
+        global_config.authority = *ctx.accounts.authority.key;
+        global_config.disabled_instructions = 0;
+
+        Ok(())
+    }
+
+    pub fn set_instruction_enabled(
+        ctx: Context<SetInstructionEnabled>,
+        instruction: ProgramInstruction,
+        enabled: bool,
+    ) -> Result<()> {
+        let global_config = &mut ctx.accounts.global_config;
+        require!(
+            global_config.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        if enabled {
+            global_config.disabled_instructions &= !instruction.mask();
+        } else {
+            global_config.disabled_instructions |= instruction.mask();
+        }
+
+        emit!(InstructionSwitchChanged {
+            instruction,
+            enabled,
+            disabled_instructions: global_config.disabled_instructions,
+        });
+
+        Ok(())
+    }
+
+    pub fn initialize_market(
+        ctx: Context<InitializeMarket>,
+        maker_rebate_rate: u16,
+        taker_fee_rate: u16,
+        referral_rate: u16,
+        fee_precision: FeePrecision,
+        go_live_ts: i64, // 0 => live immediately
+        price_decimals: u8,
+        recovery_committee: Option<RecoveryCommittee>, // None => authority is never recoverable
+    ) -> Result<()> {
+        let market_state = &mut ctx.accounts.market_state;
+        let now = SysvarClock::load()?.now();
+        let params = MarketParams::new(
+            FeeParameters {
+                maker_rebate_rate,
+                taker_fee_rate,
+                referral_rate,
+            },
+            fee_precision,
+            price_decimals,
+            *ctx.accounts.authority.key,
+            recovery_committee,
+        );
+        market_state.initialize(*ctx.accounts.authority.key, &params, go_live_ts, now)?;
+
+        let market = market_state.key();
+        ctx.accounts.market_stats.initialize(market, now);
+        ctx.accounts
+            .market_health
+            .initialize(market, market_state, &ctx.accounts.global_config, now);
+        ctx.accounts.top_of_book.initialize(market);
+        ctx.accounts.open_order_index.initialize(market);
+        ctx.accounts.event_cursor.initialize(market);
+
+        Ok(())
+    }
+
+    pub fn migrate_market_state(ctx: Context<MigrateMarketState>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::MigrateAccounts)?;
+
+        let info = ctx.accounts.market_state.to_account_info();
+        let legacy = LegacyMarketState::load(info.owner, &info.try_borrow_data()?)?;
+        require!(
+            legacy.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let space = 8 + MarketState::SIZE;
+        let shortfall = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(info.lamports());
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(ctx.accounts.authority.key, info.key, shortfall),
+                &[
+                    ctx.accounts.authority.to_account_info(),
+                    info.clone(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+        info.realloc(space, true)?;
+        info.try_borrow_mut_data()?[8..].fill(0);
+
+        let now = SysvarClock::load()?.now();
+        let mut market_state = MarketState::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+        market_state.initialize(legacy.authority, &legacy.params(), 0, now)?;
+        market_state.total_fees_collected = legacy.total_fees_collected;
+        market_state.total_liquidity_rewards_distributed =
+            legacy.total_liquidity_rewards_distributed;
+        market_state.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+        let market = info.key();
+        ctx.accounts.market_stats.initialize(market, now);
+        ctx.accounts
+            .market_health
+            .initialize(market, &market_state, &ctx.accounts.global_config, now);
+        ctx.accounts.top_of_book.initialize(market);
+        ctx.accounts.open_order_index.initialize(market);
+        ctx.accounts.event_cursor.initialize(market);
+
+        emit!(MarketMigrated {
+            market,
+            authority: legacy.authority,
+            total_fees_collected: legacy.total_fees_collected,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_fee_parameters(
+        ctx: Context<UpdateFeeParameters>,
+        new_maker_rebate_rate: u16,
+        new_taker_fee_rate: u16,
+        new_referral_rate: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateFeeParameters)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        let params = FeeParameters {
+            maker_rebate_rate: new_maker_rebate_rate,
+            taker_fee_rate: new_taker_fee_rate,
+            referral_rate: new_referral_rate,
+        };
+        market_state.set_fee_parameters(&params)?;
+
+        emit!(FeeParametersUpdated {
+            market: market_state.key(),
+            maker_rebate_rate: new_maker_rebate_rate,
+            taker_fee_rate: new_taker_fee_rate,
+            referral_rate: new_referral_rate,
+            fee_precision: market_state.fee_precision,
+        });
+
+        Ok(())
+    }
+
+    pub fn batch_update_fees<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchUpdateFees<'info>>,
+        markets: Vec<Pubkey>,
+        params: FeeParameters,
+        fee_precision: FeePrecision,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateFeeParameters)?;
+        require!(
+            !markets.is_empty() && markets.len() == ctx.remaining_accounts.len(),
+            FeeError::InvalidBatch
+        );
+        require!(
+            markets.windows(2).all(|pair| pair[0] < pair[1]),
+            FeeError::InvalidBatch
+        );
+
+        for (market, info) in markets.iter().zip(ctx.remaining_accounts.iter()) {
+            require!(
+                info.key() == *market && info.is_writable,
+                FeeError::InvalidBatch
+            );
+            let mut market_state = Account::<MarketState>::try_from(info)?;
+            require!(
+                market_state.authority == *ctx.accounts.authority.key,
+                FeeError::Unauthorized
+            );
+            market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+            let restated = params.restate(
+                fee_precision,
+                market_state.fee_precision,
+                market_state.referral_basis,
+            )?;
+            market_state.set_fee_parameters(&restated)?;
+            market_state.exit(&crate::ID)?;
+
+            emit!(FeeParametersUpdated {
+                market: *market,
+                maker_rebate_rate: restated.maker_rebate_rate,
+                taker_fee_rate: restated.taker_fee_rate,
+                referral_rate: restated.referral_rate,
+                fee_precision: market_state.fee_precision,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn update_fee_tiers(
+        ctx: Context<UpdateFeeParameters>,
+        new_fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateFeeParameters)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        validate_fee_schedule(
+            market_state.maker_rebate_rate,
+            market_state.taker_fee_rate,
+            market_state.referral_rate,
+            market_state.referral_basis,
+            market_state.fee_precision,
+            &new_fee_tiers,
+        )?;
+
+        market_state.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        market_state.fee_tiers[..new_fee_tiers.len()].copy_from_slice(&new_fee_tiers);
+        market_state.fee_tier_count = new_fee_tiers.len() as u8;
+
+        emit!(FeeTiersUpdated {
+            market: market_state.key(),
+            fee_tiers: new_fee_tiers,
+        });
+
+        Ok(())
+    }
+
+    pub fn validate_market_params(
+        _ctx: Context<ValidateMarketParams>,
+        params: MarketParams,
+    ) -> Result<MarketParamsValidation> {
+        Ok(params.validate())
+    }
+
+    pub fn simulate_tier_assignment(
+        ctx: Context<SimulateTierAssignment>,
+        volume: u64,
+    ) -> Result<TierAssignment> {
+        Ok(ctx.accounts.market_state.tier_for_volume(volume))
+    }
+
+    pub fn update_referral_basis(
+        ctx: Context<UpdateFeeParameters>,
+        new_referral_basis: ReferralBasis,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateFeeParameters)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        validate_fee_schedule(
+            market_state.maker_rebate_rate,
+            market_state.taker_fee_rate,
+            market_state.referral_rate,
+            new_referral_basis,
+            market_state.fee_precision,
+            market_state.active_fee_tiers(),
+        )?;
+
+        market_state.referral_basis = new_referral_basis;
+
+        emit!(ReferralBasisUpdated {
+            market: market_state.key(),
+            referral_basis: new_referral_basis,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_fee_precision(
+        ctx: Context<UpdateFeeParameters>,
+        fee_precision: FeePrecision,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateFeeParameters)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        market_state.convert_fee_precision(fee_precision)?;
+
+        emit!(FeePrecisionUpdated {
+            market: market_state.key(),
+            fee_precision,
+            maker_rebate_rate: market_state.maker_rebate_rate,
+            taker_fee_rate: market_state.taker_fee_rate,
+            referral_rate: market_state.referral_rate,
+            fee_tiers: market_state.active_fee_tiers().to_vec(),
+        });
+
+        Ok(())
+    }
+
+    pub fn update_opt_out_policy(
+        ctx: Context<UpdateFeeParameters>,
+        new_opt_out_policy: OptOutPolicy,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.opt_out_policy = new_opt_out_policy;
+
+        emit!(OptOutPolicyUpdated {
+            market: market_state.key(),
+            opt_out_policy: new_opt_out_policy,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_price_improvement_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        new_price_improvement_rebate_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            new_price_improvement_rebate_bps <= BPS_DENOMINATOR,
+            FeeError::InvalidFeeConfiguration
+        );
+
+        market_state.price_improvement_rebate_bps = new_price_improvement_rebate_bps;
+
+        emit!(PriceImprovementRebateUpdated {
+            market: market_state.key(),
+            price_improvement_rebate_bps: new_price_improvement_rebate_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_cancel_penalty(
+        ctx: Context<UpdateFeeParameters>,
+        max_cancel_to_fill_bps: u32,
+        min_cancels: u32,
+        rebate_haircut_bps: u16,
+        placement_fee: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            rebate_haircut_bps <= BPS_DENOMINATOR,
+            FeeError::InvalidFeeConfiguration
+        );
+
+        market_state.penalty_cancel_to_fill_bps = max_cancel_to_fill_bps;
+        market_state.penalty_min_cancels = min_cancels;
+        market_state.penalty_rebate_haircut_bps = rebate_haircut_bps;
+        market_state.penalty_placement_fee = placement_fee;
+
+        emit!(CancelPenaltyUpdated {
+            market: market_state.key(),
+            max_cancel_to_fill_bps,
+            min_cancels,
+            rebate_haircut_bps,
+            placement_fee,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_market_status(
+        ctx: Context<UpdateFeeParameters>,
+        status: MarketStatus,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::SetMarketStatus)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            status != MarketStatus::Closed,
+            FeeError::InvalidStatusTransition
+        );
+
+        let previous = market_state.transition_to(status)?;
+
+        emit!(MarketStatusChanged {
+            market: market_state.key(),
+            from: previous,
+            to: status,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_side_incentive(
+        ctx: Context<UpdateFeeParameters>,
+        side: OrderSide,
+        multiplier_bps: u16,
+        expires_at: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        let now = SysvarClock::load()?.now();
+        require!(
+            multiplier_bps == 0 || expires_at > now,
+            FeeError::InvalidSideIncentive
+        );
+
+        market_state.side_incentive = SideIncentive {
+            side,
+            multiplier_bps,
+            starts_at: now,
+            expires_at,
+        };
+
+        emit!(SideIncentiveUpdated {
+            market: market_state.key(),
+            side,
+            multiplier_bps,
+            starts_at: now,
+            expires_at,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_first_fill_bonus(
+        ctx: Context<UpdateFeeParameters>,
+        bonus: FirstFillBonus,
+        promotion_budget: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            bonus.discount_bps <= BPS_DENOMINATOR && bonus.window_secs >= 0,
+            FeeError::InvalidFeeConfiguration
+        );
+        market_state.set_promotion_budget(promotion_budget)?;
+        market_state.first_fill_bonus = bonus;
+
+        emit!(FirstFillBonusUpdated {
+            market: market_state.key(),
+            max_fills: bonus.max_fills,
+            window_secs: bonus.window_secs,
+            discount_bps: bonus.discount_bps,
+            promotion_budget,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_liquidity_band(
+        ctx: Context<UpdateFeeParameters>,
+        liquidity_band_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            liquidity_band_bps <= BPS_DENOMINATOR,
+            FeeError::InvalidFeeConfiguration
+        );
+
+        market_state.liquidity_band_bps = liquidity_band_bps;
+
+        emit!(LiquidityBandUpdated {
+            market: market_state.key(),
+            liquidity_band_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_max_open_orders(
+        ctx: Context<UpdateFeeParameters>,
+        max_open_orders: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        validate_max_open_orders(max_open_orders)?;
+
+        market_state.max_open_orders = max_open_orders;
+
+        emit!(MaxOpenOrdersUpdated {
+            market: market_state.key(),
+            max_open_orders,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_order_ttl(
+        ctx: Context<UpdateFeeParameters>,
+        default_order_ttl_secs: i64,
+        max_order_ttl_secs: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        validate_order_ttl(default_order_ttl_secs, max_order_ttl_secs)?;
+
+        market_state.default_order_ttl_secs = default_order_ttl_secs;
+        market_state.max_order_ttl_secs = max_order_ttl_secs;
+
+        emit!(OrderTtlUpdated {
+            market: market_state.key(),
+            default_order_ttl_secs,
+            max_order_ttl_secs,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_top_level_only(
+        ctx: Context<UpdateFeeParameters>,
+        top_level_only: bool,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateAccessControls)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.top_level_only = top_level_only;
+
+        emit!(TopLevelOnlyUpdated {
+            market: market_state.key(),
+            top_level_only,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_promo_maker_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        promo_maker_rebate_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        validate_promo_maker_rebate(promo_maker_rebate_bps)?;
+
+        market_state.promo_maker_rebate_bps = promo_maker_rebate_bps;
+
+        emit!(PromoMakerRebateUpdated {
+            market: market_state.key(),
+            promo_maker_rebate_bps,
+            promotion_budget: market_state.promotion_budget,
+            promotion_reserved: market_state.promotion_reserved,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_promotion_epoch_cap(
+        ctx: Context<UpdateFeeParameters>,
+        promotion_epoch_cap: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.promotion_epoch_cap = promotion_epoch_cap;
+
+        emit!(PromotionEpochCapUpdated {
+            market: market_state.key(),
+            promotion_epoch_cap,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_skew_rebate(
+        ctx: Context<UpdateFeeParameters>,
+        skew_rebate: SkewRebate,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        skew_rebate.validate()?;
+
+        market_state.skew_rebate = skew_rebate;
+
+        emit!(SkewRebateUpdated {
+            market: market_state.key(),
+            max_bonus_bps: skew_rebate.max_bonus_bps,
+            max_haircut_bps: skew_rebate.max_haircut_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_referral_cap(
+        ctx: Context<UpdateFeeParameters>,
+        referral_cap_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(referral_cap_bps <= BPS_DENOMINATOR, FeeError::InvalidReferralCap);
+
+        market_state.referral_cap_bps = referral_cap_bps;
+
+        emit!(ReferralCapUpdated {
+            market: market_state.key(),
+            referral_cap_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_large_fill_threshold(
+        ctx: Context<UpdateFeeParameters>,
+        new_large_fill_notional: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.large_fill_notional = new_large_fill_notional;
+
+        emit!(LargeFillThresholdUpdated {
+            market: market_state.key(),
+            large_fill_notional: new_large_fill_notional,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_scoring_plugin(
+        ctx: Context<UpdateFeeParameters>,
+        new_scoring_program: Pubkey,
+        new_max_score_delta: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            new_scoring_program != crate::ID && new_max_score_delta <= i64::MAX as u64,
+            FeeError::InvalidScoringPlugin
+        );
+
+        market_state.scoring_program = new_scoring_program;
+        market_state.max_score_delta = new_max_score_delta;
+
+        emit!(ScoringPluginUpdated {
+            market: market_state.key(),
+            scoring_program: new_scoring_program,
+            max_score_delta: new_max_score_delta,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_min_fill_notional(
+        ctx: Context<UpdateFeeParameters>,
+        new_min_fill_notional: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.min_fill_notional = new_min_fill_notional;
+
+        emit!(MinFillNotionalUpdated {
+            market: market_state.key(),
+            min_fill_notional: new_min_fill_notional,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_epoch_config(
+        ctx: Context<UpdateFeeParameters>,
+        new_epoch_duration_secs: i64,
+        new_claim_deadline_secs: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            new_epoch_duration_secs >= 0 && new_claim_deadline_secs >= 0,
+            FeeError::InvalidEpochConfiguration
+        );
+
+        market_state.epoch_duration_secs = new_epoch_duration_secs;
+        market_state.claim_deadline_secs = new_claim_deadline_secs;
+
+        emit!(EpochConfigUpdated {
+            market: market_state.key(),
+            epoch_duration_secs: new_epoch_duration_secs,
+            claim_deadline_secs: new_claim_deadline_secs,
+        });
+
+        Ok(())
+    }
+
+    pub fn roll_epoch(ctx: Context<RollEpoch>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RollEpoch)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let clock = SysvarClock::load()?;
+        market_state.roll_epoch(&clock)?;
+        ctx.accounts
+            .market_health
+            .refresh(market_state, &ctx.accounts.global_config, clock.now());
+
+        emit!(EpochRolled {
+            market: market_state.key(),
+            epoch: market_state.current_epoch,
+            epoch_start_ts: market_state.epoch_start_ts,
+        });
+
+        Ok(())
+    }
+
+    pub fn checkpoint_order_book<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CheckpointOrderBook<'info>>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointOrderBook)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let checkpoint = &mut ctx.accounts.book_checkpoint;
+        checkpoint.market = market_state.key();
+        checkpoint.epoch = market_state.current_epoch;
+        checkpoint.restart(market_state.user_set_version, ctx.accounts.event_cursor.event_seq);
+        checkpoint.append_users(ctx.remaining_accounts)?;
+        market_state.add_auxiliary_account()?;
+
+        Ok(())
+    }
+
+    pub fn extend_book_checkpoint<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UpdateBookCheckpoint<'info>>,
+        restart: bool,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointOrderBook)?;
+
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let event_seq = ctx.accounts.event_cursor.event_seq;
+        let checkpoint = &mut ctx.accounts.book_checkpoint;
+        require!(!checkpoint.is_final(), FeeError::BookCheckpointFinal);
+        if restart {
+            checkpoint.restart(market_state.user_set_version, event_seq);
+        }
+        checkpoint.require_current(market_state, event_seq)?;
+        checkpoint.append_users(ctx.remaining_accounts)?;
+
+        Ok(())
+    }
+
+    pub fn finalize_book_checkpoint(ctx: Context<UpdateBookCheckpoint>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointOrderBook)?;
+
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let checkpoint = &mut ctx.accounts.book_checkpoint;
+        require!(!checkpoint.is_final(), FeeError::BookCheckpointFinal);
+        checkpoint.require_current(market_state, ctx.accounts.event_cursor.event_seq)?;
+        require!(
+            checkpoint.user_count as u64 == market_state.user_count,
+            FeeError::IncompleteBookAccounts
+        );
+        checkpoint.finalize(&SysvarClock::load()?);
+
+        emit!(BookCheckpointed {
+            market: checkpoint.market,
+            epoch: checkpoint.epoch,
+            order_count: checkpoint.summary.order_count,
+            book_hash: checkpoint.book_hash,
+        });
+
+        Ok(())
+    }
+
+    pub fn checkpoint_referral_graph<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CheckpointReferralGraph<'info>>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointReferralGraph)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let checkpoint = &mut ctx.accounts.referral_checkpoint;
+        checkpoint.market = market_state.key();
+        checkpoint.epoch = market_state.current_epoch;
+        checkpoint.restart(market_state.user_set_version);
+        checkpoint.append_users(ctx.remaining_accounts)?;
+        market_state.add_auxiliary_account()?;
+
+        Ok(())
+    }
+
+    pub fn extend_referral_checkpoint<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UpdateReferralCheckpoint<'info>>,
+        restart: bool,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointReferralGraph)?;
+
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let checkpoint = &mut ctx.accounts.referral_checkpoint;
+        require!(!checkpoint.is_final(), FeeError::ReferralCheckpointFinal);
+        if restart {
+            checkpoint.restart(market_state.user_set_version);
+        }
+        checkpoint.require_current(market_state)?;
+        checkpoint.append_users(ctx.remaining_accounts)?;
+
+        Ok(())
+    }
+
+    pub fn finalize_referral_checkpoint(ctx: Context<UpdateReferralCheckpoint>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CheckpointReferralGraph)?;
+
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let checkpoint = &mut ctx.accounts.referral_checkpoint;
+        require!(!checkpoint.is_final(), FeeError::ReferralCheckpointFinal);
+        checkpoint.require_current(market_state)?;
+        require!(
+            checkpoint.user_count as u64 == market_state.user_count,
+            FeeError::IncompleteReferralGraph
+        );
+
+        checkpoint.registered_users = market_state.user_count;
+        checkpoint.taken_at = SysvarClock::load()?.now();
+        checkpoint.root = checkpoint.frontier_root();
+
+        emit!(ReferralGraphCheckpointed {
+            market: checkpoint.market,
+            epoch: checkpoint.epoch,
+            user_count: checkpoint.user_count,
+            root: checkpoint.root,
+        });
+
+        Ok(())
+    }
+
+    pub fn refresh_market_health(ctx: Context<RefreshMarketHealth>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RefreshMarketHealth)?;
+
+        let clock = SysvarClock::load()?;
+        ctx.accounts.market_health.refresh(
+            &ctx.accounts.market_state,
+            &ctx.accounts.global_config,
+            clock.now(),
+        );
+
+        Ok(())
+    }
+
+    pub fn audit_accounts(ctx: Context<AuditAccounts>) -> Result<()> {
+        require!(
+            !ctx.remaining_accounts.is_empty()
+                && ctx.remaining_accounts.len() <= MAX_AUDIT_ACCOUNTS,
+            FeeError::InvalidAuditBatch
+        );
+
+        let mut findings = Vec::new();
+        for info in ctx.remaining_accounts.iter() {
+            let data = info.try_borrow_data()?;
+            let (kind, status) = audit_account(info.owner, &data);
+            if status != AccountAuditStatus::Healthy {
+                findings.push(AccountAuditFinding {
+                    address: info.key(),
+                    kind,
+                    status,
+                });
+            }
+        }
+
+        emit!(AccountsAudited {
+            checked: ctx.remaining_accounts.len() as u16,
+            findings,
+        });
+
+        Ok(())
+    }
+
+    pub fn report_rejection(
+        ctx: Context<ReportRejection>,
+        reason: RejectionReason,
+        count: u32,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ReportRejection)?;
+
+        let market_state = &ctx.accounts.market_state;
+        let reporter = ctx.accounts.reporter.key();
+        require!(
+            reporter == market_state.authority || reporter == market_state.rejection_reporter,
+            FeeError::Unauthorized
+        );
+        require!(count > 0, FeeError::InvalidRejectionReport);
+
+        let market_stats = &mut ctx.accounts.market_stats;
+        market_stats.record_rejections(reason, count, SysvarClock::load()?.now());
+
+        emit!(RejectionsReported {
+            market: market_state.key(),
+            reason,
+            count,
+            total: market_stats.rejections[reason as usize],
+        });
+
+        Ok(())
+    }
+
+    pub fn export_user_stats_page<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExportUserStatsPage<'info>>,
+        start: Pubkey,
+        count: u8,
+    ) -> Result<UserStatsPage> {
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            (count as usize) <= MAX_EXPORT_PAGE
+                && ctx.remaining_accounts.len() == 2 * count as usize,
+            FeeError::InvalidExportPage
+        );
+
+        let mut expected = if start == Pubkey::default() {
+            market_state.user_index_head
+        } else {
+            start
+        };
+        let mut users = Vec::with_capacity(count as usize);
+        for pair in ctx.remaining_accounts.chunks(2) {
+            let entry = Account::<UserIndexEntry>::try_from(&pair[0])?;
+            require!(
+                entry.market == market_state.key()
+                    && entry.user == expected
+                    && expected != Pubkey::default(),
+                FeeError::InvalidExportPage
+            );
+            require!(
+                pair[1].key() == entry.user_state,
+                FeeError::InvalidExportPage
+            );
+            let user_state = Account::<UserState>::try_from(&pair[1])?;
+            users.push(UserStatsSummary::from(&*user_state));
+            expected = entry.next;
+        }
+
+        Ok(UserStatsPage {
+            start,
+            next: expected,
+            total: market_state.user_count,
+            users,
+        })
+    }
+
+    pub fn update_oracle_config(
+        ctx: Context<UpdateFeeParameters>,
+        new_oracle_authority: Pubkey,
+        new_max_reference_age_secs: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            new_max_reference_age_secs >= 0,
+            FeeError::InvalidOracleConfiguration
+        );
+
+        market_state.oracle_authority = new_oracle_authority;
+        market_state.max_reference_age_secs = new_max_reference_age_secs;
+
+        emit!(OracleConfigUpdated {
+            market: market_state.key(),
+            oracle_authority: new_oracle_authority,
+            max_reference_age_secs: new_max_reference_age_secs,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_rejection_reporter(
+        ctx: Context<UpdateFeeParameters>,
+        new_rejection_reporter: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateMarketParams)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.rejection_reporter = new_rejection_reporter;
+
+        emit!(RejectionReporterUpdated {
+            market: market_state.key(),
+            rejection_reporter: new_rejection_reporter,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_reference_price(
+        ctx: Context<UpdateReferencePrice>,
+        reference_price: u64,
+        confidence: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateReferencePrice)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.oracle_authority == *ctx.accounts.oracle_authority.key,
+            FeeError::Unauthorized
+        );
+        require!(reference_price > 0, FeeError::ReferencePriceUnavailable);
+
+        let now = SysvarClock::load()?.now();
+        market_state.reference_price = reference_price;
+        market_state.reference_price_ts = now;
+        market_state.reference_confidence = confidence;
+        let overwritten = market_state.push_reference_snapshot(reference_price, now);
+
+        emit!(ReferencePriceUpdated {
+            market: market_state.key(),
+            reference_price,
+            confidence,
+            timestamp: now,
+            overwritten_ts: overwritten.map_or(0, |snapshot| snapshot.ts),
+        });
+
+        Ok(())
+    }
+
+    pub fn register_user(
+        ctx: Context<RegisterUser>,
+        referrer: Option<Pubkey>,
+        referrer_commitment: Option<[u8; 32]>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RegisterUser)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::RegisterUser)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.add_user()?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        user_state.initialize(
+            *ctx.accounts.user_authority.key,
+            market_state.key(),
+            referrer,
+            market_state.max_open_orders as usize,
+        );
+        let now = SysvarClock::load()?.now();
+        let first_registration = record_registration(
+            &ctx.accounts.registration_record,
+            &ctx.accounts.user_authority,
+            &ctx.accounts.system_program,
+            market_state.key(),
+            *ctx.accounts.user_authority.key,
+            ctx.bumps.registration_record,
+            now,
+        )?;
+        if first_registration {
+            user_state.registered_ts = now;
+        }
+        if let Some(commitment) = referrer_commitment {
+            require!(
+                referrer.is_none() && commitment != [0; 32],
+                FeeError::InvalidReferrer
+            );
+            user_state.referrer_commitment = commitment;
+        }
+        let (market, user_state_key) = (market_state.key(), user_state.key());
+        ctx.accounts.user_index_entry.append(
+            market_state,
+            market,
+            *ctx.accounts.user_authority.key,
+            user_state_key,
+        );
+
+        Ok(())
+    }
+
+    pub fn import_user(
+        ctx: Context<ImportUser>,
+        user: Pubkey,
+        legacy_stats: LegacyUserStats,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ImportUser)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::ImportUser)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        require!(
+            !market_state.is_live(SysvarClock::load()?.now()),
+            FeeError::ImportWindowClosed
+        );
+        market_state.add_user()?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        user_state.initialize(
+            user,
+            market_state.key(),
+            legacy_stats.referrer,
+            market_state.max_open_orders as usize,
+        );
+        user_state.import_legacy_stats(&legacy_stats);
+        record_registration(
+            &ctx.accounts.registration_record,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+            market_state.key(),
+            user,
+            ctx.bumps.registration_record,
+            SysvarClock::load()?.now(),
+        )?;
+        market_state.sync_liquidity_score(0, user_state.liquidity_score);
+        let (market, user_state_key) = (market_state.key(), user_state.key());
+        ctx.accounts.user_index_entry.append(market_state, market, user, user_state_key);
+
+        emit!(UserImported {
+            market: market_state.key(),
+            user,
+            maker_volume: legacy_stats.maker_volume,
+            taker_volume: legacy_stats.taker_volume,
+            liquidity_score: legacy_stats.liquidity_score,
+        });
+
+        Ok(())
+    }
+
+    pub fn migrate_user_state(ctx: Context<MigrateUserState>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::MigrateAccounts)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::RegisterUser)?;
+
+        let legacy_info = ctx.accounts.legacy_user_state.to_account_info();
+        let legacy = LegacyUserState::load(legacy_info.owner, &legacy_info.try_borrow_data()?)?;
+        let user = *ctx.accounts.user_authority.key;
+        require!(legacy.authority == user, FeeError::Unauthorized);
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.add_user()?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        user_state.initialize(
+            user,
+            market_state.key(),
+            legacy.referrer,
+            market_state.max_open_orders as usize,
+        );
+        user_state.import_legacy_stats(&legacy.stats());
+        record_registration(
+            &ctx.accounts.registration_record,
+            &ctx.accounts.user_authority,
+            &ctx.accounts.system_program,
+            market_state.key(),
+            user,
+            ctx.bumps.registration_record,
+            SysvarClock::load()?.now(),
+        )?;
+        market_state.sync_liquidity_score(0, user_state.liquidity_score);
+        let (market, user_state_key) = (market_state.key(), user_state.key());
+        ctx.accounts.user_index_entry.append(market_state, market, user, user_state_key);
+
+        let rent = legacy_info.lamports();
+        let recipient = ctx.accounts.user_authority.to_account_info();
+        **recipient.try_borrow_mut_lamports()? = recipient
+            .lamports()
+            .checked_add(rent)
+            .ok_or(FeeError::Overflow)?;
+        **legacy_info.try_borrow_mut_lamports()? = 0;
+        legacy_info.assign(&system_program::ID);
+        legacy_info.realloc(0, false)?;
+
+        emit!(UserMigrated {
+            market,
+            user,
+            maker_volume: legacy.maker_volume,
+            taker_volume: legacy.taker_volume,
+            liquidity_score: legacy.liquidity_score,
+            dropped_orders: legacy.open_order_count(),
+        });
+
+        Ok(())
+    }
+
+    pub fn resize_order_slots(ctx: Context<ResizeOrderSlots>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ResizeOrderSlots)?;
+
+        let order_slots = ctx.accounts.market_state.max_open_orders;
+        let user_state = &mut ctx.accounts.user_state;
+        let previous_slots = user_state.orders.len() as u8;
+        user_state.resize_order_slots(order_slots as usize)?;
+
+        emit!(OrderSlotsResized {
+            market: user_state.market,
+            user: user_state.authority,
+            previous_slots,
+            order_slots,
+        });
+
+        Ok(())
+    }
+
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        side: OrderSide,
+        price: u64,
+        size: u64,
+        expiry_timestamp: i64, // 0 => the market's default
+        price_mode: PriceMode,
+        strategy_id: u16,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::PlaceOrder)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::PlaceOrder)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        if let Some(penalty) = user_state.sync_activity_epoch(market_state) {
+            emit!(CancelPenaltyApplied::new(user_state, &penalty));
+        }
+        if user_state.is_penalized(market_state.current_epoch)
+            && market_state.penalty_placement_fee > 0
+        {
+            let placement_fee = market_state.penalty_placement_fee;
+            user_state.penalty_fees_paid = user_state
+                .penalty_fees_paid
+                .checked_add(placement_fee)
+                .ok_or(FeeError::Overflow)?;
+            market_state.total_fees_collected = market_state
+                .total_fees_collected
+                .checked_add(placement_fee)
+                .ok_or(FeeError::Overflow)?;
+            emit!(CancelPenaltyCharged {
+                user: user_state.authority,
+                epoch: market_state.current_epoch,
+                placement_fee,
+                rebate_haircut: 0,
+            });
+        }
+        user_state.epoch_places = user_state.epoch_places.saturating_add(1);
+        require!(
+            user_state.open_order_count() < market_state.max_open_orders as usize,
+            FeeError::OpenOrderLimitReached
+        );
+        // Pegged orders have no price until a fill resolves one.
+        if price_mode == PriceMode::Fixed {
+            market_state.require_min_order_notional(size, price)?;
+        }
+
+        let promo_reserve =
+            market_state.reserve_promotion(size, user_state.promo_reserved())?;
+
+        let clock = SysvarClock::load()?;
+        let expiry_timestamp = market_state.order_expiry(expiry_timestamp, clock.now())?;
+        user_state.last_activity_ts = clock.now();
+        let order_index = user_state.insert_order(Order {
+            side,
+            price,
+            size_remaining: size,
+            creation_timestamp: clock.now(),
+            expiry_timestamp,
+            price_mode,
+            strategy_id,
+            order_id: 0,
+            scored_until: 0,
+            promo_reserve,
+            book_level: 0,
+        })?;
+        let order_id = user_state.orders[order_index].order_id;
+        if price_mode == PriceMode::Fixed {
+            user_state.orders[order_index].book_level =
+                ctx.accounts.top_of_book.add(side, price, size);
+        }
+        ctx.accounts.open_order_index.insert(
+            OpenOrderEntry {
+                user: user_state.key(),
+                order_id,
+                order_index: order_index as u8,
+                side,
+                price_mode,
+                price,
+                size,
+                expiry_timestamp,
+            },
+            clock.now(),
+        );
+
+        ctx.accounts.market_stats.record_place(clock.now());
+        let market_health = &mut ctx.accounts.market_health;
+        market_health.record_order_opened();
+        market_health.refresh(market_state, &ctx.accounts.global_config, clock.now());
+
+        //  Emit an event (no longer holding a mutable reference to the array slot)
+        let event_cursor = &mut ctx.accounts.event_cursor;
+        let event = OrderPlaced {
+            user: user_state.authority,
+            order_id,
+            side,
+            price,
+            size,
+            expiry_timestamp,
+            price_mode,
+            strategy_id,
+            event_seq: event_cursor.next_seq(),
+        };
+        event_cursor.record(&event, Clock::get()?.slot);
+        emit!(event);
+
+        Ok(())
+    }
+
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        order_index: u8,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CancelOrder)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::CancelOrder)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        if let Some(penalty) = user_state.sync_activity_epoch(&ctx.accounts.market_state) {
+            emit!(CancelPenaltyApplied::new(user_state, &penalty));
+        }
+
+        let clock = SysvarClock::load()?;
+        ctx.accounts.cancel(order_index, &clock, ctx.remaining_accounts)
+    }
+
+    pub fn cancel_orders_in_range(
+        ctx: Context<CancelOrder>,
+        side: OrderSide,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CancelOrder)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::CancelOrder)?;
+        require!(min_price <= max_price, FeeError::InvalidPriceRange);
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        if let Some(penalty) = user_state.sync_activity_epoch(&ctx.accounts.market_state) {
+            emit!(CancelPenaltyApplied::new(user_state, &penalty));
+        }
+
+        let clock = SysvarClock::load()?;
+        let order_indexes = user_state.orders_in_range(
+            side,
+            min_price,
+            max_price,
+            &ctx.accounts.market_state,
+            clock.now(),
+        );
+        for order_index in order_indexes {
+            ctx.accounts.cancel(order_index, &clock, ctx.remaining_accounts)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel_expired_orders(ctx: Context<CancelOrder>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CancelOrder)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::CancelOrder)?;
+
+        let clock = SysvarClock::load()?;
+        let order_indexes = ctx.accounts.user_state.expired_orders(clock.now());
+        require!(!order_indexes.is_empty(), FeeError::NoExpiredOrders);
+        for order_index in order_indexes {
+            ctx.accounts.expire(order_index, &clock)?;
+        }
+
+        Ok(())
+    }
+
+    /// `fill_order` and, with `whole_order`, `fill_order_exact`.
+
+    pub fn fill_order(
+        ctx: Context<FillOrder>,
+        maker_order_index: u8,
+        fill_size: u64,
+        taker_limit_price: u64,
+        worst_price: u64,
+        whole_order: bool,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::FillOrder)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::FillOrder)?;
+
+        let clock = SysvarClock::load()?;
+        let book_imbalance_bps = ctx
+            .accounts
+            .market_state
+            .book_imbalance_bps(&ctx.accounts.open_order_index, clock.now())?;
+        let market_state = &mut ctx.accounts.market_state;
+        let maker_user = &mut ctx.accounts.maker_user;
+        let taker_user = &mut ctx.accounts.taker_user;
+
+        // Check signers
+        require!(
+            taker_user.authority == *ctx.accounts.taker_authority.key,
+            FeeError::Unauthorized
+        );
+        if whole_order {
+            maker_user.require_whole_order(maker_order_index, fill_size)?;
+        }
+
+        let fill = execute_fill(
+            market_state,
+            maker_user,
+            taker_user,
+            FillParams {
+                maker_order_index,
+                fill_size,
+                taker_limit_price,
+                worst_price,
+                book_imbalance_bps,
+            },
+            &clock,
+        )?;
+
+        record_fill(ctx.accounts, maker_order_index, &fill)?;
+        ctx.accounts.score_fill(ctx.remaining_accounts, &fill)
+    }
+
+    pub fn get_effective_fees(
+        ctx: Context<GetEffectiveFees>,
+        maker_order_index: u8,
+        fill_size: u64,
+        taker_limit_price: u64,
+        worst_price: u64,
+    ) -> Result<FillQuote> {
+        let market_state = &ctx.accounts.market_state;
+        let now = SysvarClock::load()?.now();
+        let book_imbalance_bps =
+            market_state.book_imbalance_bps(&ctx.accounts.open_order_index, now)?;
+        let mut maker_user = (*ctx.accounts.maker_user).clone();
+        maker_user.sync_activity_epoch(market_state);
+
+        quote_fill(
+            market_state,
+            &maker_user,
+            &ctx.accounts.user_state,
+            FillParams {
+                maker_order_index,
+                fill_size,
+                taker_limit_price,
+                worst_price,
+                book_imbalance_bps,
+            },
+            now,
+        )
+    }
+
+    pub fn fund_liquidity_rewards(ctx: Context<UpdateFeeParameters>, amount: u64) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::FundLiquidityRewards)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        require!(
+            market_state.total_fees_collected >= amount,
+            FeeError::InsufficientFunds
+        );
+
+        market_state.total_fees_collected -= amount;
+        market_state.liquidity_reward_pool = market_state
+            .liquidity_reward_pool
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+
+        emit!(LiquidityRewardsFunded {
+            market: market_state.key(),
+            amount,
+            liquidity_reward_pool: market_state.liquidity_reward_pool,
+        });
+
+        Ok(())
+    }
+
+    pub fn distribute_liquidity_rewards(ctx: Context<DistributeLiquidityRewards>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::DistributeLiquidityRewards)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+
+        let distribution =
+            market_state.distribute_liquidity_reward(user_state, SysvarClock::load()?.now())?;
+        if distribution.forfeited.total() > 0 {
+            emit!(ClaimsForfeited::new(user_state, distribution.forfeited));
+        }
+        if distribution.redirected {
+            emit!(LiquidityRewardsRedirected {
+                user: user_state.authority,
+                amount: distribution.amount,
+                policy: market_state.opt_out_policy,
+            });
+        } else if distribution.amount > 0 {
+            emit!(LiquidityRewardsDistributed {
+                user: user_state.authority,
+                distributed_amount: distribution.amount,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn set_rewards_opt_out(ctx: Context<SetRewardsOptOut>, opt_out: bool) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::SetRewardsOptOut)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        user_state.rewards_opt_out = opt_out;
+
+        emit!(RewardsOptOutChanged {
+            user: user_state.authority,
+            opt_out,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_attestation(ctx: Context<CreateAttestation>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::IssueAttestation)?;
+
+        let user_state = &ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let attestation = &mut ctx.accounts.attestation;
+        attestation.record(user_state, &SysvarClock::load()?);
+
+        emit!(AttestationIssued {
+            user: attestation.user,
+            market: attestation.market,
+            digest: attestation.digest,
+        });
+
+        Ok(())
+    }
+
+    pub fn refresh_attestation(ctx: Context<RefreshAttestation>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::IssueAttestation)?;
+
+        let user_state = &ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let attestation = &mut ctx.accounts.attestation;
+        attestation.record(user_state, &SysvarClock::load()?);
+
+        emit!(AttestationIssued {
+            user: attestation.user,
+            market: attestation.market,
+            digest: attestation.digest,
+        });
+
+        Ok(())
+    }
+
+    pub fn reset_strategy_stats(ctx: Context<ResetStrategyStats>, strategy_id: u16) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ResetStrategyStats)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let stats = user_state
+            .strategy_stats
+            .iter_mut()
+            .find(|stats| stats.strategy_id == strategy_id && strategy_id != UNTAGGED_STRATEGY)
+            .ok_or(FeeError::UnknownStrategy)?;
+        let cleared = *stats;
+        *stats = StrategyStats::default();
+
+        emit!(StrategyStatsReset {
+            user: user_state.authority,
+            strategy_id,
+            volume: cleared.volume,
+            rebates_earned: cleared.rebates_earned,
+            fill_count: cleared.fill_count,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_taker_rebates(
+        ctx: Context<ClaimTakerRebates>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimTakerRebates)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        user_state.record_claim_key(idempotency_key)?;
+
+        let expired = user_state.take_expired_claims(SysvarClock::load()?.now());
+        let amount = user_state.take_claims(true, false).rebates;
+        require!(
+            amount > 0,
+            if expired.rebates > 0 {
+                FeeError::ClaimDeadlinePassed
+            } else {
+                FeeError::NothingToClaim
+            }
+        );
+        if expired.total() > 0 {
+            market_state.forfeit_claims(expired)?;
+            emit!(ClaimsForfeited::new(user_state, expired));
+        }
+
+        market_state.outstanding_claims =
+            market_state.outstanding_claims.saturating_sub(amount);
+
+        emit!(TakerRebatesClaimed {
+            user: user_state.authority,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_referral_rewards(
+        ctx: Context<ClaimReferralRewards>,
+        salt: Option<[u8; 32]>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimReferralRewards)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        let referrer = *ctx.accounts.referrer.key;
+        require!(
+            user_state.is_referred_by(&referrer, salt.as_ref()),
+            FeeError::Unauthorized
+        );
+
+        user_state.record_claim_key(idempotency_key)?;
+
+        let expired = user_state.take_expired_claims(SysvarClock::load()?.now());
+        let amount = user_state.take_referral_rewards();
+        require!(
+            amount > 0,
+            if expired.referral_rewards > 0 {
+                FeeError::ClaimDeadlinePassed
+            } else {
+                FeeError::NothingToClaim
+            }
+        );
+        if expired.total() > 0 {
+            market_state.forfeit_claims(expired)?;
+            emit!(ClaimsForfeited::new(user_state, expired));
+        }
+
+        market_state.outstanding_claims =
+            market_state.outstanding_claims.saturating_sub(amount);
+
+        emit!(ReferralRewardsClaimed {
+            referrer,
+            referee: user_state.authority,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_liquidity_rewards(
+        ctx: Context<ClaimLiquidityRewards>,
+        idempotency_key: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimLiquidityRewards)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        user_state.record_claim_key(idempotency_key)?;
+
+        let expired = user_state.take_expired_claims(SysvarClock::load()?.now());
+        let amount = user_state.take_claims(false, true).rewards;
+        require!(
+            amount > 0,
+            if expired.rewards > 0 {
+                FeeError::ClaimDeadlinePassed
+            } else {
+                FeeError::NothingToClaim
+            }
+        );
+        if expired.total() > 0 {
+            market_state.forfeit_claims(expired)?;
+            emit!(ClaimsForfeited::new(user_state, expired));
+        }
+
+        market_state.outstanding_claims =
+            market_state.outstanding_claims.saturating_sub(amount);
+
+        emit!(LiquidityRewardsClaimed {
+            user: user_state.authority,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn open_stake_account(ctx: Context<OpenStakeAccount>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimAndStake)?;
+
+        let stake_account = &mut ctx.accounts.stake_account;
+        stake_account.user = *ctx.accounts.user_authority.key;
+        stake_account.market = ctx.accounts.market_state.key();
+        stake_account.staked_amount = 0;
+        stake_account.total_compounded = 0;
+        stake_account.last_stake_ts = 0;
+        stake_account.unstaking_amount = 0;
+        stake_account.unstake_available_ts = 0;
+
+        Ok(())
+    }
+
+    pub fn claim_and_stake(ctx: Context<ClaimAndStake>, idempotency_key: u64) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimAndStake)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        user_state.record_claim_key(idempotency_key)?;
+
+        let clock = SysvarClock::load()?;
+        let expired = user_state.take_expired_claims(clock.now());
+        let ClaimAmounts { rebates, rewards, .. } = user_state.take_claims(true, true);
+        let amount = rebates.checked_add(rewards).ok_or(FeeError::Overflow)?;
+        require!(
+            amount > 0,
+            if expired.total() > 0 {
+                FeeError::ClaimDeadlinePassed
+            } else {
+                FeeError::NothingToClaim
+            }
+        );
+        if expired.total() > 0 {
+            market_state.forfeit_claims(expired)?;
+            emit!(ClaimsForfeited::new(user_state, expired));
+        }
+
+        market_state.outstanding_claims = market_state.outstanding_claims.saturating_sub(amount);
+
+        let stake_account = &mut ctx.accounts.stake_account;
+        stake_account.compound(amount, &clock)?;
+        user_state.staked_amount = stake_account.staked_amount;
+
+        emit!(ClaimedAndStaked {
+            user: user_state.authority,
+            rebates,
+            rewards,
+            staked_amount: stake_account.staked_amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn request_unstake(ctx: Context<ClaimAndStake>, amount: u64) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimAndStake)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let stake_account = &mut ctx.accounts.stake_account;
+        stake_account.request_unstake(amount, &SysvarClock::load()?)?;
+        user_state.staked_amount = stake_account.staked_amount;
+
+        emit!(UnstakeRequested {
+            user: user_state.authority,
+            amount,
+            staked_amount: stake_account.staked_amount,
+            available_ts: stake_account.unstake_available_ts,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_unstaked(ctx: Context<ClaimAndStake>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ClaimAndStake)?;
+
+        let user_state = &ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let amount = ctx
+            .accounts
+            .stake_account
+            .withdraw_unstaked(&SysvarClock::load()?)?;
+
+        emit!(UnstakeWithdrawn {
+            user: user_state.authority,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_reward_program(
+        ctx: Context<CreateRewardProgram>,
+        name: [u8; 32],
+        reward_mint: Pubkey,
+        start_ts: i64,
+        end_ts: i64,
+        budget: u64,
+        epoch_reward_pool: u64,
+        eligibility: RewardEligibility,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RewardPrograms)?;
+        require!(start_ts < end_ts, FeeError::InvalidRewardProgram);
+        require!(epoch_reward_pool <= budget, FeeError::InvalidRewardProgram);
+
+        let reward_program = &mut ctx.accounts.reward_program;
+        reward_program.market = ctx.accounts.market_state.key();
+        reward_program.sponsor = *ctx.accounts.sponsor.key;
+        reward_program.name = name;
+        reward_program.reward_mint = reward_mint;
+        reward_program.start_ts = start_ts;
+        reward_program.end_ts = end_ts;
+        reward_program.budget = budget;
+        reward_program.epoch_reward_pool = epoch_reward_pool;
+        reward_program.distributed = 0;
+        reward_program.eligibility = eligibility;
+        reward_program.position_count = 0;
+        reward_program.pool_epoch = None;
+        reward_program.epoch_total_score = 0;
+        ctx.accounts.market_state.add_auxiliary_account()?;
+
+        emit!(RewardProgramCreated {
+            market: reward_program.market,
+            reward_program: reward_program.key(),
+            sponsor: reward_program.sponsor,
+            name,
+            reward_mint,
+            budget,
+            epoch_reward_pool,
+        });
+
+        Ok(())
+    }
+
+    pub fn open_reward_position(ctx: Context<OpenRewardPosition>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RewardPrograms)?;
+
+        let reward_position = &mut ctx.accounts.reward_position;
+        reward_position.reward_program = ctx.accounts.reward_program.key();
+        reward_position.user = *ctx.accounts.user_authority.key;
+        reward_position.claimable = 0;
+        reward_position.total_earned = 0;
+        reward_position.last_distributed_epoch = None;
+
+        let reward_program = &mut ctx.accounts.reward_program;
+        reward_program.position_count = reward_program
+            .position_count
+            .checked_add(1)
+            .ok_or(FeeError::Overflow)?;
+
+        Ok(())
+    }
+
+    pub fn close_reward_position(ctx: Context<CloseRewardPosition>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RewardPrograms)?;
+
+        let reward_program = &mut ctx.accounts.reward_program;
+        let reward_position = &ctx.accounts.reward_position;
+        require!(
+            reward_position.is_closable_by(
+                ctx.accounts.signer.key,
+                reward_program,
+                SysvarClock::load()?.now()
+            ),
+            FeeError::RewardPositionNotClosable
+        );
+        reward_program.position_count = reward_program.position_count.saturating_sub(1);
+
+        emit!(RewardPositionClosed {
+            reward_program: reward_program.key(),
+            user: reward_position.user,
+            forfeited: reward_position.claimable,
+        });
+
+        Ok(())
+    }
+
+    pub fn distribute_program_rewards(ctx: Context<DistributeProgramRewards>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RewardPrograms)?;
+
+        let reward_program = &mut ctx.accounts.reward_program;
+        require!(
+            reward_program.sponsor == *ctx.accounts.sponsor.key,
+            FeeError::Unauthorized
+        );
+
+        let market_state = &ctx.accounts.market_state;
+        let amount = reward_program.credit(
+            &mut ctx.accounts.reward_position,
+            &ctx.accounts.user_state,
+            market_state.current_epoch,
+            market_state.total_liquidity_score,
+            SysvarClock::load()?.now(),
+        )?;
+
+        emit!(ProgramRewardsDistributed {
+            reward_program: reward_program.key(),
+            user: ctx.accounts.user_state.authority,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_program_rewards(ctx: Context<ClaimProgramRewards>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::RewardPrograms)?;
+
+        let reward_position = &mut ctx.accounts.reward_position;
+        let amount = reward_position.claimable;
+        require!(amount > 0, FeeError::NothingToClaim);
+        reward_position.claimable = 0;
+
+        emit!(ProgramRewardsClaimed {
+            reward_program: reward_position.reward_program,
+            user: reward_position.user,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn accrue_liquidity_score(ctx: Context<AccrueLiquidityScore>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AccrueLiquidityScore)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+        let before = user_state.liquidity_score;
+        let added = user_state.accrue_open_orders(market_state, SysvarClock::load()?.now());
+        market_state.sync_liquidity_score(before, user_state.liquidity_score);
+
+        emit!(LiquidityScoreAccrued {
+            user: user_state.authority,
+            added,
+            liquidity_score: user_state.liquidity_score,
+        });
+
+        Ok(())
+    }
+
+    pub fn forfeit_expired_claims(ctx: Context<ForfeitExpiredClaims>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ForfeitExpiredClaims)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        let user_state = &mut ctx.accounts.user_state;
+
+        let expired = user_state.take_expired_claims(SysvarClock::load()?.now());
+        require!(expired.total() > 0, FeeError::ClaimDeadlineNotReached);
+        market_state.forfeit_claims(expired)?;
+
+        emit!(ClaimsForfeited::new(user_state, expired));
+
+        Ok(())
+    }
+
+    pub fn request_rent_reclaim(ctx: Context<RequestRentReclaim>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ReclaimRent)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        let now = SysvarClock::load()?.now();
+        user_state.reclaim_requested_ts = now;
+
+        let rent_escrow = &mut ctx.accounts.rent_escrow;
+        rent_escrow.user = user_state.authority;
+        rent_escrow.market = user_state.market;
+        rent_escrow.escrowed_lamports = 0;
+        rent_escrow.reclaimed_ts = 0;
+
+        emit!(RentReclaimRequested {
+            market: user_state.market,
+            user: user_state.authority,
+            reclaimable_at: now.saturating_add(RENT_RECLAIM_DELAY_SECS),
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_rent_reclaim(ctx: Context<CancelRentReclaim>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ReclaimRent)?;
+
+        let user_state = &mut ctx.accounts.user_state;
+        require!(
+            user_state.authority == *ctx.accounts.user_authority.key,
+            FeeError::Unauthorized
+        );
+
+        user_state.reclaim_requested_ts = 0;
+
+        emit!(RentReclaimCanceled {
+            market: user_state.market,
+            user: user_state.authority,
+        });
+
+        Ok(())
+    }
+
+    pub fn reclaim_rent_after_inactivity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ReclaimRent<'info>>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ReclaimRent)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::ReclaimRent)?;
+
+        let now = SysvarClock::load()?.now();
+        let user_state = &ctx.accounts.user_state;
+        require!(
+            user_state.is_rent_reclaimable(now),
+            FeeError::RentNotReclaimable
+        );
+
+        let user_index_entry = &ctx.accounts.user_index_entry;
+        let lamports = user_state
+            .to_account_info()
+            .lamports()
+            .checked_add(user_index_entry.to_account_info().lamports())
+            .ok_or(FeeError::Overflow)?;
+        let rent_escrow = &mut ctx.accounts.rent_escrow;
+        rent_escrow.escrowed_lamports = lamports;
+        rent_escrow.reclaimed_ts = now;
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.remove_user()?;
+        market_state.sync_liquidity_score(user_state.liquidity_score, 0);
+
+        match ctx.remaining_accounts.first() {
+            Some(info) => {
+                let mut previous = Account::<UserIndexEntry>::try_from(info)?;
+                user_index_entry.unlink(market_state, Some(&mut previous))?;
+                previous.exit(ctx.program_id)?;
+            }
+            None => user_index_entry.unlink(market_state, None)?,
+        }
+
+        emit!(UserRentReclaimed {
+            market: market_state.key(),
+            user: rent_escrow.user,
+            lamports,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_reclaimed_rent(ctx: Context<WithdrawReclaimedRent>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ReclaimRent)?;
+
+        let rent_escrow = &ctx.accounts.rent_escrow;
+        require!(rent_escrow.reclaimed_ts != 0, FeeError::RentNotReclaimed);
+
+        emit!(ReclaimedRentWithdrawn {
+            market: rent_escrow.market,
+            user: rent_escrow.user,
+            lamports: rent_escrow.to_account_info().lamports(),
+        });
+
+        Ok(())
+    }
+
+    pub fn propose_authority_recovery(
+        ctx: Context<AuthorityRecovery>,
+        new_authority: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AuthorityRecovery)?;
+
+        let now = SysvarClock::load()?.now();
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.propose_recovery(ctx.accounts.signer.key, new_authority, now)?;
+
+        emit!(AuthorityRecoveryProposed {
+            market: market_state.key(),
+            proposer: *ctx.accounts.signer.key,
+            new_authority,
+            executable_ts: now.saturating_add(RECOVERY_TIMELOCK_SECS),
+        });
+
+        Ok(())
+    }
+
+    pub fn approve_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AuthorityRecovery)?;
+
+        let now = SysvarClock::load()?.now();
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.approve_recovery(ctx.accounts.signer.key, now)?;
+
+        let pending = market_state.pending_recovery;
+        emit!(AuthorityRecoveryApproved {
+            market: market_state.key(),
+            member: *ctx.accounts.signer.key,
+            approvals: pending.approvals.count_ones() as u8,
+            approved_ts: pending.approved_ts,
+        });
+
+        Ok(())
+    }
+
+    pub fn reject_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AuthorityRecovery)?;
+
+        let now = SysvarClock::load()?.now();
+        let market_state = &mut ctx.accounts.market_state;
+        let pending = market_state.pending_recovery;
+        let dropped = market_state.reject_recovery(ctx.accounts.signer.key, now)?;
+
+        emit!(AuthorityRecoveryRejected {
+            market: market_state.key(),
+            member: *ctx.accounts.signer.key,
+            new_authority: pending.new_authority,
+            rejections: market_state.pending_recovery.rejections.count_ones() as u8,
+            dropped,
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AuthorityRecovery)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.signer.key,
+            FeeError::Unauthorized
+        );
+        let pending = market_state.pending_recovery;
+        require!(pending.is_pending(), FeeError::NoPendingRecovery);
+        market_state.pending_recovery = RecoveryProposal::default();
+
+        emit!(AuthorityRecoveryCanceled {
+            market: market_state.key(),
+            new_authority: pending.new_authority,
+        });
+
+        Ok(())
+    }
+
+    pub fn execute_authority_recovery(ctx: Context<AuthorityRecovery>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::AuthorityRecovery)?;
+
+        let now = SysvarClock::load()?.now();
+        let market_state = &mut ctx.accounts.market_state;
+        let previous_authority = market_state.authority;
+        market_state.execute_recovery(now)?;
+
+        emit!(AuthorityRecovered {
+            market: market_state.key(),
+            previous_authority,
+            new_authority: market_state.authority,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CloseMarket)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::CloseMarket)?;
+
+        let market_state = &ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        require!(market_state.is_closable(), FeeError::MarketNotEmpty);
+
+        let market_state = &mut ctx.accounts.market_state;
+        let previous = market_state.transition_to(MarketStatus::Closed)?;
+        emit!(MarketStatusChanged {
+            market: market_state.key(),
+            from: previous,
+            to: MarketStatus::Closed,
+        });
+        emit!(MarketClosed {
+            market: market_state.key(),
+            authority: market_state.authority,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_market_accounts<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CloseMarketAccounts<'info>>,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::CloseMarket)?;
+        ctx.accounts
+            .market_state
+            .require_allowed(ProgramInstruction::CloseMarket)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        require!(market_state.user_count == 0, FeeError::MarketNotEmpty);
+        require!(
+            ctx.remaining_accounts.len() % 2 == 0,
+            FeeError::InvalidMarketAccount
+        );
+
+        let market = market_state.key();
+        for pair in ctx.remaining_accounts.chunks(2) {
+            let (info, rent_recipient) = (&pair[0], &pair[1]);
+            let (kind, status) = audit_account(info.owner, &info.try_borrow_data()?);
+            require!(
+                status == AccountAuditStatus::Healthy,
+                FeeError::InvalidMarketAccount
+            );
+            let (account_market, recipient, auxiliary) = match kind {
+                Some(AccountKind::RewardProgram) => {
+                    let reward_program = Account::<RewardProgram>::try_from(info)?;
+                    require!(
+                        reward_program.position_count == 0,
+                        FeeError::RewardPositionsOpen
+                    );
+                    (reward_program.market, reward_program.sponsor, true)
+                }
+                Some(AccountKind::BookCheckpoint) => {
+                    let checkpoint = Account::<BookCheckpoint>::try_from(info)?;
+                    (checkpoint.market, market_state.authority, true)
+                }
+                Some(AccountKind::ReferralCheckpoint) => {
+                    let checkpoint = Account::<ReferralCheckpoint>::try_from(info)?;
+                    (checkpoint.market, market_state.authority, true)
+                }
+                // Not counted in `auxiliary_accounts`: users create these, not the market.
+                Some(AccountKind::RegistrationRecord) => {
+                    let record = Account::<RegistrationRecord>::try_from(info)?;
+                    (record.market, record.payer, false)
+                }
+                _ => return err!(FeeError::InvalidMarketAccount),
+            };
+            require!(
+                account_market == market && rent_recipient.key() == recipient,
+                FeeError::InvalidMarketAccount
+            );
+
+            let rent = info.lamports();
+            **rent_recipient.try_borrow_mut_lamports()? = rent_recipient
+                .lamports()
+                .checked_add(rent)
+                .ok_or(FeeError::Overflow)?;
+            **info.try_borrow_mut_lamports()? = 0;
+            info.assign(&system_program::ID);
+            info.realloc(0, false)?;
+            if auxiliary {
+                market_state.auxiliary_accounts =
+                    market_state.auxiliary_accounts.saturating_sub(1);
+            }
+        }
+
+        emit!(MarketAccountsClosed {
+            market,
+            closed: (ctx.remaining_accounts.len() / 2) as u32,
+            remaining: market_state.auxiliary_accounts,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::WithdrawFees)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+
+        // Check authority
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        // Basic check if there are enough fees
+        require!(
+            market_state.total_fees_collected >= amount,
+            FeeError::InsufficientFunds
+        );
+        let now = SysvarClock::load()?.now();
+        let cosigned = market_state.authorize_withdrawal(amount, now)?;
+
+        market_state.total_fees_collected = market_state
+            .total_fees_collected
+            .checked_sub(amount)
+            .ok_or(FeeError::Overflow)?;
+
+        emit!(FeesWithdrawn {
+            authority: market_state.authority,
+            amount,
+            cosigned,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_withdrawal_cosign(
+        ctx: Context<UpdateWithdrawalCosign>,
+        new_cosigner: Pubkey,
+        new_threshold: u64,
+        new_window_secs: i64,
+    ) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::UpdateAccessControls)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.authority == *ctx.accounts.authority.key,
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+        let required_cosigner = if market_state.withdrawal_cosign_threshold > 0 {
+            market_state.withdrawal_cosigner
+        } else {
+            new_cosigner
+        };
+        require!(
+            ctx.accounts.cosigner.key() == required_cosigner,
+            FeeError::Unauthorized
+        );
+        require!(
+            new_threshold == 0
+                || (new_cosigner != Pubkey::default() && new_cosigner != market_state.authority),
+            FeeError::InvalidWithdrawalCosigner
+        );
+        require!(
+            new_threshold == 0 || new_window_secs > 0,
+            FeeError::InvalidWithdrawalWindow
+        );
+
+        market_state.withdrawal_cosigner = new_cosigner;
+        market_state.withdrawal_cosign_threshold = new_threshold;
+        market_state.withdrawal_window_secs = new_window_secs;
+        market_state.approved_withdrawal = 0;
+
+        emit!(WithdrawalCosignUpdated {
+            market: market_state.key(),
+            cosigner: new_cosigner,
+            threshold: new_threshold,
+            window_secs: new_window_secs,
+        });
+
+        Ok(())
+    }
+
+    pub fn approve_large_withdrawal(ctx: Context<ApproveWithdrawal>, amount: u64) -> Result<()> {
+        ctx.accounts
+            .global_config
+            .require_enabled(ProgramInstruction::ApproveLargeWithdrawal)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(
+            market_state.withdrawal_cosign_threshold > 0
+                && market_state.withdrawal_cosigner == ctx.accounts.cosigner.key(),
+            FeeError::Unauthorized
+        );
+        market_state.require_caller_allowed(&ctx.accounts.instructions)?;
+
+        market_state.approved_withdrawal = amount;
+
+        emit!(LargeWithdrawalApproved {
+            market: market_state.key(),
+            cosigner: market_state.withdrawal_cosigner,
+            amount,
+        });
+
+        Ok(())
+    }
+}
+
+// ----------------------------------
+// ACCOUNTS
+// ----------------------------------
+
+#[derive(Accounts)]
+pub struct InitializeGlobalConfig<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + GlobalConfig::SIZE,
+        seeds = [pda::GLOBAL_CONFIG_SEED],
+        bump
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetInstructionEnabled<'info> {
+    #[account(mut, seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(maker_rebate_rate: u16, taker_fee_rate: u16, referral_rate: u16)]
+pub struct InitializeMarket<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(init, payer = authority, space = 8 + MarketState::SIZE)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + MarketStats::SIZE,
+        seeds = [pda::MARKET_STATS_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + MarketHealth::SIZE,
+        seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_health: Account<'info, MarketHealth>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + TopOfBook::SIZE,
+        seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub top_of_book: Account<'info, TopOfBook>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + OpenOrderIndex::SIZE,
+        seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + EventCursor::SIZE,
+        seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// System Program required for account creation
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarketState<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    /// A `MarketState` in the legacy layout, which no longer deserializes; checked by
+    /// `LegacyMarketState::load`, then rewritten in place.
+    #[account(mut)]
+    pub market_state: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + MarketStats::SIZE,
+        seeds = [pda::MARKET_STATS_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + MarketHealth::SIZE,
+        seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_health: Account<'info, MarketHealth>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + TopOfBook::SIZE,
+        seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub top_of_book: Account<'info, TopOfBook>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + OpenOrderIndex::SIZE,
+        seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + EventCursor::SIZE,
+        seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFeeParameters<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    /// The instructions sysvar, read by `MarketState::require_caller_allowed`.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AuthorityRecovery<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+    /// A committee member, the current authority or, to execute, any cranker.
+    pub signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BatchUpdateFees<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+    /// The instructions sysvar, read by `MarketState::require_caller_allowed`.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReferencePrice<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+    #[account(signer)]
+    pub oracle_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(referrer: Option<Pubkey>, referrer_commitment: Option<[u8; 32]>)]
+pub struct RegisterUser<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + UserState::space(market_state.max_open_orders as usize),
+        seeds = [pda::USER_STATE_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + UserIndexEntry::SIZE,
+        seeds = [pda::USER_INDEX_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub user_index_entry: Account<'info, UserIndexEntry>,
+
+    /// `RegistrationRecord`, created by the handler on the user's first registration.
+    #[account(
+        mut,
+        seeds = [
+            pda::REGISTRATION_SEED,
+            market_state.key().as_ref(),
+            user_authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub registration_record: AccountInfo<'info>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct ImportUser<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + UserState::space(market_state.max_open_orders as usize),
+        seeds = [pda::USER_STATE_SEED, market_state.key().as_ref(), user.as_ref()],
+        bump
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + UserIndexEntry::SIZE,
+        seeds = [pda::USER_INDEX_SEED, market_state.key().as_ref(), user.as_ref()],
+        bump
+    )]
+    pub user_index_entry: Account<'info, UserIndexEntry>,
+
+    /// `RegistrationRecord`, created by the handler unless the user registered before.
+    #[account(
+        mut,
+        seeds = [pda::REGISTRATION_SEED, market_state.key().as_ref(), user.as_ref()],
+        bump
+    )]
+    pub registration_record: AccountInfo<'info>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserState<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    /// The signer's `UserState` in the legacy layout, at the legacy PDA of
+    /// `["user_state", user]`; checked by `LegacyUserState::load`, then closed.
+    #[account(
+        mut,
+        seeds = [pda::USER_STATE_SEED, user_authority.key().as_ref()],
+        bump
+    )]
+    pub legacy_user_state: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + UserState::space(market_state.max_open_orders as usize),
+        seeds = [pda::USER_STATE_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + UserIndexEntry::SIZE,
+        seeds = [pda::USER_INDEX_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub user_index_entry: Account<'info, UserIndexEntry>,
+
+    /// `RegistrationRecord`, created by the handler unless the user registered before.
+    #[account(
+        mut,
+        seeds = [
+            pda::REGISTRATION_SEED,
+            market_state.key().as_ref(),
+            user_authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub registration_record: AccountInfo<'info>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ResizeOrderSlots<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [pda::USER_STATE_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump,
+        realloc = 8 + UserState::space(market_state.max_open_orders as usize),
+        realloc::payer = user_authority,
+        realloc::zero = false
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExportUserStatsPage<'info> {
+    pub market_state: Account<'info, MarketState>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceOrder<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub market_state: Box<Account<'info, MarketState>>,
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+    #[account(mut, seeds = [pda::MARKET_STATS_SEED, user_state.market.as_ref()], bump)]
+    pub market_stats: Account<'info, MarketStats>,
+    #[account(mut, seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()], bump)]
+    pub market_health: Account<'info, MarketHealth>,
+    #[account(mut, seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()], bump)]
+    pub top_of_book: Box<Account<'info, TopOfBook>>,
+    #[account(mut, seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()], bump)]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+    #[account(mut, seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()], bump)]
+    pub event_cursor: Account<'info, EventCursor>,
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)] // releases promotion reserves
+    pub market_state: Box<Account<'info, MarketState>>,
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+    #[account(mut, seeds = [pda::MARKET_STATS_SEED, user_state.market.as_ref()], bump)]
+    pub market_stats: Account<'info, MarketStats>,
+    #[account(mut, seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()], bump)]
+    pub market_health: Account<'info, MarketHealth>,
+    #[account(mut, seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()], bump)]
+    pub top_of_book: Box<Account<'info, TopOfBook>>,
+    #[account(mut, seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()], bump)]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+    #[account(mut, seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()], bump)]
+    pub event_cursor: Account<'info, EventCursor>,
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+impl CancelOrder<'_> {
+    /// Cancel the user's open order `order_index`: take it off the book, release its
+    /// promotion reserve, record the cancel, emit `OrderCanceled` and run the scoring
+    /// plugin, if any (the first of `remaining_accounts`), without letting it block.
+    fn cancel(
+        &mut self,
+        order_index: u8,
+        clock: &SysvarClock,
+        remaining_accounts: &[AccountInfo],
+    ) -> Result<()> {
+        let (order, canceled_size) = self.close_order(order_index, clock)?;
+        let user_state = &mut self.user_state;
+        user_state.epoch_cancels = user_state.epoch_cancels.saturating_add(1);
+        user_state.last_activity_ts = clock.now();
+
+        let event_cursor = &mut self.event_cursor;
+        let event = OrderCanceled {
+            user: user_state.authority,
+            order_index,
+            canceled_size,
+            event_seq: event_cursor.next_seq(),
+        };
+        event_cursor.record(&event, Clock::get()?.slot);
+        emit!(event);
+
+        let request = ScoringRequest {
+            market: self.market_state.key(),
+            user: user_state.authority,
+            kind: ScoringEventKind::Cancel,
+            order_id: order.order_id,
+            side: order.side,
+            price: order.price,
+            size: canceled_size,
+            size_remaining: 0,
+            liquidity_score: user_state.liquidity_score,
+            timestamp: clock.now(),
+        };
+        try_scoring_plugin(&mut self.market_state, user_state, remaining_accounts, request)
+    }
+
+    /// Cancel the user's expired order `order_index` for `cancel_expired_orders` and emit
+    /// `OrderExpired`.
+    fn expire(&mut self, order_index: u8, clock: &SysvarClock) -> Result<()> {
+        let (order, expired_size) = self.close_order(order_index, clock)?;
+        let event_cursor = &mut self.event_cursor;
+        let event = OrderExpired {
+            market: self.market_state.key(),
+            user: self.user_state.authority,
+            order_index,
+            order_id: order.order_id,
+            expiry_timestamp: order.expiry_timestamp,
+            expired_size,
+            event_seq: event_cursor.next_seq(),
+        };
+        event_cursor.record(&event, Clock::get()?.slot);
+        emit!(event);
+        Ok(())
+    }
+
+    /// Free the user's open order `order_index`, take it off the book and release its
+    /// promotion reserve. Returns the order as it was and its canceled size.
+    fn close_order(&mut self, order_index: u8, clock: &SysvarClock) -> Result<(Order, u64)> {
+        let user_state = &mut self.user_state;
+        let order = user_state
+            .orders
+            .get(order_index as usize)
+            .copied()
+            .unwrap_or_default();
+        let score = user_state.liquidity_score;
+        let canceled_size = user_state.cancel_order(order_index, &self.market_state, clock)?;
+        self.market_state.sync_liquidity_score(score, user_state.liquidity_score);
+        if order.price_mode == PriceMode::Fixed {
+            self.top_of_book
+                .remove(order.side, order.price, canceled_size, true, order.book_level);
+        }
+        self.open_order_index.update(&user_state.key(), order.order_id, 0);
+        self.market_state.release_promotion(order.promo_reserve);
+
+        self.market_stats.record_cancel(clock.now());
+        let market_health = &mut self.market_health;
+        market_health.record_order_closed();
+        market_health.refresh(&self.market_state, &self.global_config, clock.now());
+        Ok((order, canceled_size))
+    }
+}
+
+#[derive(Accounts)]
+pub struct FillOrder<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Box<Account<'info, MarketState>>,
+
+    #[account(mut, seeds = [pda::MARKET_STATS_SEED, market_state.key().as_ref()], bump)]
+    pub market_stats: Account<'info, MarketStats>,
+
+    #[account(mut, seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()], bump)]
+    pub market_health: Account<'info, MarketHealth>,
+
+    #[account(mut, seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()], bump)]
+    pub top_of_book: Box<Account<'info, TopOfBook>>,
+
+    #[account(mut, seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()], bump)]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+
+    #[account(mut, seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()], bump)]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    #[account(mut, constraint = maker_user.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub maker_user: Account<'info, UserState>,
+
+    #[account(mut, constraint = taker_user.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub taker_user: Account<'info, UserState>,
+
+    #[account(signer)]
+    pub taker_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GetEffectiveFees<'info> {
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(constraint = maker_user.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub maker_user: Account<'info, UserState>,
+
+    #[account(seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()], bump)]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+}
+
+#[derive(Accounts)]
+pub struct ValidateMarketParams {}
+
+#[derive(Accounts)]
+pub struct SimulateTierAssignment<'info> {
+    pub market_state: Account<'info, MarketState>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeLiquidityRewards<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+    // The market authority, which decides on distribution intervals
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: [u8; 32])]
+pub struct CreateRewardProgram<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = sponsor,
+        space = 8 + RewardProgram::SIZE,
+        seeds = [
+            pda::REWARD_PROGRAM_SEED,
+            market_state.key().as_ref(),
+            sponsor.key().as_ref(),
+            name.as_ref()
+        ],
+        bump
+    )]
+    pub reward_program: Account<'info, RewardProgram>,
+
+    #[account(mut)]
+    pub sponsor: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenRewardPosition<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub reward_program: Account<'info, RewardProgram>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + RewardPosition::SIZE,
+        seeds = [
+            pda::REWARD_POSITION_SEED,
+            reward_program.key().as_ref(),
+            user_authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub reward_position: Account<'info, RewardPosition>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseRewardPosition<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub reward_program: Account<'info, RewardProgram>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [pda::REWARD_POSITION_SEED, reward_program.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub reward_position: Account<'info, RewardPosition>,
+
+    /// The position's user, who gets the rent back.
+    #[account(mut)]
+    pub user: AccountInfo<'info>,
+
+    #[account(signer)]
+    pub signer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeProgramRewards<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = reward_program.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub reward_program: Account<'info, RewardProgram>,
+
+    #[account(constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        seeds = [
+            pda::REWARD_POSITION_SEED,
+            reward_program.key().as_ref(),
+            user_state.authority.as_ref()
+        ],
+        bump
+    )]
+    pub reward_position: Account<'info, RewardPosition>,
+
+    #[account(signer)]
+    pub sponsor: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimProgramRewards<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub reward_program: Account<'info, RewardProgram>,
+
+    #[account(
+        mut,
+        seeds = [
+            pda::REWARD_POSITION_SEED,
+            reward_program.key().as_ref(),
+            user_authority.key().as_ref()
+        ],
+        bump
+    )]
+    pub reward_position: Account<'info, RewardPosition>,
+
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardsOptOut<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub user_state: Account<'info, UserState>,
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateAttestation<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + VolumeAttestation::SIZE,
+        seeds = [pda::ATTESTATION_SEED, user_state.market.as_ref(), user_state.authority.as_ref()],
+        bump
+    )]
+    pub attestation: Account<'info, VolumeAttestation>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshAttestation<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        seeds = [pda::ATTESTATION_SEED, user_state.market.as_ref(), user_state.authority.as_ref()],
+        bump
+    )]
+    pub attestation: Account<'info, VolumeAttestation>,
+
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResetStrategyStats<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    #[account(mut)]
+    pub user_state: Account<'info, UserState>,
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTakerRebates<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(signer)]
+    pub referrer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimLiquidityRewards<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OpenStakeAccount<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + StakeAccount::SIZE,
+        seeds = [pda::STAKE_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub stake_account: Account<'info, StakeAccount>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAndStake<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        seeds = [pda::STAKE_SEED, market_state.key().as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub stake_account: Account<'info, StakeAccount>,
+
+    #[account(signer)]
+    pub user_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RollEpoch<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()], bump)]
+    pub market_health: Account<'info, MarketHealth>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshMarketHealth<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()], bump)]
+    pub market_health: Account<'info, MarketHealth>,
+}
+
+#[derive(Accounts)]
+pub struct AuditAccounts {}
+
+#[derive(Accounts)]
+pub struct ReportRejection<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    pub market_state: Account<'info, MarketState>,
+    #[account(mut, seeds = [pda::MARKET_STATS_SEED, market_state.key().as_ref()], bump)]
+    pub market_stats: Account<'info, MarketStats>,
+    pub reporter: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CheckpointOrderBook<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + BookCheckpoint::SIZE,
+        seeds = [
+            pda::BOOK_CHECKPOINT_SEED,
+            market_state.key().as_ref(),
+            &market_state.current_epoch.to_le_bytes()
+        ],
+        bump
+    )]
+    pub book_checkpoint: Account<'info, BookCheckpoint>,
+
+    /// Its `event_seq` dates the book the checkpoint is built from.
+    #[account(seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()], bump)]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateBookCheckpoint<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        constraint = book_checkpoint.market == market_state.key() @ FeeError::MarketMismatch
+    )]
+    pub book_checkpoint: Account<'info, BookCheckpoint>,
+
+    #[account(seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()], bump)]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CheckpointReferralGraph<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + ReferralCheckpoint::SIZE,
+        seeds = [
+            pda::REFERRAL_CHECKPOINT_SEED,
+            market_state.key().as_ref(),
+            &market_state.current_epoch.to_le_bytes()
+        ],
+        bump
+    )]
+    pub referral_checkpoint: Account<'info, ReferralCheckpoint>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReferralCheckpoint<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        constraint = referral_checkpoint.market == market_state.key() @ FeeError::MarketMismatch
+    )]
+    pub referral_checkpoint: Account<'info, ReferralCheckpoint>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AccrueLiquidityScore<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)] // tracks the market's total liquidity score
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+}
+
+#[derive(Accounts)]
+pub struct ForfeitExpiredClaims<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut, constraint = user_state.market == market_state.key() @ FeeError::MarketMismatch)]
+    pub user_state: Account<'info, UserState>,
+}
+
+#[derive(Accounts)]
+pub struct RequestRentReclaim<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        init,
+        payer = user_authority,
+        space = 8 + RentEscrow::SIZE,
+        seeds = [pda::RENT_ESCROW_SEED, user_state.market.as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub rent_escrow: Account<'info, RentEscrow>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelRentReclaim<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        close = user_authority,
+        seeds = [pda::RENT_ESCROW_SEED, user_state.market.as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub rent_escrow: Account<'info, RentEscrow>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimRent<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        close = rent_escrow,
+        seeds = [pda::USER_STATE_SEED, market_state.key().as_ref(), user_state.authority.as_ref()],
+        bump
+    )]
+    pub user_state: Account<'info, UserState>,
+
+    #[account(
+        mut,
+        close = rent_escrow,
+        seeds = [pda::USER_INDEX_SEED, market_state.key().as_ref(), user_state.authority.as_ref()],
+        bump
+    )]
+    pub user_index_entry: Account<'info, UserIndexEntry>,
+
+    #[account(
+        mut,
+        seeds = [pda::RENT_ESCROW_SEED, market_state.key().as_ref(), user_state.authority.as_ref()],
+        bump
+    )]
+    pub rent_escrow: Account<'info, RentEscrow>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawReclaimedRent<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(
+        mut,
+        close = user_authority,
+        seeds = [pda::RENT_ESCROW_SEED, rent_escrow.market.as_ref(), user_authority.key().as_ref()],
+        bump
+    )]
+    pub rent_escrow: Account<'info, RentEscrow>,
+
+    #[account(mut)]
+    pub user_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarketAccounts<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut, close = authority)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [pda::MARKET_STATS_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [pda::MARKET_HEALTH_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub market_health: Account<'info, MarketHealth>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [pda::TOP_OF_BOOK_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub top_of_book: Account<'info, TopOfBook>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [pda::OPEN_ORDER_INDEX_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub open_order_index: Box<Account<'info, OpenOrderIndex>>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [pda::EVENT_CURSOR_SEED, market_state.key().as_ref()],
+        bump
+    )]
+    pub event_cursor: Account<'info, EventCursor>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+
+    /// The instructions sysvar, read by `MarketState::require_caller_allowed`.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateWithdrawalCosign<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(signer)]
+    pub authority: AccountInfo<'info>,
+
+    pub cosigner: Signer<'info>,
+
+    /// The instructions sysvar, read by `MarketState::require_caller_allowed`.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveWithdrawal<'info> {
+    #[account(seeds = [pda::GLOBAL_CONFIG_SEED], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    pub cosigner: Signer<'info>,
+
+    /// The instructions sysvar, read by `MarketState::require_caller_allowed`.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+}
+
+// ----------------------------------
+// ACCOUNT DATA STRUCTS
+// ----------------------------------
+
+/// GlobalConfig is a program-wide singleton holding the protocol authority and
+/// the per-instruction kill-switches.
+#[account]
+pub struct GlobalConfig {
+    pub authority: Pubkey,
+    pub disabled_instructions: u64, // bit set => instruction disabled, see `ProgramInstruction`
+}
+
+impl GlobalConfig {
+    pub const SIZE: usize =
+          32 // authority
+        + 8; // disabled_instructions
+
+    pub fn is_enabled(&self, instruction: ProgramInstruction) -> bool {
+        self.disabled_instructions & instruction.mask() == 0
+    }
+
+    pub fn require_enabled(&self, instruction: ProgramInstruction) -> Result<()> {
+        require!(self.is_enabled(instruction), FeeError::InstructionDisabled);
+        Ok(())
+    }
+}
+
+/// MarketState holds global info like fee rates, fee collection, etc.
+#[account]
+pub struct MarketState {
+    pub authority: Pubkey,
+    pub maker_rebate_rate: u16,     // in fee_precision, e.g., 2 bps
+    pub taker_fee_rate: u16,        // in fee_precision, e.g., 5 bps
+    pub referral_rate: u16,         // fee_precision under FillSize, bps of the fee under TakerFee
+    pub referral_basis: ReferralBasis, // what referral_rate applies to
+    pub fee_tier_count: u8,
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS], // volume tiers above the base rates
+    pub price_improvement_rebate_bps: u16, // share of price improvement rebated to takers
+    pub total_fees_collected: u64,
+    pub total_liquidity_rewards_distributed: u64,
+    pub epoch_duration_secs: i64,   // 0 => epochs never roll
+    pub current_epoch: u64,
+    pub epoch_start_ts: i64,
+    pub claim_deadline_secs: i64,   // 0 => claimable balances never expire
+    pub outstanding_claims: u64,    // unclaimed rebates + rewards owed to users
+    pub opt_out_policy: OptOutPolicy, // where opted-out users' reward shares go
+    pub oracle_authority: Pubkey,   // publishes reference_price
+    pub reference_price: u64,       // mid price pegged orders resolve against; 0 => unset
+    pub reference_price_ts: i64,
+    pub max_reference_age_secs: i64, // 0 => no staleness check
+    pub large_fill_notional: u64,   // fills at/above this notional also emit LargeFill; 0 => off
+    pub go_live_ts: i64,            // legacy users can only be imported before this
+    pub penalty_cancel_to_fill_bps: u32, // per-epoch cancel/fill limit; 0 => no penalty
+    pub penalty_min_cancels: u32,   // cancels below this never trigger the penalty
+    pub penalty_rebate_haircut_bps: u16, // cut from a penalized maker's rebates
+    pub penalty_placement_fee: u64, // charged per order a penalized user places
+    pub user_count: u64,            // UserStates currently registered in this market
+    pub side_incentive: SideIncentive, // temporary one-sided liquidity score boost
+    pub status: MarketStatus,
+    pub user_index_head: Pubkey,    // newest UserIndexEntry's user; default => empty index
+    pub price_decimals: u8,         // prices are quote units per base unit * 10^price_decimals
+    pub max_open_orders: u8,        // per-user open order limit, 1..=MAX_ORDERS
+    pub gross_fees_charged: u64,    // lifetime taker fees charged, before any payouts
+    pub net_fees_retained: u64,     // lifetime share of those kept after rebates and referrals
+    pub liquidity_band_bps: u16,    // score only time within this of the reference; 0 => off
+    pub reference_history: [ReferenceSnapshot; REFERENCE_HISTORY], // ring of recent prices
+    pub reference_history_cursor: u8,
+    pub first_fill_bonus: FirstFillBonus, // onboarding taker fee discount
+    pub promotion_budget: u64,      // set aside from the treasury to fund promotions
+    pub promotion_spent: u64,       // lifetime amount promotions have paid out
+    pub referral_cap_bps: u16,      // referral payouts per referee, vs. that referee's fees
+    pub recovery_committee: RecoveryCommittee, // can replace a lost authority, set at init
+    pub pending_recovery: RecoveryProposal,
+    pub top_level_only: bool,       // admin instructions and withdrawals refuse CPI callers
+    pub skew_rebate: SkewRebate,    // maker rebate adjustment for book imbalance
+    pub promo_maker_rebate_bps: u16, // extra maker rebate on fills, paid from promotion_budget
+    pub promotion_reserved: u64,    // promotion_budget held for open promotional orders
+    pub reference_confidence: u64,  // oracle uncertainty around reference_price
+    pub min_fill_notional: u64,     // fills below this notional are rejected; 0 => off
+    pub withdrawal_cosigner: Pubkey, // approves withdrawals past the threshold
+    pub withdrawal_cosign_threshold: u64, // per-window un-co-signed withdrawal limit; 0 => off
+    pub approved_withdrawal: u64,   // co-signed amount not yet withdrawn
+    pub withdrawal_window_secs: i64, // length of the threshold's window, set with the cosigner
+    pub withdrawal_window_start: i64, // window window_uncosigned_withdrawn counts
+    pub window_uncosigned_withdrawn: u64,
+    pub scoring_program: Pubkey,    // liquidity scoring plugin; default => none
+    pub max_score_delta: u64,       // bound on the plugin's delta per fill or cancel
+    pub promotion_epoch_cap: u64,   // most promotions may pay out per epoch; 0 => no cap
+    pub promotion_cap_epoch: u64,   // epoch epoch_promotion_spent counts
+    pub epoch_promotion_spent: u64,
+    pub default_order_ttl_secs: i64, // lifetime of orders placed without expiry; 0 => none
+    pub max_order_ttl_secs: i64,     // longest order lifetime allowed; 0 => no cap
+    pub fee_precision: FeePrecision, // unit of the fee schedule rates
+    pub rejection_reporter: Pubkey,  // may report_rejection besides the authority
+    pub total_liquidity_score: u64, // sum of every user's liquidity_score
+    pub liquidity_reward_pool: u64, // funded, not yet distributed rewards
+    pub auxiliary_accounts: u64,    // RewardProgram and checkpoint PDAs still open
+    pub user_set_version: u64,      // bumped whenever a UserState joins or leaves
+}
+
+impl MarketState {
+    pub const SIZE: usize = 
+          32 // authority
+        + 2  // maker_rebate_rate
+        + 2  // taker_fee_rate
+        + 2  // referral_rate
+        + 1  // referral_basis
+        + 1  // fee_tier_count
+        + FeeTier::SIZE * MAX_FEE_TIERS // fee_tiers
+        + 2  // price_improvement_rebate_bps
+        + 8  // total_fees_collected
+        + 8  // total_liquidity_rewards_distributed
+        + 8  // epoch_duration_secs
+        + 8  // current_epoch
+        + 8  // epoch_start_ts
+        + 8  // claim_deadline_secs
+        + 8  // outstanding_claims
+        + 1  // opt_out_policy
+        + 32 // oracle_authority
+        + 8  // reference_price
+        + 8  // reference_price_ts
+        + 8  // max_reference_age_secs
+        + 8  // large_fill_notional
+        + 8  // go_live_ts
+        + 4  // penalty_cancel_to_fill_bps
+        + 4  // penalty_min_cancels
+        + 2  // penalty_rebate_haircut_bps
+        + 8  // penalty_placement_fee
+        + 8  // user_count
+        + SideIncentive::SIZE // side_incentive
+        + 1  // status
+        + 32 // user_index_head
+        + 1  // price_decimals
+        + 1  // max_open_orders
+        + 8  // gross_fees_charged
+        + 8  // net_fees_retained
+        + 2  // liquidity_band_bps
+        + ReferenceSnapshot::SIZE * REFERENCE_HISTORY // reference_history
+        + 1  // reference_history_cursor
+        + FirstFillBonus::SIZE // first_fill_bonus
+        + 8  // promotion_budget
+        + 8  // promotion_spent
+        + 2  // referral_cap_bps
+        + RecoveryCommittee::SIZE // recovery_committee
+        + RecoveryProposal::SIZE // pending_recovery
+        + 1  // top_level_only
+        + SkewRebate::SIZE // skew_rebate
+        + 2  // promo_maker_rebate_bps
+        + 8  // promotion_reserved
+        + 8  // reference_confidence
+        + 8  // min_fill_notional
+        + 32 // withdrawal_cosigner
+        + 8  // withdrawal_cosign_threshold
+        + 8  // approved_withdrawal
+        + 8  // withdrawal_window_secs
+        + 8  // withdrawal_window_start
+        + 8  // window_uncosigned_withdrawn
+        + 32 // scoring_program
+        + 8  // max_score_delta
+        + 8  // promotion_epoch_cap
+        + 8  // promotion_cap_epoch
+        + 8  // epoch_promotion_spent
+        + 8  // default_order_ttl_secs
+        + 8  // max_order_ttl_secs
+        + 1  // fee_precision
+        + 32 // rejection_reporter
+        + 8  // total_liquidity_score
+        + 8  // liquidity_reward_pool
+        + 8  // auxiliary_accounts
+        + 8; // user_set_version
+
+    /// Set up a new market from `params`, which must pass `MarketParams::require_valid`:
+    /// what `initialize_market` and `migrate_market_state` write.
+    pub fn initialize(
+        &mut self,
+        authority: Pubkey,
+        params: &MarketParams,
+        go_live_ts: i64,
+        now: i64,
+    ) -> Result<()> {
+        params.require_valid()?;
+
+        self.authority = authority;
+        self.maker_rebate_rate = params.fees.maker_rebate_rate;
+        self.taker_fee_rate = params.fees.taker_fee_rate;
+        self.referral_rate = params.fees.referral_rate;
+        self.referral_basis = params.referral_basis;
+        self.fee_tier_count = 0;
+        self.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        self.price_improvement_rebate_bps = 0;
+        self.total_fees_collected = 0;
+        self.total_liquidity_rewards_distributed = 0;
+        self.epoch_duration_secs = 0;
+        self.current_epoch = 0;
+        self.epoch_start_ts = now;
+        self.claim_deadline_secs = 0;
+        self.outstanding_claims = 0;
+        self.opt_out_policy = OptOutPolicy::Treasury;
+        self.oracle_authority = params.oracle_authority;
+        self.reference_price = 0;
+        self.reference_price_ts = 0;
+        self.max_reference_age_secs = params.max_reference_age_secs;
+        self.large_fill_notional = 0;
+        self.go_live_ts = go_live_ts.max(now);
+        self.penalty_cancel_to_fill_bps = 0;
+        self.penalty_min_cancels = 0;
+        self.penalty_rebate_haircut_bps = 0;
+        self.penalty_placement_fee = 0;
+        self.user_count = 0;
+        self.side_incentive = SideIncentive::default();
+        self.status = MarketStatus::Uninitialized;
+        self.user_index_head = Pubkey::default();
+        self.price_decimals = params.price_decimals;
+        self.max_open_orders = params.max_open_orders;
+        self.gross_fees_charged = 0;
+        self.net_fees_retained = 0;
+        self.liquidity_band_bps = 0;
+        self.reference_history = [ReferenceSnapshot::default(); REFERENCE_HISTORY];
+        self.reference_history_cursor = 0;
+        self.first_fill_bonus = FirstFillBonus::default();
+        self.promotion_budget = 0;
+        self.promotion_spent = 0;
+        self.referral_cap_bps = params.referral_cap_bps;
+        self.recovery_committee = params.recovery_committee.unwrap_or_default();
+        self.pending_recovery = RecoveryProposal::default();
+        self.top_level_only = false;
+        self.skew_rebate = params.skew_rebate;
+        self.promo_maker_rebate_bps = params.promo_maker_rebate_bps;
+        self.promotion_reserved = 0;
+        self.reference_confidence = 0;
+        self.min_fill_notional = params.min_fill_notional;
+        self.withdrawal_cosigner = Pubkey::default();
+        self.withdrawal_cosign_threshold = 0;
+        self.approved_withdrawal = 0;
+        self.withdrawal_window_secs = 0;
+        self.withdrawal_window_start = 0;
+        self.window_uncosigned_withdrawn = 0;
+        self.scoring_program = Pubkey::default();
+        self.max_score_delta = 0;
+        self.promotion_epoch_cap = params.promotion_epoch_cap;
+        self.promotion_cap_epoch = 0;
+        self.epoch_promotion_spent = 0;
+        self.default_order_ttl_secs = params.default_order_ttl_secs;
+        self.max_order_ttl_secs = params.max_order_ttl_secs;
+        self.fee_precision = params.fee_precision;
+        self.rejection_reporter = Pubkey::default();
+        self.total_liquidity_score = 0;
+        self.liquidity_reward_pool = 0;
+        self.auxiliary_accounts = 0;
+        self.user_set_version = 0;
+        self.transition_to(MarketStatus::Active)?;
+        Ok(())
+    }
+
+    pub fn require_allowed(&self, instruction: ProgramInstruction) -> Result<()> {
+        require!(
+            self.status.allows(instruction),
+            FeeError::MarketStatusDisallows
+        );
+        Ok(())
+    }
+
+    /// Move to `next`, if the lifecycle allows it. Returns the previous status.
+    pub fn transition_to(&mut self, next: MarketStatus) -> Result<MarketStatus> {
+        require!(
+            self.status.can_transition_to(next),
+            FeeError::InvalidStatusTransition
+        );
+        let previous = self.status;
+        self.status = next;
+        Ok(previous)
+    }
+
+    /// A market can only be closed once nobody is registered, nothing is owed to users
+    /// and the treasury has been fully withdrawn.
+    pub fn is_closable(&self) -> bool {
+        self.user_count == 0
+            && self.outstanding_claims == 0
+            && self.total_fees_collected == 0
+            && self.promotion_budget == 0
+            && self.liquidity_reward_pool == 0
+            && self.auxiliary_accounts == 0
+    }
+
+    /// Count a `UserState` registered or imported into this market.
+    pub fn add_user(&mut self) -> Result<()> {
+        self.user_count = self.user_count.checked_add(1).ok_or(FeeError::Overflow)?;
+        self.user_set_version = self.user_set_version.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Count a `UserState` of this market closed for good.
+    pub fn remove_user(&mut self) -> Result<()> {
+        self.user_count = self.user_count.checked_sub(1).ok_or(FeeError::Overflow)?;
+        self.user_set_version = self.user_set_version.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Count a new `RewardProgram` or checkpoint PDA of this market,
+    /// which `close_market_accounts` has to close before the market itself can close.
+    pub fn add_auxiliary_account(&mut self) -> Result<()> {
+        self.auxiliary_accounts = self
+            .auxiliary_accounts
+            .checked_add(1)
+            .ok_or(FeeError::Overflow)?;
+        Ok(())
+    }
+
+    /// Whether an epoch with `cancels` cancels and `fills` fills breaches the
+    /// cancel-to-fill limit. With no fills at all, any qualifying cancel count does.
+    pub fn cancel_penalty_triggered(&self, cancels: u32, fills: u32) -> bool {
+        if self.penalty_cancel_to_fill_bps == 0 || cancels < self.penalty_min_cancels {
+            return false;
+        }
+        cancels as u64 * BPS_DENOMINATOR as u64
+            > self.penalty_cancel_to_fill_bps as u64 * fills as u64
+    }
+
+    /// Whether the market has launched; `import_user` is closed from then on.
+    pub fn is_live(&self, now: i64) -> bool {
+        now >= self.go_live_ts
+    }
+
+    /// Book imbalance for skew-aware rebates at `now`, from the open order index; see
+    /// `OpenOrderIndex::banded_summary`. Skipped (0) while the market has them off.
+    pub fn book_imbalance_bps(&self, open_order_index: &OpenOrderIndex, now: i64) -> Result<i32> {
+        if !self.skew_rebate.is_enabled() {
+            return Ok(0);
+        }
+        Ok(open_order_index.banded_summary(now)?.imbalance_bps())
+    }
+
+    /// Record a reference price in the ring of recent prices banded scoring reads. Once the
+    /// ring is full this overwrites the oldest snapshot, which is returned: from then on,
+    /// orders not checkpointed since it was taken lose their unscored time before the
+    /// oldest kept snapshot, so keepers crank `accrue_liquidity_score` before that happens.
+    pub fn push_reference_snapshot(&mut self, price: u64, ts: i64) -> Option<ReferenceSnapshot> {
+        let i = self.reference_history_cursor as usize % REFERENCE_HISTORY;
+        let overwritten = std::mem::replace(
+            &mut self.reference_history[i],
+            ReferenceSnapshot { price, ts },
+        );
+        self.reference_history_cursor = ((i + 1) % REFERENCE_HISTORY) as u8;
+        (overwritten.price != 0).then_some(overwritten)
+    }
+
+    /// Seconds of `from..to` that `order` rested within `liquidity_band_bps` of the
+    /// reference price, each stored snapshot applying until the next one. Without a band
+    /// every second counts; with one, time before the oldest kept snapshot cannot be
+    /// judged and does not count (see `push_reference_snapshot`).
+    pub fn in_band_secs(&self, order: &Order, from: i64, to: i64) -> i64 {
+        if to <= from {
+            return 0;
+        }
+        if self.liquidity_band_bps == 0 {
+            return to - from;
+        }
+
+        let mut snapshots = self.reference_history;
+        snapshots.sort_by_key(|snapshot| snapshot.ts);
+        let mut secs = 0i64;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            if snapshot.price == 0 {
+                continue;
+            }
+            let start = snapshot.ts.max(from);
+            let end = snapshots.get(i + 1).map_or(to, |next| next.ts).min(to);
+            if end > start && order.within_band(snapshot.price, self.liquidity_band_bps) {
+                secs = secs.saturating_add(end - start);
+            }
+        }
+        secs
+    }
+
+    /// Move funds between the treasury and the promotion budget so the budget ends at
+    /// `promotion_budget`.
+    pub fn set_promotion_budget(&mut self, promotion_budget: u64) -> Result<()> {
+        require!(
+            promotion_budget >= self.promotion_reserved,
+            FeeError::PromotionLiabilityExceeded
+        );
+        if promotion_budget >= self.promotion_budget {
+            let top_up = promotion_budget - self.promotion_budget;
+            require!(
+                self.total_fees_collected >= top_up,
+                FeeError::InsufficientFunds
+            );
+            self.total_fees_collected -= top_up;
+        } else {
+            self.total_fees_collected = self
+                .total_fees_collected
+                .checked_add(self.promotion_budget - promotion_budget)
+                .ok_or(FeeError::Overflow)?;
+        }
+        self.promotion_budget = promotion_budget;
+        Ok(())
+    }
+
+    /// Promotion budget not yet held for open promotional orders.
+    pub fn available_promotion_budget(&self) -> u64 {
+        self.promotion_budget.saturating_sub(self.promotion_reserved)
+    }
+
+    /// Hold the promotional rebate an order of `size` could earn, so every promise is
+    /// funded. The hold is clipped so a maker whose open orders already hold
+    /// `user_reserved` keeps at most `MAX_MAKER_PROMOTION_SHARE_BPS` of the budget; the
+    /// order then earns only what was held. Fails if the unreserved budget cannot cover
+    /// it; 0 while promotions are off, including for the rest of an epoch whose promotion
+    /// cap has been reached. Expired orders hold theirs until `cancel_expired_orders`.
+    pub fn reserve_promotion(&mut self, size: u64, user_reserved: u64) -> Result<u64> {
+        if self.promotion_epoch_room() == 0 {
+            return Ok(0);
+        }
+        let maker_room = bps_amount(self.promotion_budget, MAX_MAKER_PROMOTION_SHARE_BPS)?
+            .saturating_sub(user_reserved);
+        let reserve = bps_amount(size, self.promo_maker_rebate_bps)?.min(maker_room);
+        require!(
+            reserve <= self.available_promotion_budget(),
+            FeeError::PromotionLiabilityExceeded
+        );
+        self.promotion_reserved += reserve;
+        Ok(reserve)
+    }
+
+    /// Return an order's unused reserve to the available budget.
+    pub fn release_promotion(&mut self, reserve: u64) {
+        self.promotion_reserved = self.promotion_reserved.saturating_sub(reserve);
+    }
+
+    /// Pay `amount` of reserve out of the budget as a promotional rebate. Returns whether
+    /// it used up the epoch's promotion cap, see `record_promotion_spend`.
+    pub fn pay_promotion(&mut self, amount: u64) -> Result<bool> {
+        require!(
+            amount <= self.promotion_reserved && amount <= self.promotion_budget,
+            FeeError::PromotionLiabilityExceeded
+        );
+        self.promotion_reserved -= amount;
+        self.promotion_budget -= amount;
+        Ok(self.record_promotion_spend(amount))
+    }
+
+    /// What promotions may still pay out this epoch; `u64::MAX` without a cap.
+    pub fn promotion_epoch_room(&self) -> u64 {
+        if self.promotion_epoch_cap == 0 {
+            return u64::MAX;
+        }
+        let spent = if self.promotion_cap_epoch == self.current_epoch {
+            self.epoch_promotion_spent
+        } else {
+            0
+        };
+        self.promotion_epoch_cap.saturating_sub(spent)
+    }
+
+    /// Count `amount` of promotional spend. Returns true when it is the spend that uses
+    /// up the epoch's cap, which shuts promotions off until the next epoch.
+    pub fn record_promotion_spend(&mut self, amount: u64) -> bool {
+        self.promotion_spent = self.promotion_spent.saturating_add(amount);
+        if self.promotion_epoch_cap == 0 || amount == 0 {
+            return false;
+        }
+        if self.promotion_cap_epoch != self.current_epoch {
+            self.promotion_cap_epoch = self.current_epoch;
+            self.epoch_promotion_spent = 0;
+        }
+        self.epoch_promotion_spent = self.epoch_promotion_spent.saturating_add(amount);
+        self.epoch_promotion_spent >= self.promotion_epoch_cap
+    }
+
+    /// Switch the fee schedule to `fee_precision`, restating the base rates, the tiers and a
+    /// `FillSize` referral rate so every fee stays the same. Fails, changing nothing, if any
+    /// rate can't be restated exactly, e.g. 1 pip in bps or more than 655 bps in pips.
+    pub fn convert_fee_precision(&mut self, fee_precision: FeePrecision) -> Result<()> {
+        let from = self.fee_precision;
+        let convert = |rate: u16| -> Result<u16> {
+            from.convert(rate, fee_precision)
+                .ok_or_else(|| error!(FeeError::InvalidFeePrecision))
+        };
+
+        let maker_rebate_rate = convert(self.maker_rebate_rate)?;
+        let taker_fee_rate = convert(self.taker_fee_rate)?;
+        let referral_rate = match self.referral_basis {
+            ReferralBasis::FillSize => convert(self.referral_rate)?,
+            ReferralBasis::TakerFee => self.referral_rate,
+        };
+        let mut fee_tiers = self.fee_tiers;
+        for tier in fee_tiers[..self.fee_tier_count as usize].iter_mut() {
+            tier.taker_fee_rate = convert(tier.taker_fee_rate)?;
+            tier.maker_rebate_rate = convert(tier.maker_rebate_rate)?;
+        }
+
+        self.maker_rebate_rate = maker_rebate_rate;
+        self.taker_fee_rate = taker_fee_rate;
+        self.referral_rate = referral_rate;
+        self.fee_tiers = fee_tiers;
+        self.fee_precision = fee_precision;
+        Ok(())
+    }
+
+    /// Validate `params` against this market's referral basis and volume tiers, then apply.
+    pub fn set_fee_parameters(&mut self, params: &FeeParameters) -> Result<()> {
+        require!(
+            params.maker_rebate_rate <= params.taker_fee_rate,
+            FeeError::InvalidFeeConfiguration
+        );
+        validate_fee_schedule(
+            params.maker_rebate_rate,
+            params.taker_fee_rate,
+            params.referral_rate,
+            self.referral_basis,
+            self.fee_precision,
+            self.active_fee_tiers(),
+        )?;
+
+        self.maker_rebate_rate = params.maker_rebate_rate;
+        self.taker_fee_rate = params.taker_fee_rate;
+        self.referral_rate = params.referral_rate;
+        Ok(())
+    }
+
+    /// Check a withdrawal of `amount` at `now` against co-signing. Withdrawals stay
+    /// un-co-signed while their total over the current `withdrawal_window_secs` window is
+    /// below `withdrawal_cosign_threshold`, so large amounts can't be split to dodge it;
+    /// past that, the cosigner's approval pays for it. The window is set together with
+    /// the cosigner, not by the epoch length the authority controls alone. Returns
+    /// whether the withdrawal drew on the approval.
+    pub fn authorize_withdrawal(&mut self, amount: u64, now: i64) -> Result<bool> {
+        if self.withdrawal_cosign_threshold == 0 {
+            return Ok(false);
+        }
+        if now >= self.withdrawal_window_start.saturating_add(self.withdrawal_window_secs) {
+            self.withdrawal_window_start = now;
+            self.window_uncosigned_withdrawn = 0;
+        }
+
+        let uncosigned = self.window_uncosigned_withdrawn.saturating_add(amount);
+        if uncosigned < self.withdrawal_cosign_threshold {
+            self.window_uncosigned_withdrawn = uncosigned;
+            return Ok(false);
+        }
+        require!(
+            amount <= self.approved_withdrawal,
+            FeeError::WithdrawalNeedsCosigner
+        );
+        self.approved_withdrawal -= amount;
+        Ok(true)
+    }
+
+    /// The expiry an order placed at `now` with `expiry_timestamp` gets. 0 takes the
+    /// default TTL, or the maximum if there is no default, and stays 0 (never expires)
+    /// only when the market has neither. Any other expiry must be in the future, and fails
+    /// if the order would outlive the maximum.
+    pub fn order_expiry(&self, expiry_timestamp: i64, now: i64) -> Result<i64> {
+        if expiry_timestamp == 0 {
+            let ttl = if self.default_order_ttl_secs > 0 {
+                self.default_order_ttl_secs
+            } else {
+                self.max_order_ttl_secs
+            };
+            return Ok(if ttl > 0 { now.saturating_add(ttl) } else { 0 });
+        }
+        // Negative or already-past expiries would rest as dead orders until swept.
+        require!(
+            expiry_timestamp > 0 && expiry_timestamp > now,
+            FeeError::InvalidOrderExpiry
+        );
+        require!(
+            self.max_order_ttl_secs == 0
+                || expiry_timestamp.saturating_sub(now) <= self.max_order_ttl_secs,
+            FeeError::OrderTtlTooLong
+        );
+        Ok(expiry_timestamp)
+    }
+
+    /// Rejects a fill of `size` at `price` whose notional is below `min_fill_notional`,
+    /// unless it takes the order's whole `size_remaining`: a remainder that partial fills
+    /// left under the floor can always be filled off.
+    pub fn require_min_fill_notional(
+        &self,
+        size: u64,
+        price: u64,
+        size_remaining: u64,
+    ) -> Result<()> {
+        require!(
+            size >= size_remaining || self.meets_min_notional(size, price),
+            FeeError::FillBelowMinNotional
+        );
+        Ok(())
+    }
+
+    /// Rejects placing an order of `size` at `price` that could never fill at the floor.
+    pub fn require_min_order_notional(&self, size: u64, price: u64) -> Result<()> {
+        require!(
+            self.meets_min_notional(size, price),
+            FeeError::OrderBelowMinNotional
+        );
+        Ok(())
+    }
+
+    fn meets_min_notional(&self, size: u64, price: u64) -> bool {
+        self.min_fill_notional == 0
+            || notional_value(size, price, self.price_decimals).unwrap_or(u64::MAX)
+                >= self.min_fill_notional
+    }
+
+    /// Whether a fill of `notional` quote units crosses the large-fill alert threshold.
+    pub fn is_large_fill(&self, notional: u64) -> bool {
+        self.large_fill_notional > 0 && notional >= self.large_fill_notional
+    }
+
+    /// The price `order` executes at right now: its fixed price, or its peg resolved
+    /// against a sufficiently fresh reference price.
+    pub fn resolve_order_price(&self, order: &Order, now: i64) -> Result<u64> {
+        if order.price_mode == PriceMode::Fixed {
+            return Ok(order.price);
+        }
+
+        require!(self.reference_price > 0, FeeError::ReferencePriceUnavailable);
+        require!(
+            !self.reference_price_stale(now),
+            FeeError::ReferencePriceStale
+        );
+
+        order.pegged_price(self.reference_price)
+    }
+
+    /// With `top_level_only` set, fail unless the running instruction is a top-level
+    /// instruction of this program: it must run at transaction level, and the instructions
+    /// sysvar must show this program's instruction. A CPI from another program shows up as
+    /// that program's instruction; one that re-enters this program through another program
+    /// runs below transaction level.
+    pub fn require_caller_allowed(&self, instructions: &AccountInfo) -> Result<()> {
+        self.require_caller_allowed_at(get_stack_height(), instructions)
+    }
+
+    /// `require_caller_allowed` for an instruction running at `stack_height`.
+    fn require_caller_allowed_at(
+        &self,
+        stack_height: usize,
+        instructions: &AccountInfo,
+    ) -> Result<()> {
+        if !self.top_level_only {
+            return Ok(());
+        }
+        require!(
+            stack_height == TRANSACTION_LEVEL_STACK_HEIGHT,
+            FeeError::CpiNotAllowed
+        );
+        let current = sysvar::instructions::get_instruction_relative(0, instructions)?;
+        require!(current.program_id == crate::ID, FeeError::CpiNotAllowed);
+        Ok(())
+    }
+
+    /// The oracle's reference price and confidence as of `now`, for fill records; zeros
+    /// when no price has been published or it is stale.
+    pub fn oracle_sample(&self, now: i64) -> (u64, u64) {
+        if self.reference_price == 0 || self.reference_price_stale(now) {
+            return (0, 0);
+        }
+        (self.reference_price, self.reference_confidence)
+    }
+
+    pub fn reference_price_stale(&self, now: i64) -> bool {
+        self.max_reference_age_secs > 0
+            && now.saturating_sub(self.reference_price_ts) > self.max_reference_age_secs
+    }
+
+    pub fn active_fee_tiers(&self) -> &[FeeTier] {
+        &self.fee_tiers[..self.fee_tier_count as usize]
+    }
+
+    /// The highest tier whose threshold `volume` reaches, or the base rates below the first.
+    pub fn tier_for_volume(&self, volume: u64) -> TierAssignment {
+        let mut assignment = TierAssignment {
+            tier: None,
+            taker_fee_rate: self.taker_fee_rate,
+            maker_rebate_rate: self.maker_rebate_rate,
+        };
+        for (i, tier) in self.active_fee_tiers().iter().enumerate() {
+            if volume < tier.min_volume {
+                break;
+            }
+            assignment = TierAssignment {
+                tier: Some(i as u8),
+                taker_fee_rate: tier.taker_fee_rate,
+                maker_rebate_rate: tier.maker_rebate_rate,
+            };
+        }
+        assignment
+    }
+
+    /// Resolve the fees for a fill of `size` taken by `taker` from a maker with
+    /// `maker_volume` (its `tier_volume`). Each side's rate comes from its own volume tier.
+    /// This is the single source of truth for fee math: `fill_order` charges it and
+    /// `get_effective_fees` quotes it.
+    pub fn effective_fees(
+        &self,
+        maker_volume: u64,
+        taker: &UserState,
+        size: u64,
+    ) -> Result<EffectiveFees> {
+        let referral_rate = if taker.has_referrer() {
+            self.referral_rate
+        } else {
+            0
+        };
+        let taker_fee_rate = self.tier_for_volume(taker.tier_volume()).taker_fee_rate;
+        let maker_rebate_rate = self.tier_for_volume(maker_volume).maker_rebate_rate;
+
+        let denominator = self.fee_precision.denominator();
+        let taker_fee = rate_amount(size, taker_fee_rate, denominator)?;
+        let maker_rebate = rate_amount(size, maker_rebate_rate, denominator)?;
+        let referral_reward = match self.referral_basis {
+            ReferralBasis::FillSize => rate_amount(size, referral_rate, denominator)?,
+            ReferralBasis::TakerFee => bps_amount(taker_fee, referral_rate)?,
+        };
+        let referral_reward = taker
+            .referral_link
+            .capped_reward(taker_fee, referral_reward, self.referral_cap_bps)?;
+        let net_fee = taker_fee
+            .checked_sub(maker_rebate)
+            .ok_or(FeeError::NegativeFee)?;
+
+        // Rebate and referral are both carved out of the taker fee.
+        require!(
+            maker_rebate
+                .checked_add(referral_reward)
+                .ok_or(FeeError::Overflow)?
+                <= taker_fee,
+            FeeError::FeeSharesExceedFee
+        );
+
+        Ok(EffectiveFees {
+            taker_fee_rate,
+            maker_rebate_rate,
+            referral_rate,
+            referral_basis: self.referral_basis,
+            fee_precision: self.fee_precision,
+            taker_fee,
+            maker_rebate,
+            referral_reward,
+            net_fee,
+        })
+    }
+
+    /// Advance to the next epoch once the current one has run for `epoch_duration_secs`.
+    pub fn roll_epoch(&mut self, clock: &impl TimeProvider) -> Result<()> {
+        let now = clock.now();
+        require!(
+            self.epoch_duration_secs > 0
+                && now >= self.epoch_start_ts.saturating_add(self.epoch_duration_secs),
+            FeeError::EpochNotFinished
+        );
+
+        self.current_epoch = self
+            .current_epoch
+            .checked_add(1)
+            .ok_or(FeeError::Overflow)?;
+        self.epoch_start_ts = now;
+
+        Ok(())
+    }
+
+    /// Keep `total_liquidity_score` in step with one user's score moving from `before` to
+    /// `after`.
+    pub fn sync_liquidity_score(&mut self, before: u64, after: u64) {
+        self.total_liquidity_score = self
+            .total_liquidity_score
+            .saturating_sub(before)
+            .saturating_add(after);
+    }
+
+    /// A user with liquidity score `score`'s share of the reward pool, rounded down. Never
+    /// more than the pool, even for a score the total doesn't cover yet.
+    pub fn liquidity_reward_share(&self, score: u64) -> u64 {
+        let total = self.total_liquidity_score.max(score);
+        if total == 0 {
+            return 0;
+        }
+        (score as u128 * self.liquidity_reward_pool as u128 / total as u128) as u64
+    }
+
+    /// Pay `user_state` its share of the liquidity reward pool and reset its score. The
+    /// share is credited as a claimable reward, or for an opted-out user goes where
+    /// `opt_out_policy` says. A user with no score gets nothing.
+    pub fn distribute_liquidity_reward(
+        &mut self,
+        user_state: &mut UserState,
+        now: i64,
+    ) -> Result<LiquidityDistribution> {
+        let score = user_state.liquidity_score;
+        if score == 0 {
+            return Ok(LiquidityDistribution::default());
+        }
+
+        let amount = self.liquidity_reward_share(score);
+        self.liquidity_reward_pool -= amount;
+        self.sync_liquidity_score(score, 0);
+        user_state.liquidity_score = 0;
+
+        // Opted-out users keep their score for statistics but receive nothing.
+        if user_state.rewards_opt_out {
+            match self.opt_out_policy {
+                OptOutPolicy::Treasury => {
+                    self.total_fees_collected = self
+                        .total_fees_collected
+                        .checked_add(amount)
+                        .ok_or(FeeError::Overflow)?;
+                }
+                // The score has already left the total, so the share left in the pool is
+                // spread over everyone else's score by their own distributions.
+                OptOutPolicy::ProRata => self.liquidity_reward_pool += amount,
+            }
+            return Ok(LiquidityDistribution {
+                amount,
+                redirected: true,
+                forfeited: ClaimAmounts::default(),
+            });
+        }
+
+        // Credit the share as a claimable balance; it is paid out by `claim_liquidity_rewards`.
+        let forfeited = user_state.take_expired_claims(now);
+        self.forfeit_claims(forfeited)?;
+        user_state.credit_claimable(0, amount, self.claim_expiry(now))?;
+        self.outstanding_claims = self
+            .outstanding_claims
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+        self.total_liquidity_rewards_distributed =
+            self.total_liquidity_rewards_distributed.saturating_add(amount);
+
+        Ok(LiquidityDistribution {
+            amount,
+            redirected: false,
+            forfeited,
+        })
+    }
+
+    /// When a balance credited at `now` stops being claimable, fixed at credit time: at
+    /// least `claim_deadline_secs` later, rounded up to a multiple of a third of the deadline.
+    /// The rounding keeps a user's unexpired credits on at most `CLAIM_LOTS` deadlines.
+    /// 0 => never.
+    pub fn claim_expiry(&self, now: i64) -> i64 {
+        if self.claim_deadline_secs <= 0 {
+            return 0;
+        }
+        let lots = CLAIM_LOTS as i64 - 1;
+        let step = (self.claim_deadline_secs + lots - 1) / lots;
+        let deadline = now.saturating_add(self.claim_deadline_secs);
+        deadline.saturating_add(step - 1) / step * step
+    }
+
+    /// Move claimable balances a user forfeited back into the treasury.
+    pub fn forfeit_claims(&mut self, forfeited: ClaimAmounts) -> Result<()> {
+        self.outstanding_claims = self.outstanding_claims.saturating_sub(forfeited.total());
+        self.total_fees_collected = self
+            .total_fees_collected
+            .checked_add(forfeited.total())
+            .ok_or(FeeError::Overflow)?;
+        Ok(())
+    }
+}
+
+/// MarketStats holds router-facing metrics for one market, so aggregators can score
+/// this venue from a single account fetch without running an indexer.
+#[account]
+pub struct MarketStats {
+    pub market: Pubkey,
+    pub volume_buckets: [u64; VOLUME_BUCKETS], // hourly fill volume, ring buffer
+    pub last_bucket_hour: i64,                 // unix hour of the newest bucket
+    pub volume_24h: u64,                       // sum of buckets as of last_update_ts
+    pub fill_count: u64,
+    pub total_fill_volume: u64,
+    pub avg_fill_size: u64,
+    pub place_count: u64,
+    pub cancel_count: u64,
+    pub cancel_to_fill_bps: u64,               // cancel_count / fill_count in bps
+    pub last_update_ts: i64,
+    pub rejections: [u64; REJECTION_REASONS],  // reported rejections, by `RejectionReason`
+}
+
+/// Most of the promotion budget one maker's open orders may hold in reserve, so a single
+/// large order cannot lock every other maker out of the promotion.
+pub const MAX_MAKER_PROMOTION_SHARE_BPS: u16 = 2_500;
+
+/// A promotional maker rebate is a share of the fill, so at most the whole of it.
+pub fn validate_promo_maker_rebate(promo_maker_rebate_bps: u16) -> Result<()> {
+    require!(
+        promo_maker_rebate_bps <= BPS_DENOMINATOR,
+        FeeError::InvalidFeeConfiguration
+    );
+    Ok(())
+}
+
+pub const VOLUME_BUCKETS: usize = 24;
+pub const VOLUME_BUCKET_SECS: i64 = 3_600;
+
+impl MarketStats {
+    pub const SIZE: usize =
+          32                        // market
+        + 8 * VOLUME_BUCKETS        // volume_buckets
+        + 8                         // last_bucket_hour
+        + 8                         // volume_24h
+        + 8                         // fill_count
+        + 8                         // total_fill_volume
+        + 8                         // avg_fill_size
+        + 8                         // place_count
+        + 8                         // cancel_count
+        + 8                         // cancel_to_fill_bps
+        + 8                         // last_update_ts
+        + 8 * REJECTION_REASONS;    // rejections
+
+    /// Empty stats of a new market, as of `now`.
+    pub fn initialize(&mut self, market: Pubkey, now: i64) {
+        self.market = market;
+        self.volume_buckets = [0; VOLUME_BUCKETS];
+        self.last_bucket_hour = 0;
+        self.volume_24h = 0;
+        self.fill_count = 0;
+        self.total_fill_volume = 0;
+        self.avg_fill_size = 0;
+        self.place_count = 0;
+        self.cancel_count = 0;
+        self.cancel_to_fill_bps = 0;
+        self.last_update_ts = now;
+        self.rejections = [0; REJECTION_REASONS];
+    }
+
+    pub fn record_place(&mut self, now: i64) {
+        self.place_count = self.place_count.saturating_add(1);
+        self.refresh(now);
+    }
+
+    pub fn record_cancel(&mut self, now: i64) {
+        self.cancel_count = self.cancel_count.saturating_add(1);
+        self.refresh(now);
+    }
+
+    pub fn record_rejections(&mut self, reason: RejectionReason, count: u32, now: i64) {
+        let total = &mut self.rejections[reason as usize];
+        *total = total.saturating_add(count as u64);
+        self.refresh(now);
+    }
+
+    pub fn record_fill(&mut self, size: u64, now: i64) {
+        self.roll_volume_window(now);
+        let bucket = self.last_bucket_hour.rem_euclid(VOLUME_BUCKETS as i64) as usize;
+        self.volume_buckets[bucket] = self.volume_buckets[bucket].saturating_add(size);
+        self.fill_count = self.fill_count.saturating_add(1);
+        self.total_fill_volume = self.total_fill_volume.saturating_add(size);
+        self.refresh(now);
+    }
+
+    /// Clear the hourly buckets that fell out of the 24h window since the last update.
+    fn roll_volume_window(&mut self, now: i64) {
+        let hour = now.div_euclid(VOLUME_BUCKET_SECS);
+        let elapsed = hour.saturating_sub(self.last_bucket_hour);
+        if elapsed <= 0 {
+            return;
+        }
+        if elapsed >= VOLUME_BUCKETS as i64 {
+            self.volume_buckets = [0; VOLUME_BUCKETS];
+        } else {
+            for h in 1..=elapsed {
+                let bucket = (self.last_bucket_hour + h).rem_euclid(VOLUME_BUCKETS as i64) as usize;
+                self.volume_buckets[bucket] = 0;
+            }
+        }
+        self.last_bucket_hour = hour;
+    }
+
+    /// Recompute the derived metrics so readers never need to do the math themselves.
+    fn refresh(&mut self, now: i64) {
+        self.roll_volume_window(now);
+        self.volume_24h = self
+            .volume_buckets
+            .iter()
+            .fold(0u64, |acc, v| acc.saturating_add(*v));
+        self.avg_fill_size = self
+            .total_fill_volume
+            .checked_div(self.fill_count)
+            .unwrap_or(0);
+        self.cancel_to_fill_bps = self
+            .cancel_count
+            .saturating_mul(BPS_DENOMINATOR as u64)
+            / self.fill_count.max(1);
+        self.last_update_ts = now;
+    }
+}
+
+/// MarketHealth is the operations view of one market: solvency, open orders, epoch and
+/// oracle freshness, and paused instructions in a single account. It is refreshed
+/// opportunistically by instructions that already touch the market, and by
+/// `refresh_market_health` on demand, so `last_update_ts` says how current it is.
+#[account]
+pub struct MarketHealth {
+    pub market: Pubkey,
+    pub treasury_balance: u64,        // MarketState::total_fees_collected
+    pub outstanding_liabilities: u64, // MarketState::outstanding_claims
+    pub liabilities_covered: bool,    // treasury_balance >= outstanding_liabilities
+    pub open_orders: u64,
+    pub current_epoch: u64,
+    pub last_epoch_roll_ts: i64,
+    pub disabled_instructions: u64,   // GlobalConfig bitmask at last refresh
+    pub market_status: MarketStatus,
+    pub reference_price_ts: i64,
+    pub oracle_stale: bool,
+    pub last_update_ts: i64,
+}
+
+impl MarketHealth {
+    pub const SIZE: usize =
+          32 // market
+        + 8  // treasury_balance
+        + 8  // outstanding_liabilities
+        + 1  // liabilities_covered
+        + 8  // open_orders
+        + 8  // current_epoch
+        + 8  // last_epoch_roll_ts
+        + 8  // disabled_instructions
+        + 1  // market_status
+        + 8  // reference_price_ts
+        + 1  // oracle_stale
+        + 8; // last_update_ts
+
+    pub fn initialize(
+        &mut self,
+        market: Pubkey,
+        market_state: &MarketState,
+        global_config: &GlobalConfig,
+        now: i64,
+    ) {
+        self.market = market;
+        self.open_orders = 0;
+        self.refresh(market_state, global_config, now);
+    }
+
+    pub fn record_order_opened(&mut self) {
+        self.open_orders = self.open_orders.saturating_add(1);
+    }
+
+    pub fn record_order_closed(&mut self) {
+        self.open_orders = self.open_orders.saturating_sub(1);
+    }
+
+    /// Copy the current market and config state in.
+    pub fn refresh(&mut self, market_state: &MarketState, global_config: &GlobalConfig, now: i64) {
+        self.treasury_balance = market_state.total_fees_collected;
+        self.outstanding_liabilities = market_state.outstanding_claims;
+        self.liabilities_covered =
+            market_state.total_fees_collected >= market_state.outstanding_claims;
+        self.current_epoch = market_state.current_epoch;
+        self.last_epoch_roll_ts = market_state.epoch_start_ts;
+        self.disabled_instructions = global_config.disabled_instructions;
+        self.market_status = market_state.status;
+        self.reference_price_ts = market_state.reference_price_ts;
+        self.oracle_stale = market_state.reference_price_stale(now);
+        self.last_update_ts = now;
+    }
+}
+
+/// Each user’s state includes:
+///   - maker/taker stats
+///   - referral info
+///   - liquidity score
+///   - active orders array (for demonstration)
+#[account]
 pub struct UserState {
     pub authority: Pubkey,
+    pub market: Pubkey,
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub maker_rebates_earned: u64,
+    pub taker_fees_paid: u64,
+    pub liquidity_score: u64,
+    pub taker_rebates_earned: u64,
+    pub claimable_rebates: u64,
+    pub claimable_rewards: u64,
+    pub claim_lots: [ClaimLot; CLAIM_LOTS], // the claimable balances above, by deadline
+    pub rewards_opt_out: bool, // score still accrues, but distributions skip this user
+    pub recent_claim_keys: [u64; CLAIM_KEY_HISTORY], // ring buffer of claim idempotency keys
+    pub claim_key_cursor: u8,
+    pub strategy_stats: [StrategyStats; MAX_STRATEGY_TAGS], // bounded map by strategy_id
+    pub activity_epoch: u64,  // epoch the counters below belong to
+    pub epoch_places: u32,
+    pub epoch_cancels: u32,
+    pub epoch_fills: u32,     // own orders filled as maker
+    pub penalty_epoch: u64,   // epoch the cancel penalty applies in; 0 => never penalized
+    pub penalty_fees_paid: u64,
+    pub last_activity_ts: i64,      // last place, cancel or fill on either side
+    pub reclaim_requested_ts: i64,  // 0 => owner has not asked for rent reclaim
+    pub last_order_id: u64,         // order ids are per user and start at 1
+    pub referrer_commitment: [u8; 32], // confidential referrer, see `referral_commitment`
+    pub referral_rewards_owed: u64, // accrued for the referrer, paid on their claim; in claim_lots
+    pub registered_ts: i64,         // 0 => imported or returning, no first-fill bonus
+    pub first_fill_ts: i64,         // first fill as taker; 0 => none yet
+    pub bonus_fills: u8,            // fills that received the first-fill bonus
+    pub referral_link: ReferralLink, // totals that bound what the referrer can earn
+    pub staked_amount: u64,         // mirror of the StakeAccount's stake, for fee tiers
+    pub referrer: Option<Pubkey>,
+    pub orders: Vec<Order>, // the account's order slots, see `UserState::space`
+}
+
+// `SIZE` covers everything but the order slots, which are sized per account: each
+// `Order` occupies `Order::SIZE` bytes. `Option<Pubkey>` is sized for the `Some` case.
+// The unit tests below serialize maximal instances to keep these in sync.
+impl UserState {
+    pub const SIZE: usize =
+          32  // authority
+        + 32  // market
+        + 8   // maker_volume
+        + 8   // taker_volume
+        + 8   // maker_rebates_earned
+        + 8   // taker_fees_paid
+        + 8   // liquidity_score
+        + 8   // taker_rebates_earned
+        + 8   // claimable_rebates
+        + 8   // claimable_rewards
+        + ClaimLot::SIZE * CLAIM_LOTS // claim_lots
+        + 1   // rewards_opt_out
+        + 8 * CLAIM_KEY_HISTORY // recent_claim_keys
+        + 1   // claim_key_cursor
+        + StrategyStats::SIZE * MAX_STRATEGY_TAGS // strategy_stats
+        + 8   // activity_epoch
+        + 4   // epoch_places
+        + 4   // epoch_cancels
+        + 4   // epoch_fills
+        + 8   // penalty_epoch
+        + 8   // penalty_fees_paid
+        + 8   // last_activity_ts
+        + 8   // reclaim_requested_ts
+        + 8   // last_order_id
+        + 32  // referrer_commitment
+        + 8   // referral_rewards_owed
+        + 8   // registered_ts
+        + 8   // first_fill_ts
+        + 1   // bonus_fills
+        + ReferralLink::SIZE // referral_link
+        + 8   // staked_amount
+        + 1 + 32  // referrer: Option<Pubkey> => 1 + 32 bytes
+        + 4;  // orders: Vec length
+
+    /// Account space (discriminator excluded) of a `UserState` with `order_slots` slots.
+    pub fn space(order_slots: usize) -> usize {
+        Self::SIZE + Order::SIZE * order_slots
+    }
+
+    /// Reset every field for a freshly created account with `order_slots` order slots.
+    pub fn initialize(
+        &mut self,
+        authority: Pubkey,
+        market: Pubkey,
+        referrer: Option<Pubkey>,
+        order_slots: usize,
+    ) {
+        self.authority = authority;
+        self.market = market;
+        self.maker_volume = 0;
+        self.taker_volume = 0;
+        self.maker_rebates_earned = 0;
+        self.taker_fees_paid = 0;
+        self.liquidity_score = 0;
+        self.taker_rebates_earned = 0;
+        self.claimable_rebates = 0;
+        self.claimable_rewards = 0;
+        self.claim_lots = [ClaimLot::default(); CLAIM_LOTS];
+        self.rewards_opt_out = false;
+        self.recent_claim_keys = [0; CLAIM_KEY_HISTORY];
+        self.claim_key_cursor = 0;
+        self.strategy_stats = [StrategyStats::default(); MAX_STRATEGY_TAGS];
+        self.activity_epoch = 0;
+        self.epoch_places = 0;
+        self.epoch_cancels = 0;
+        self.epoch_fills = 0;
+        self.penalty_epoch = 0;
+        self.penalty_fees_paid = 0;
+        self.last_activity_ts = 0;
+        self.reclaim_requested_ts = 0;
+        self.last_order_id = 0;
+        self.referrer_commitment = [0; 32];
+        self.referral_rewards_owed = 0;
+        self.registered_ts = 0;
+        self.first_fill_ts = 0;
+        self.bonus_fills = 0;
+        self.referral_link = ReferralLink::default();
+        self.staked_amount = 0;
+        self.referrer = referrer;
+        self.orders = vec![Order::default(); order_slots];
+    }
+
+    /// Check that `fill_size` takes the whole of the open order at `order_index`.
+    pub fn require_whole_order(&self, order_index: u8, fill_size: u64) -> Result<()> {
+        require!(
+            fill_size > 0
+                && self
+                    .orders
+                    .get(order_index as usize)
+                    .map(|order| order.size_remaining)
+                    == Some(fill_size),
+            FeeError::FillSizeMismatch
+        );
+        Ok(())
+    }
+
+    /// Grow or shrink the order slots to `order_slots`; the dropped slots must be empty.
+    pub fn resize_order_slots(&mut self, order_slots: usize) -> Result<()> {
+        require!(
+            self.orders
+                .iter()
+                .skip(order_slots)
+                .all(|order| order.size_remaining == 0),
+            FeeError::OrderSlotsInUse
+        );
+        self.orders.resize(order_slots, Order::default());
+        Ok(())
+    }
+
+    /// Seed lifetime stats carried over from a legacy deployment. Nothing claimable is
+    /// imported: balances owed by the old program are settled there.
+    pub fn import_legacy_stats(&mut self, legacy_stats: &LegacyUserStats) {
+        self.maker_volume = legacy_stats.maker_volume;
+        self.taker_volume = legacy_stats.taker_volume;
+        self.maker_rebates_earned = legacy_stats.maker_rebates_earned;
+        self.taker_fees_paid = legacy_stats.taker_fees_paid;
+        self.liquidity_score = legacy_stats.liquidity_score;
+        self.taker_rebates_earned = legacy_stats.taker_rebates_earned;
+    }
+
+    /// Credit claimable `rebates` and `rewards` that expire at `expires_at` (see
+    /// `MarketState::claim_expiry`), in the lot of earlier credits with the same deadline
+    /// or a free one. Older credits keep their own deadlines. Take expired lots out first.
+    pub fn credit_claimable(&mut self, rebates: u64, rewards: u64, expires_at: i64) -> Result<()> {
+        self.claimable_rebates = self
+            .claimable_rebates
+            .checked_add(rebates)
+            .ok_or(FeeError::Overflow)?;
+        self.claimable_rewards = self
+            .claimable_rewards
+            .checked_add(rewards)
+            .ok_or(FeeError::Overflow)?;
+
+        let lot = self.claim_lot(expires_at)?;
+        lot.rebates = lot.rebates.saturating_add(rebates);
+        lot.rewards = lot.rewards.saturating_add(rewards);
+        Ok(())
+    }
+
+    /// Accrue referral rewards for this user's referrer, expiring at `expires_at` like
+    /// `credit_claimable`. Take expired lots out first.
+    pub fn credit_referral_rewards(&mut self, amount: u64, expires_at: i64) -> Result<()> {
+        self.referral_rewards_owed = self
+            .referral_rewards_owed
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+
+        let lot = self.claim_lot(expires_at)?;
+        lot.referral_rewards = lot.referral_rewards.saturating_add(amount);
+        Ok(())
+    }
+
+    /// The lot of earlier credits expiring at `expires_at`, or a free one.
+    fn claim_lot(&mut self, expires_at: i64) -> Result<&mut ClaimLot> {
+        // Deadlines compare with 0 (never) last.
+        let deadline = |expires_at: i64| if expires_at == 0 { i64::MAX } else { expires_at };
+        let lots = &mut self.claim_lots;
+        let index = lots
+            .iter()
+            .position(|lot| !lot.is_empty() && lot.expires_at == expires_at)
+            .or_else(|| lots.iter().position(ClaimLot::is_empty))
+            // Only possible after the deadline was shortened: join the latest lot.
+            .or_else(|| (0..CLAIM_LOTS).max_by_key(|&i| deadline(lots[i].expires_at)))
+            .ok_or(FeeError::Overflow)?;
+        let lot = &mut lots[index];
+        if lot.is_empty() || deadline(expires_at) > deadline(lot.expires_at) {
+            lot.expires_at = expires_at;
+        }
+        Ok(lot)
+    }
+
+    /// Take every lot expired at `now` out of the claimable balances, for the market to
+    /// forfeit.
+    pub fn take_expired_claims(&mut self, now: i64) -> ClaimAmounts {
+        let mut expired = ClaimAmounts::default();
+        for lot in self.claim_lots.iter_mut().filter(|lot| lot.is_expired(now)) {
+            expired.rebates = expired.rebates.saturating_add(lot.rebates);
+            expired.rewards = expired.rewards.saturating_add(lot.rewards);
+            expired.referral_rewards = expired
+                .referral_rewards
+                .saturating_add(lot.referral_rewards);
+            *lot = ClaimLot::default();
+        }
+        self.claimable_rebates = self.claimable_rebates.saturating_sub(expired.rebates);
+        self.claimable_rewards = self.claimable_rewards.saturating_sub(expired.rewards);
+        self.referral_rewards_owed = self
+            .referral_rewards_owed
+            .saturating_sub(expired.referral_rewards);
+        expired
+    }
+
+    /// Take all claimable rebates (if `rebates`) and rewards (if `rewards`), expired or
+    /// not; take expired lots out first to forfeit them instead.
+    pub fn take_claims(&mut self, rebates: bool, rewards: bool) -> ClaimAmounts {
+        let mut taken = ClaimAmounts::default();
+        if rebates {
+            taken.rebates = std::mem::take(&mut self.claimable_rebates);
+        }
+        if rewards {
+            taken.rewards = std::mem::take(&mut self.claimable_rewards);
+        }
+        for lot in self.claim_lots.iter_mut() {
+            if rebates {
+                lot.rebates = 0;
+            }
+            if rewards {
+                lot.rewards = 0;
+            }
+            if lot.is_empty() {
+                *lot = ClaimLot::default();
+            }
+        }
+        taken
+    }
+
+    /// Take all referral rewards owed, expired or not; take expired lots out first to
+    /// forfeit them instead.
+    pub fn take_referral_rewards(&mut self) -> u64 {
+        for lot in self.claim_lots.iter_mut() {
+            lot.referral_rewards = 0;
+            if lot.is_empty() {
+                *lot = ClaimLot::default();
+            }
+        }
+        std::mem::take(&mut self.referral_rewards_owed)
+    }
+
+    /// Move the activity counters onto the market's current epoch. If they held the epoch
+    /// right before it and that epoch breached the cancel-to-fill limit, the user is
+    /// penalized for the current epoch and the breach is returned for the caller to emit.
+    pub fn sync_activity_epoch(&mut self, market_state: &MarketState) -> Option<CancelPenalty> {
+        let current_epoch = market_state.current_epoch;
+        if self.activity_epoch == current_epoch {
+            return None;
+        }
+
+        let mut penalty = None;
+        if self.activity_epoch.checked_add(1) == Some(current_epoch)
+            && market_state.cancel_penalty_triggered(self.epoch_cancels, self.epoch_fills)
+        {
+            self.penalty_epoch = current_epoch;
+            penalty = Some(CancelPenalty {
+                offending_epoch: self.activity_epoch,
+                penalty_epoch: current_epoch,
+                places: self.epoch_places,
+                cancels: self.epoch_cancels,
+                fills: self.epoch_fills,
+            });
+        }
+
+        self.activity_epoch = current_epoch;
+        self.epoch_places = 0;
+        self.epoch_cancels = 0;
+        self.epoch_fills = 0;
+        penalty
+    }
+
+    pub fn is_penalized(&self, epoch: u64) -> bool {
+        self.penalty_epoch != 0 && self.penalty_epoch == epoch
+    }
+
+    /// Remember a claim's idempotency key, rejecting 0 and any key among the last
+    /// `CLAIM_KEY_HISTORY` claims.
+    pub fn record_claim_key(&mut self, idempotency_key: u64) -> Result<()> {
+        require!(idempotency_key != 0, FeeError::InvalidIdempotencyKey);
+        require!(
+            !self.recent_claim_keys.contains(&idempotency_key),
+            FeeError::DuplicateClaim
+        );
+
+        let slot = self.claim_key_cursor as usize % CLAIM_KEY_HISTORY;
+        self.recent_claim_keys[slot] = idempotency_key;
+        self.claim_key_cursor = ((slot + 1) % CLAIM_KEY_HISTORY) as u8;
+
+        Ok(())
+    }
+
+    /// Whether `reclaim_rent_after_inactivity` may close this account: the owner asked for
+    /// it, no orders or claimable balances are left, and `RENT_RECLAIM_DELAY_SECS` have
+    /// passed since the later of the request and the last activity.
+    pub fn is_rent_reclaimable(&self, now: i64) -> bool {
+        if self.reclaim_requested_ts == 0 {
+            return false;
+        }
+        let idle_since = self.reclaim_requested_ts.max(self.last_activity_ts);
+        self.orders.iter().all(|order| order.size_remaining == 0)
+            && self.claimable_rebates == 0
+            && self.claimable_rewards == 0
+            && self.referral_rewards_owed == 0
+            && now >= idle_since.saturating_add(RENT_RECLAIM_DELAY_SECS)
+    }
+
+    /// Whether referral fees are carved out of this user's taker fees, for a plain-text
+    /// or a confidential referrer.
+    pub fn has_referrer(&self) -> bool {
+        self.referrer.is_some() || self.referrer_commitment != [0; 32]
+    }
+
+    /// Whether `referrer` referred this user: it is the plain-text referrer, or `salt`
+    /// opens the stored commitment to it.
+    pub fn is_referred_by(&self, referrer: &Pubkey, salt: Option<&[u8; 32]>) -> bool {
+        if self.referrer == Some(*referrer) {
+            return true;
+        }
+        match salt {
+            Some(salt) => {
+                self.referrer_commitment != [0; 32]
+                    && referral_commitment(referrer, salt) == self.referrer_commitment
+            }
+            None => false,
+        }
+    }
+
+    pub fn open_order_count(&self) -> usize {
+        self.orders
+            .iter()
+            .filter(|order| order.size_remaining > 0)
+            .count()
+    }
+
+    /// Promotion budget held by this user's open orders.
+    pub fn promo_reserved(&self) -> u64 {
+        self.orders
+            .iter()
+            .filter(|order| order.size_remaining > 0)
+            .fold(0u64, |total, order| total.saturating_add(order.promo_reserve))
+    }
+
+    /// Lifetime maker + taker volume.
+    pub fn total_volume(&self) -> u64 {
+        self.maker_volume.saturating_add(self.taker_volume)
+    }
+
+    /// What decides the user's fee tier: lifetime volume plus `STAKE_TIER_WEIGHT` per
+    /// staked unit.
+    pub fn tier_volume(&self) -> u64 {
+        self.total_volume()
+            .saturating_add(self.staked_amount.saturating_mul(STAKE_TIER_WEIGHT))
+    }
+
+    /// Write a new order into the first free slot and return its index. The order gets the
+    /// user's next `order_id`, overriding whatever it carried. A tagged order also reserves
+    /// its strategy's stats slot, so attributing its fills can never fail.
+    pub fn insert_order(&mut self, order: Order) -> Result<usize> {
+        if let PriceMode::PrimaryPeg { offset_bps } = order.price_mode {
+            require!(offset_bps <= BPS_DENOMINATOR, FeeError::InvalidPegOffset);
+        }
+
+        //  Find an empty slot index
+        let idx = self
+            .orders
+            .iter()
+            .position(|order_slot| order_slot.size_remaining == 0)
+            .ok_or(FeeError::NoFreeOrderSlot)?;
+
+        if order.strategy_id != UNTAGGED_STRATEGY {
+            self.strategy_slot(order.strategy_id)?;
+        }
+
+        let order_id = self.last_order_id.checked_add(1).ok_or(FeeError::Overflow)?;
+        self.last_order_id = order_id;
+        self.orders[idx] = Order {
+            order_id,
+            scored_until: order.creation_timestamp,
+            ..order
+        };
+
+        Ok(idx)
+    }
+
+    /// Index of `strategy_id`'s stats, claiming an empty slot for a new tag.
+    fn strategy_slot(&mut self, strategy_id: u16) -> Result<usize> {
+        if let Some(i) = self
+            .strategy_stats
+            .iter()
+            .position(|stats| stats.strategy_id == strategy_id)
+        {
+            return Ok(i);
+        }
+
+        let i = self
+            .strategy_stats
+            .iter()
+            .position(|stats| stats.strategy_id == UNTAGGED_STRATEGY)
+            .ok_or(FeeError::StrategyTableFull)?;
+        self.strategy_stats[i] = StrategyStats {
+            strategy_id,
+            ..StrategyStats::default()
+        };
+        Ok(i)
+    }
+
+    /// Attribute a maker fill to the order's strategy tag, if it still has a stats slot.
+    pub fn record_strategy_fill(&mut self, strategy_id: u16, size: u64, maker_rebate: u64) {
+        if strategy_id == UNTAGGED_STRATEGY {
+            return;
+        }
+        if let Some(stats) = self
+            .strategy_stats
+            .iter_mut()
+            .find(|stats| stats.strategy_id == strategy_id)
+        {
+            stats.volume = stats.volume.saturating_add(size);
+            stats.rebates_earned = stats.rebates_earned.saturating_add(maker_rebate);
+            stats.fill_count = stats.fill_count.saturating_add(1);
+        }
+    }
+
+    /// Free the slot at `order_index` and "reward" the liquidity_score for the order's
+    /// scoreable time since its last checkpoint. Returns the canceled size.
+    pub fn cancel_order(
+        &mut self,
+        order_index: u8,
+        market_state: &MarketState,
+        clock: &impl TimeProvider,
+    ) -> Result<u64> {
+        require!(
+            (order_index as usize) < self.orders.len(),
+            FeeError::InvalidOrderIndex
+        );
+        let index = order_index as usize;
+        let canceled_size = self.orders[index].size_remaining;
+        require!(canceled_size > 0, FeeError::NoOpenOrders);
+
+        self.accrue_order_score(index, canceled_size, market_state, clock.now());
+
+        // Mark slot as free
+        self.orders[index] = Order::default();
+
+        Ok(canceled_size)
+    }
+
+    /// Slots of the open orders on `side` whose price at `now` is within
+    /// `min_price..=max_price`. Pegged orders whose price can't be resolved don't match.
+    pub fn orders_in_range(
+        &self,
+        side: OrderSide,
+        min_price: u64,
+        max_price: u64,
+        market_state: &MarketState,
+        now: i64,
+    ) -> Vec<u8> {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| order.size_remaining > 0 && order.side == side)
+            .filter(|(_, order)| {
+                market_state
+                    .resolve_order_price(order, now)
+                    .is_ok_and(|price| (min_price..=max_price).contains(&price))
+            })
+            .map(|(index, _)| index as u8)
+            .collect()
+    }
+
+    /// Slots of the open orders that have expired by `now`.
+    pub fn expired_orders(&self, now: i64) -> Vec<u8> {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| order.size_remaining > 0 && order.is_expired(now))
+            .map(|(index, _)| index as u8)
+            .collect()
+    }
+
+    /// Credit `size` resting for the in-band time of order `index` since its checkpoint,
+    /// boosting the part inside the side incentive's window, and move the checkpoint to `now`.
+    pub fn accrue_order_score(
+        &mut self,
+        index: usize,
+        size: u64,
+        market_state: &MarketState,
+        now: i64,
+    ) -> u64 {
+        let order = &mut self.orders[index];
+        let from = order.scored_until.max(order.creation_timestamp);
+        let secs = market_state.in_band_secs(order, from, now);
+        let incentive = &market_state.side_incentive;
+        let boosted_secs = match incentive.window(order.side, from, now) {
+            Some((start, end)) => market_state.in_band_secs(order, start, end),
+            None => 0,
+        };
+        let score = |secs: i64| secs.saturating_mul(size as i64).max(0) as u64;
+        let added_liq =
+            score(secs - boosted_secs).saturating_add(incentive.boost(score(boosted_secs)));
+        order.scored_until = order.scored_until.max(now);
+
+        self.liquidity_score = self.liquidity_score.saturating_add(added_liq);
+        added_liq
+    }
+
+    /// Add a scoring plugin's (already clamped) delta to the liquidity score, saturating.
+    pub fn apply_score_delta(&mut self, delta: i64) {
+        self.liquidity_score = if delta >= 0 {
+            self.liquidity_score.saturating_add(delta as u64)
+        } else {
+            self.liquidity_score.saturating_sub(delta.unsigned_abs())
+        };
+    }
+
+    /// Checkpoint every open order's score up to `now`. Returns the score added.
+    pub fn accrue_open_orders(&mut self, market_state: &MarketState, now: i64) -> u64 {
+        let mut added = 0u64;
+        for index in 0..self.orders.len() {
+            let size = self.orders[index].size_remaining;
+            if size > 0 {
+                added = added.saturating_add(self.accrue_order_score(
+                    index,
+                    size,
+                    market_state,
+                    now,
+                ));
+            }
+        }
+        added
+    }
+}
+
+// ----------------------------------
+// FEE MATH
+// ----------------------------------
+
+/// Basis points in a whole: 1 bps = 1 / `BPS_DENOMINATOR`.
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Pips in a whole: 1 pip = 1 / `PIPS_DENOMINATOR`, a hundredth of a bps.
+pub const PIPS_DENOMINATOR: u32 = 1_000_000;
+
+/// `rate / denominator` of `amount`, rounded down.
+pub fn rate_amount(amount: u64, rate: u16, denominator: u64) -> Result<u64> {
+    let value = (amount as u128)
+        .checked_mul(rate as u128)
+        .ok_or(FeeError::Overflow)?
+        / denominator as u128;
+    u64::try_from(value).map_err(|_| error!(FeeError::Overflow))
+}
+
+/// `bps` basis points of `amount`, rounded down.
+pub fn bps_amount(amount: u64, bps: u16) -> Result<u64> {
+    rate_amount(amount, bps, BPS_DENOMINATOR as u64)
+}
+
+/// Most `price_decimals` a market may use; `10^18` still fits in a u64.
+pub const MAX_PRICE_DECIMALS: u8 = 18;
+
+/// `10^price_decimals`: the raw price that stands for one quote unit per base unit.
+pub fn price_scale(price_decimals: u8) -> Result<u64> {
+    require!(
+        price_decimals <= MAX_PRICE_DECIMALS,
+        FeeError::InvalidPriceDecimals
+    );
+    Ok(10u64.pow(price_decimals as u32))
+}
+
+/// Divide a product with a raw price in it back down to quote units, rounded down.
+pub fn scale_down(value: u128, price_decimals: u8) -> Result<u64> {
+    let scaled = value / price_scale(price_decimals)? as u128;
+    u64::try_from(scaled).map_err(|_| error!(FeeError::Overflow))
+}
+
+/// Quote-unit value of `size` base units at raw `price`.
+pub fn notional_value(size: u64, price: u64, price_decimals: u8) -> Result<u64> {
+    scale_down(size as u128 * price as u128, price_decimals)
+}
+
+/// Per-unit price a taker effectively trades at against a maker order on `maker_side`
+/// once the taker fee is applied, rounded against the taker: a buyer (hitting an Ask)
+/// pays `price` plus the fee, a seller (hitting a Bid) receives `price` minus it.
+/// `taker_fee_rate` is in units of `fee_precision`.
+pub fn taker_effective_price(
+    maker_side: OrderSide,
+    price: u64,
+    taker_fee_rate: u16,
+    fee_precision: FeePrecision,
+) -> Result<u64> {
+    let denominator = fee_precision.denominator() as u128;
+    let value = match maker_side {
+        OrderSide::Ask => {
+            ((price as u128) * (denominator + taker_fee_rate as u128)).div_ceil(denominator)
+        }
+        OrderSide::Bid => {
+            (price as u128) * denominator.saturating_sub(taker_fee_rate as u128) / denominator
+        }
+    };
+    u64::try_from(value).map_err(|_| error!(FeeError::Overflow))
+}
+
+/// Check that the maker rebate and the referral reward together can never exceed the
+/// taker fee they are paid out of. Holds in either fee precision: under `FillSize` all
+/// three rates share a unit, and under `TakerFee` the referral is bps of the fee.
+pub fn validate_fee_shares(
+    maker_rebate_rate: u16,
+    taker_fee_rate: u16,
+    referral_rate: u16,
+    referral_basis: ReferralBasis,
+) -> Result<()> {
+    let (maker, taker, referral, whole) = (
+        maker_rebate_rate as u64,
+        taker_fee_rate as u64,
+        referral_rate as u64,
+        BPS_DENOMINATOR as u64,
+    );
+    let within_fee = match referral_basis {
+        ReferralBasis::FillSize => maker + referral <= taker,
+        // maker / taker + referral / BPS_DENOMINATOR <= 1
+        ReferralBasis::TakerFee => {
+            referral <= whole && maker * whole + taker * referral <= taker * whole
+        }
+    };
+    require!(within_fee, FeeError::FeeSharesExceedFee);
+    Ok(())
+}
+
+/// Check a whole fee schedule: the base rates followed by the volume tiers. Every rate, base
+/// or tier, must be bounded by `fee_precision`, thresholds must strictly increase, each
+/// tier must be no worse for traders than the one below it (taker fee never rises, maker
+/// rebate never falls), and the most generous tier must still leave room for the referral
+/// share inside the taker fee. Rates are in units of `fee_precision`.
+pub fn validate_fee_schedule(
+    maker_rebate_rate: u16,
+    taker_fee_rate: u16,
+    referral_rate: u16,
+    referral_basis: ReferralBasis,
+    fee_precision: FeePrecision,
+    tiers: &[FeeTier],
+) -> Result<()> {
+    require!(tiers.len() <= MAX_FEE_TIERS, FeeError::TooManyFeeTiers);
+    require!(
+        taker_fee_rate as u64 <= fee_precision.denominator()
+            && maker_rebate_rate as u64 <= fee_precision.denominator(),
+        FeeError::InvalidFeeConfiguration
+    );
+
+    let mut prev: Option<&FeeTier> = None;
+    let (mut top_maker, mut top_taker) = (maker_rebate_rate, taker_fee_rate);
+    for tier in tiers {
+        require!(
+            tier.taker_fee_rate as u64 <= fee_precision.denominator()
+                && tier.maker_rebate_rate <= tier.taker_fee_rate,
+            FeeError::InvalidFeeTiers
+        );
+        if let Some(prev) = prev {
+            require!(tier.min_volume > prev.min_volume, FeeError::InvalidFeeTiers);
+        }
+        require!(
+            tier.taker_fee_rate <= top_taker && tier.maker_rebate_rate >= top_maker,
+            FeeError::InvalidFeeTiers
+        );
+        top_maker = tier.maker_rebate_rate;
+        top_taker = tier.taker_fee_rate;
+        prev = Some(tier);
+    }
+
+    validate_fee_shares(top_maker, top_taker, referral_rate, referral_basis)
+}
+
+/// One row of a market's volume-based fee schedule, in the market's `fee_precision`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FeeTier {
+    pub min_volume: u64, // lifetime maker + taker volume needed to reach this tier
+    pub taker_fee_rate: u16,
+    pub maker_rebate_rate: u16,
+}
+
+impl FeeTier {
+    pub const SIZE: usize =
+          8  // min_volume
+        + 2  // taker_fee_rate
+        + 2; // maker_rebate_rate
+}
+
+pub const MAX_FEE_TIERS: usize = 8;
+
+/// Return data of `simulate_tier_assignment`. `tier` is `None` for the base rates.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TierAssignment {
+    pub tier: Option<u8>,
+    pub taker_fee_rate: u16,
+    pub maker_rebate_rate: u16,
+}
+
+/// What `referral_rate` is applied to when computing referral rewards.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReferralBasis {
+    /// `fee_precision` units of the filled size (legacy behaviour).
+    FillSize,
+    /// bps of the taker fee amount.
+    TakerFee,
+}
+
+impl Default for ReferralBasis {
+    fn default() -> Self {
+        ReferralBasis::FillSize
+    }
+}
+
+/// Unit of a market's fee schedule: the taker fee, maker rebate and tier rates, and
+/// `referral_rate` under `ReferralBasis::FillSize`. Shares of other amounts (referral of
+/// the taker fee, caps, haircuts, bands) are always bps. Fields holding rates in it are
+/// named `*_rate` rather than `*_bps`: on a `Pips` market they hold pips.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeePrecision {
+    /// 1 / `BPS_DENOMINATOR`.
+    Bps,
+    /// 1 / `PIPS_DENOMINATOR`, for low-fee markets where 1 bps steps are too coarse.
+    Pips,
+}
+
+impl Default for FeePrecision {
+    fn default() -> Self {
+        FeePrecision::Bps
+    }
+}
+
+impl FeePrecision {
+    pub fn denominator(self) -> u64 {
+        match self {
+            FeePrecision::Bps => BPS_DENOMINATOR as u64,
+            FeePrecision::Pips => PIPS_DENOMINATOR as u64,
+        }
+    }
+
+    /// `rate` in this precision restated in `to`; `None` if it doesn't convert exactly
+    /// or no longer fits a u16.
+    pub fn convert(self, rate: u16, to: FeePrecision) -> Option<u16> {
+        let scaled = rate as u64 * to.denominator();
+        if scaled % self.denominator() != 0 {
+            return None;
+        }
+        u16::try_from(scaled / self.denominator()).ok()
+    }
+}
+
+/// A market's base fee rates, as set by `update_fee_parameters` and `batch_update_fees`,
+/// in the market's `fee_precision`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FeeParameters {
+    pub maker_rebate_rate: u16,
+    pub taker_fee_rate: u16,
+    pub referral_rate: u16,
+}
+
+impl FeeParameters {
+    /// These rates, given in `from`, restated in `to` for a market with `referral_basis`:
+    /// a `TakerFee` referral rate is a share of the fee, always bps, and stays as is. Fails
+    /// if any rate can't be restated exactly.
+    pub fn restate(
+        self,
+        from: FeePrecision,
+        to: FeePrecision,
+        referral_basis: ReferralBasis,
+    ) -> Result<FeeParameters> {
+        let convert = |rate: u16| -> Result<u16> {
+            from.convert(rate, to)
+                .ok_or_else(|| error!(FeeError::InvalidFeePrecision))
+        };
+        Ok(FeeParameters {
+            maker_rebate_rate: convert(self.maker_rebate_rate)?,
+            taker_fee_rate: convert(self.taker_fee_rate)?,
+            referral_rate: match referral_basis {
+                ReferralBasis::FillSize => convert(self.referral_rate)?,
+                ReferralBasis::TakerFee => self.referral_rate,
+            },
+        })
+    }
+}
+
+/// Fee rates and amounts for a single fill, as resolved by `MarketState::effective_fees`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct EffectiveFees {
+    pub taker_fee_rate: u16,
+    pub maker_rebate_rate: u16,
+    pub referral_rate: u16,
+    pub referral_basis: ReferralBasis,
+    pub fee_precision: FeePrecision, // unit of the rates above
+    pub taker_fee: u64,
+    pub maker_rebate: u64,
+    pub referral_reward: u64,
+    pub net_fee: u64,
+}
+
+/// A fill's price and fee split, as resolved by `quote_fill`; the return data of
+/// `get_effective_fees`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FillQuote {
+    pub trade_size: u64,
+    pub execution_price: u64,
+    pub notional: u64, // quote units, saturating; see `notional_value`
+    pub fees: EffectiveFees, // maker_rebate with every adjustment below applied
+    pub net_fee: u64,        // what the market keeps
+    pub taker_rebate: u64,   // price improvement share, claimable by the taker
+    pub rebate_haircut: u64, // already deducted from fees.maker_rebate
+    pub skew_adjustment: i64, // already applied to fees.maker_rebate
+    pub promo_rebate: u64,   // included in fees.maker_rebate, paid by the promotion budget
+    pub promo_released: u64, // reserve the epoch's promotion cap sends back to the budget
+    pub bonus_discount: u64, // taken off fees.taker_fee, paid by the promotion budget
+}
+
+// ----------------------------------
+// MARKET PARAMS
+// ----------------------------------
+
+/// Everything a deployment configures on a new market, checked as one bundle by
+/// `validate_market_params` and by `initialize_market`, which fills in its defaults for
+/// what it does not take. `min_fill_notional` and `promotion_epoch_cap` accept any value.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MarketParams {
+    pub fees: FeeParameters, // in `fee_precision`
+    pub referral_basis: ReferralBasis,
+    pub fee_tiers: Vec<FeeTier>,
+    pub price_decimals: u8,
+    pub max_open_orders: u8,
+    pub referral_cap_bps: u16,
+    pub oracle_authority: Pubkey,
+    pub max_reference_age_secs: i64,
+    pub recovery_committee: Option<RecoveryCommittee>,
+    pub fee_precision: FeePrecision,
+    pub min_fill_notional: u64,
+    pub default_order_ttl_secs: i64,
+    pub max_order_ttl_secs: i64,
+    pub skew_rebate: SkewRebate,
+    pub promo_maker_rebate_bps: u16,
+    pub promotion_epoch_cap: u64,
+}
+
+/// Which part of a `MarketParams` bundle an issue is about.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MarketParam {
+    Fees,
+    FeeTiers,
+    PriceDecimals,
+    MaxOpenOrders,
+    ReferralCap,
+    Oracle,
+    RecoveryCommittee,
+    OrderTtl,
+    SkewRebate,
+    PromoMakerRebate,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MarketParamIssue {
+    pub param: MarketParam,
+    pub error_code: u32, // the `FeeError` code the instruction would fail with
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default, Debug)]
+pub struct MarketParamsValidation {
+    pub valid: bool,
+    pub issues: Vec<MarketParamIssue>, // in `MarketParam` order
+}
+
+impl MarketParams {
+    /// `initialize_market`'s bundle: the given fees and settings, everything else at a new
+    /// market's defaults.
+    pub fn new(
+        fees: FeeParameters,
+        fee_precision: FeePrecision,
+        price_decimals: u8,
+        oracle_authority: Pubkey,
+        recovery_committee: Option<RecoveryCommittee>,
+    ) -> Self {
+        MarketParams {
+            fees,
+            referral_basis: ReferralBasis::FillSize,
+            fee_tiers: vec![],
+            price_decimals,
+            max_open_orders: DEFAULT_MAX_OPEN_ORDERS,
+            referral_cap_bps: BPS_DENOMINATOR,
+            oracle_authority,
+            max_reference_age_secs: 0,
+            recovery_committee,
+            fee_precision,
+            min_fill_notional: 0,
+            default_order_ttl_secs: 0,
+            max_order_ttl_secs: 0,
+            skew_rebate: SkewRebate::default(),
+            promo_maker_rebate_bps: 0,
+            promotion_epoch_cap: 0,
+        }
+    }
+
+    /// Fail with the first issue, as `initialize_market` does.
+    pub fn require_valid(&self) -> Result<()> {
+        self.checks().into_iter().try_for_each(|(_, result)| result)
+    }
+
+    /// Report every issue instead of stopping at the first.
+    pub fn validate(&self) -> MarketParamsValidation {
+        let issues: Vec<MarketParamIssue> = self
+            .checks()
+            .into_iter()
+            .filter_map(|(param, result)| {
+                result.err().map(|error| MarketParamIssue {
+                    param,
+                    error_code: error_code(&error),
+                })
+            })
+            .collect();
+        MarketParamsValidation {
+            valid: issues.is_empty(),
+            issues,
+        }
+    }
+
+    /// The checks `initialize_market` and the parameter setters run, one per
+    /// `MarketParam`, in order.
+    fn checks(&self) -> [(MarketParam, Result<()>); 10] {
+        let fees = &self.fees;
+        let fee_rates = || {
+            require!(
+                fees.maker_rebate_rate <= fees.taker_fee_rate
+                    && fees.taker_fee_rate as u64 <= self.fee_precision.denominator(),
+                FeeError::InvalidFeeConfiguration
+            );
+            validate_fee_shares(
+                fees.maker_rebate_rate,
+                fees.taker_fee_rate,
+                fees.referral_rate,
+                self.referral_basis,
+            )
+        };
+        [
+            (MarketParam::Fees, fee_rates()),
+            (
+                MarketParam::FeeTiers,
+                validate_fee_schedule(
+                    fees.maker_rebate_rate,
+                    fees.taker_fee_rate,
+                    fees.referral_rate,
+                    self.referral_basis,
+                    self.fee_precision,
+                    &self.fee_tiers,
+                ),
+            ),
+            (
+                MarketParam::PriceDecimals,
+                price_scale(self.price_decimals).map(|_| ()),
+            ),
+            (
+                MarketParam::MaxOpenOrders,
+                validate_max_open_orders(self.max_open_orders),
+            ),
+            (
+                MarketParam::ReferralCap,
+                if self.referral_cap_bps <= BPS_DENOMINATOR {
+                    Ok(())
+                } else {
+                    err!(FeeError::InvalidReferralCap)
+                },
+            ),
+            (
+                MarketParam::Oracle,
+                if self.max_reference_age_secs >= 0 {
+                    Ok(())
+                } else {
+                    err!(FeeError::InvalidOracleConfiguration)
+                },
+            ),
+            (
+                MarketParam::RecoveryCommittee,
+                self.recovery_committee.unwrap_or_default().validate(),
+            ),
+            (
+                MarketParam::OrderTtl,
+                validate_order_ttl(self.default_order_ttl_secs, self.max_order_ttl_secs),
+            ),
+            (MarketParam::SkewRebate, self.skew_rebate.validate()),
+            (
+                MarketParam::PromoMakerRebate,
+                validate_promo_maker_rebate(self.promo_maker_rebate_bps),
+            ),
+        ]
+    }
+}
+
+fn error_code(error: &anchor_lang::error::Error) -> u32 {
+    match error {
+        anchor_lang::error::Error::AnchorError(error) => error.error_code_number,
+        anchor_lang::error::Error::ProgramError(error) => {
+            u64::from(error.program_error.clone()) as u32
+        }
+    }
+}
+
+// ----------------------------------
+// TIME
+// ----------------------------------
+
+/// Source of the current unix timestamp. Handler logic takes one of these instead of
+/// calling `Clock::get()` directly, so it can run under plain `cargo test`.
+pub trait TimeProvider {
+    fn now(&self) -> i64;
+}
+
+/// The on-chain `Clock` sysvar, read once per instruction.
+pub struct SysvarClock {
+    unix_timestamp: i64,
+}
+
+impl SysvarClock {
+    pub fn load() -> Result<Self> {
+        Ok(Self {
+            unix_timestamp: Clock::get()?.unix_timestamp,
+        })
+    }
+}
+
+impl TimeProvider for SysvarClock {
+    fn now(&self) -> i64 {
+        self.unix_timestamp
+    }
+}
+
+/// A fixed timestamp, for unit tests and off-chain simulation.
+pub struct FixedClock(pub i64);
+
+impl TimeProvider for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
+
+// ----------------------------------
+// FILL LOGIC
+// ----------------------------------
+
+/// The taker's side of a fill request.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FillParams {
+    pub maker_order_index: u8,
+    pub fill_size: u64,
+    pub taker_limit_price: u64, // 0 => no limit
+    pub worst_price: u64,       // 0 => no slippage bound
+    pub book_imbalance_bps: i32, // `MarketState::book_imbalance_bps` before the fill
+}
+
+/// Everything a fill changed, for the handler to record and emit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FillOutcome {
+    pub trade_size: u64,
+    pub execution_price: u64,
+    pub notional: u64, // quote units, saturating; see `notional_value`
+    pub maker_side: OrderSide,
+    pub maker_price_mode: PriceMode,
+    pub maker_rebate: u64,
+    pub taker_fee: u64,
+    pub referral_reward: u64,
+    pub referral_basis: ReferralBasis,
+    pub taker_rebate: u64,
+    pub net_fee: u64,
+    pub fully_filled: bool,
+    pub maker_strategy_id: u16,
+    pub maker_order_id: u64,
+    pub maker_size_remaining: u64,
+    pub maker_book_level: u64, // `Order::book_level` of the maker's order
+    pub maker_rebate_haircut: u64, // already deducted from maker_rebate
+    pub maker_skew_adjustment: i64, // already applied to maker_rebate
+    pub maker_promo_rebate: u64, // included in maker_rebate, paid by the promotion budget
+    pub maker_penalty: Option<CancelPenalty>, // assessed on the maker by this fill
+    pub taker_bonus_discount: u64, // taken off taker_fee, paid by the promotion budget
+    pub promotion_cap_reached: bool, // this fill used up the epoch's promotion cap
+    pub taker_forfeited: ClaimAmounts, // the taker's expired claims, taken back by this fill
+    pub oracle_price: u64,      // reference price at fill time; 0 => none or stale
+    pub oracle_confidence: u64,
+    pub timestamp: i64,
+}
+
+impl FillOrder<'_> {
+    /// Run the scoring plugin, if any (the first of `remaining_accounts`), for the maker's
+    /// side of `fill`.
+    fn score_fill(&mut self, remaining_accounts: &[AccountInfo], fill: &FillOutcome) -> Result<()> {
+        let request = ScoringRequest {
+            market: self.market_state.key(),
+            user: self.maker_user.authority,
+            kind: ScoringEventKind::Fill,
+            order_id: fill.maker_order_id,
+            side: fill.maker_side,
+            price: fill.execution_price,
+            size: fill.trade_size,
+            size_remaining: fill.maker_size_remaining,
+            liquidity_score: self.maker_user.liquidity_score,
+            timestamp: fill.timestamp,
+        };
+        run_scoring_plugin(
+            &mut self.market_state,
+            &mut self.maker_user,
+            remaining_accounts,
+            request,
+        )
+    }
+}
+
+/// Record a fill executed by `fill_order` or `fill_order_exact` in the market's stats and
+/// health, and emit its events.
+fn record_fill(
+    accounts: &mut FillOrder,
+    maker_order_index: u8,
+    fill: &FillOutcome,
+) -> Result<()> {
+    let market_state = &accounts.market_state;
+    let maker_user = &accounts.maker_user;
+    let taker_user = &accounts.taker_user;
+
+    accounts.market_stats.record_fill(fill.trade_size, fill.timestamp);
+    let market_health = &mut accounts.market_health;
+    if fill.fully_filled {
+        market_health.record_order_closed();
+    }
+    market_health.refresh(market_state, &accounts.global_config, fill.timestamp);
+    if fill.maker_price_mode == PriceMode::Fixed {
+        accounts.top_of_book.remove(
+            fill.maker_side,
+            fill.execution_price,
+            fill.trade_size,
+            fill.fully_filled,
+            fill.maker_book_level,
+        );
+    }
+    accounts.open_order_index.update(
+        &maker_user.key(),
+        fill.maker_order_id,
+        fill.maker_size_remaining,
+    );
+
+    //  Emit the fill event now that it's done with all references
+    let event = OrderFilled {
+        maker: maker_user.authority,
+        taker: taker_user.authority,
+        trade_size: fill.trade_size,
+        execution_price: fill.execution_price,
+        maker_rebate: fill.maker_rebate,
+        taker_fee: fill.taker_fee,
+        referral_reward: fill.referral_reward,
+        referral_basis: fill.referral_basis,
+        taker_rebate: fill.taker_rebate,
+        net_fee: fill.net_fee,
+        maker_strategy_id: fill.maker_strategy_id,
+        maker_order_id: fill.maker_order_id,
+        maker_size_remaining: fill.maker_size_remaining,
+        taker_bonus_discount: fill.taker_bonus_discount,
+        maker_skew_adjustment: fill.maker_skew_adjustment,
+        maker_promo_rebate: fill.maker_promo_rebate,
+        oracle_price: fill.oracle_price,
+        oracle_confidence: fill.oracle_confidence,
+        event_seq: accounts.event_cursor.next_seq(),
+    };
+    accounts.event_cursor.record(&event, Clock::get()?.slot);
+    emit!(event);
+
+    if let Some(penalty) = fill.maker_penalty {
+        emit!(CancelPenaltyApplied::new(maker_user, &penalty));
+    }
+    if fill.taker_forfeited.total() > 0 {
+        emit!(ClaimsForfeited::new(taker_user, fill.taker_forfeited));
+    }
+    if fill.maker_rebate_haircut > 0 {
+        emit!(CancelPenaltyCharged {
+            user: maker_user.authority,
+            epoch: market_state.current_epoch,
+            placement_fee: 0,
+            rebate_haircut: fill.maker_rebate_haircut,
+        });
+    }
+
+    if fill.promotion_cap_reached {
+        emit!(PromotionCapReached {
+            market: market_state.key(),
+            epoch: market_state.current_epoch,
+            epoch_promotion_spent: market_state.epoch_promotion_spent,
+            promotion_epoch_cap: market_state.promotion_epoch_cap,
+        });
+    }
+
+    if market_state.is_large_fill(fill.notional) {
+        emit!(LargeFill {
+            market: market_state.key(),
+            maker: maker_user.authority,
+            taker: taker_user.authority,
+            maker_side: fill.maker_side,
+            maker_order_index,
+            trade_size: fill.trade_size,
+            execution_price: fill.execution_price,
+            notional: fill.notional,
+            threshold: market_state.large_fill_notional,
+            taker_fee: fill.taker_fee,
+            maker_rebate: fill.maker_rebate,
+            reference_price: market_state.reference_price,
+            fully_filled: fill.fully_filled,
+            timestamp: fill.timestamp,
+        });
+    }
+
+    Ok(())
+}
+
+/// Price and fee split of a fill of `maker_user`'s order by `taker_user` right now, with
+/// every adjustment `execute_fill` makes: the tier fees, the referral cut, the penalty
+/// haircut, the skew adjustment, the taker's price improvement rebate, the promotional
+/// rebate and the first-fill discount. `get_effective_fees` quotes with it, so a quote is
+/// exactly what the fill does. The maker's activity epoch must already be synced.
+pub fn quote_fill(
+    market_state: &MarketState,
+    maker_user: &UserState,
+    taker_user: &UserState,
+    params: FillParams,
+    now: i64,
+) -> Result<FillQuote> {
+    let FillParams {
+        maker_order_index,
+        fill_size,
+        taker_limit_price,
+        worst_price,
+        book_imbalance_bps,
+    } = params;
+    let maker_order = maker_user
+        .orders
+        .get(maker_order_index as usize)
+        .ok_or(FeeError::InvalidOrderIndex)?;
+    require!(maker_order.size_remaining > 0, FeeError::NoOpenOrders);
+
+    // Check if order expired
+    if maker_order.is_expired(now) {
+        return err!(FeeError::OrderExpired);
+    }
+
+    let trade_size = fill_size.min(maker_order.size_remaining);
+
+    // Pegged orders resolve their price against the reference price right now.
+    let execution_price = market_state.resolve_order_price(maker_order, now)?;
+    market_state.require_min_fill_notional(
+        trade_size,
+        execution_price,
+        maker_order.size_remaining,
+    )?;
+
+    // Fee/Rebate Calculation
+    let mut fees = market_state.effective_fees(maker_user.tier_volume(), taker_user, trade_size)?;
+
+    // A maker penalized for quote flicker forfeits part of the rebate to the market.
+    let rebate_haircut = if maker_user.is_penalized(market_state.current_epoch) {
+        bps_amount(fees.maker_rebate, market_state.penalty_rebate_haircut_bps)?
+    } else {
+        0
+    };
+    fees.maker_rebate -= rebate_haircut;
+    fees.net_fee += rebate_haircut;
+
+    // The referral cut is owed to the taker's referrer, so the market does not keep it.
+    // `effective_fees` guarantees rebate + referral never exceed the taker fee.
+    fees.net_fee -= fees.referral_reward;
+
+    // Skew: resting on the thin side of a lopsided book earns a bonus, paid out of the
+    // net fee; resting on the heavy side costs part of the rebate.
+    let skew_adjustment = match market_state.skew_rebate.adjustment(
+        maker_order.side,
+        book_imbalance_bps,
+        fees.maker_rebate,
+    )? {
+        bonus if bonus >= 0 => {
+            let bonus = (bonus as u64).min(fees.net_fee);
+            fees.maker_rebate += bonus;
+            fees.net_fee -= bonus;
+            bonus as i64
+        }
+        cut => {
+            fees.maker_rebate -= cut.unsigned_abs();
+            fees.net_fee += cut.unsigned_abs();
+            cut
+        }
+    };
+
+    // Price improvement: how much better the maker's price was than the taker's limit.
+    // A maker Ask is hit by a buying taker, a maker Bid by a selling taker.
+    let improvement_per_unit = if taker_limit_price == 0 {
+        0
+    } else {
+        match maker_order.side {
+            OrderSide::Ask => {
+                require!(
+                    taker_limit_price >= execution_price,
+                    FeeError::LimitPriceNotCrossed
+                );
+                taker_limit_price - execution_price
+            }
+            OrderSide::Bid => {
+                require!(
+                    taker_limit_price <= execution_price,
+                    FeeError::LimitPriceNotCrossed
+                );
+                execution_price - taker_limit_price
+            }
+        }
+    };
+
+    // The taker's share of the improvement is capped so the market never pays out
+    // more than it keeps.
+    let taker_rebate = if improvement_per_unit == 0 {
+        0
+    } else {
+        let improvement = (improvement_per_unit as u128)
+            .checked_mul(trade_size as u128)
+            .ok_or(FeeError::Overflow)?
+            .checked_mul(market_state.price_improvement_rebate_bps as u128)
+            .ok_or(FeeError::Overflow)?
+            / BPS_DENOMINATOR as u128;
+        scale_down(improvement, market_state.price_decimals)?.min(fees.net_fee)
+    };
+    let net_fee = fees.net_fee - taker_rebate;
+
+    // A promotional order draws its share of the reserve held since placement; the
+    // final fill takes whatever is left. Promotional rebates come out of the promotion
+    // budget on top of the fee split, so the maker can net more than the taker paid.
+    // Whatever the epoch's promotion cap no longer allows goes back to the budget instead.
+    let promo_due = if trade_size == maker_order.size_remaining {
+        maker_order.promo_reserve
+    } else {
+        (maker_order.promo_reserve as u128 * trade_size as u128
+            / maker_order.size_remaining as u128) as u64
+    };
+    let promo_rebate = promo_due.min(market_state.promotion_epoch_room());
+    fees.maker_rebate = fees
+        .maker_rebate
+        .checked_add(promo_rebate)
+        .ok_or(FeeError::Overflow)?;
+
+    // A new user's first fills get part of the taker fee waived, paid from the promotion
+    // budget out of what this fill's promotional rebate leaves. The market still keeps its
+    // full net fee; referral and fee totals still see the gross fee.
+    let bonus_discount = market_state.first_fill_bonus.discount(
+        taker_user,
+        fees.taker_fee,
+        market_state
+            .available_promotion_budget()
+            .saturating_add(promo_due - promo_rebate)
+            .min(market_state.promotion_epoch_room().saturating_sub(promo_rebate)),
+        now,
+    )?;
+    let gross_taker_fee = fees.taker_fee;
+    fees.taker_fee -= bonus_discount;
+
+    // Slippage bound: judged on what the taker actually pays (or receives) per unit, so
+    // at the rate left after the first-fill discount.
+    if worst_price > 0 {
+        let charged_fee_bps = if bonus_discount == 0 {
+            fees.taker_fee_rate
+        } else {
+            (fees.taker_fee_rate as u64 * fees.taker_fee).div_ceil(gross_taker_fee) as u16
+        };
+        let effective_price = taker_effective_price(
+            maker_order.side,
+            execution_price,
+            charged_fee_bps,
+            fees.fee_precision,
+        )?;
+        let within_bound = match maker_order.side {
+            OrderSide::Ask => effective_price <= worst_price,
+            OrderSide::Bid => effective_price >= worst_price,
+        };
+        require!(within_bound, FeeError::SlippageExceeded);
+    }
+
+    Ok(FillQuote {
+        trade_size,
+        execution_price,
+        // Saturate rather than fail the fill: the notional only feeds stats and alerts.
+        notional: notional_value(trade_size, execution_price, market_state.price_decimals)
+            .unwrap_or(u64::MAX),
+        fees,
+        net_fee,
+        taker_rebate,
+        rebate_haircut,
+        skew_adjustment,
+        promo_rebate,
+        promo_released: promo_due - promo_rebate,
+        bonus_discount,
+    })
+}
+
+/// Fill a maker’s order partially or fully against the taker: charge fees, credit rebates,
+/// update volumes and the market's fee total. Signer checks are left to the handler.
+pub fn execute_fill(
+    market_state: &mut MarketState,
+    maker_user: &mut UserState,
+    taker_user: &mut UserState,
+    params: FillParams,
+    clock: &impl TimeProvider,
+) -> Result<FillOutcome> {
+    let maker_order_index = params.maker_order_index;
+    require!(
+        (maker_order_index as usize) < maker_user.orders.len(),
+        FeeError::InvalidOrderIndex
+    );
+
+    let now = clock.now();
+    let maker_score = maker_user.liquidity_score;
+    let maker_penalty = maker_user.sync_activity_epoch(market_state);
+    let FillQuote {
+        trade_size,
+        execution_price,
+        notional,
+        fees,
+        net_fee,
+        taker_rebate,
+        rebate_haircut,
+        skew_adjustment,
+        promo_rebate,
+        promo_released,
+        bonus_discount,
+    } = quote_fill(market_state, maker_user, taker_user, params, now)?;
+
+    // Taking the whole order skips the partial-fill bookkeeping: the order is scored at its
+    // full size and its slot cleared in one go, without first writing down its size and
+    // promotional reserve.
+    let index = maker_order_index as usize;
+    let fully_filled = trade_size == maker_user.orders[index].size_remaining;
+    let maker_order = if fully_filled {
+        maker_user.accrue_order_score(index, trade_size, market_state, now);
+        let mut maker_order = std::mem::take(&mut maker_user.orders[index]);
+        maker_order.size_remaining = 0;
+        maker_order
+    } else {
+        let maker_order = &mut maker_user.orders[index];
+        maker_order.promo_reserve -= promo_rebate + promo_released;
+        maker_order.size_remaining -= trade_size;
+        *maker_order
+    };
+    let maker_side = maker_order.side;
+    let maker_price_mode = maker_order.price_mode;
+    let strategy_id = maker_order.strategy_id;
+    let maker_order_id = maker_order.order_id;
+    let maker_size_remaining = maker_order.size_remaining;
+    let maker_book_level = maker_order.book_level;
+
+    market_state.release_promotion(promo_released);
+    let mut promotion_cap_reached = if promo_rebate > 0 {
+        market_state.pay_promotion(promo_rebate)?
+    } else {
+        false
+    };
+
+    // Update the user accounts & global market state:
+    //    - maker/taker volumes,
+    //    - total_fees_collected,
+    //    - liquidity_score checkpoints, etc.
+
+    // Update maker stats
+    maker_user.maker_volume = maker_user
+        .maker_volume
+        .checked_add(trade_size)
+        .ok_or(FeeError::Overflow)?;
+    maker_user.maker_rebates_earned = maker_user
+        .maker_rebates_earned
+        .checked_add(fees.maker_rebate)
+        .ok_or(FeeError::Overflow)?;
+    maker_user.record_strategy_fill(strategy_id, trade_size, fees.maker_rebate);
+    maker_user.epoch_fills = maker_user.epoch_fills.saturating_add(1);
+    maker_user.last_activity_ts = now;
+
+    // The first-fill discount is paid from the promotion budget, not the net fee.
+    if bonus_discount > 0 {
+        taker_user.bonus_fills = taker_user.bonus_fills.saturating_add(1);
+        market_state.promotion_budget -= bonus_discount;
+        promotion_cap_reached |= market_state.record_promotion_spend(bonus_discount);
+    }
+
+    // Update taker stats
+    taker_user.last_activity_ts = now;
+    if taker_user.first_fill_ts == 0 {
+        taker_user.first_fill_ts = now;
+    }
+    taker_user.taker_volume = taker_user
+        .taker_volume
+        .checked_add(trade_size)
+        .ok_or(FeeError::Overflow)?;
+    taker_user.taker_fees_paid = taker_user
+        .taker_fees_paid
+        .checked_add(fees.taker_fee)
+        .ok_or(FeeError::Overflow)?;
+    taker_user.taker_rebates_earned = taker_user
+        .taker_rebates_earned
+        .checked_add(taker_rebate)
+        .ok_or(FeeError::Overflow)?;
+    let mut taker_forfeited = ClaimAmounts::default();
+    if taker_rebate > 0 || fees.referral_reward > 0 {
+        taker_forfeited = taker_user.take_expired_claims(now);
+        market_state.forfeit_claims(taker_forfeited)?;
+    }
+    if taker_rebate > 0 {
+        taker_user.credit_claimable(taker_rebate, 0, market_state.claim_expiry(now))?;
+        market_state.outstanding_claims = market_state
+            .outstanding_claims
+            .checked_add(taker_rebate)
+            .ok_or(FeeError::Overflow)?;
+    }
+
+    // Accrue the referral cut on the taker, for their referrer to claim. The referrer's
+    // cut and the market's fee totals are on the fee before the first-fill discount.
+    let gross_taker_fee = fees.taker_fee + bonus_discount;
+    if taker_user.has_referrer() {
+        taker_user
+            .referral_link
+            .record(gross_taker_fee, fees.referral_reward);
+    }
+    if fees.referral_reward > 0 {
+        taker_user.credit_referral_rewards(fees.referral_reward, market_state.claim_expiry(now))?;
+        market_state.outstanding_claims = market_state
+            .outstanding_claims
+            .checked_add(fees.referral_reward)
+            .ok_or(FeeError::Overflow)?;
+    }
+
+    // Collect net fees
+    market_state.total_fees_collected = market_state
+        .total_fees_collected
+        .checked_add(net_fee)
+        .ok_or(FeeError::Overflow)?;
+    market_state.gross_fees_charged = market_state
+        .gross_fees_charged
+        .saturating_add(gross_taker_fee);
+    market_state.net_fees_retained = market_state.net_fees_retained.saturating_add(net_fee);
+
+    // Checkpoint the maker's liquidity score on every fill, at the size that rested until
+    // now, so a partial fill never shrinks the size credited for the time before it.
+    if !fully_filled {
+        let rested_size = trade_size.saturating_add(maker_size_remaining);
+        maker_user.accrue_order_score(index, rested_size, market_state, now);
+    }
+    market_state.sync_liquidity_score(maker_score, maker_user.liquidity_score);
+
+    let (oracle_price, oracle_confidence) = market_state.oracle_sample(now);
+
+    Ok(FillOutcome {
+        trade_size,
+        execution_price,
+        notional,
+        maker_side,
+        maker_price_mode,
+        maker_rebate: fees.maker_rebate,
+        taker_fee: fees.taker_fee,
+        referral_reward: fees.referral_reward,
+        referral_basis: fees.referral_basis,
+        taker_rebate,
+        net_fee,
+        fully_filled,
+        maker_strategy_id: strategy_id,
+        maker_order_id,
+        maker_size_remaining,
+        maker_book_level,
+        maker_rebate_haircut: rebate_haircut,
+        maker_skew_adjustment: skew_adjustment,
+        maker_promo_rebate: promo_rebate,
+        maker_penalty,
+        taker_bonus_discount: bonus_discount,
+        promotion_cap_reached,
+        taker_forfeited,
+        oracle_price,
+        oracle_confidence,
+        timestamp: now,
+    })
+}
+
+// ----------------------------------
+// ATTESTATIONS
+// ----------------------------------
+
+/// A portable snapshot of one user's cumulative stats in one market. Partners trust it by
+/// checking the account owner is this program and the address is the PDA of
+/// `["attestation", market, user]`; `digest` lets them compare or relay it compactly.
+#[account]
+pub struct VolumeAttestation {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub maker_rebates_earned: u64,
+    pub taker_fees_paid: u64,
+    pub taker_rebates_earned: u64,
+    pub issued_at: i64,
+    pub digest: [u8; 32], // hash over all fields above
+}
+
+impl VolumeAttestation {
+    pub const SIZE: usize =
+          32 // user
+        + 32 // market
+        + 8  // maker_volume
+        + 8  // taker_volume
+        + 8  // maker_rebates_earned
+        + 8  // taker_fees_paid
+        + 8  // taker_rebates_earned
+        + 8  // issued_at
+        + 32; // digest
+
+    pub fn record(&mut self, user_state: &UserState, clock: &impl TimeProvider) {
+        self.user = user_state.authority;
+        self.market = user_state.market;
+        self.maker_volume = user_state.maker_volume;
+        self.taker_volume = user_state.taker_volume;
+        self.maker_rebates_earned = user_state.maker_rebates_earned;
+        self.taker_fees_paid = user_state.taker_fees_paid;
+        self.taker_rebates_earned = user_state.taker_rebates_earned;
+        self.issued_at = clock.now();
+        self.digest = self.compute_digest();
+    }
+
+    pub fn compute_digest(&self) -> [u8; 32] {
+        hashv(&[
+            b"fee_rebate_attestation",
+            self.user.as_ref(),
+            self.market.as_ref(),
+            &self.maker_volume.to_le_bytes(),
+            &self.taker_volume.to_le_bytes(),
+            &self.maker_rebates_earned.to_le_bytes(),
+            &self.taker_fees_paid.to_le_bytes(),
+            &self.taker_rebates_earned.to_le_bytes(),
+            &self.issued_at.to_le_bytes(),
+        ])
+        .to_bytes()
+    }
+}
+
+// ----------------------------------
+// REFERRALS
+// ----------------------------------
+
+/// Salted hash stored in place of a confidential referrer. Clients pick a random `salt`,
+/// register with the commitment, and hand the salt to the referrer for claiming.
+pub fn referral_commitment(referrer: &Pubkey, salt: &[u8; 32]) -> [u8; 32] {
+    hashv(&[b"referral", referrer.as_ref(), salt]).to_bytes()
+}
+
+/// Lifetime totals between a referee and their referrer, kept on the referee's UserState.
+/// They cap what the referrer can ever earn from this referee at `referral_cap_bps` of the
+/// taker fees the referee has paid while referred.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ReferralLink {
+    pub fees_paid: u64,       // taker fees charged to the referee
+    pub rewards_accrued: u64, // referral rewards accrued from those fees
+}
+
+impl ReferralLink {
+    pub const SIZE: usize = 8 + 8;
+
+    /// The part of `reward` that keeps lifetime rewards within `cap_bps` of lifetime fees,
+    /// counting the `fee` that earns it.
+    pub fn capped_reward(&self, fee: u64, reward: u64, cap_bps: u16) -> Result<u64> {
+        let limit = bps_amount(self.fees_paid.saturating_add(fee), cap_bps)?;
+        Ok(reward.min(limit.saturating_sub(self.rewards_accrued)))
+    }
+
+    pub fn record(&mut self, fee: u64, reward: u64) {
+        self.fees_paid = self.fees_paid.saturating_add(fee);
+        self.rewards_accrued = self.rewards_accrued.saturating_add(reward);
+    }
+}
+
+// ----------------------------------
+// USER INDEX
+// ----------------------------------
+
+/// Most users `export_user_stats_page` returns per call, to stay within return data limits.
+pub const MAX_EXPORT_PAGE: usize = 12;
+
+/// `user`'s entry in a market's user index, at the PDA of `["user_index", market, user]`.
+/// Entries are linked newest first from `MarketState::user_index_head` through `next`, so
+/// clients can enumerate a market's users without getProgramAccounts. An entry lives
+/// exactly as long as the user's `UserState`, so each user appears once.
+#[account]
+pub struct UserIndexEntry {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub user_state: Pubkey,
+    pub next: Pubkey, // the next older user; default => end of the index
+}
+
+impl UserIndexEntry {
+    pub const SIZE: usize =
+          32 // market
+        + 32 // user
+        + 32 // user_state
+        + 32; // next
+
+    /// Fill in the entry for a newly registered `user` and put it at the head of the index.
+    pub fn append(
+        &mut self,
+        market_state: &mut MarketState,
+        market: Pubkey,
+        user: Pubkey,
+        user_state: Pubkey,
+    ) {
+        self.market = market;
+        self.user = user;
+        self.user_state = user_state;
+        self.next = market_state.user_index_head;
+        market_state.user_index_head = user;
+    }
+
+    /// Take this entry out of the index, before it is closed. Unless it is the head,
+    /// `previous` must be the entry linking to it.
+    pub fn unlink(
+        &self,
+        market_state: &mut MarketState,
+        previous: Option<&mut UserIndexEntry>,
+    ) -> Result<()> {
+        if market_state.user_index_head == self.user {
+            market_state.user_index_head = self.next;
+            return Ok(());
+        }
+        match previous {
+            Some(previous) if previous.next == self.user && previous.market == self.market => {
+                previous.next = self.next;
+                Ok(())
+            }
+            _ => err!(FeeError::InvalidUserIndex),
+        }
+    }
+}
+
+/// One user's lifetime stats, as exported by `export_user_stats_page`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct UserStatsSummary {
+    pub user: Pubkey,
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub maker_rebates_earned: u64,
+    pub taker_fees_paid: u64,
+    pub taker_rebates_earned: u64,
+    pub liquidity_score: u64,
+}
+
+impl UserStatsSummary {
+    pub const SIZE: usize = 32 + 8 * 6;
+}
+
+impl From<&UserState> for UserStatsSummary {
+    fn from(user_state: &UserState) -> Self {
+        Self {
+            user: user_state.authority,
+            maker_volume: user_state.maker_volume,
+            taker_volume: user_state.taker_volume,
+            maker_rebates_earned: user_state.maker_rebates_earned,
+            taker_fees_paid: user_state.taker_fees_paid,
+            taker_rebates_earned: user_state.taker_rebates_earned,
+            liquidity_score: user_state.liquidity_score,
+        }
+    }
+}
+
+/// Return data of `export_user_stats_page`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default, Debug)]
+pub struct UserStatsPage {
+    pub start: Pubkey,
+    pub next: Pubkey, // where the next page starts; default => end of the index
+    pub total: u64,   // users in the market
+    pub users: Vec<UserStatsSummary>,
+}
+
+/// Proof that `user` has been registered in `market`, at the PDA of
+/// `["registration", market, user]`. It outlives the user's `UserState`, so reclaiming
+/// its rent and registering again does not make them a new user.
+#[account]
+pub struct RegistrationRecord {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub payer: Pubkey, // gets the rent back in `close_market_accounts`
+    pub first_registered_ts: i64,
+}
+
+impl RegistrationRecord {
+    pub const SIZE: usize =
+          32 // market
+        + 32 // user
+        + 32 // payer
+        + 8; // first_registered_ts
+}
+
+/// Create `user`'s `RegistrationRecord` at `record`, paid by `payer`, unless it already
+/// exists. Returns whether it was created, i.e. whether this is the user's first
+/// registration in `market`. Lamports someone already sent to the address only lower
+/// what `payer` has to add.
+fn record_registration<'info>(
+    record: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    market: Pubkey,
+    user: Pubkey,
+    bump: u8,
+    now: i64,
+) -> Result<bool> {
+    if *record.owner == crate::ID {
+        return Ok(false);
+    }
+    let space = 8 + RegistrationRecord::SIZE;
+    let shortfall = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(record.lamports());
+    if shortfall > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, record.key, shortfall),
+            &[payer.clone(), record.clone(), system_program.clone()],
+        )?;
+    }
+    let seeds: &[&[u8]] = &[pda::REGISTRATION_SEED, market.as_ref(), user.as_ref(), &[bump]];
+    let accounts = [record.clone(), system_program.clone()];
+    invoke_signed(
+        &system_instruction::allocate(record.key, space as u64),
+        &accounts,
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(record.key, &crate::ID),
+        &accounts,
+        &[seeds],
+    )?;
+
+    let registration = RegistrationRecord {
+        market,
+        user,
+        payer: *payer.key,
+        first_registered_ts: now,
+    };
+    registration.try_serialize(&mut &mut record.try_borrow_mut_data()?[..])?;
+    Ok(true)
+}
+
+// ----------------------------------
+// RENT RECLAIM
+// ----------------------------------
+
+/// How long a `UserState` marked reclaimable must sit idle before it can be closed.
+pub const RENT_RECLAIM_DELAY_SECS: i64 = 180 * 24 * 60 * 60;
+
+/// Rent of a reclaimed `UserState`, held for its owner at the PDA of
+/// `["rent_escrow", market, user]`. The owner creates it in `request_rent_reclaim`, and
+/// only the owner can close it, so a crank can move rent here but never take it.
+#[account]
+pub struct RentEscrow {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub escrowed_lamports: u64, // UserState lamports moved in by the crank
+    pub reclaimed_ts: i64,      // 0 => UserState not reclaimed yet
+}
+
+impl RentEscrow {
+    pub const SIZE: usize =
+          32 // user
+        + 32 // market
+        + 8  // escrowed_lamports
+        + 8; // reclaimed_ts
+}
+
+// ----------------------------------
+// AUTHORITY RECOVERY
+// ----------------------------------
+
+/// Members a market's recovery committee can have.
+pub const MAX_RECOVERY_MEMBERS: usize = 5;
+/// How long after a recovery is proposed it can first be executed.
+pub const RECOVERY_TIMELOCK_SECS: i64 = 30 * 24 * 60 * 60;
+/// How long the current authority can still challenge a recovery once it has enough
+/// approvals. A proposal still short of approvals when its timelock ends lapses.
+pub const RECOVERY_CHALLENGE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// `threshold`-of-`member_count` keys that can replace a lost market authority.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct RecoveryCommittee {
+    pub members: [Pubkey; MAX_RECOVERY_MEMBERS],
+    pub member_count: u8,
+    pub threshold: u8, // 0 => no committee
+}
+
+impl RecoveryCommittee {
+    pub const SIZE: usize = 32 * MAX_RECOVERY_MEMBERS + 1 + 1;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.threshold == 0 {
+            require!(*self == Self::default(), FeeError::InvalidRecoveryCommittee);
+            return Ok(());
+        }
+        let count = self.member_count as usize;
+        require!(
+            count <= MAX_RECOVERY_MEMBERS && self.threshold <= self.member_count,
+            FeeError::InvalidRecoveryCommittee
+        );
+        let members = &self.members[..count];
+        for (i, member) in members.iter().enumerate() {
+            require!(
+                *member != Pubkey::default() && !members[..i].contains(member),
+                FeeError::InvalidRecoveryCommittee
+            );
+        }
+        require!(
+            self.members[count..].iter().all(|member| *member == Pubkey::default()),
+            FeeError::InvalidRecoveryCommittee
+        );
+        Ok(())
+    }
+
+    pub fn member_index(&self, key: &Pubkey) -> Option<usize> {
+        self.members[..self.member_count as usize]
+            .iter()
+            .position(|member| member == key)
+    }
+}
+
+/// A committee proposal to replace the market authority.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct RecoveryProposal {
+    pub new_authority: Pubkey, // default => nothing pending
+    pub proposed_ts: i64,
+    pub approvals: u8,     // bit i => members[i] approved
+    pub rejections: u8,    // bit i => members[i] rejected
+    pub approved_ts: i64,  // when approvals reached the threshold; 0 => not yet
+}
+
+impl RecoveryProposal {
+    pub const SIZE: usize = 32 + 8 + 1 + 1 + 8;
+
+    pub fn is_pending(&self) -> bool {
+        self.new_authority != Pubkey::default()
+    }
+
+    /// Pending, but its timelock ended before it reached the threshold.
+    pub fn is_lapsed(&self, now: i64) -> bool {
+        self.is_pending()
+            && self.approved_ts == 0
+            && now > self.proposed_ts.saturating_add(RECOVERY_TIMELOCK_SECS)
+    }
+}
+
+impl MarketState {
+    pub fn propose_recovery(
+        &mut self,
+        member: &Pubkey,
+        new_authority: Pubkey,
+        now: i64,
+    ) -> Result<()> {
+        require!(
+            self.recovery_committee.member_index(member).is_some(),
+            FeeError::Unauthorized
+        );
+        require!(
+            !self.pending_recovery.is_pending() || self.pending_recovery.is_lapsed(now),
+            FeeError::RecoveryAlreadyPending
+        );
+        require!(
+            new_authority != Pubkey::default() && new_authority != self.authority,
+            FeeError::InvalidRecoveryCommittee
+        );
+        self.pending_recovery = RecoveryProposal {
+            new_authority,
+            proposed_ts: now,
+            approvals: 0,
+            rejections: 0,
+            approved_ts: 0,
+        };
+        self.approve_recovery(member, now)
+    }
+
+    pub fn approve_recovery(&mut self, member: &Pubkey, now: i64) -> Result<()> {
+        let index = match self.recovery_committee.member_index(member) {
+            Some(index) => index,
+            None => return err!(FeeError::Unauthorized),
+        };
+        let pending = &mut self.pending_recovery;
+        require!(pending.is_pending(), FeeError::NoPendingRecovery);
+        require!(!pending.is_lapsed(now), FeeError::RecoveryLapsed);
+        pending.rejections &= !(1 << index);
+        pending.approvals |= 1 << index;
+        if pending.approved_ts == 0
+            && pending.approvals.count_ones() >= self.recovery_committee.threshold as u32
+        {
+            pending.approved_ts = now;
+        }
+        Ok(())
+    }
+
+    /// Count `member`'s rejection, withdrawing their approval. Returns whether the
+    /// proposal was dropped because too few members are left to reach the threshold.
+    pub fn reject_recovery(&mut self, member: &Pubkey, now: i64) -> Result<bool> {
+        let index = match self.recovery_committee.member_index(member) {
+            Some(index) => index,
+            None => return err!(FeeError::Unauthorized),
+        };
+        let committee = self.recovery_committee;
+        let pending = &mut self.pending_recovery;
+        require!(pending.is_pending(), FeeError::NoPendingRecovery);
+        require!(!pending.is_lapsed(now), FeeError::RecoveryLapsed);
+        require!(pending.approved_ts == 0, FeeError::RecoveryAlreadyApproved);
+        pending.approvals &= !(1 << index);
+        pending.rejections |= 1 << index;
+        let dropped = pending.rejections.count_ones()
+            > (committee.member_count - committee.threshold) as u32;
+        if dropped {
+            self.pending_recovery = RecoveryProposal::default();
+        }
+        Ok(dropped)
+    }
+
+    pub fn execute_recovery(&mut self, now: i64) -> Result<()> {
+        let pending = self.pending_recovery;
+        require!(pending.is_pending(), FeeError::NoPendingRecovery);
+        require!(
+            pending.approved_ts > 0
+                && now >= pending.proposed_ts.saturating_add(RECOVERY_TIMELOCK_SECS)
+                && now >= pending.approved_ts.saturating_add(RECOVERY_CHALLENGE_SECS),
+            FeeError::RecoveryNotReady
+        );
+        self.authority = pending.new_authority;
+        self.pending_recovery = RecoveryProposal::default();
+        Ok(())
+    }
+}
+
+// ----------------------------------
+// SCORING PLUGIN
+// ----------------------------------
+
+/// Instruction data prefix of the scoring CPI: Anchor's sighash of `score_order`, so a
+/// plugin can be an Anchor program with a `score_order(request: ScoringRequest)`
+/// instruction that takes no accounts.
+pub const SCORE_ORDER_DISCRIMINATOR: [u8; 8] = [153, 160, 91, 165, 81, 177, 93, 77];
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScoringEventKind {
+    Fill,
+    Cancel,
+}
+
+/// What the scoring plugin is told about a maker order that was filled or canceled.
+/// It answers with a Borsh `i64` score delta via return data.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ScoringRequest {
+    pub market: Pubkey,
+    pub user: Pubkey, // the order's owner
+    pub kind: ScoringEventKind,
+    pub order_id: u64,
+    pub side: OrderSide,
+    pub price: u64, // execution price for fills, the order's price for cancels
+    pub size: u64,  // filled or canceled
+    pub size_remaining: u64,
+    pub liquidity_score: u64, // the user's score before the delta
+    pub timestamp: i64,
+}
+
+impl MarketState {
+    pub fn scoring_enabled(&self) -> bool {
+        self.scoring_program != Pubkey::default()
+    }
+
+    /// A plugin's delta, bounded to `max_score_delta` either way.
+    pub fn clamp_score_delta(&self, delta: i64) -> i64 {
+        let max = self.max_score_delta.min(i64::MAX as u64) as i64;
+        delta.clamp(-max, max)
+    }
+}
+
+/// CPI the market's scoring plugin, if it has one, with `request` and apply the delta it
+/// returns to `user_state`. The plugin must be the first of `remaining_accounts`. It is
+/// given no accounts and no signers, so it can only answer, not touch any state.
+fn run_scoring_plugin(
+    market_state: &mut MarketState,
+    user_state: &mut UserState,
+    remaining_accounts: &[AccountInfo],
+    request: ScoringRequest,
+) -> Result<()> {
+    if !market_state.scoring_enabled() {
+        return Ok(());
+    }
+    let program = match remaining_accounts.first() {
+        Some(program) if program.key() == market_state.scoring_program && program.executable => {
+            program
+        }
+        _ => return err!(FeeError::ScoringProgramMissing),
+    };
+
+    let mut data = SCORE_ORDER_DISCRIMINATOR.to_vec();
+    request.serialize(&mut data)?;
+    invoke(
+        &Instruction {
+            program_id: program.key(),
+            accounts: vec![],
+            data,
+        },
+        &[program.clone()],
+    )?;
+    let delta = match get_return_data() {
+        Some((program_id, data)) if program_id == program.key() => {
+            i64::try_from_slice(&data).map_err(|_| error!(FeeError::InvalidScoreDelta))?
+        }
+        _ => return err!(FeeError::InvalidScoreDelta),
+    };
+
+    let delta = market_state.clamp_score_delta(delta);
+    let score = user_state.liquidity_score;
+    user_state.apply_score_delta(delta);
+    market_state.sync_liquidity_score(score, user_state.liquidity_score);
+
+    emit!(LiquidityScoreAdjusted {
+        market: request.market,
+        user: request.user,
+        kind: request.kind,
+        order_id: request.order_id,
+        delta,
+    });
+
+    Ok(())
+}
+
+/// `run_scoring_plugin` for cancels, which the plugin must never block: if its account
+/// is missing or it answers without a usable delta, the order goes unscored and
+/// `ScoringSkipped` is emitted instead. A plugin that aborts still fails the
+/// transaction, since a failed CPI can't be caught, so a maker can always cancel by
+/// leaving the plugin account out.
+fn try_scoring_plugin(
+    market_state: &mut MarketState,
+    user_state: &mut UserState,
+    remaining_accounts: &[AccountInfo],
+    request: ScoringRequest,
+) -> Result<()> {
+    if let Err(error) = run_scoring_plugin(market_state, user_state, remaining_accounts, request) {
+        emit!(ScoringSkipped {
+            market: request.market,
+            user: request.user,
+            kind: request.kind,
+            order_id: request.order_id,
+            error_code: error_code(&error),
+        });
+    }
+    Ok(())
+}
+
+// ----------------------------------
+// STAKING
+// ----------------------------------
+
+/// How long unstaked funds wait before they can be withdrawn.
+pub const UNSTAKE_COOLDOWN_SECS: i64 = 7 * 24 * 60 * 60;
+/// Volume each staked unit counts as toward the staker's fee tier.
+pub const STAKE_TIER_WEIGHT: u64 = 100;
+
+/// A user's stake position in one market, at the PDA of `["stake", market, user]`.
+/// Funded by `claim_and_stake` compounding claimable rebates and rewards; the stake lifts
+/// the user's fee tier (see `UserState::tier_volume`) until it is unstaked.
+#[account]
+pub struct StakeAccount {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub staked_amount: u64,
+    pub total_compounded: u64, // lifetime amount staked from claims
+    pub last_stake_ts: i64,
+    pub unstaking_amount: u64,    // unstaked, waiting out the cooldown
+    pub unstake_available_ts: i64, // when `unstaking_amount` can be withdrawn
+}
+
+impl StakeAccount {
+    pub const SIZE: usize =
+          32 // user
+        + 32 // market
+        + 8  // staked_amount
+        + 8  // total_compounded
+        + 8  // last_stake_ts
+        + 8  // unstaking_amount
+        + 8; // unstake_available_ts
+
+    /// Move `amount` out of the stake into the cooldown. Any earlier unstake still cooling
+    /// down waits for the new one.
+    pub fn request_unstake(&mut self, amount: u64, clock: &impl TimeProvider) -> Result<()> {
+        require!(
+            amount > 0 && amount <= self.staked_amount,
+            FeeError::InsufficientStake
+        );
+        self.unstaking_amount = self
+            .unstaking_amount
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+        self.staked_amount -= amount;
+        self.unstake_available_ts = clock.now().saturating_add(UNSTAKE_COOLDOWN_SECS);
+        Ok(())
+    }
+
+    /// Take everything that has finished its cooldown.
+    pub fn withdraw_unstaked(&mut self, clock: &impl TimeProvider) -> Result<u64> {
+        require!(self.unstaking_amount > 0, FeeError::InsufficientStake);
+        require!(
+            clock.now() >= self.unstake_available_ts,
+            FeeError::UnstakeCooldownActive
+        );
+        Ok(std::mem::take(&mut self.unstaking_amount))
+    }
+
+    pub fn compound(&mut self, amount: u64, clock: &impl TimeProvider) -> Result<()> {
+        self.staked_amount = self
+            .staked_amount
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+        self.total_compounded = self
+            .total_compounded
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+        self.last_stake_ts = clock.now();
+        Ok(())
+    }
+}
+
+// ----------------------------------
+// REWARD PROGRAMS
+// ----------------------------------
+
+/// Who may earn from a reward program.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RewardEligibility {
+    /// Every user with a liquidity score.
+    AllMakers,
+    /// Users whose lifetime maker volume is at least `min`.
+    MinMakerVolume { min: u64 },
+    /// Users whose current liquidity score is at least `min`.
+    MinLiquidityScore { min: u64 },
+}
+
+impl Default for RewardEligibility {
+    fn default() -> Self {
+        RewardEligibility::AllMakers
+    }
+}
+
+impl RewardEligibility {
+    pub fn is_eligible(&self, user_state: &UserState) -> bool {
+        match *self {
+            RewardEligibility::AllMakers => true,
+            RewardEligibility::MinMakerVolume { min } => user_state.maker_volume >= min,
+            RewardEligibility::MinLiquidityScore { min } => user_state.liquidity_score >= min,
+        }
+    }
+}
+
+/// One named incentive on a market, at the PDA of `["reward_program", market, sponsor, name]`.
+#[account]
+pub struct RewardProgram {
+    pub market: Pubkey,
+    pub sponsor: Pubkey, // runs distributions and funds the rewards
+    pub name: [u8; 32],
+    pub reward_mint: Pubkey,
+    pub start_ts: i64,
+    pub end_ts: i64,     // exclusive
+    pub budget: u64,
+    pub epoch_reward_pool: u64, // shared by score each epoch, until the budget runs out
+    pub distributed: u64,
+    pub eligibility: RewardEligibility,
+    pub position_count: u64, // RewardPositions not yet closed
+    pub pool_epoch: Option<u64>, // epoch `epoch_total_score` was taken in
+    pub epoch_total_score: u64,  // market's total_liquidity_score at its first distribution
+}
+
+impl RewardProgram {
+    pub const SIZE: usize =
+          32 // market
+        + 32 // sponsor
+        + 32 // name
+        + 32 // reward_mint
+        + 8  // start_ts
+        + 8  // end_ts
+        + 8  // budget
+        + 8  // epoch_reward_pool
+        + 8  // distributed
+        + 1 + 8  // eligibility
+        + 8  // position_count
+        + 1 + 8  // pool_epoch
+        + 8; // epoch_total_score
+
+    /// Credit `reward_position` with `user_state`'s share of `epoch_reward_pool` for
+    /// `epoch`, capped by the remaining budget. The share is of `total_liquidity_score` as
+    /// first seen in `epoch`, so every user of an epoch is scored against the same total.
+    /// Ineligible users get nothing. Returns the amount.
+    pub fn credit(
+        &mut self,
+        reward_position: &mut RewardPosition,
+        user_state: &UserState,
+        epoch: u64,
+        total_liquidity_score: u64,
+        now: i64,
+    ) -> Result<u64> {
+        require!(
+            now >= self.start_ts && now < self.end_ts,
+            FeeError::RewardProgramInactive
+        );
+        require!(
+            reward_position.last_distributed_epoch != Some(epoch),
+            FeeError::AlreadyDistributed
+        );
+        reward_position.last_distributed_epoch = Some(epoch);
+        if self.pool_epoch != Some(epoch) {
+            self.pool_epoch = Some(epoch);
+            self.epoch_total_score = total_liquidity_score;
+        }
+
+        // Never more than the pool, even for a score the total doesn't cover yet.
+        let total = self.epoch_total_score.max(user_state.liquidity_score);
+        if total == 0 || !self.eligibility.is_eligible(user_state) {
+            return Ok(0);
+        }
+
+        let share = (user_state.liquidity_score as u128)
+            .checked_mul(self.epoch_reward_pool as u128)
+            .ok_or(FeeError::Overflow)?
+            / (total as u128);
+        let remaining = self.budget.saturating_sub(self.distributed);
+        let amount = share.min(remaining as u128) as u64;
+
+        self.distributed += amount;
+        reward_position.claimable = reward_position
+            .claimable
+            .checked_add(amount)
+            .ok_or(FeeError::Overflow)?;
+        reward_position.total_earned = reward_position.total_earned.saturating_add(amount);
+        Ok(amount)
+    }
+}
+
+/// A user's balance in one reward program, at the PDA of
+/// `["reward_position", reward_program, user]`.
+#[account]
+pub struct RewardPosition {
+    pub reward_program: Pubkey,
+    pub user: Pubkey,
+    pub claimable: u64,
+    pub total_earned: u64,
+    pub last_distributed_epoch: Option<u64>,
+}
+
+impl RewardPosition {
+    pub const SIZE: usize =
+          32 // reward_program
+        + 32 // user
+        + 8  // claimable
+        + 8  // total_earned
+        + 1 + 8; // last_distributed_epoch
+
+    /// Whether `signer` may close this position at `now`: its user once nothing is left
+    /// to claim, or the program's sponsor once the program has ended, forfeiting whatever
+    /// the user never claimed.
+    pub fn is_closable_by(
+        &self,
+        signer: &Pubkey,
+        reward_program: &RewardProgram,
+        now: i64,
+    ) -> bool {
+        (*signer == self.user && self.claimable == 0)
+            || (*signer == reward_program.sponsor && now >= reward_program.end_ts)
+    }
+}
+
+// ----------------------------------
+// BOOK CHECKPOINTS
+// ----------------------------------
+
+/// Aggregate view of every resting order in a market, plus a rolling hash over the
+/// orders themselves in canonical order (users by `UserState` address, then slot index).
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct OrderBookSummary {
+    pub order_count: u32,
+    pub bid_count: u32,
+    pub ask_count: u32,
+    pub best_bid: u64, // 0 => no bids
+    pub best_ask: u64, // 0 => no asks
+    pub bid_size: u64,
+    pub ask_size: u64,
+    pub orders_hash: [u8; 32],
+}
+
+impl OrderBookSummary {
+    pub const SIZE: usize =
+          4  // order_count
+        + 4  // bid_count
+        + 4  // ask_count
+        + 8  // best_bid
+        + 8  // best_ask
+        + 8  // bid_size
+        + 8  // ask_size
+        + 32; // orders_hash
+
+    /// An empty book of `market`: no orders, and the seed of the rolling hash.
+    pub fn new(market: Pubkey) -> Self {
+        OrderBookSummary {
+            orders_hash: hashv(&[b"fee_rebate_book", market.as_ref()]).to_bytes(),
+            ..OrderBookSummary::default()
+        }
+    }
+
+    /// Add the resting orders of the `UserState` at `address`, the next user in canonical
+    /// order. Orders are taken exactly as stored: expiry and pegging are left to the
+    /// simulator.
+    pub fn add_user(&mut self, address: Pubkey, user_state: &UserState) -> Result<()> {
+        for (slot, order) in user_state.orders.iter().enumerate() {
+            if order.size_remaining == 0 {
+                continue;
+            }
+            self.order_count += 1;
+            match order.side {
+                OrderSide::Bid => {
+                    self.bid_count += 1;
+                    self.best_bid = self.best_bid.max(order.price);
+                    self.bid_size = self
+                        .bid_size
+                        .checked_add(order.size_remaining)
+                        .ok_or(FeeError::Overflow)?;
+                }
+                OrderSide::Ask => {
+                    self.ask_count += 1;
+                    if self.best_ask == 0 || order.price < self.best_ask {
+                        self.best_ask = order.price;
+                    }
+                    self.ask_size = self
+                        .ask_size
+                        .checked_add(order.size_remaining)
+                        .ok_or(FeeError::Overflow)?;
+                }
+            }
+            let price_mode = match order.price_mode {
+                PriceMode::Fixed => [0, 0, 0],
+                PriceMode::MidPeg => [1, 0, 0],
+                PriceMode::PrimaryPeg { offset_bps } => {
+                    let [lo, hi] = offset_bps.to_le_bytes();
+                    [2, lo, hi]
+                }
+            };
+            self.orders_hash = hashv(&[
+                &self.orders_hash,
+                address.as_ref(),
+                &[slot as u8, order.side as u8],
+                &order.price.to_le_bytes(),
+                &order.size_remaining.to_le_bytes(),
+                &order.creation_timestamp.to_le_bytes(),
+                &order.expiry_timestamp.to_le_bytes(),
+                &price_mode,
+                &order.strategy_id.to_le_bytes(),
+            ])
+            .to_bytes();
+        }
+        Ok(())
+    }
+
+    /// Summarize `user_states`, `(address, UserState)` pairs the caller passes already in
+    /// canonical order, as a `BookCheckpoint` built from them would.
+    pub fn build(market: Pubkey, user_states: &[(Pubkey, UserState)]) -> Result<Self> {
+        let mut summary = OrderBookSummary::new(market);
+        for (address, user_state) in user_states {
+            summary.add_user(*address, user_state)?;
+        }
+        Ok(summary)
+    }
+
+    /// Signed imbalance of resting size, `(bid - ask) / (bid + ask)` in bps: positive when
+    /// the book is bid-heavy, 0 when balanced or empty.
+    pub fn imbalance_bps(&self) -> i32 {
+        let (bid, ask) = (self.bid_size as i128, self.ask_size as i128);
+        if bid + ask == 0 {
+            return 0;
+        }
+        ((bid - ask) * BPS_DENOMINATOR as i128 / (bid + ask)) as i32
+    }
+}
+
+/// One epoch's order book checkpoint, at the PDA of
+/// `["book_checkpoint", market, epoch (u64 LE)]`. Built a batch of users at a time, the
+/// summary running over the users so far; `book_hash` commits to the whole summary once
+/// finalized.
+#[account]
+pub struct BookCheckpoint {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub user_count: u32,           // users summarized
+    pub summary: OrderBookSummary, // over the users so far
+    pub taken_at: i64,             // when finalized; 0 => still being built
+    pub book_hash: [u8; 32],       // set when finalized
+    pub user_set_version: u64,     // market's user_set_version the build started from
+    pub event_seq: u64,            // market's EventCursor::event_seq the build started from
+    pub last_user: Pubkey,         // last UserState summarized; the next must sort after it
+}
+
+impl BookCheckpoint {
+    pub const SIZE: usize =
+          32 // market
+        + 8  // epoch
+        + 4  // user_count
+        + OrderBookSummary::SIZE // summary
+        + 8  // taken_at
+        + 32 // book_hash
+        + 8  // user_set_version
+        + 8  // event_seq
+        + 32; // last_user
+
+    pub fn is_final(&self) -> bool {
+        self.taken_at != 0
+    }
+
+    /// Drop the summary so far and start over from the market's current users and book.
+    pub fn restart(&mut self, user_set_version: u64, event_seq: u64) {
+        self.user_count = 0;
+        self.summary = OrderBookSummary::new(self.market);
+        self.taken_at = 0;
+        self.book_hash = [0; 32];
+        self.user_set_version = user_set_version;
+        self.event_seq = event_seq;
+        self.last_user = Pubkey::default();
+    }
+
+    /// The summary only describes one book while no order changes (every place, fill,
+    /// cancel and expiry advances the market's `event_seq`) and nobody joins or leaves.
+    pub fn require_current(&self, market_state: &MarketState, event_seq: u64) -> Result<()> {
+        require!(
+            self.user_set_version == market_state.user_set_version
+                && self.event_seq == event_seq,
+            FeeError::BookChanged
+        );
+        Ok(())
+    }
+
+    /// Summarize `user_states`, this market's `UserState`s in strictly increasing address
+    /// order, all after the previous batch: one canonical order, each user once.
+    pub fn append_users<'info>(&mut self, user_states: &'info [AccountInfo<'info>]) -> Result<()> {
+        for info in user_states.iter() {
+            require!(
+                self.user_count == 0 || info.key() > self.last_user,
+                FeeError::NonCanonicalBookAccounts
+            );
+            let user_state = Account::<UserState>::try_from(info)?;
+            require!(user_state.market == self.market, FeeError::MarketMismatch);
+            self.summary.add_user(info.key(), &user_state)?;
+            self.user_count = self.user_count.checked_add(1).ok_or(FeeError::Overflow)?;
+            self.last_user = info.key();
+        }
+        Ok(())
+    }
+
+    pub fn finalize(&mut self, clock: &impl TimeProvider) {
+        self.taken_at = clock.now();
+        self.book_hash = self.compute_hash();
+    }
+
+    pub fn compute_hash(&self) -> [u8; 32] {
+        hashv(&[
+            b"fee_rebate_book_checkpoint",
+            self.market.as_ref(),
+            &self.epoch.to_le_bytes(),
+            &self.user_count.to_le_bytes(),
+            &self.summary.order_count.to_le_bytes(),
+            &self.summary.bid_count.to_le_bytes(),
+            &self.summary.ask_count.to_le_bytes(),
+            &self.summary.best_bid.to_le_bytes(),
+            &self.summary.best_ask.to_le_bytes(),
+            &self.summary.bid_size.to_le_bytes(),
+            &self.summary.ask_size.to_le_bytes(),
+            &self.summary.orders_hash,
+        ])
+        .to_bytes()
+    }
+}
+
+// ----------------------------------
+// REFERRAL GRAPH
+// ----------------------------------
+
+/// Levels a `ReferralCheckpoint` keeps pending subtree roots for: enough for `u32::MAX`
+/// leaves.
+pub const REFERRAL_TREE_DEPTH: usize = 32;
+
+/// A user's leaf in the referral graph Merkle tree, before hashing: who, if anyone,
+/// referred them. A confidential referral commits to its `referrer_commitment`, so the
+/// tree doesn't reveal the referrer before they claim. Every user gets a leaf, so proving
+/// a user's leaf also proves they had no other referrer; the `UserState` address orders
+/// the leaves, so two adjacent leaves prove nobody sorts between them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReferralLeaf {
+    pub user_state: Pubkey,
+    pub authority: Pubkey,
+    pub kind: u8, // 0 => no referrer, 1 => plain referrer, 2 => confidential commitment
+    pub referrer: [u8; 32],
+}
+
+impl ReferralLeaf {
+    pub fn new(address: Pubkey, user_state: &UserState) -> Self {
+        let (kind, referrer) = match user_state.referrer {
+            Some(referrer) => (1, referrer.to_bytes()),
+            None if user_state.referrer_commitment != [0; 32] => {
+                (2, user_state.referrer_commitment)
+            }
+            None => (0, [0; 32]),
+        };
+        Self {
+            user_state: address,
+            authority: user_state.authority,
+            kind,
+            referrer,
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        hashv(&[
+            b"fee_rebate_referral_leaf",
+            self.user_state.as_ref(),
+            self.authority.as_ref(),
+            &[self.kind],
+            &self.referrer,
+        ])
+        .to_bytes()
+    }
+}
+
+/// Hash of the leaf of the `UserState` at `address`.
+pub fn referral_leaf(address: Pubkey, user_state: &UserState) -> [u8; 32] {
+    ReferralLeaf::new(address, user_state).hash()
+}
+
+/// Parent of two tree nodes. Children keep their left-right order, so a leaf's proof also
+/// pins down its position, which exclusion proofs rely on.
+fn referral_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hashv(&[b"fee_rebate_referral_node", left, right]).to_bytes()
+}
+
+/// Merkle root over `leaves`, which the caller passes in canonical order (users by
+/// `UserState` address). An odd node out moves up a level unchanged; no leaves => zeros.
+pub fn referral_merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => referral_node(a, b),
+                _ => pair[0],
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Siblings from the bottom up of the leaf at `index`, for `verify_referral_proof`. Levels
+/// where the node moves up unchanged contribute none.
+pub fn referral_proof(leaves: &[[u8; 32]], index: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut index = index;
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(index ^ 1) {
+            proof.push(*sibling);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => referral_node(a, b),
+                _ => pair[0],
+            })
+            .collect();
+        index /= 2;
+    }
+    proof
+}
+
+/// Whether `proof`, the siblings from `referral_proof`, leads from `leaf` at `index` of a
+/// `leaf_count`-leaf tree to `root`. What an auditor runs against a `ReferralCheckpoint`.
+pub fn verify_referral_proof(
+    leaf: [u8; 32],
+    index: u32,
+    leaf_count: u32,
+    proof: &[[u8; 32]],
+    root: [u8; 32],
+) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+    let (mut node, mut index, mut width) = (leaf, index, leaf_count);
+    let mut siblings = proof.iter();
+    while width > 1 {
+        if index % 2 == 1 {
+            match siblings.next() {
+                Some(sibling) => node = referral_node(sibling, &node),
+                None => return false,
+            }
+        } else if index + 1 < width {
+            match siblings.next() {
+                Some(sibling) => node = referral_node(&node, sibling),
+                None => return false,
+            }
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    siblings.next().is_none() && node == root
+}
+
+/// A leaf of a `ReferralCheckpoint` with where it sits and its inclusion proof.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReferralLeafProof {
+    pub leaf: ReferralLeaf,
+    pub index: u32,
+    pub proof: Vec<[u8; 32]>,
+}
+
+impl ReferralLeafProof {
+    pub fn verify(&self, checkpoint: &ReferralCheckpoint) -> bool {
+        checkpoint.is_final()
+            && verify_referral_proof(
+                self.leaf.hash(),
+                self.index,
+                checkpoint.user_count,
+                &self.proof,
+                checkpoint.root,
+            )
+    }
+}
+
+/// Whether the finalized `checkpoint` has no leaf for the `UserState` at `address`, i.e. it
+/// wasn't a user of the market then. `below` and `above` are the leaves sorting right
+/// before and after it: both adjacent, or just one when `address` sorts before the first
+/// or after the last leaf. An empty tree needs neither.
+pub fn verify_referral_exclusion(
+    address: &Pubkey,
+    below: Option<&ReferralLeafProof>,
+    above: Option<&ReferralLeafProof>,
+    checkpoint: &ReferralCheckpoint,
+) -> bool {
+    let count = checkpoint.user_count;
+    match (below, above) {
+        (None, None) => checkpoint.is_final() && count == 0,
+        (Some(below), None) => {
+            below.verify(checkpoint)
+                && below.leaf.user_state < *address
+                && below.index + 1 == count
+        }
+        (None, Some(above)) => {
+            above.verify(checkpoint) && *address < above.leaf.user_state && above.index == 0
+        }
+        (Some(below), Some(above)) => {
+            below.verify(checkpoint)
+                && above.verify(checkpoint)
+                && below.leaf.user_state < *address
+                && *address < above.leaf.user_state
+                && above.index == below.index + 1
+        }
+    }
+}
+
+/// One epoch's commitment to the market's referral graph, at the PDA of
+/// `["referral_checkpoint", market, epoch (u64 LE)]`. Built a batch of users at a time:
+/// `frontier` holds the root of each complete subtree not yet paired up, the way the
+/// binary digits of `user_count` add up, so the account stays the same size however many
+/// users the market has.
+#[account]
+pub struct ReferralCheckpoint {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub user_count: u32,       // leaves in the tree
+    pub registered_users: u64, // market's user_count when finalized, for completeness
+    pub taken_at: i64,         // when finalized; 0 => still being built
+    pub root: [u8; 32],        // set when finalized
+    pub user_set_version: u64, // market's user_set_version the build started from
+    pub last_user: Pubkey,     // UserState of the last leaf; the next must sort after it
+    pub frontier: [[u8; 32]; REFERRAL_TREE_DEPTH], // by level; bit `level` of user_count
+}
+
+impl ReferralCheckpoint {
+    pub const SIZE: usize =
+          32 // market
+        + 8  // epoch
+        + 4  // user_count
+        + 8  // registered_users
+        + 8  // taken_at
+        + 32 // root
+        + 8  // user_set_version
+        + 32 // last_user
+        + 32 * REFERRAL_TREE_DEPTH; // frontier
+
+    pub fn is_final(&self) -> bool {
+        self.taken_at != 0
+    }
+
+    /// Drop any leaves and start over from the market's current user set.
+    pub fn restart(&mut self, user_set_version: u64) {
+        self.user_count = 0;
+        self.registered_users = 0;
+        self.taken_at = 0;
+        self.root = [0; 32];
+        self.user_set_version = user_set_version;
+        self.last_user = Pubkey::default();
+        self.frontier = [[0; 32]; REFERRAL_TREE_DEPTH];
+    }
+
+    /// Leaves only describe one user set while nobody joins or leaves the market.
+    pub fn require_current(&self, market_state: &MarketState) -> Result<()> {
+        require!(
+            self.user_set_version == market_state.user_set_version,
+            FeeError::ReferralGraphChanged
+        );
+        Ok(())
+    }
+
+    /// Add the leaf of the next user in canonical order, pairing it up with every pending
+    /// subtree of the same size.
+    pub fn push_leaf(&mut self, leaf: [u8; 32]) -> Result<()> {
+        let count = self.user_count;
+        let next = count.checked_add(1).ok_or(FeeError::Overflow)?;
+        let mut node = leaf;
+        let mut level = 0;
+        while (count >> level) & 1 == 1 {
+            node = referral_node(&self.frontier[level], &node);
+            level += 1;
+        }
+        self.frontier[level] = node;
+        self.user_count = next;
+        Ok(())
+    }
+
+    /// Root over the leaves pushed so far, equal to `referral_merkle_root` over them: the
+    /// pending subtrees folded together, smallest (rightmost) first.
+    pub fn frontier_root(&self) -> [u8; 32] {
+        let mut root: Option<[u8; 32]> = None;
+        for level in 0..REFERRAL_TREE_DEPTH {
+            if (self.user_count >> level) & 1 == 1 {
+                root = Some(match root {
+                    Some(right) => referral_node(&self.frontier[level], &right),
+                    None => self.frontier[level],
+                });
+            }
+        }
+        root.unwrap_or([0; 32])
+    }
+
+    /// Add the leaves of `user_states`, this market's `UserState`s in strictly increasing
+    /// address order, all after the previous batch: one canonical order, one leaf per user.
+    pub fn append_users<'info>(&mut self, user_states: &'info [AccountInfo<'info>]) -> Result<()> {
+        for info in user_states.iter() {
+            require!(
+                self.user_count == 0 || info.key() > self.last_user,
+                FeeError::NonCanonicalBookAccounts
+            );
+            let user_state = Account::<UserState>::try_from(info)?;
+            require!(user_state.market == self.market, FeeError::MarketMismatch);
+            self.push_leaf(referral_leaf(info.key(), &user_state))?;
+            self.last_user = info.key();
+        }
+        Ok(())
+    }
+}
+
+// ----------------------------------
+// TOP OF BOOK
+// ----------------------------------
+
+/// Price levels tracked per side in `TopOfBook`.
+pub const MAX_PRICE_LEVELS: usize = 16;
+
+/// Resting fixed-price size at one price.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct PriceLevel {
+    pub price: u64,
+    pub size: u64, // 0 => free slot
+    pub order_count: u32,
+    pub id: u64, // stored on the orders it holds as `Order::book_level`
+}
+
+impl PriceLevel {
+    pub const SIZE: usize = 8 + 8 + 4 + 8;
+}
+
+/// Live best bid/ask of a market, at the PDA of `["top_of_book", market]`, kept up to
+/// date by `place_order`, the cancel instructions and the fill instructions so takers need
+/// not crawl every UserState. Each side is a bounded, unsorted array of price levels: a
+/// new price is appended, an emptied level is freed in place, and the array is only
+/// compacted when an append finds it full. Pegged orders have no fixed price and are
+/// left out. Expired orders stay until `cancel_expired_orders` is cranked for them.
+///
+/// With more than `MAX_PRICE_LEVELS` prices on a side, the worst ones are dropped; the
+/// best prices stay exact until the tracked levels drain. Every level gets a fresh `id`,
+/// and each order remembers the id of the level it joined, so an order whose level was
+/// dropped never touches a later level at the same price. Such orders are counted in
+/// `untracked_orders` until they close.
+#[account]
+pub struct TopOfBook {
+    pub market: Pubkey,
+    pub bids: [PriceLevel; MAX_PRICE_LEVELS],
+    pub asks: [PriceLevel; MAX_PRICE_LEVELS],
+    pub bid_len: u8, // levels in use, freed ones included
+    pub ask_len: u8,
+    pub truncated: bool, // untracked_orders > 0: some resting size is not in the levels
+    pub next_level_id: u64, // last level id handed out
+    pub untracked_orders: u32, // open fixed-price orders outside the tracked levels
+}
+
+impl TopOfBook {
+    pub const SIZE: usize =
+          32 // market
+        + PriceLevel::SIZE * MAX_PRICE_LEVELS // bids
+        + PriceLevel::SIZE * MAX_PRICE_LEVELS // asks
+        + 1  // bid_len
+        + 1  // ask_len
+        + 1  // truncated
+        + 8  // next_level_id
+        + 4; // untracked_orders
+
+    /// Add an order of `size` at `price`. Returns the id of the level holding it, for the
+    /// order's `book_level`; 0 => the order is not tracked.
+    pub fn add(&mut self, side: OrderSide, price: u64, size: u64) -> u64 {
+        let new_id = self.next_level_id.saturating_add(1);
+        let (levels, len) = match side {
+            OrderSide::Bid => (&mut self.bids, &mut self.bid_len),
+            OrderSide::Ask => (&mut self.asks, &mut self.ask_len),
+        };
+        let (id, dropped_orders) = add_level(levels, len, side, price, size, new_id);
+        if id == new_id {
+            self.next_level_id = new_id;
+        }
+        let untracked = dropped_orders.saturating_add((id == 0) as u32);
+        self.untracked_orders = self.untracked_orders.saturating_add(untracked);
+        self.truncated = self.untracked_orders > 0;
+        id
+    }
+
+    /// Take `size` off the level `book_level` the order joined; `closes_order` also drops
+    /// the order from it. Orders whose level was dropped only update `untracked_orders`.
+    pub fn remove(
+        &mut self,
+        side: OrderSide,
+        price: u64,
+        size: u64,
+        closes_order: bool,
+        book_level: u64,
+    ) {
+        let (levels, len) = match side {
+            OrderSide::Bid => (&mut self.bids, &mut self.bid_len),
+            OrderSide::Ask => (&mut self.asks, &mut self.ask_len),
+        };
+        match levels[..*len as usize]
+            .iter_mut()
+            .find(|level| book_level != 0 && level.id == book_level && level.price == price)
+        {
+            Some(level) => {
+                level.size = level.size.saturating_sub(size);
+                if closes_order {
+                    level.order_count = level.order_count.saturating_sub(1);
+                }
+                if level.size == 0 || level.order_count == 0 {
+                    *level = PriceLevel::default();
+                }
+            }
+            None if closes_order => {
+                self.untracked_orders = self.untracked_orders.saturating_sub(1);
+                self.truncated = self.untracked_orders > 0;
+            }
+            None => {}
+        }
+        while *len > 0 && levels[*len as usize - 1].size == 0 {
+            *len -= 1;
+        }
+    }
+
+    /// Highest tracked bid; 0 => none.
+    pub fn best_bid(&self) -> u64 {
+        self.bids[..self.bid_len as usize]
+            .iter()
+            .filter(|level| level.size > 0)
+            .map(|level| level.price)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Lowest tracked ask; 0 => none.
+    pub fn best_ask(&self) -> u64 {
+        self.asks[..self.ask_len as usize]
+            .iter()
+            .filter(|level| level.size > 0)
+            .map(|level| level.price)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Add `size` at `price` to one side's levels, opening a level with `new_id` if none holds
+/// `price`. Returns the id of the level the size went to (0 => not tracked, every slot
+/// holds a better live level) and how many orders a dropped worse level held.
+fn add_level(
+    levels: &mut [PriceLevel; MAX_PRICE_LEVELS],
+    len: &mut u8,
+    side: OrderSide,
+    price: u64,
+    size: u64,
+    new_id: u64,
+) -> (u64, u32) {
+    if let Some(level) = levels[..*len as usize]
+        .iter_mut()
+        .find(|level| level.size > 0 && level.price == price)
+    {
+        level.size = level.size.saturating_add(size);
+        level.order_count = level.order_count.saturating_add(1);
+        return (level.id, 0);
+    }
+
+    let new_level = PriceLevel {
+        price,
+        size,
+        order_count: 1,
+        id: new_id,
+    };
+    if *len as usize == MAX_PRICE_LEVELS {
+        compact_levels(levels, len);
+    }
+    if (*len as usize) < MAX_PRICE_LEVELS {
+        levels[*len as usize] = new_level;
+        *len += 1;
+        return (new_id, 0);
+    }
+
+    // Full of live levels: keep the better prices.
+    let worse = |a: u64, b: u64| match side {
+        OrderSide::Bid => a < b,
+        OrderSide::Ask => a > b,
+    };
+    let mut worst = 0;
+    for i in 1..MAX_PRICE_LEVELS {
+        if worse(levels[i].price, levels[worst].price) {
+            worst = i;
+        }
+    }
+    if worse(levels[worst].price, price) {
+        let dropped_orders = levels[worst].order_count;
+        levels[worst] = new_level;
+        return (new_id, dropped_orders);
+    }
+    (0, 0)
+}
+
+/// Slide live levels to the front, keeping their order, and free the tail.
+fn compact_levels(levels: &mut [PriceLevel; MAX_PRICE_LEVELS], len: &mut u8) {
+    let mut kept = 0;
+    for i in 0..*len as usize {
+        if levels[i].size > 0 {
+            levels[kept] = levels[i];
+            kept += 1;
+        }
+    }
+    for level in levels[kept..].iter_mut() {
+        *level = PriceLevel::default();
+    }
+    *len = kept as u8;
+}
+
+// ----------------------------------
+// OPEN ORDER INDEX
+// ----------------------------------
+
+/// Resting orders an `OpenOrderIndex` can list.
+pub const MAX_INDEXED_ORDERS: usize = 128;
+
+/// Resting orders of one maker an `OpenOrderIndex` lists at most.
+pub const MAX_INDEXED_ORDERS_PER_USER: usize = 8;
+
+/// How far from its side's best price, in bps of it, a fixed-price order still counts
+/// toward the book imbalance that skew-aware rebates use.
+pub const SKEW_BAND_BPS: u16 = 50;
+
+/// One resting order, with what a taker needs to fill it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct OpenOrderEntry {
+    pub user: Pubkey, // maker's UserState, the `maker_user` of `fill_order`
+    pub order_id: u64,
+    pub order_index: u8, // `maker_order_index` of `fill_order`
+    pub side: OrderSide,
+    pub price_mode: PriceMode, // `price` only applies to Fixed orders
+    pub price: u64,
+    pub size: u64, // remaining size
+    pub expiry_timestamp: i64, // 0 => never expires
+}
+
+impl OpenOrderEntry {
+    pub const SIZE: usize = 32 + 8 + 1 + 1 + (1 + 2) + 8 + 8 + 8;
+
+    pub fn initialize(&mut self, market: Pubkey) {
+        self.market = market;
+        self.bids = [PriceLevel::default(); MAX_PRICE_LEVELS];
+        self.asks = [PriceLevel::default(); MAX_PRICE_LEVELS];
+        self.bid_len = 0;
+        self.ask_len = 0;
+        self.truncated = false;
+        self.next_level_id = 0;
+        self.untracked_orders = 0;
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry_timestamp > 0 && now > self.expiry_timestamp
+    }
+}
+
+/// Resting orders of a market, at the PDA of `["open_order_index", market]`, so takers
+/// and routers can find fillable orders by reading one account instead of crawling
+/// UserStates. `place_order` appends, fills shrink entries and cancels and full fills
+/// swap-remove them, so `entries[..len]` is unsorted.
+///
+/// Each maker gets at most `MAX_INDEXED_ORDERS_PER_USER` entries, so a few makers can't
+/// fill the index with dust. Once it is full, expired entries are pruned, and then a
+/// fixed-price order only gets in by pushing out the worst-priced fixed entry on its side.
+/// Orders left out or pushed out rest unlisted and are only counted in `unindexed_orders`.
+#[account]
+pub struct OpenOrderIndex {
+    pub market: Pubkey,
+    pub len: u16,
+    pub unindexed_orders: u32,
+    pub entries: [OpenOrderEntry; MAX_INDEXED_ORDERS],
+}
+
+impl OpenOrderIndex {
+    pub const SIZE: usize =
+          32 // market
+        + 2  // len
+        + 4  // unindexed_orders
+        + OpenOrderEntry::SIZE * MAX_INDEXED_ORDERS; // entries
+
+    /// List a newly placed order, if its maker has room under the quota and the index has
+    /// room at `now` or a worse-priced entry to push out.
+    pub fn insert(&mut self, entry: OpenOrderEntry, now: i64) {
+        let listed = self.entries[..self.len as usize]
+            .iter()
+            .filter(|listed| listed.user == entry.user)
+            .count();
+        if listed >= MAX_INDEXED_ORDERS_PER_USER {
+            self.unindexed_orders = self.unindexed_orders.saturating_add(1);
+            return;
+        }
+        if self.len as usize == MAX_INDEXED_ORDERS {
+            self.prune_expired(now);
+        }
+        if (self.len as usize) < MAX_INDEXED_ORDERS {
+            self.entries[self.len as usize] = entry;
+            self.len += 1;
+            return;
+        }
+
+        // Full: the new order or the entry it pushes out goes unlisted.
+        self.unindexed_orders = self.unindexed_orders.saturating_add(1);
+        if entry.price_mode != PriceMode::Fixed {
+            return;
+        }
+        let worse = |a: u64, b: u64| match entry.side {
+            OrderSide::Bid => a < b,
+            OrderSide::Ask => a > b,
+        };
+        let worst = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, listed)| listed.side == entry.side)
+            .filter(|(_, listed)| listed.price_mode == PriceMode::Fixed)
+            .reduce(|a, b| if worse(b.1.price, a.1.price) { b } else { a })
+            .map(|(i, listed)| (i, listed.price));
+        if let Some((i, price)) = worst {
+            if worse(price, entry.price) {
+                self.entries[i] = entry;
+            }
+        }
+    }
+
+    /// Unlist the orders that have expired by `now`; they count as unindexed until they
+    /// are canceled. Returns how many were unlisted.
+    pub fn prune_expired(&mut self, now: i64) -> u32 {
+        let mut pruned = 0;
+        let mut i = 0;
+        while i < self.len as usize {
+            if self.entries[i].is_expired(now) {
+                self.swap_remove(i);
+                pruned += 1;
+            } else {
+                i += 1;
+            }
+        }
+        self.unindexed_orders = self.unindexed_orders.saturating_add(pruned);
+        pruned
+    }
+
+    fn swap_remove(&mut self, i: usize) {
+        let last = self.len as usize - 1;
+        self.entries[i] = self.entries[last];
+        self.entries[last] = OpenOrderEntry::default();
+        self.len -= 1;
+    }
+
+    /// Set the remaining size of `user`'s order `order_id`, dropping it at 0.
+    pub fn update(&mut self, user: &Pubkey, order_id: u64, size: u64) {
+        let len = self.len as usize;
+        let position = self.entries[..len]
+            .iter()
+            .position(|entry| entry.user == *user && entry.order_id == order_id);
+        match position {
+            Some(i) if size == 0 => self.swap_remove(i),
+            Some(i) => self.entries[i].size = size,
+            None if size == 0 => {
+                self.unindexed_orders = self.unindexed_orders.saturating_sub(1);
+            }
+            None => {}
+        }
+    }
+
+    /// Counts, sizes and best fixed prices of the indexed orders still live at `now`.
+    /// Expired and unindexed orders and `orders_hash` are left out.
+    pub fn summary(&self, now: i64) -> Result<OrderBookSummary> {
+        let mut summary = OrderBookSummary::default();
+        for entry in self.live_entries(now) {
+            summary.order_count += 1;
+            let fixed = entry.price_mode == PriceMode::Fixed;
+            match entry.side {
+                OrderSide::Bid => {
+                    summary.bid_count += 1;
+                    if fixed {
+                        summary.best_bid = summary.best_bid.max(entry.price);
+                    }
+                    summary.bid_size = summary
+                        .bid_size
+                        .checked_add(entry.size)
+                        .ok_or(FeeError::Overflow)?;
+                }
+                OrderSide::Ask => {
+                    summary.ask_count += 1;
+                    if fixed && (summary.best_ask == 0 || entry.price < summary.best_ask) {
+                        summary.best_ask = entry.price;
+                    }
+                    summary.ask_size = summary
+                        .ask_size
+                        .checked_add(entry.size)
+                        .ok_or(FeeError::Overflow)?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// `summary` with bid and ask sizes only counting live fixed-price orders within
+    /// `SKEW_BAND_BPS` of their side's best price. Pegged orders and size parked away from
+    /// the touch are left out, so a far-off order can't make the other side look thin.
+    pub fn banded_summary(&self, now: i64) -> Result<OrderBookSummary> {
+        let mut summary = self.summary(now)?;
+        let bid_floor = summary
+            .best_bid
+            .saturating_sub(bps_amount(summary.best_bid, SKEW_BAND_BPS)?);
+        let ask_ceiling = summary
+            .best_ask
+            .saturating_add(bps_amount(summary.best_ask, SKEW_BAND_BPS)?);
+        summary.bid_size = 0;
+        summary.ask_size = 0;
+        for entry in self.live_entries(now) {
+            if entry.price_mode != PriceMode::Fixed {
+                continue;
+            }
+            match entry.side {
+                OrderSide::Bid if entry.price >= bid_floor => {
+                    summary.bid_size = summary.bid_size.saturating_add(entry.size);
+                }
+                OrderSide::Ask if entry.price <= ask_ceiling => {
+                    summary.ask_size = summary.ask_size.saturating_add(entry.size);
+                }
+                _ => {}
+            }
+        }
+        Ok(summary)
+    }
+
+    fn live_entries(&self, now: i64) -> impl Iterator<Item = &OpenOrderEntry> {
+        self.entries[..self.len as usize]
+            .iter()
+            .filter(move |entry| !entry.is_expired(now))
+    }
+}
+
+// ----------------------------------
+// EVENT CURSOR
+// ----------------------------------
+
+/// Position of a market's order-flow event stream (`OrderPlaced`, `OrderCanceled` and
+/// `OrderFilled`), at the PDA of `["event_cursor", market]`. Every such event carries the
+/// `event_seq` it was given, and `rolling_hash` chains the logged bytes of each one, so a
+/// fresh indexer that replays the stream can check it saw every event, in order, up to
+/// what this account shows before it serves any data.
+#[account]
+pub struct EventCursor {
+    pub market: Pubkey,
+    pub event_seq: u64,         // seq of the last event; 0 => none yet
+    pub last_slot: u64,         // slot that event was emitted in
+    pub rolling_hash: [u8; 32], // hash(previous rolling_hash, event data), from [0; 32]
+}
+
+impl EventCursor {
+    pub const SIZE: usize =
+          32 // market
+        + 8  // event_seq
+        + 8  // last_slot
+        + 32; // rolling_hash
+
+    /// The `event_seq` to put in the next event.
+    pub fn next_seq(&self) -> u64 {
+        self.event_seq.saturating_add(1)
+    }
+
+    /// Chain `event`, built with `next_seq()`, into the stream. Call right before
+    /// emitting it.
+    pub fn record(&mut self, event: &impl anchor_lang::Event, slot: u64) {
+        self.event_seq = self.next_seq();
+        self.last_slot = slot;
+        self.rolling_hash = hashv(&[&self.rolling_hash, &event.data()]).to_bytes();
+    }
+}
+
+// ----------------------------------
+// ACCOUNT AUDIT
+// ----------------------------------
+
+/// Most accounts one `audit_accounts` call takes; keeps the report event bounded.
+pub const MAX_AUDIT_ACCOUNTS: usize = 24;
+
+/// Every account type the program owns. None carries an explicit schema version: a
+/// layout change always changes `SIZE`, so the data length identifies the layout.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccountKind {
+    GlobalConfig,
+    MarketState,
+    MarketStats,
+    MarketHealth,
+    UserState,
+    VolumeAttestation,
+    UserIndexEntry,
+    RentEscrow,
+    StakeAccount,
+    RewardProgram,
+    RewardPosition,
+    BookCheckpoint,
+    TopOfBook,
+    OpenOrderIndex,
+    EventCursor,
+    ReferralCheckpoint,
+    RegistrationRecord,
+}
+
+impl AccountKind {
+    pub const ALL: [AccountKind; 17] = [
+        AccountKind::GlobalConfig,
+        AccountKind::MarketState,
+        AccountKind::MarketStats,
+        AccountKind::MarketHealth,
+        AccountKind::UserState,
+        AccountKind::VolumeAttestation,
+        AccountKind::UserIndexEntry,
+        AccountKind::RentEscrow,
+        AccountKind::StakeAccount,
+        AccountKind::RewardProgram,
+        AccountKind::RewardPosition,
+        AccountKind::BookCheckpoint,
+        AccountKind::TopOfBook,
+        AccountKind::OpenOrderIndex,
+        AccountKind::EventCursor,
+        AccountKind::ReferralCheckpoint,
+        AccountKind::RegistrationRecord,
+    ];
+
+    /// Full data length an account of this kind that is `actual` bytes long should have.
+    /// `UserState`s are sized by their order slots, so any 1..=`MAX_ORDERS` of them fit.
+    pub fn expected_len(self, actual: usize) -> usize {
+        match self {
+            AccountKind::UserState => {
+                let order_slots = actual.saturating_sub(8 + UserState::SIZE) / Order::SIZE;
+                8 + UserState::space(order_slots.clamp(1, MAX_ORDERS))
+            }
+            kind => kind.layout().1,
+        }
+    }
+
+    /// Full data length of the first deployment's layout, for the kinds that had one.
+    pub fn legacy_len(self) -> Option<usize> {
+        match self {
+            AccountKind::MarketState => Some(8 + LegacyMarketState::SIZE),
+            AccountKind::UserState => Some(8 + LegacyUserState::SIZE),
+            _ => None,
+        }
+    }
+
+    /// Discriminator and full data length (discriminator included) of the current layout;
+    /// the largest one for `UserState`.
+    pub fn layout(self) -> ([u8; 8], usize) {
+        match self {
+            AccountKind::GlobalConfig => (GlobalConfig::DISCRIMINATOR, 8 + GlobalConfig::SIZE),
+            AccountKind::MarketState => (MarketState::DISCRIMINATOR, 8 + MarketState::SIZE),
+            AccountKind::MarketStats => (MarketStats::DISCRIMINATOR, 8 + MarketStats::SIZE),
+            AccountKind::MarketHealth => (MarketHealth::DISCRIMINATOR, 8 + MarketHealth::SIZE),
+            AccountKind::UserState => {
+                (UserState::DISCRIMINATOR, 8 + UserState::space(MAX_ORDERS))
+            }
+            AccountKind::VolumeAttestation => {
+                (VolumeAttestation::DISCRIMINATOR, 8 + VolumeAttestation::SIZE)
+            }
+            AccountKind::UserIndexEntry => {
+                (UserIndexEntry::DISCRIMINATOR, 8 + UserIndexEntry::SIZE)
+            }
+            AccountKind::RentEscrow => (RentEscrow::DISCRIMINATOR, 8 + RentEscrow::SIZE),
+            AccountKind::StakeAccount => (StakeAccount::DISCRIMINATOR, 8 + StakeAccount::SIZE),
+            AccountKind::RewardProgram => {
+                (RewardProgram::DISCRIMINATOR, 8 + RewardProgram::SIZE)
+            }
+            AccountKind::RewardPosition => {
+                (RewardPosition::DISCRIMINATOR, 8 + RewardPosition::SIZE)
+            }
+            AccountKind::BookCheckpoint => {
+                (BookCheckpoint::DISCRIMINATOR, 8 + BookCheckpoint::SIZE)
+            }
+            AccountKind::TopOfBook => (TopOfBook::DISCRIMINATOR, 8 + TopOfBook::SIZE),
+            AccountKind::OpenOrderIndex => {
+                (OpenOrderIndex::DISCRIMINATOR, 8 + OpenOrderIndex::SIZE)
+            }
+            AccountKind::EventCursor => (EventCursor::DISCRIMINATOR, 8 + EventCursor::SIZE),
+            AccountKind::ReferralCheckpoint => {
+                (ReferralCheckpoint::DISCRIMINATOR, 8 + ReferralCheckpoint::SIZE)
+            }
+            AccountKind::RegistrationRecord => {
+                (RegistrationRecord::DISCRIMINATOR, 8 + RegistrationRecord::SIZE)
+            }
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccountAuditStatus {
+    Healthy,
+    WrongOwner,
+    UnknownDiscriminator,
+    /// Known type, but neither the current layout nor a legacy one: corrupt, or from a
+    /// layout no migration covers.
+    SizeMismatch { expected: u32, actual: u32 },
+    /// Known type in the first deployment's layout, which `migrate_market_state` or
+    /// `migrate_user_state` brings up to date.
+    Legacy,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AccountAuditFinding {
+    pub address: Pubkey,
+    pub kind: Option<AccountKind>, // None unless the discriminator was recognized
+    pub status: AccountAuditStatus,
+}
+
+/// Classify an account by its owner and data against the program's current layouts.
+pub fn audit_account(owner: &Pubkey, data: &[u8]) -> (Option<AccountKind>, AccountAuditStatus) {
+    if *owner != crate::ID {
+        return (None, AccountAuditStatus::WrongOwner);
+    }
+    let kind = AccountKind::ALL
+        .into_iter()
+        .find(|kind| data.len() >= 8 && data[..8] == kind.layout().0);
+    match kind {
+        None => (None, AccountAuditStatus::UnknownDiscriminator),
+        Some(kind) if Some(data.len()) == kind.legacy_len() => {
+            (Some(kind), AccountAuditStatus::Legacy)
+        }
+        Some(kind) if data.len() != kind.expected_len(data.len()) => (
+            Some(kind),
+            AccountAuditStatus::SizeMismatch {
+                expected: kind.expected_len(data.len()) as u32,
+                actual: data.len() as u32,
+            },
+        ),
+        Some(kind) => (Some(kind), AccountAuditStatus::Healthy),
+    }
+}
+
+// ----------------------------------
+// MIGRATION
+// ----------------------------------
+
+/// Order slots of a legacy `UserState`.
+pub const LEGACY_MAX_ORDERS: usize = 5;
+
+/// `MarketState` as the first deployment laid it out, before markets had fee precision,
+/// a lifecycle or any of the later settings. `migrate_market_state` reads it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LegacyMarketState {
+    pub authority: Pubkey,
+    pub maker_rebate_bps: u16,
+    pub taker_fee_bps: u16,
+    pub referral_bps: u16,
+    pub total_fees_collected: u64,
+    pub total_liquidity_rewards_distributed: u64,
+}
+
+impl LegacyMarketState {
+    pub const SIZE: usize =
+          32 // authority
+        + 2  // maker_rebate_bps
+        + 2  // taker_fee_bps
+        + 2  // referral_bps
+        + 8  // total_fees_collected
+        + 8; // total_liquidity_rewards_distributed
+
+    /// Decode a `MarketState` account still in the legacy layout; anything else, a market
+    /// already migrated included, fails.
+    pub fn load(owner: &Pubkey, data: &[u8]) -> Result<Self> {
+        let expected = (Some(AccountKind::MarketState), AccountAuditStatus::Legacy);
+        require!(
+            audit_account(owner, data) == expected,
+            FeeError::NotLegacyAccount
+        );
+        Self::deserialize(&mut &data[8..]).map_err(|_| error!(FeeError::NotLegacyAccount))
+    }
+
+    /// The bundle the market is rebuilt from: its rates, in bps as they always were, and a
+    /// new market's defaults. Legacy prices had no decimals.
+    pub fn params(&self) -> MarketParams {
+        MarketParams::new(
+            FeeParameters {
+                maker_rebate_rate: self.maker_rebate_bps,
+                taker_fee_rate: self.taker_fee_bps,
+                referral_rate: self.referral_bps,
+            },
+            FeePrecision::Bps,
+            0,
+            self.authority,
+            None,
+        )
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LegacyOrder {
+    pub side: OrderSide,
+    pub price: u64,
+    pub size_remaining: u64,
+    pub creation_timestamp: i64,
+    pub expiry_timestamp: i64,
+}
+
+/// `UserState` as the first deployment laid it out: one per user rather than per market,
+/// with a fixed array of orders. `migrate_user_state` reads it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LegacyUserState {
+    pub authority: Pubkey,
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub maker_rebates_earned: u64,
+    pub taker_fees_paid: u64,
+    pub liquidity_score: u64,
+    pub referrer: Option<Pubkey>,
+    pub orders: [LegacyOrder; LEGACY_MAX_ORDERS],
+}
+
+impl LegacyUserState {
+    pub const SIZE: usize =
+          32  // authority
+        + 8   // maker_volume
+        + 8   // taker_volume
+        + 8   // maker_rebates_earned
+        + 8   // taker_fees_paid
+        + 8   // liquidity_score
+        + 1 + 32  // referrer: Option<Pubkey> => 1 + 32 bytes
+        + (LegacyOrder::SIZE * LEGACY_MAX_ORDERS);
+
+    /// Decode a `UserState` account still in the legacy layout; anything else fails.
+    pub fn load(owner: &Pubkey, data: &[u8]) -> Result<Self> {
+        let expected = (Some(AccountKind::UserState), AccountAuditStatus::Legacy);
+        require!(
+            audit_account(owner, data) == expected,
+            FeeError::NotLegacyAccount
+        );
+        Self::deserialize(&mut &data[8..]).map_err(|_| error!(FeeError::NotLegacyAccount))
+    }
+
+    pub fn stats(&self) -> LegacyUserStats {
+        LegacyUserStats {
+            maker_volume: self.maker_volume,
+            taker_volume: self.taker_volume,
+            maker_rebates_earned: self.maker_rebates_earned,
+            taker_fees_paid: self.taker_fees_paid,
+            liquidity_score: self.liquidity_score,
+            taker_rebates_earned: 0,
+            referrer: self.referrer,
+        }
+    }
+
+    pub fn open_order_count(&self) -> u8 {
+        self.orders.iter().filter(|order| order.size_remaining > 0).count() as u8
+    }
+}
+
+impl LegacyOrder {
+    pub const SIZE: usize =
+          1  // side
+        + 8  // price
+        + 8  // size_remaining
+        + 8  // creation_timestamp
+        + 8; // expiry_timestamp
+}
+
+// ----------------------------------
+// ORDER STRUCT
+// ----------------------------------
+
+/// Most order slots a `UserState` can have; the upper bound for a market's
+/// `max_open_orders`.
+pub const MAX_ORDERS: usize = 32;
+
+/// `max_open_orders` of a new market, which keeps `UserState`s cheap.
+pub const DEFAULT_MAX_OPEN_ORDERS: u8 = 5;
+
+/// A market's `max_open_orders` must allow at least one order and stay within `MAX_ORDERS`.
+pub fn validate_max_open_orders(max_open_orders: u8) -> Result<()> {
+    require!(
+        max_open_orders > 0 && max_open_orders as usize <= MAX_ORDERS,
+        FeeError::InvalidMaxOpenOrders
+    );
+    Ok(())
+}
+
+/// Order lifetimes are never negative, and a default TTL must fit under the maximum.
+/// 0 means no default and no maximum respectively.
+pub fn validate_order_ttl(default_order_ttl_secs: i64, max_order_ttl_secs: i64) -> Result<()> {
+    require!(
+        default_order_ttl_secs >= 0
+            && max_order_ttl_secs >= 0
+            && (max_order_ttl_secs == 0 || default_order_ttl_secs <= max_order_ttl_secs),
+        FeeError::InvalidOrderTtl
+    );
+    Ok(())
+}
+
+/// Onboarding incentive: a user's first `max_fills` taker fills within `window_secs` of
+/// registering get `discount_bps` of the taker fee paid from the promotion budget.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FirstFillBonus {
+    pub max_fills: u8, // 0 => off
+    pub window_secs: i64,
+    pub discount_bps: u16,
+}
+
+impl FirstFillBonus {
+    pub const SIZE: usize = 1 + 8 + 2;
+
+    /// The discount `taker` gets on `taker_fee` right now, capped by what is left in
+    /// `budget`. Imported and returning users (no `registered_ts`) never qualify.
+    pub fn discount(
+        &self,
+        taker: &UserState,
+        taker_fee: u64,
+        budget: u64,
+        now: i64,
+    ) -> Result<u64> {
+        let eligible = self.max_fills > 0
+            && taker.registered_ts > 0
+            && taker.bonus_fills < self.max_fills
+            && now <= taker.registered_ts.saturating_add(self.window_secs);
+        if !eligible {
+            return Ok(0);
+        }
+        Ok(bps_amount(taker_fee, self.discount_bps)?.min(budget))
+    }
+}
+
+/// Rebate adjustment for book imbalance. A maker whose order rests on the thin side earns
+/// up to `max_bonus_bps` more rebate, one on the heavy side up to `max_haircut_bps` less,
+/// both scaled by the imbalance. Each is at most 10_000, so the rebate stays within 0..=2x.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct SkewRebate {
+    pub max_bonus_bps: u16,
+    pub max_haircut_bps: u16,
+}
+
+impl SkewRebate {
+    pub const SIZE: usize = 2 + 2;
+
+    pub fn initialize(&mut self, market: Pubkey) {
+        self.market = market;
+        self.event_seq = 0;
+        self.last_slot = 0;
+        self.rolling_hash = [0; 32];
+    }
+
+    pub fn initialize(&mut self, market: Pubkey) {
+        self.market = market;
+        self.len = 0;
+        self.unindexed_orders = 0;
+        self.entries = [OpenOrderEntry::default(); MAX_INDEXED_ORDERS];
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bonus_bps > 0 || self.max_haircut_bps > 0
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.max_bonus_bps <= BPS_DENOMINATOR && self.max_haircut_bps <= BPS_DENOMINATOR,
+            FeeError::InvalidSkewRebate
+        );
+        Ok(())
+    }
+
+    /// Signed change to `maker_rebate` for a maker resting on `side` while the book's
+    /// imbalance is `imbalance_bps` (positive => bid-heavy).
+    pub fn adjustment(
+        &self,
+        side: OrderSide,
+        imbalance_bps: i32,
+        maker_rebate: u64,
+    ) -> Result<i64> {
+        let bid_heavy = match imbalance_bps.signum() {
+            0 => return Ok(0),
+            sign => sign > 0,
+        };
+        let thin_side = (side == OrderSide::Ask) == bid_heavy;
+        let max_bps = if thin_side {
+            self.max_bonus_bps
+        } else {
+            self.max_haircut_bps
+        };
+        let skew = imbalance_bps.unsigned_abs().min(BPS_DENOMINATOR as u32) as u128;
+        let whole = BPS_DENOMINATOR as u128;
+        let amount = maker_rebate as u128 * max_bps as u128 * skew / (whole * whole);
+        let amount = i64::try_from(amount).map_err(|_| error!(FeeError::Overflow))?;
+        Ok(if thin_side { amount } else { -amount })
+    }
+}
+
+/// How many recent reference prices a market keeps for banded liquidity scoring.
+pub const REFERENCE_HISTORY: usize = 8;
+
+/// A reference price and when it was published.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ReferenceSnapshot {
+    pub price: u64, // 0 => empty slot
+    pub ts: i64,
+}
+
+impl ReferenceSnapshot {
+    pub const SIZE: usize = 8 + 8;
+}
+
+/// A temporary liquidity score multiplier for one side of the book, e.g. 2x for asks
+/// while the book is bid-heavy. Boosts only the resting time within `starts_at..expires_at`,
+/// however the accrual around it is checkpointed. Not a promotion: it spends nothing,
+/// since liquidity rewards are shares of a fixed pool, so the promotion cap ignores it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct SideIncentive {
+    pub side: OrderSide,
+    pub multiplier_bps: u16, // 10_000 => 1x; 0 => no incentive
+    pub starts_at: i64,
+    pub expires_at: i64,
+}
+
+impl SideIncentive {
+    pub const SIZE: usize =
+          1  // side
+        + 2  // multiplier_bps
+        + 8  // starts_at
+        + 8; // expires_at
+
+    /// The part of `from..to` the incentive covers for an order on `side`, if any.
+    pub fn window(&self, side: OrderSide, from: i64, to: i64) -> Option<(i64, i64)> {
+        if self.multiplier_bps == 0 || side != self.side {
+            return None;
+        }
+        let (start, end) = (from.max(self.starts_at), to.min(self.expires_at));
+        (start < end).then_some((start, end))
+    }
+
+    /// `score` earned inside the incentive's window, boosted.
+    pub fn boost(&self, score: u64) -> u64 {
+        let boosted = score as u128 * self.multiplier_bps as u128 / BPS_DENOMINATOR as u128;
+        boosted.min(u64::MAX as u128) as u64
+    }
+}
+
+/// One epoch's cancel-to-fill breach, and the epoch its penalty applies in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CancelPenalty {
+    pub offending_epoch: u64,
+    pub penalty_epoch: u64,
+    pub places: u32,
+    pub cancels: u32,
+    pub fills: u32,
+}
+
+/// A user's lifetime stats as exported from the legacy program, for `import_user`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct LegacyUserStats {
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub maker_rebates_earned: u64,
+    pub taker_fees_paid: u64,
+    pub liquidity_score: u64,
+    pub taker_rebates_earned: u64,
+    pub referrer: Option<Pubkey>,
+}
+
+/// How many distinct strategy tags each user can track stats for at once.
+pub const MAX_STRATEGY_TAGS: usize = 4;
+
+/// `strategy_id` of orders that are not attributed to any strategy.
+pub const UNTAGGED_STRATEGY: u16 = 0;
+
+/// Per-strategy maker performance, keyed by the `strategy_id` makers tag orders with.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct StrategyStats {
+    pub strategy_id: u16, // UNTAGGED_STRATEGY => empty slot
+    pub volume: u64,
+    pub rebates_earned: u64,
+    pub fill_count: u64,
+}
+
+impl StrategyStats {
+    pub const SIZE: usize =
+          2  // strategy_id
+        + 8  // volume
+        + 8  // rebates_earned
+        + 8; // fill_count
+}
+
+/// How many recent claim idempotency keys each user remembers.
+pub const CLAIM_KEY_HISTORY: usize = 4;
+
+/// How many claim deadlines each user's claimable balances can be split over. See
+/// `MarketState::claim_expiry` for why this many is always enough.
+pub const CLAIM_LOTS: usize = 4;
+
+/// The part of a user's claimable balances credited with the same deadline.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ClaimLot {
+    pub expires_at: i64, // 0 => never expires
+    pub rebates: u64,
+    pub rewards: u64,
+    pub referral_rewards: u64, // part of `referral_rewards_owed`, for the referrer
+}
+
+impl ClaimLot {
+    pub const SIZE: usize =
+          8  // expires_at
+        + 8  // rebates
+        + 8  // rewards
+        + 8; // referral_rewards
+
+    pub fn is_empty(&self) -> bool {
+        self.rebates == 0 && self.rewards == 0 && self.referral_rewards == 0
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at > 0 && now >= self.expires_at
+    }
+}
+
+/// What `MarketState::distribute_liquidity_reward` did for one user.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct LiquidityDistribution {
+    pub amount: u64,             // the user's share of the pool
+    pub redirected: bool,        // opted out: `amount` went where `opt_out_policy` says
+    pub forfeited: ClaimAmounts, // the user's expired claims, taken back first
+}
+
+/// Claimable rebates and rewards moved in one go: claimed, or forfeited once expired.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ClaimAmounts {
+    pub rebates: u64,
+    pub rewards: u64,
+    pub referral_rewards: u64,
+}
+
+impl ClaimAmounts {
+    pub fn total(&self) -> u64 {
+        self.rebates
+            .saturating_add(self.rewards)
+            .saturating_add(self.referral_rewards)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Order {
+    pub side: OrderSide,
+    pub price: u64,
+    pub size_remaining: u64,
+    pub creation_timestamp: i64,
+    pub expiry_timestamp: i64,
+    pub price_mode: PriceMode,
+    pub strategy_id: u16, // maker's own label, 0 => untagged
+    pub order_id: u64,    // assigned by `UserState::insert_order`; 0 => free slot
+    pub scored_until: i64, // liquidity score is credited up to here
+    pub promo_reserve: u64, // promotion budget still held for this order's promotional rebate
+    pub book_level: u64, // id of its `TopOfBook` level; 0 => not tracked there
+}
+
+impl Order {
+    // side (enum) as 1 byte, price_mode as 1 byte tag + largest variant (u16)
+    pub const SIZE: usize =
+          1  // side
+        + 8  // price
+        + 8  // size_remaining
+        + 8  // creation_timestamp
+        + 8  // expiry_timestamp
+        + 1 + 2 // price_mode
+        + 2  // strategy_id
+        + 8  // order_id
+        + 8  // scored_until
+        + 8  // promo_reserve
+        + 8; // book_level
+
+    /// Whether the order has an expiry and `now` is past it.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry_timestamp > 0 && now > self.expiry_timestamp
+    }
+
+    /// Resolve a pegged order against `reference_price`. A non-zero `price` caps the
+    /// result: bids never pay more, asks never sell for less.
+    pub fn pegged_price(&self, reference_price: u64) -> Result<u64> {
+        let pegged = match self.price_mode {
+            PriceMode::Fixed => return Ok(self.price),
+            PriceMode::MidPeg => reference_price,
+            PriceMode::PrimaryPeg { offset_bps } => {
+                let offset = bps_amount(reference_price, offset_bps)?;
+                match self.side {
+                    OrderSide::Bid => reference_price.saturating_sub(offset),
+                    OrderSide::Ask => reference_price
+                        .checked_add(offset)
+                        .ok_or(FeeError::Overflow)?,
+                }
+            }
+        };
+
+        if self.price == 0 {
+            return Ok(pegged);
+        }
+        Ok(match self.side {
+            OrderSide::Bid => pegged.min(self.price),
+            OrderSide::Ask => pegged.max(self.price),
+        })
+    }
+
+    /// Whether this order's price sits within `band_bps` of `reference_price`.
+    pub fn within_band(&self, reference_price: u64, band_bps: u16) -> bool {
+        let price = match self.pegged_price(reference_price) {
+            Ok(price) => price,
+            Err(_) => return false,
+        };
+        price.abs_diff(reference_price) as u128 * BPS_DENOMINATOR as u128
+            <= reference_price as u128 * band_bps as u128
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrderSide {
+    Bid,
+    Ask,
+}
+
+impl Default for OrderSide {
+    fn default() -> Self {
+        OrderSide::Bid
+    }
+}
+
+/// How an order's execution price is determined.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PriceMode {
+    /// Executes at the order's fixed `price`.
+    Fixed,
+    /// Executes at the market reference (mid) price.
+    MidPeg,
+    /// Executes `offset_bps` away from the reference price on the order's own side.
+    PrimaryPeg { offset_bps: u16 },
+}
+
+impl Default for PriceMode {
+    fn default() -> Self {
+        PriceMode::Fixed
+    }
+}
+
+/// Lifecycle of a market. `allows` is the single place that decides which instructions
+/// a status permits, and `can_transition_to` which status changes are legal.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MarketStatus {
+    /// Never set up; nothing is allowed.
+    Uninitialized,
+    /// Normal trading.
+    Active,
+    /// Temporarily halted: no new users, orders or fills. Cancels and claims still work.
+    Paused,
+    /// Winding down: no new users or orders, the resting book can still be filled or
+    /// canceled, and the market may be closed once empty.
+    SunsetPending,
+    /// Closed by `close_market`; nothing is allowed.
+    Closed,
+}
+
+impl Default for MarketStatus {
+    fn default() -> Self {
+        MarketStatus::Uninitialized
+    }
+}
+
+impl MarketStatus {
+    pub fn allows(self, instruction: ProgramInstruction) -> bool {
+        use ProgramInstruction::*;
+        match self {
+            MarketStatus::Uninitialized | MarketStatus::Closed => false,
+            MarketStatus::Active => !matches!(instruction, CloseMarket),
+            MarketStatus::Paused => !matches!(
+                instruction,
+                RegisterUser | ImportUser | PlaceOrder | FillOrder | CloseMarket
+            ),
+            MarketStatus::SunsetPending => {
+                !matches!(instruction, RegisterUser | ImportUser | PlaceOrder)
+            }
+        }
+    }
+
+    pub fn can_transition_to(self, next: MarketStatus) -> bool {
+        use MarketStatus::*;
+        matches!(
+            (self, next),
+            (Uninitialized, Active)
+                | (Active, Paused)
+                | (Paused, Active)
+                | (Active, SunsetPending)
+                | (Paused, SunsetPending)
+                | (SunsetPending, Active)
+                | (SunsetPending, Closed)
+        )
+    }
+}
+
+/// Where the liquidity reward share of a user who opted out of rewards goes.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OptOutPolicy {
+    /// Credited to the market treasury.
+    Treasury,
+    /// Left in the reward pool. The user's score leaves the market total either way, so
+    /// the share is spread pro-rata over everyone else's score.
+    ProRata,
+}
+
+impl Default for OptOutPolicy {
+    fn default() -> Self {
+        OptOutPolicy::Treasury
+    }
+}
+
+/// Instructions that can be individually switched off through `GlobalConfig`.
+/// Each variant's discriminant is its bit index, so only ever append new variants.
+/// A variant covers every instruction that does the same job: `CancelOrder` gates all three
+/// cancels, `RewardPrograms` the whole reward program lifecycle, and so on. Read-only views
+/// (`validate_market_params`, `simulate_tier_assignment`, `get_effective_fees`,
+/// `export_user_stats_page`, `audit_accounts`) change nothing and have no switch, nor
+/// do `initialize_global_config`, `initialize_market` and `set_instruction_enabled`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgramInstruction {
+    /// The fee schedule: base rates (single market or batch), tiers, referral basis and
+    /// fee precision.
+    UpdateFeeParameters,
+    RegisterUser,
+    PlaceOrder,
+    CancelOrder,
+    FillOrder,
+    DistributeLiquidityRewards,
+    ClaimTakerRebates,
+    WithdrawFees,
+    ClaimLiquidityRewards,
+    RollEpoch,
+    ForfeitExpiredClaims,
+    UpdateReferencePrice,
+    SetRewardsOptOut,
+    IssueAttestation,
+    ResetStrategyStats,
+    CheckpointOrderBook,
+    ClaimAndStake,
+    ImportUser,
+    CloseMarket,
+    RewardPrograms,
+    ReclaimRent,
+    AccrueLiquidityScore,
+    ClaimReferralRewards,
+    AuthorityRecovery,
+    CheckpointReferralGraph,
+    ReportRejection,
+    ResizeOrderSlots,
+    MigrateAccounts,
+    FundLiquidityRewards,
+    ApproveLargeWithdrawal,
+    /// Every other market parameter setter, from incentives and limits to the oracle.
+    UpdateMarketParams,
+    SetMarketStatus,
+    /// `set_top_level_only` and `update_withdrawal_cosign`.
+    UpdateAccessControls,
+    RefreshMarketHealth,
+}
+
+impl ProgramInstruction {
+    pub fn mask(self) -> u64 {
+        1u64 << (self as u8)
+    }
+}
+
+// ----------------------------------
+// FAILURE LOGS
+// ----------------------------------
+
+/// Pass on an instruction handler's `result`. If it failed, log one compact line first,
+/// e.g. `fill_order failed: market=<key> status=Active maker_order_index=1 ...`, right
+/// before Anchor logs the error itself, so a failed transaction's logs say which market,
+/// in which status, and which request it was without decoding the instruction. `params`
+/// is only formatted then. Every instruction goes through it; `market` is `None` for the
+/// global ones and `status` for those that don't load the `MarketState`, and they log
+/// as `-`.
+pub fn with_failure_log<T>(
+    instruction: &str,
+    market: Option<Pubkey>,
+    status: Option<MarketStatus>,
+    params: impl FnOnce() -> String,
+    result: Result<T>,
+) -> Result<T> {
+    result.map_err(|error| {
+        let market = market.map_or_else(|| "-".to_string(), |market| market.to_string());
+        let status = status.map_or_else(|| "-".to_string(), |status| format!("{:?}", status));
+        msg!("{} failed: market={} status={} {}", instruction, market, status, params());
+        error
+    })
+}
+
+/// Why a placement or fill was rejected, as counted in `MarketStats::rejections`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectionReason {
+    /// The order had expired, or would have outlived the market's max TTL.
+    Expired,
+    /// The user had no free order slot, or hit the market's open order limit.
+    NoSlot,
+    /// Turned away by the keeper's or relayer's own rate limiting; nothing on-chain
+    /// rejects for this.
+    RateLimited,
+    /// Stopped by a price or size protection: slippage bound, minimum fill notional, or
+    /// a stale reference price.
+    RiskLimit,
+}
+
+pub const REJECTION_REASONS: usize = 4;
+
+impl RejectionReason {
+    /// The reason a failed placement or fill with `error` counts under, if any.
+    pub fn for_error(error: FeeError) -> Option<RejectionReason> {
+        match error {
+            FeeError::OrderExpired | FeeError::OrderTtlTooLong => Some(RejectionReason::Expired),
+            FeeError::NoFreeOrderSlot | FeeError::OpenOrderLimitReached => {
+                Some(RejectionReason::NoSlot)
+            }
+            FeeError::SlippageExceeded
+            | FeeError::FillBelowMinNotional
+            | FeeError::OrderBelowMinNotional
+            | FeeError::ReferencePriceStale => Some(RejectionReason::RiskLimit),
+            _ => None,
+        }
+    }
+}
+
+// ----------------------------------
+// ERRORS
+// ----------------------------------
+
+#[error_code]
+pub enum FeeError {
+    #[msg("Overflow or underflow detected.")]
+    Overflow,
+    #[msg("Configuration leads to negative net fee.")]
+    NegativeFee,
+    #[msg("Unauthorized operation.")]
+    Unauthorized,
+    #[msg("No open orders found.")]
+    NoOpenOrders,
+    #[msg("Invalid fee configuration.")]
+    InvalidFeeConfiguration,
+    #[msg("Insufficient funds.")]
+    InsufficientFunds,
+    #[msg("No free slot to place a new order; resize_order_slots may add some.")]
+    NoFreeOrderSlot,
+    #[msg("Invalid order index.")]
+    InvalidOrderIndex,
+    #[msg("Order is expired.")]
+    OrderExpired,
+    #[msg("Taker limit price does not cross the maker's price.")]
+    LimitPriceNotCrossed,
+    #[msg("Nothing to claim.")]
+    NothingToClaim,
+    #[msg("This instruction is currently disabled.")]
+    InstructionDisabled,
+    #[msg("Account belongs to a different market.")]
+    MarketMismatch,
+    #[msg("Invalid epoch configuration.")]
+    InvalidEpochConfiguration,
+    #[msg("Current epoch has not finished yet.")]
+    EpochNotFinished,
+    #[msg("Claim deadline has passed.")]
+    ClaimDeadlinePassed,
+    #[msg("Claim deadline has not been reached.")]
+    ClaimDeadlineNotReached,
+    #[msg("Invalid oracle configuration.")]
+    InvalidOracleConfiguration,
+    #[msg("Reference price is not available.")]
+    ReferencePriceUnavailable,
+    #[msg("Reference price is stale.")]
+    ReferencePriceStale,
+    #[msg("Peg offset must be at most 10000 bps.")]
+    InvalidPegOffset,
+    #[msg("Maker rebate plus referral reward would exceed the taker fee.")]
+    FeeSharesExceedFee,
+    #[msg("Too many fee tiers.")]
+    TooManyFeeTiers,
+    #[msg("Fee tiers must have increasing thresholds and never-worse, bounded rates.")]
+    InvalidFeeTiers,
+    #[msg("Idempotency key must be non-zero.")]
+    InvalidIdempotencyKey,
+    #[msg("A claim with this idempotency key was already processed.")]
+    DuplicateClaim,
+    #[msg("No free slot to track another strategy tag.")]
+    StrategyTableFull,
+    #[msg("No stats tracked for this strategy tag.")]
+    UnknownStrategy,
+    #[msg("Book accounts must be passed once each, sorted by address.")]
+    NonCanonicalBookAccounts,
+    #[msg("Effective fill price is worse than the taker's worst_price.")]
+    SlippageExceeded,
+    #[msg("Withdrawal would leave the treasury below rent exemption.")]
+    WouldBreakRentExemption,
+    #[msg("Users can only be imported before the market goes live.")]
+    ImportWindowClosed,
+    #[msg("Market still has users, outstanding claims or treasury funds.")]
+    MarketNotEmpty,
+    #[msg("A side incentive must expire in the future.")]
+    InvalidSideIncentive,
+    #[msg("The market's status does not allow this instruction.")]
+    MarketStatusDisallows,
+    #[msg("Invalid market status transition.")]
+    InvalidStatusTransition,
+    #[msg("Export page accounts do not match the requested user index range.")]
+    InvalidExportPage,
+    #[msg("Reward program window must end after it starts.")]
+    InvalidRewardProgram,
+    #[msg("Reward program is not active.")]
+    RewardProgramInactive,
+    #[msg("Rewards were already distributed to this user this epoch.")]
+    AlreadyDistributed,
+    #[msg("price_decimals is above MAX_PRICE_DECIMALS.")]
+    InvalidPriceDecimals,
+    #[msg("UserState is not reclaimable yet.")]
+    RentNotReclaimable,
+    #[msg("UserState has not been reclaimed yet.")]
+    RentNotReclaimed,
+    #[msg("max_open_orders must be between 1 and MAX_ORDERS.")]
+    InvalidMaxOpenOrders,
+    #[msg("Open order limit for this market reached.")]
+    OpenOrderLimitReached,
+    #[msg("Fill size does not match the order's remaining size.")]
+    FillSizeMismatch,
+    #[msg("Give either a plain-text referrer or a non-zero referrer commitment.")]
+    InvalidReferrer,
+    #[msg("Batch markets must be distinct, increasing and match the writable accounts passed.")]
+    InvalidBatch,
+    #[msg("Referral cap must be at most 10_000 bps.")]
+    InvalidReferralCap,
+    #[msg("Recovery committee members must be distinct and meet the threshold.")]
+    InvalidRecoveryCommittee,
+    #[msg("An authority recovery is already pending.")]
+    RecoveryAlreadyPending,
+    #[msg("No authority recovery is pending.")]
+    NoPendingRecovery,
+    #[msg("Authority recovery lacks approvals or is still in its timelock or challenge period.")]
+    RecoveryNotReady,
+    #[msg("This market only accepts the instruction at the top level of a transaction.")]
+    CpiNotAllowed,
+    #[msg("Skew rebate bonus and haircut must each be at most 10_000 bps.")]
+    InvalidSkewRebate,
+    #[msg("Promotional rebates would exceed the funded promotion budget.")]
+    PromotionLiabilityExceeded,
+    #[msg("Fill notional is below the market's min_fill_notional.")]
+    FillBelowMinNotional,
+    #[msg("Audit between 1 and MAX_AUDIT_ACCOUNTS accounts per call.")]
+    InvalidAuditBatch,
+    #[msg("min_price must not be above max_price.")]
+    InvalidPriceRange,
+    #[msg("This withdrawal needs the withdrawal cosigner's approval.")]
+    WithdrawalNeedsCosigner,
+    #[msg("The withdrawal cosigner must be set and differ from the market authority.")]
+    InvalidWithdrawalCosigner,
+    #[msg("The scoring plugin can't be this program, and max_score_delta must fit an i64.")]
+    InvalidScoringPlugin,
+    #[msg("Pass the market's scoring program as the first remaining account.")]
+    ScoringProgramMissing,
+    #[msg("The scoring program didn't return an i64 score delta.")]
+    InvalidScoreDelta,
+    #[msg("Order TTLs must be non-negative, with the default at most the maximum.")]
+    InvalidOrderTtl,
+    #[msg("The order's expiry is further out than the market's max_order_ttl_secs.")]
+    OrderTtlTooLong,
+    #[msg("A fee rate can't be restated exactly in the requested fee precision.")]
+    InvalidFeePrecision,
+    #[msg("A rejection report must count at least one rejection.")]
+    InvalidRejectionReport,
+    #[msg("Every UserState of the market must be passed in.")]
+    IncompleteBookAccounts,
+    #[msg("Not that much is staked, or nothing is unstaking.")]
+    InsufficientStake,
+    #[msg("Unstaked funds are still cooling down.")]
+    UnstakeCooldownActive,
+    #[msg("Not one of this market's auxiliary accounts, or its rent goes elsewhere.")]
+    InvalidMarketAccount,
+    #[msg("Reward program still has open positions.")]
+    RewardPositionsOpen,
+    #[msg("Only the user with nothing left to claim, or the sponsor after the program ends.")]
+    RewardPositionNotClosable,
+    #[msg("Pass the user index entry that links to the one being removed.")]
+    InvalidUserIndex,
+    #[msg("Order slots that would be dropped still hold open orders.")]
+    OrderSlotsInUse,
+    #[msg("The user has no expired orders to cancel.")]
+    NoExpiredOrders,
+    #[msg("The pending authority recovery lapsed before reaching its threshold.")]
+    RecoveryLapsed,
+    #[msg("The pending authority recovery already has its approvals.")]
+    RecoveryAlreadyApproved,
+    #[msg("Order notional is below the market's min_fill_notional.")]
+    OrderBelowMinNotional,
+    #[msg("A withdrawal co-signing threshold needs a positive window.")]
+    InvalidWithdrawalWindow,
+    #[msg("The referral checkpoint has already been finalized.")]
+    ReferralCheckpointFinal,
+    #[msg("Users joined or left the market since the referral checkpoint began; restart it.")]
+    ReferralGraphChanged,
+    #[msg("The referral checkpoint is missing some of the market's users.")]
+    IncompleteReferralGraph,
+    #[msg("An order's expiry must be 0 (the market default) or in the future.")]
+    InvalidOrderExpiry,
+    #[msg("The account is not in a legacy layout that can be migrated.")]
+    NotLegacyAccount,
+    #[msg("The book checkpoint has already been finalized.")]
+    BookCheckpointFinal,
+    #[msg("Orders or users changed since the book checkpoint began; restart it.")]
+    BookChanged,
+}
+
+// ----------------------------------
+// EVENTS
+// ----------------------------------
+
+#[event]
+pub struct InstructionSwitchChanged {
+    pub instruction: ProgramInstruction,
+    pub enabled: bool,
+    pub disabled_instructions: u64,
+}
+
+#[event]
+pub struct FeeParametersUpdated {
+    pub market: Pubkey,
+    pub maker_rebate_rate: u16,
+    pub taker_fee_rate: u16,
+    pub referral_rate: u16,
+    pub fee_precision: FeePrecision, // unit of the rates above
+}
+
+#[event]
+pub struct FeeTiersUpdated {
+    pub market: Pubkey,
+    pub fee_tiers: Vec<FeeTier>,
+}
+
+#[event]
+pub struct ReferralBasisUpdated {
+    pub market: Pubkey,
+    pub referral_basis: ReferralBasis,
+}
+
+#[event]
+pub struct FeePrecisionUpdated {
+    pub market: Pubkey,
+    pub fee_precision: FeePrecision,
+    pub maker_rebate_rate: u16,
+    pub taker_fee_rate: u16,
+    pub referral_rate: u16,
+    pub fee_tiers: Vec<FeeTier>,
+}
+
+#[event]
+pub struct PriceImprovementRebateUpdated {
+    pub market: Pubkey,
+    pub price_improvement_rebate_bps: u16,
+}
+
+#[event]
+pub struct FirstFillBonusUpdated {
+    pub market: Pubkey,
+    pub max_fills: u8,
+    pub window_secs: i64,
+    pub discount_bps: u16,
+    pub promotion_budget: u64,
+}
+
+#[event]
+pub struct LiquidityBandUpdated {
+    pub market: Pubkey,
+    pub liquidity_band_bps: u16,
+}
+
+#[event]
+pub struct LiquidityScoreAccrued {
+    pub user: Pubkey,
+    pub added: u64,
+    pub liquidity_score: u64,
+}
+
+#[event]
+pub struct SideIncentiveUpdated {
+    pub market: Pubkey,
+    pub side: OrderSide,
+    pub multiplier_bps: u16,
+    pub starts_at: i64,
+    pub expires_at: i64,
+}
+
+#[event]
+pub struct CancelPenaltyUpdated {
+    pub market: Pubkey,
+    pub max_cancel_to_fill_bps: u32,
+    pub min_cancels: u32,
+    pub rebate_haircut_bps: u16,
+    pub placement_fee: u64,
+}
+
+/// A user breached the cancel-to-fill limit in `offending_epoch` and is penalized
+/// throughout `penalty_epoch`.
+#[event]
+pub struct CancelPenaltyApplied {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub offending_epoch: u64,
+    pub penalty_epoch: u64,
+    pub places: u32,
+    pub cancels: u32,
+    pub fills: u32,
+}
+
+impl CancelPenaltyApplied {
+    pub fn new(user_state: &UserState, penalty: &CancelPenalty) -> Self {
+        Self {
+            user: user_state.authority,
+            market: user_state.market,
+            offending_epoch: penalty.offending_epoch,
+            penalty_epoch: penalty.penalty_epoch,
+            places: penalty.places,
+            cancels: penalty.cancels,
+            fills: penalty.fills,
+        }
+    }
+}
+
+#[event]
+pub struct CancelPenaltyCharged {
+    pub user: Pubkey,
+    pub epoch: u64,
+    pub placement_fee: u64,
+    pub rebate_haircut: u64,
+}
+
+#[event]
+pub struct MaxOpenOrdersUpdated {
+    pub market: Pubkey,
+    pub max_open_orders: u8,
+}
+
+#[event]
+pub struct OrderSlotsResized {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub previous_slots: u8,
+    pub order_slots: u8,
+}
+
+#[event]
+pub struct RejectionReporterUpdated {
+    pub market: Pubkey,
+    pub rejection_reporter: Pubkey,
+}
+
+#[event]
+pub struct OrderTtlUpdated {
+    pub market: Pubkey,
+    pub default_order_ttl_secs: i64,
+    pub max_order_ttl_secs: i64,
+}
+
+#[event]
+pub struct AuthorityRecoveryProposed {
+    pub market: Pubkey,
+    pub proposer: Pubkey,
+    pub new_authority: Pubkey,
+    pub executable_ts: i64, // earliest execution, if approved in time
+}
+
+#[event]
+pub struct AuthorityRecoveryApproved {
+    pub market: Pubkey,
+    pub member: Pubkey,
+    pub approvals: u8,
+    pub approved_ts: i64, // 0 => threshold not reached yet
+}
+
+#[event]
+pub struct AuthorityRecoveryRejected {
+    pub market: Pubkey,
+    pub member: Pubkey,
+    pub new_authority: Pubkey,
+    pub rejections: u8, // 0 once dropped
+    pub dropped: bool,  // too few members left to reach the threshold
+}
+
+#[event]
+pub struct AuthorityRecoveryCanceled {
+    pub market: Pubkey,
+    pub new_authority: Pubkey,
+}
+
+#[event]
+pub struct AuthorityRecovered {
+    pub market: Pubkey,
+    pub previous_authority: Pubkey,
+    pub new_authority: Pubkey,
+}
+
+#[event]
+pub struct PromoMakerRebateUpdated {
+    pub market: Pubkey,
+    pub promo_maker_rebate_bps: u16,
+    pub promotion_budget: u64,
+    pub promotion_reserved: u64,
+}
+
+#[event]
+pub struct PromotionEpochCapUpdated {
+    pub market: Pubkey,
+    pub promotion_epoch_cap: u64,
+}
+
+/// Promotions have paid out the epoch's cap and are off until the next epoch.
+#[event]
+pub struct PromotionCapReached {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub epoch_promotion_spent: u64,
+    pub promotion_epoch_cap: u64,
+}
+
+#[event]
+pub struct SkewRebateUpdated {
+    pub market: Pubkey,
+    pub max_bonus_bps: u16,
+    pub max_haircut_bps: u16,
+}
+
+#[event]
+pub struct TopLevelOnlyUpdated {
+    pub market: Pubkey,
+    pub top_level_only: bool,
+}
+
+#[event]
+pub struct ReferralCapUpdated {
+    pub market: Pubkey,
+    pub referral_cap_bps: u16,
+}
+
+#[event]
+pub struct LargeFillThresholdUpdated {
+    pub market: Pubkey,
+    pub large_fill_notional: u64,
+}
+
+#[event]
+pub struct ScoringPluginUpdated {
+    pub market: Pubkey,
+    pub scoring_program: Pubkey,
+    pub max_score_delta: u64,
+}
+
+#[event]
+pub struct LiquidityScoreAdjusted {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub kind: ScoringEventKind,
+    pub order_id: u64,
+    pub delta: i64, // after clamping
+}
+
+#[event]
+pub struct ScoringSkipped {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub kind: ScoringEventKind,
+    pub order_id: u64,
+    pub error_code: u32, // why the plugin couldn't score it
+}
+
+#[event]
+pub struct MinFillNotionalUpdated {
+    pub market: Pubkey,
+    pub min_fill_notional: u64,
+}
+
+/// Emitted alongside `OrderFilled` for fills at or above the market's
+/// `large_fill_notional`, with enough context to act on without a lookup.
+#[event]
+pub struct LargeFill {
+    pub market: Pubkey,
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub maker_side: OrderSide,
+    pub maker_order_index: u8,
+    pub trade_size: u64,
+    pub execution_price: u64,
+    pub notional: u64,
+    pub threshold: u64,
+    pub taker_fee: u64,
+    pub maker_rebate: u64,
+    pub reference_price: u64,
+    pub fully_filled: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct EpochConfigUpdated {
+    pub market: Pubkey,
+    pub epoch_duration_secs: i64,
+    pub claim_deadline_secs: i64,
+}
+
+#[event]
+pub struct EpochRolled {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub epoch_start_ts: i64,
+}
+
+#[event]
+pub struct OptOutPolicyUpdated {
+    pub market: Pubkey,
+    pub opt_out_policy: OptOutPolicy,
+}
+
+#[event]
+pub struct RewardsOptOutChanged {
+    pub user: Pubkey,
+    pub opt_out: bool,
+}
+
+#[event]
+pub struct LiquidityRewardsRedirected {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub policy: OptOutPolicy,
+}
+
+#[event]
+pub struct OracleConfigUpdated {
+    pub market: Pubkey,
+    pub oracle_authority: Pubkey,
+    pub max_reference_age_secs: i64,
+}
+
+#[event]
+pub struct ReferencePriceUpdated {
+    pub market: Pubkey,
+    pub reference_price: u64,
+    pub confidence: u64,
+    pub timestamp: i64,
+    pub overwritten_ts: i64, // snapshot pushed out of the reference history; 0 => none
+}
+
+#[event]
+pub struct OrderPlaced {
+    pub user: Pubkey,
+    pub order_id: u64,
+    pub side: OrderSide,
+    pub price: u64,
+    pub size: u64,
+    pub expiry_timestamp: i64,
+    pub price_mode: PriceMode,
+    pub strategy_id: u16,
+    pub event_seq: u64, // see `EventCursor`
+}
+
+#[event]
+pub struct OrderCanceled {
+    pub user: Pubkey,
+    pub order_index: u8,
+    pub canceled_size: u64,
+    pub event_seq: u64,
+}
+
+/// An expired order canceled by the `cancel_expired_orders` crank.
+#[event]
+pub struct OrderExpired {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub order_index: u8,
+    pub order_id: u64,
+    pub expiry_timestamp: i64,
+    pub expired_size: u64,
+    pub event_seq: u64,
+}
+
+#[event]
+pub struct OrderFilled {
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub trade_size: u64,
+    pub execution_price: u64,
+    pub maker_rebate: u64,
+    pub taker_fee: u64, // charged to the taker, after taker_bonus_discount
+    pub referral_reward: u64,
+    pub referral_basis: ReferralBasis,
+    pub taker_rebate: u64,
+    pub net_fee: u64,   // retained by the market after rebates and referrals
+    pub maker_strategy_id: u16,
+    pub maker_order_id: u64,
+    pub maker_size_remaining: u64, // after this fill; 0 => order closed
+    pub taker_bonus_discount: u64, // first-fill bonus, already taken off taker_fee
+    pub maker_skew_adjustment: i64, // included in maker_rebate; > 0 => thin-side bonus
+    pub maker_promo_rebate: u64, // included in maker_rebate, paid by the promotion budget
+    pub oracle_price: u64,      // reference price at fill time; 0 => none or stale
+    pub oracle_confidence: u64,
+    pub event_seq: u64,
+}
+
+#[event]
+pub struct FeesWithdrawn {
+    pub authority: Pubkey,
+    pub amount: u64,
+    pub cosigned: bool, // drawn on the withdrawal cosigner's approval
+}
+
+#[event]
+pub struct WithdrawalCosignUpdated {
+    pub market: Pubkey,
+    pub cosigner: Pubkey,
+    pub threshold: u64,
+    pub window_secs: i64,
+}
+
+#[event]
+pub struct LargeWithdrawalApproved {
+    pub market: Pubkey,
+    pub cosigner: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct LiquidityRewardsFunded {
+    pub market: Pubkey,
+    pub amount: u64,
+    pub liquidity_reward_pool: u64,
+}
+
+#[event]
+pub struct LiquidityRewardsDistributed {
+    pub user: Pubkey,
+    pub distributed_amount: u64,
+}
+
+/// Result of `audit_accounts`: how many accounts were checked, and each that failed.
+#[event]
+pub struct AccountsAudited {
+    pub checked: u16,
+    pub findings: Vec<AccountAuditFinding>,
+}
+
+#[event]
+pub struct RejectionsReported {
+    pub market: Pubkey,
+    pub reason: RejectionReason,
+    pub count: u32,
+    pub total: u64, // all-time count for `reason`
+}
+
+#[event]
+pub struct ReferralGraphCheckpointed {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub user_count: u32,
+    pub root: [u8; 32],
+}
+
+#[event]
+pub struct BookCheckpointed {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub order_count: u32,
+    pub book_hash: [u8; 32],
+}
+
+#[event]
+pub struct AttestationIssued {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub digest: [u8; 32],
+}
+
+#[event]
+pub struct StrategyStatsReset {
+    pub user: Pubkey,
+    pub strategy_id: u16,
+    pub volume: u64,
+    pub rebates_earned: u64,
+    pub fill_count: u64,
+}
+
+#[event]
+pub struct TakerRebatesClaimed {
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ReferralRewardsClaimed {
+    pub referrer: Pubkey,
+    pub referee: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct LiquidityRewardsClaimed {
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct MarketStatusChanged {
+    pub market: Pubkey,
+    pub from: MarketStatus,
+    pub to: MarketStatus,
+}
+
+#[event]
+pub struct MarketAccountsClosed {
+    pub market: Pubkey,
+    pub closed: u32,
+    pub remaining: u64, // auxiliary accounts still to close
+}
+
+#[event]
+pub struct RewardPositionClosed {
+    pub reward_program: Pubkey,
+    pub user: Pubkey,
+    pub forfeited: u64, // unclaimed rewards, when the sponsor closed it
+}
+
+#[event]
+pub struct MarketClosed {
+    pub market: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct MarketMigrated {
+    pub market: Pubkey,
+    pub authority: Pubkey,
+    pub total_fees_collected: u64,
+}
+
+#[event]
+pub struct UserMigrated {
+    pub market: Pubkey,
+    pub user: Pubkey,
     pub maker_volume: u64,
     pub taker_volume: u64,
-    pub maker_rebates_earned: u64,
-    pub taker_fees_paid: u64,
     pub liquidity_score: u64,
-    pub referrer: Option<Pubkey>,
-    pub orders: [Order; MAX_ORDERS],
+    pub dropped_orders: u8, // legacy orders still open, dropped as if canceled
+}
+
+#[event]
+pub struct UserImported {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub maker_volume: u64,
+    pub taker_volume: u64,
+    pub liquidity_score: u64,
+}
+
+#[event]
+pub struct RewardProgramCreated {
+    pub market: Pubkey,
+    pub reward_program: Pubkey,
+    pub sponsor: Pubkey,
+    pub name: [u8; 32],
+    pub reward_mint: Pubkey,
+    pub budget: u64,
+    pub epoch_reward_pool: u64,
+}
+
+#[event]
+pub struct ProgramRewardsDistributed {
+    pub reward_program: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ProgramRewardsClaimed {
+    pub reward_program: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ClaimedAndStaked {
+    pub user: Pubkey,
+    pub rebates: u64,
+    pub rewards: u64,
+    pub staked_amount: u64,
+}
+
+#[event]
+pub struct UnstakeRequested {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64, // left staked
+    pub available_ts: i64,
+}
+
+#[event]
+pub struct UnstakeWithdrawn {
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ClaimsForfeited {
+    pub user: Pubkey,
+    pub forfeited_rebates: u64,
+    pub forfeited_rewards: u64,
+    pub forfeited_referral_rewards: u64,
+}
+
+impl ClaimsForfeited {
+    pub fn new(user_state: &UserState, forfeited: ClaimAmounts) -> Self {
+        Self {
+            user: user_state.authority,
+            forfeited_rebates: forfeited.rebates,
+            forfeited_rewards: forfeited.rewards,
+            forfeited_referral_rewards: forfeited.referral_rewards,
+        }
+    }
+}
+
+#[event]
+pub struct RentReclaimRequested {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub reclaimable_at: i64,
+}
+
+#[event]
+pub struct RentReclaimCanceled {
+    pub market: Pubkey,
+    pub user: Pubkey,
+}
+
+#[event]
+pub struct UserRentReclaimed {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub lamports: u64,
+}
+
+#[event]
+pub struct ReclaimedRentWithdrawn {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub lamports: u64,
 }
 
-// The array of orders must be carefully sized for the account.
-// Each `Order` occupies 1 + 8 + 8 + 8 + 8 = 33 bytes. The sizing should be re-checked to ensure accuracy:
-impl UserState {
-    pub const SIZE: usize = 
-          32  // authority
-        + 8   // maker_volume
-        + 8   // taker_volume
-        + 8   // maker_rebates_earned
-        + 8   // taker_fees_paid
-        + 8   // liquidity_score
-        + 1 + 32  // referrer: Option<Pubkey> => 1 + 32 bytes
-        + (Order::SIZE * MAX_ORDERS);
-}
+// ----------------------------------
+// TESTS
+// ----------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::program::MAX_RETURN_DATA;
+
+    /// Largest space Anchor can allocate for an `init` account in one CPI.
+    const MAX_INIT_SPACE: usize = 10_240;
+
+    fn serialized_len<T: AnchorSerialize>(value: &T) -> usize {
+        value.try_to_vec().unwrap().len()
+    }
+
+    fn max_order() -> Order {
+        Order {
+            side: OrderSide::Ask,
+            price: u64::MAX,
+            size_remaining: u64::MAX,
+            creation_timestamp: i64::MAX,
+            expiry_timestamp: i64::MAX,
+            price_mode: PriceMode::PrimaryPeg { offset_bps: u16::MAX },
+            strategy_id: u16::MAX,
+            order_id: u64::MAX,
+            scored_until: i64::MAX,
+            promo_reserve: u64::MAX,
+            book_level: u64::MAX,
+        }
+    }
+
+    fn max_market_state() -> MarketState {
+        MarketState {
+            authority: Pubkey::new_unique(),
+            maker_rebate_rate: u16::MAX,
+            taker_fee_rate: u16::MAX,
+            referral_rate: u16::MAX,
+            referral_basis: ReferralBasis::TakerFee,
+            fee_tier_count: u8::MAX,
+            fee_tiers: [FeeTier {
+                min_volume: u64::MAX,
+                taker_fee_rate: u16::MAX,
+                maker_rebate_rate: u16::MAX,
+            }; MAX_FEE_TIERS],
+            price_improvement_rebate_bps: u16::MAX,
+            total_fees_collected: u64::MAX,
+            total_liquidity_rewards_distributed: u64::MAX,
+            epoch_duration_secs: i64::MAX,
+            current_epoch: u64::MAX,
+            epoch_start_ts: i64::MAX,
+            claim_deadline_secs: i64::MAX,
+            outstanding_claims: u64::MAX,
+            opt_out_policy: OptOutPolicy::ProRata,
+            oracle_authority: Pubkey::new_unique(),
+            reference_price: u64::MAX,
+            reference_price_ts: i64::MAX,
+            max_reference_age_secs: i64::MAX,
+            large_fill_notional: u64::MAX,
+            go_live_ts: i64::MAX,
+            penalty_cancel_to_fill_bps: u32::MAX,
+            penalty_min_cancels: u32::MAX,
+            penalty_rebate_haircut_bps: u16::MAX,
+            penalty_placement_fee: u64::MAX,
+            user_count: u64::MAX,
+            side_incentive: SideIncentive {
+                side: OrderSide::Ask,
+                multiplier_bps: u16::MAX,
+                starts_at: i64::MAX,
+                expires_at: i64::MAX,
+            },
+            status: MarketStatus::Closed,
+            user_index_head: Pubkey::new_unique(),
+            price_decimals: u8::MAX,
+            max_open_orders: u8::MAX,
+            gross_fees_charged: u64::MAX,
+            net_fees_retained: u64::MAX,
+            liquidity_band_bps: u16::MAX,
+            reference_history: [ReferenceSnapshot {
+                price: u64::MAX,
+                ts: i64::MAX,
+            }; REFERENCE_HISTORY],
+            reference_history_cursor: u8::MAX,
+            first_fill_bonus: FirstFillBonus {
+                max_fills: u8::MAX,
+                window_secs: i64::MAX,
+                discount_bps: u16::MAX,
+            },
+            promotion_budget: u64::MAX,
+            promotion_spent: u64::MAX,
+            referral_cap_bps: u16::MAX,
+            recovery_committee: RecoveryCommittee {
+                members: [Pubkey::new_unique(); MAX_RECOVERY_MEMBERS],
+                member_count: u8::MAX,
+                threshold: u8::MAX,
+            },
+            pending_recovery: RecoveryProposal {
+                new_authority: Pubkey::new_unique(),
+                proposed_ts: i64::MAX,
+                approvals: u8::MAX,
+                rejections: u8::MAX,
+                approved_ts: i64::MAX,
+            },
+            top_level_only: true,
+            skew_rebate: SkewRebate {
+                max_bonus_bps: u16::MAX,
+                max_haircut_bps: u16::MAX,
+            },
+            promo_maker_rebate_bps: u16::MAX,
+            promotion_reserved: u64::MAX,
+            reference_confidence: u64::MAX,
+            min_fill_notional: u64::MAX,
+            withdrawal_cosigner: Pubkey::new_unique(),
+            withdrawal_cosign_threshold: u64::MAX,
+            approved_withdrawal: u64::MAX,
+            withdrawal_window_secs: i64::MAX,
+            withdrawal_window_start: i64::MAX,
+            window_uncosigned_withdrawn: u64::MAX,
+            scoring_program: Pubkey::new_unique(),
+            max_score_delta: u64::MAX,
+            promotion_epoch_cap: u64::MAX,
+            promotion_cap_epoch: u64::MAX,
+            epoch_promotion_spent: u64::MAX,
+            default_order_ttl_secs: i64::MAX,
+            max_order_ttl_secs: i64::MAX,
+            fee_precision: FeePrecision::Pips,
+            rejection_reporter: Pubkey::new_unique(),
+            total_liquidity_score: u64::MAX,
+            liquidity_reward_pool: u64::MAX,
+            auxiliary_accounts: u64::MAX,
+            user_set_version: u64::MAX,
+        }
+    }
+
+    fn max_user_state() -> UserState {
+        UserState {
+            authority: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            maker_volume: u64::MAX,
+            taker_volume: u64::MAX,
+            maker_rebates_earned: u64::MAX,
+            taker_fees_paid: u64::MAX,
+            liquidity_score: u64::MAX,
+            taker_rebates_earned: u64::MAX,
+            claimable_rebates: u64::MAX,
+            claimable_rewards: u64::MAX,
+            claim_lots: [ClaimLot {
+                expires_at: i64::MAX,
+                rebates: u64::MAX,
+                rewards: u64::MAX,
+                referral_rewards: u64::MAX,
+            }; CLAIM_LOTS],
+            rewards_opt_out: true,
+            recent_claim_keys: [u64::MAX; CLAIM_KEY_HISTORY],
+            claim_key_cursor: u8::MAX,
+            strategy_stats: [StrategyStats {
+                strategy_id: u16::MAX,
+                volume: u64::MAX,
+                rebates_earned: u64::MAX,
+                fill_count: u64::MAX,
+            }; MAX_STRATEGY_TAGS],
+            activity_epoch: u64::MAX,
+            epoch_places: u32::MAX,
+            epoch_cancels: u32::MAX,
+            epoch_fills: u32::MAX,
+            penalty_epoch: u64::MAX,
+            penalty_fees_paid: u64::MAX,
+            last_activity_ts: i64::MAX,
+            reclaim_requested_ts: i64::MAX,
+            last_order_id: u64::MAX,
+            referrer_commitment: [u8::MAX; 32],
+            referral_rewards_owed: u64::MAX,
+            registered_ts: i64::MAX,
+            first_fill_ts: i64::MAX,
+            bonus_fills: u8::MAX,
+            referral_link: ReferralLink {
+                fees_paid: u64::MAX,
+                rewards_accrued: u64::MAX,
+            },
+            staked_amount: u64::MAX,
+            referrer: Some(Pubkey::new_unique()),
+            orders: vec![max_order(); MAX_ORDERS],
+        }
+    }
+
+    fn empty_user_state(market: Pubkey) -> UserState {
+        UserState {
+            authority: Pubkey::new_unique(),
+            market,
+            maker_volume: 0,
+            taker_volume: 0,
+            maker_rebates_earned: 0,
+            taker_fees_paid: 0,
+            liquidity_score: 0,
+            taker_rebates_earned: 0,
+            claimable_rebates: 0,
+            claimable_rewards: 0,
+            claim_lots: [ClaimLot::default(); CLAIM_LOTS],
+            rewards_opt_out: false,
+            recent_claim_keys: [0; CLAIM_KEY_HISTORY],
+            claim_key_cursor: 0,
+            strategy_stats: [StrategyStats::default(); MAX_STRATEGY_TAGS],
+            activity_epoch: 0,
+            epoch_places: 0,
+            epoch_cancels: 0,
+            epoch_fills: 0,
+            penalty_epoch: 0,
+            penalty_fees_paid: 0,
+            last_activity_ts: 0,
+            reclaim_requested_ts: 0,
+            last_order_id: 0,
+            referrer_commitment: [0; 32],
+            referral_rewards_owed: 0,
+            registered_ts: 0,
+            first_fill_ts: 0,
+            bonus_fills: 0,
+            referral_link: ReferralLink::default(),
+            staked_amount: 0,
+            referrer: None,
+            orders: vec![Order::default(); MAX_ORDERS],
+        }
+    }
+
+
+    fn fixed_order(
+        side: OrderSide,
+        price: u64,
+        size: u64,
+        expiry_timestamp: i64,
+        creation_timestamp: i64,
+    ) -> Order {
+        Order {
+            side,
+            price,
+            size_remaining: size,
+            creation_timestamp,
+            expiry_timestamp,
+            ..Order::default()
+        }
+    }
+
+    /// A market charging 5 bps to takers and rebating 2 bps to makers, nothing else enabled.
+    fn test_market_state() -> MarketState {
+        MarketState {
+            authority: Pubkey::new_unique(),
+            maker_rebate_rate: 2,
+            taker_fee_rate: 5,
+            referral_rate: 0,
+            referral_basis: ReferralBasis::FillSize,
+            fee_tier_count: 0,
+            fee_tiers: [FeeTier::default(); MAX_FEE_TIERS],
+            price_improvement_rebate_bps: 0,
+            total_fees_collected: 0,
+            total_liquidity_rewards_distributed: 0,
+            epoch_duration_secs: 0,
+            current_epoch: 0,
+            epoch_start_ts: 0,
+            claim_deadline_secs: 0,
+            outstanding_claims: 0,
+            opt_out_policy: OptOutPolicy::Treasury,
+            oracle_authority: Pubkey::new_unique(),
+            reference_price: 0,
+            reference_price_ts: 0,
+            max_reference_age_secs: 0,
+            large_fill_notional: 0,
+            go_live_ts: 0,
+            penalty_cancel_to_fill_bps: 0,
+            penalty_min_cancels: 0,
+            penalty_rebate_haircut_bps: 0,
+            penalty_placement_fee: 0,
+            user_count: 0,
+            side_incentive: SideIncentive::default(),
+            status: MarketStatus::Active,
+            user_index_head: Pubkey::default(),
+            price_decimals: 0,
+            max_open_orders: MAX_ORDERS as u8,
+            gross_fees_charged: 0,
+            net_fees_retained: 0,
+            liquidity_band_bps: 0,
+            reference_history: [ReferenceSnapshot::default(); REFERENCE_HISTORY],
+            reference_history_cursor: 0,
+            first_fill_bonus: FirstFillBonus::default(),
+            promotion_budget: 0,
+            promotion_spent: 0,
+            referral_cap_bps: 10_000,
+            recovery_committee: RecoveryCommittee::default(),
+            pending_recovery: RecoveryProposal::default(),
+            top_level_only: false,
+            skew_rebate: SkewRebate::default(),
+            promo_maker_rebate_bps: 0,
+            promotion_reserved: 0,
+            reference_confidence: 0,
+            min_fill_notional: 0,
+            withdrawal_cosigner: Pubkey::default(),
+            withdrawal_cosign_threshold: 0,
+            approved_withdrawal: 0,
+            withdrawal_window_secs: 0,
+            withdrawal_window_start: 0,
+            window_uncosigned_withdrawn: 0,
+            scoring_program: Pubkey::default(),
+            max_score_delta: 0,
+            promotion_epoch_cap: 0,
+            promotion_cap_epoch: 0,
+            epoch_promotion_spent: 0,
+            default_order_ttl_secs: 0,
+            max_order_ttl_secs: 0,
+            fee_precision: FeePrecision::Bps,
+            rejection_reporter: Pubkey::default(),
+            total_liquidity_score: 0,
+            liquidity_reward_pool: 0,
+            auxiliary_accounts: 0,
+            user_set_version: 0,
+        }
+    }
+
+    #[test]
+    fn global_config_size_matches_borsh() {
+        let global_config = GlobalConfig {
+            authority: Pubkey::new_unique(),
+            disabled_instructions: u64::MAX,
+        };
+        assert_eq!(serialized_len(&global_config), GlobalConfig::SIZE);
+    }
+
+    #[test]
+    fn kill_switch_only_affects_its_own_instruction() {
+        let mut global_config = GlobalConfig {
+            authority: Pubkey::new_unique(),
+            disabled_instructions: 0,
+        };
+        global_config.disabled_instructions |= ProgramInstruction::FillOrder.mask();
+
+        assert!(!global_config.is_enabled(ProgramInstruction::FillOrder));
+        assert!(global_config.is_enabled(ProgramInstruction::WithdrawFees));
+        assert!(global_config.is_enabled(ProgramInstruction::PlaceOrder));
+
+        // Funding and paying out rewards, or fee and market setters, switch off separately.
+        global_config.disabled_instructions |=
+            ProgramInstruction::DistributeLiquidityRewards.mask()
+                | ProgramInstruction::UpdateFeeParameters.mask();
+        assert!(global_config.is_enabled(ProgramInstruction::FundLiquidityRewards));
+        assert!(global_config.is_enabled(ProgramInstruction::UpdateMarketParams));
+        assert!(global_config.is_enabled(ProgramInstruction::SetMarketStatus));
+        // Appended variants still fit the mask.
+        assert_eq!(ProgramInstruction::RefreshMarketHealth.mask(), 1 << 33);
+    }
+
+    #[test]
+    fn effective_fees_match_configured_bps() {
+        let mut market_state = test_market_state();
+        market_state.referral_rate = 1;
+
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.referrer = Some(Pubkey::new_unique());
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee, 500);
+        assert_eq!(fees.maker_rebate, 200);
+        assert_eq!(fees.referral_reward, 100);
+        assert_eq!(fees.net_fee, 300);
+
+        // No referrer => no referral cut.
+        taker.referrer = None;
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.referral_rate, 0);
+        assert_eq!(fees.referral_reward, 0);
+    }
+
+    #[test]
+    fn confidential_referrer_opens_only_with_its_salt() {
+        let referrer = Pubkey::new_unique();
+        let salt = [7u8; 32];
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        assert!(!taker.has_referrer());
+
+        taker.referrer_commitment = referral_commitment(&referrer, &salt);
+        assert!(taker.has_referrer());
+        assert!(taker.is_referred_by(&referrer, Some(&salt)));
+        assert!(!taker.is_referred_by(&referrer, Some(&[8u8; 32])));
+        assert!(!taker.is_referred_by(&referrer, None));
+        assert!(!taker.is_referred_by(&Pubkey::new_unique(), Some(&salt)));
+
+        // The referral cut accrues on the taker and leaves the market's net fee.
+        let mut market_state = test_market_state();
+        market_state.referral_rate = 1;
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1_000_000, 0, 0))
+            .unwrap();
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 1_000_000,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(0),
+        )
+        .unwrap();
+        assert_eq!(fill.referral_reward, 100);
+        assert_eq!(fill.net_fee, 500 - 200 - 100);
+        assert_eq!(taker.referral_rewards_owed, 100);
+        assert_eq!(market_state.outstanding_claims, 100);
+    }
+
+    #[test]
+    fn referral_rewards_are_capped_by_referee_fees() {
+        let mut market_state = test_market_state();
+        market_state.referral_rate = 1;
+        market_state.referral_cap_bps = 1_000; // 10% of what the referee paid
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.referrer = Some(Pubkey::new_unique());
+        // Earlier fills already paid out the full 10%.
+        taker.referral_link = ReferralLink {
+            fees_paid: 1_000,
+            rewards_accrued: 100,
+        };
+
+        // 10% of 1_500 lifetime fees leaves 50 of the uncapped 100.
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1_000_000, 0, 0))
+            .unwrap();
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 1_000_000,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(0),
+        )
+        .unwrap();
+        assert_eq!(fill.referral_reward, 50);
+        assert_eq!(fill.net_fee, 500 - 200 - 50);
+        assert_eq!(
+            taker.referral_link,
+            ReferralLink {
+                fees_paid: 1_500,
+                rewards_accrued: 150,
+            }
+        );
+
+        // Once at the cap, further fees only earn the capped share.
+        let link = taker.referral_link;
+        assert_eq!(link.capped_reward(500, 100, 1_000).unwrap(), 50);
+        assert_eq!(link.capped_reward(0, 100, 1_000).unwrap(), 0);
+    }
+
+    #[test]
+    fn set_fee_parameters_validates_before_applying() {
+        let mut market_state = test_market_state();
+        let params = FeeParameters {
+            maker_rebate_rate: 3,
+            taker_fee_rate: 8,
+            referral_rate: 1,
+        };
+        market_state.set_fee_parameters(&params).unwrap();
+        assert_eq!(market_state.taker_fee_rate, 8);
+        assert_eq!(market_state.maker_rebate_rate, 3);
+
+        // A rebate above the fee is rejected and leaves the market untouched.
+        let bad = FeeParameters {
+            maker_rebate_rate: 9,
+            ..params
+        };
+        assert!(market_state.set_fee_parameters(&bad).is_err());
+        assert_eq!(market_state.maker_rebate_rate, 3);
+    }
+
+    #[test]
+    fn fee_parameters_restate_across_precisions() {
+        let params = FeeParameters {
+            maker_rebate_rate: 2,
+            taker_fee_rate: 5,
+            referral_rate: 1,
+        };
+        let (bps, pips) = (FeePrecision::Bps, FeePrecision::Pips);
+        let fill_size = ReferralBasis::FillSize;
+        assert_eq!(params.restate(bps, bps, fill_size).unwrap(), params);
+        let in_pips = params.restate(bps, pips, fill_size).unwrap();
+        assert_eq!(in_pips.taker_fee_rate, bps.convert(5, pips).unwrap());
+        assert_eq!(in_pips.referral_rate, bps.convert(1, pips).unwrap());
+        assert_eq!(in_pips.restate(pips, bps, fill_size).unwrap(), params);
+
+        // A share of the taker fee is bps on every market.
+        let of_fee = params.restate(bps, pips, ReferralBasis::TakerFee).unwrap();
+        assert_eq!(of_fee.referral_rate, 1);
+
+        // 1 pip has no exact bps equivalent.
+        let one_pip = FeeParameters {
+            taker_fee_rate: 1,
+            ..params
+        };
+        assert_eq!(
+            error_code(&one_pip.restate(pips, bps, fill_size).unwrap_err()),
+            FeeError::InvalidFeePrecision as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+    }
+
+    #[test]
+    fn referral_can_be_based_on_taker_fee() {
+        let mut market_state = test_market_state();
+        market_state.referral_basis = ReferralBasis::TakerFee;
+        // 20% of the taker fee: more bps than the fee itself, which is fine on this basis.
+        let params = FeeParameters {
+            maker_rebate_rate: 2,
+            taker_fee_rate: 5,
+            referral_rate: 2_000,
+        };
+        market_state.set_fee_parameters(&params).unwrap();
+        assert_eq!(market_state.referral_rate, 2_000);
+
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.referrer = Some(Pubkey::new_unique());
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee, 500);
+        assert_eq!(fees.referral_reward, 100);
+        assert_eq!(fees.referral_basis, ReferralBasis::TakerFee);
+    }
+
+    fn tier(min_volume: u64, taker_fee_rate: u16, maker_rebate_rate: u16) -> FeeTier {
+        FeeTier {
+            min_volume,
+            taker_fee_rate,
+            maker_rebate_rate,
+        }
+    }
+
+    #[test]
+    fn each_side_is_charged_at_its_own_volume_tier() {
+        let mut market_state = test_market_state();
+        market_state.fee_tiers[0] = tier(1_000, 4, 2);
+        market_state.fee_tiers[1] = tier(10_000, 3, 3);
+        market_state.fee_tier_count = 2;
+
+        assert_eq!(market_state.tier_for_volume(999).tier, None);
+        assert_eq!(market_state.tier_for_volume(1_000).tier, Some(0));
+        assert_eq!(market_state.tier_for_volume(u64::MAX).tier, Some(1));
+
+        // Taker at the base rate, maker in the top tier.
+        let taker = empty_user_state(Pubkey::new_unique());
+        let fees = market_state.effective_fees(10_000, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee_rate, 5);
+        assert_eq!(fees.maker_rebate_rate, 3);
+        assert_eq!(fees.net_fee, 200);
+    }
+
+    #[test]
+    fn converting_to_pips_keeps_fees_and_allows_sub_bps_rates() {
+        let mut market_state = test_market_state();
+        market_state.referral_rate = 1;
+        market_state.fee_tiers[0] = tier(1_000, 4, 2);
+        market_state.fee_tier_count = 1;
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.referrer = Some(Pubkey::new_unique());
+        let before = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+
+        market_state.convert_fee_precision(FeePrecision::Pips).unwrap();
+        assert_eq!(market_state.taker_fee_rate, 500);
+        assert_eq!(market_state.referral_rate, 100);
+        assert_eq!(market_state.fee_tiers[0].maker_rebate_rate, 200);
+        let after = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(after.taker_fee, before.taker_fee);
+        assert_eq!(after.maker_rebate, before.maker_rebate);
+        assert_eq!(after.referral_reward, before.referral_reward);
+
+        // Half a bps, which bps can't express and so can't be converted back.
+        market_state.taker_fee_rate = 50;
+        market_state.maker_rebate_rate = 0;
+        market_state.fee_tier_count = 0;
+        market_state.referral_rate = 0;
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee, 50);
+        assert!(market_state.convert_fee_precision(FeePrecision::Bps).is_err());
+        assert_eq!(market_state.fee_precision, FeePrecision::Pips);
+
+        // 656 bps is more pips than a u16 holds.
+        let mut market_state = test_market_state();
+        market_state.taker_fee_rate = 656;
+        assert!(market_state.convert_fee_precision(FeePrecision::Pips).is_err());
+    }
+
+    #[test]
+    fn fee_schedule_rejects_misconfigured_tiers() {
+        let base = (2, 5, 0, ReferralBasis::FillSize);
+        let check = |tiers: &[FeeTier]| {
+            validate_fee_schedule(base.0, base.1, base.2, base.3, FeePrecision::Bps, tiers)
+        };
+
+        assert!(check(&[]).is_ok());
+        assert!(check(&[tier(1_000, 4, 2), tier(10_000, 3, 3)]).is_ok());
+        // Thresholds must strictly increase.
+        assert!(check(&[tier(1_000, 4, 2), tier(1_000, 3, 3)]).is_err());
+        // More volume must never mean a higher taker fee or lower maker rebate.
+        assert!(check(&[tier(1_000, 6, 2)]).is_err());
+        assert!(check(&[tier(1_000, 4, 1)]).is_err());
+        // Rebate can never exceed the fee it is paid out of.
+        assert!(check(&[tier(1_000, 3, 4)]).is_err());
+        // Bounded table size.
+        assert!(check(&[tier(0, 5, 2); MAX_FEE_TIERS + 1]).is_err());
+        // Base rates are bounded by the precision like the tiers: 600% is not a fee.
+        let err = validate_fee_schedule(0, 60_000, 0, base.3, FeePrecision::Bps, &[]).unwrap_err();
+        assert_eq!(
+            error_code(&err),
+            FeeError::InvalidFeeConfiguration as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+        assert!(validate_fee_schedule(0, 60_000, 0, base.3, FeePrecision::Pips, &[]).is_ok());
+    }
+
+    #[test]
+    fn combined_fee_shares_cannot_exceed_taker_fee() {
+        assert!(validate_fee_shares(2, 5, 3, ReferralBasis::FillSize).is_ok());
+        assert!(validate_fee_shares(2, 5, 4, ReferralBasis::FillSize).is_err());
+
+        // 2/5 of the fee to the maker leaves at most 60% for the referrer.
+        assert!(validate_fee_shares(2, 5, 6_000, ReferralBasis::TakerFee).is_ok());
+        assert!(validate_fee_shares(2, 5, 6_001, ReferralBasis::TakerFee).is_err());
+        assert!(validate_fee_shares(0, 5, 10_001, ReferralBasis::TakerFee).is_err());
+    }
+
+    #[test]
+    fn order_size_matches_borsh() {
+        assert_eq!(serialized_len(&max_order()), Order::SIZE);
+        // Fixed price mode has no payload, so a default order serializes shorter.
+        assert!(serialized_len(&Order::default()) <= Order::SIZE);
+    }
+
+    #[test]
+    fn pegged_orders_resolve_against_reference_price() {
+        let mut order = Order {
+            side: OrderSide::Bid,
+            price: 0,
+            size_remaining: 10,
+            price_mode: PriceMode::MidPeg,
+            ..Order::default()
+        };
+        assert_eq!(order.pegged_price(10_000).unwrap(), 10_000);
+
+        // Primary peg sits away from mid on the order's own side.
+        order.price_mode = PriceMode::PrimaryPeg { offset_bps: 50 };
+        assert_eq!(order.pegged_price(10_000).unwrap(), 9_950);
+        order.side = OrderSide::Ask;
+        assert_eq!(order.pegged_price(10_000).unwrap(), 10_050);
+
+        // A non-zero price acts as a protective limit.
+        order.price = 10_100;
+        assert_eq!(order.pegged_price(10_000).unwrap(), 10_100);
+    }
+
+    #[test]
+    fn stale_reference_price_is_rejected_for_pegged_orders() {
+        let mut market_state = max_market_state();
+        market_state.reference_price = 10_000;
+        market_state.reference_price_ts = 1_000;
+        market_state.max_reference_age_secs = 60;
+
+        let pegged = Order {
+            price_mode: PriceMode::MidPeg,
+            ..Order::default()
+        };
+        assert_eq!(market_state.resolve_order_price(&pegged, 1_060).unwrap(), 10_000);
+        assert!(market_state.resolve_order_price(&pegged, 1_061).is_err());
+
+        // Fixed orders never consult the reference price.
+        let fixed = Order {
+            price: 42,
+            ..Order::default()
+        };
+        assert_eq!(market_state.resolve_order_price(&fixed, i64::MAX).unwrap(), 42);
+    }
+
+    #[test]
+    fn orders_in_range_match_side_and_current_price() {
+        let mut market_state = test_market_state();
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        for (side, price) in [
+            (OrderSide::Bid, 99),
+            (OrderSide::Bid, 101),
+            (OrderSide::Ask, 100),
+            (OrderSide::Bid, 103),
+        ] {
+            user_state.insert_order(fixed_order(side, price, 10, 0, 0)).unwrap();
+        }
+        let pegged = Order {
+            side: OrderSide::Bid,
+            size_remaining: 10,
+            price_mode: PriceMode::MidPeg,
+            ..Order::default()
+        };
+        user_state.insert_order(pegged).unwrap();
+
+        // Bids at or above 100; the pegged bid has no reference price to sit at yet.
+        let in_range = |user_state: &UserState, market_state: &MarketState| {
+            user_state.orders_in_range(OrderSide::Bid, 100, u64::MAX, market_state, 0)
+        };
+        assert_eq!(in_range(&user_state, &market_state), vec![1, 3]);
+
+        market_state.reference_price = 102;
+        assert_eq!(in_range(&user_state, &market_state), vec![1, 3, 4]);
+        assert!(user_state
+            .orders_in_range(OrderSide::Ask, 0, 99, &market_state, 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn large_withdrawals_need_the_cosigners_approval() {
+        let mut market_state = test_market_state();
+        assert!(!market_state.authorize_withdrawal(u64::MAX, 0).unwrap());
+
+        market_state.withdrawal_cosign_threshold = 1_000;
+        market_state.withdrawal_window_secs = 86_400;
+        assert!(!market_state.authorize_withdrawal(600, 1_000_100).unwrap());
+        // Splitting doesn't help: the window's un-co-signed total would reach the threshold.
+        assert!(market_state.authorize_withdrawal(400, 1_000_200).is_err());
+
+        market_state.approved_withdrawal = 1_500;
+        assert!(market_state.authorize_withdrawal(1_000, 1_000_300).unwrap());
+        assert!(market_state.authorize_withdrawal(600, 1_000_300).is_err());
+        assert_eq!(market_state.approved_withdrawal, 500);
+
+        // Rolling epochs doesn't reset the allowance; only the window running out does.
+        market_state.current_epoch += 1;
+        assert!(market_state.authorize_withdrawal(999, 1_086_499).is_err());
+        assert!(!market_state.authorize_withdrawal(999, 1_086_500).unwrap());
+        assert_eq!(market_state.approved_withdrawal, 500);
+    }
+
+    #[test]
+    fn scoring_plugin_deltas_are_bounded() {
+        use anchor_lang::solana_program::hash::hash;
+        assert_eq!(
+            hash(b"global:score_order").to_bytes()[..8],
+            SCORE_ORDER_DISCRIMINATOR
+        );
+
+        let mut market_state = test_market_state();
+        assert!(!market_state.scoring_enabled());
+        market_state.max_score_delta = 100;
+        assert_eq!(market_state.clamp_score_delta(40), 40);
+        assert_eq!(market_state.clamp_score_delta(i64::MAX), 100);
+        assert_eq!(market_state.clamp_score_delta(i64::MIN), -100);
+
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state.liquidity_score = 50;
+        user_state.apply_score_delta(-100);
+        assert_eq!(user_state.liquidity_score, 0);
+        user_state.liquidity_score = u64::MAX - 1;
+        user_state.apply_score_delta(100);
+        assert_eq!(user_state.liquidity_score, u64::MAX);
+    }
+
+    #[test]
+    fn a_failing_scoring_plugin_never_blocks_cancels() {
+        let mut market_state = test_market_state();
+        market_state.scoring_program = Pubkey::new_unique();
+        market_state.max_score_delta = 100;
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state.liquidity_score = 50;
+        let request = ScoringRequest {
+            market: Pubkey::new_unique(),
+            user: user_state.authority,
+            kind: ScoringEventKind::Cancel,
+            order_id: 1,
+            side: OrderSide::Bid,
+            price: 100,
+            size: 10,
+            size_remaining: 0,
+            liquidity_score: 50,
+            timestamp: 0,
+        };
+        let code = |error: FeeError| error as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+
+        // Plugin account left out.
+        let err =
+            run_scoring_plugin(&mut market_state, &mut user_state, &[], request).unwrap_err();
+        assert_eq!(error_code(&err), code(FeeError::ScoringProgramMissing));
+        try_scoring_plugin(&mut market_state, &mut user_state, &[], request).unwrap();
+
+        // A plugin that answers without a score delta.
+        let key = market_state.scoring_program;
+        let owner = Pubkey::new_unique();
+        let (mut lamports, mut data) = (0, vec![]);
+        let plugin = [AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            true,
+            0,
+        )];
+        let err =
+            run_scoring_plugin(&mut market_state, &mut user_state, &plugin, request).unwrap_err();
+        assert_eq!(error_code(&err), code(FeeError::InvalidScoreDelta));
+        try_scoring_plugin(&mut market_state, &mut user_state, &plugin, request).unwrap();
+        assert_eq!(user_state.liquidity_score, 50);
+    }
+
+    #[test]
+    fn failure_log_only_formats_on_error() {
+        let formatted = std::cell::Cell::new(0);
+        let params = || {
+            formatted.set(formatted.get() + 1);
+            "amount=1".to_string()
+        };
+
+        let market = Some(Pubkey::default());
+        let status = Some(MarketStatus::Active);
+        let ok = with_failure_log("withdraw_fees", market, status, params, Ok(7));
+        assert_eq!(ok.unwrap(), 7);
+        assert_eq!(formatted.get(), 0);
+
+        let failed: Result<()> = with_failure_log(
+            "withdraw_fees",
+            market,
+            status,
+            params,
+            err!(FeeError::InsufficientFunds),
+        );
+        assert!(failed.is_err());
+        assert_eq!(formatted.get(), 1);
+
+        // Global instructions have neither.
+        let failed: Result<()> =
+            with_failure_log("audit_accounts", None, None, String::new, err!(FeeError::Overflow));
+        assert!(failed.is_err());
+    }
+
+    #[test]
+    fn promotions_shut_off_at_the_epoch_cap() {
+        let mut market_state = test_market_state();
+        market_state.total_fees_collected = 1_000;
+        market_state.set_promotion_budget(1_000).unwrap();
+        market_state.promo_maker_rebate_bps = 5;
+        market_state.promotion_epoch_cap = 60;
+
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        let promo_reserve = market_state.reserve_promotion(200_000, 0).unwrap();
+        assert_eq!(promo_reserve, 100);
+        maker
+            .insert_order(Order {
+                promo_reserve,
+                ..fixed_order(OrderSide::Ask, 100, 200_000, 0, 0)
+            })
+            .unwrap();
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 100_000,
+            ..FillParams::default()
+        };
+
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.maker_promo_rebate, 50);
+        assert!(!fill.promotion_cap_reached);
+
+        // Only 10 of the next 50 fit under the cap; the other 40 go back to the budget.
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.maker_promo_rebate, 10);
+        assert!(fill.promotion_cap_reached);
+        assert_eq!(market_state.promotion_reserved, 0);
+        assert_eq!(market_state.available_promotion_budget(), 940);
+
+        // Off for the rest of the epoch: no new reserves.
+        assert_eq!(market_state.reserve_promotion(200_000, 0).unwrap(), 0);
+        market_state.current_epoch += 1;
+        assert_eq!(market_state.promotion_epoch_room(), 60);
+        assert_eq!(market_state.reserve_promotion(200_000, 0).unwrap(), 100);
+    }
+
+    #[test]
+    fn order_expiry_applies_default_and_max_ttl() {
+        let mut market_state = test_market_state();
+        // Neither set: 0 still means no expiry, and any expiry is allowed.
+        assert_eq!(market_state.order_expiry(0, 1_000).unwrap(), 0);
+        assert_eq!(market_state.order_expiry(i64::MAX, 1_000).unwrap(), i64::MAX);
+
+        market_state.max_order_ttl_secs = 3_600;
+        assert_eq!(market_state.order_expiry(0, 1_000).unwrap(), 4_600);
+        market_state.default_order_ttl_secs = 60;
+        assert_eq!(market_state.order_expiry(0, 1_000).unwrap(), 1_060);
+
+        assert_eq!(market_state.order_expiry(4_600, 1_000).unwrap(), 4_600);
+        assert!(market_state.order_expiry(4_601, 1_000).is_err());
+
+        // Negative, past and present expiries are refused, even with no TTLs set.
+        let invalid = FeeError::InvalidOrderExpiry as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+        for market_state in [market_state, test_market_state()] {
+            for expiry in [-1, i64::MIN, 1, 999, 1_000] {
+                let err = market_state.order_expiry(expiry, 1_000).unwrap_err();
+                assert_eq!(error_code(&err), invalid);
+            }
+            assert_eq!(market_state.order_expiry(1_001, 1_000).unwrap(), 1_001);
+        }
+    }
+
+    #[test]
+    fn market_state_size_matches_borsh() {
+        assert_eq!(serialized_len(&max_market_state()), MarketState::SIZE);
+        assert!(8 + MarketState::SIZE <= MAX_INIT_SPACE);
+    }
+
+    #[test]
+    fn claim_deadlines_are_fixed_per_credit() {
+        let mut market_state = test_market_state();
+        market_state.claim_deadline_secs = 300;
+        let mut user = empty_user_state(Pubkey::new_unique());
+
+        // Deadlines round up to 100s steps.
+        assert_eq!(market_state.claim_expiry(1_000), 1_300);
+        assert_eq!(market_state.claim_expiry(1_001), 1_400);
+        user.credit_claimable(10, 0, market_state.claim_expiry(1_000)).unwrap();
+
+        // Rolling the epoch, even with a deadline longer than the epoch, does not move it.
+        market_state.epoch_duration_secs = 60;
+        market_state.roll_epoch(&FixedClock(1_200)).unwrap();
+        market_state.roll_epoch(&FixedClock(1_260)).unwrap();
+        assert_eq!(user.take_expired_claims(1_299), ClaimAmounts::default());
+
+        // A later credit keeps its own deadline and leaves the older one alone.
+        user.credit_claimable(0, 5, market_state.claim_expiry(1_250)).unwrap();
+        assert_eq!(user.claimable_rebates, 10);
+        assert_eq!(user.claimable_rewards, 5);
+        assert_eq!(
+            user.take_expired_claims(1_300),
+            ClaimAmounts { rebates: 10, ..ClaimAmounts::default() }
+        );
+        assert_eq!(user.claimable_rebates, 0);
+        assert_eq!(user.take_expired_claims(1_599), ClaimAmounts::default());
+        assert_eq!(
+            user.take_expired_claims(1_600),
+            ClaimAmounts { rewards: 5, ..ClaimAmounts::default() }
+        );
+        assert_eq!(user.claimable_rewards, 0);
+
+        // No deadline configured: never expires.
+        market_state.claim_deadline_secs = 0;
+        user.credit_claimable(7, 0, market_state.claim_expiry(1_000)).unwrap();
+        assert_eq!(user.take_expired_claims(i64::MAX), ClaimAmounts::default());
+        assert_eq!(
+            user.take_claims(true, true),
+            ClaimAmounts { rebates: 7, ..ClaimAmounts::default() }
+        );
+    }
+
+    #[test]
+    fn referral_rewards_expire_with_their_lot() {
+        let mut market_state = test_market_state();
+        market_state.claim_deadline_secs = 300;
+        let mut user = empty_user_state(Pubkey::new_unique());
+
+        user.credit_referral_rewards(4, market_state.claim_expiry(1_000)).unwrap();
+        user.credit_referral_rewards(6, market_state.claim_expiry(1_250)).unwrap();
+        user.credit_claimable(3, 0, market_state.claim_expiry(1_250)).unwrap();
+        assert_eq!(user.referral_rewards_owed, 10);
+
+        // The referrer missed the first deadline: that part goes back, the rest stays owed.
+        assert_eq!(
+            user.take_expired_claims(1_300),
+            ClaimAmounts { referral_rewards: 4, ..ClaimAmounts::default() }
+        );
+        assert_eq!(user.referral_rewards_owed, 6);
+
+        // Claiming them leaves the user's own rebates in the shared lot.
+        assert_eq!(user.take_referral_rewards(), 6);
+        assert_eq!(user.referral_rewards_owed, 0);
+        assert_eq!(
+            user.take_expired_claims(1_600),
+            ClaimAmounts { rebates: 3, ..ClaimAmounts::default() }
+        );
+    }
+
+    #[test]
+    fn claim_lots_never_run_out() {
+        let mut market_state = test_market_state();
+        market_state.claim_deadline_secs = 300;
+        let mut user = empty_user_state(Pubkey::new_unique());
+
+        // A credit every 10s: each one always gets its own deadline, never a merged one.
+        for now in (0..2_000).step_by(10) {
+            user.take_expired_claims(now);
+            let expires_at = market_state.claim_expiry(now);
+            user.credit_claimable(1, 0, expires_at).unwrap();
+            assert!(user.claim_lots.iter().any(|lot| lot.expires_at == expires_at));
+            assert!(user.claim_lots.iter().all(|lot| lot.is_empty() || lot.expires_at > now));
+        }
+        let lots: u64 = user.claim_lots.iter().map(|lot| lot.rebates).sum();
+        assert_eq!(lots, user.claimable_rebates);
+    }
+
+    #[test]
+    fn liquidity_rewards_follow_on_chain_scores() {
+        let mut market_state = test_market_state();
+        market_state.liquidity_reward_pool = 1_000;
+        market_state.sync_liquidity_score(0, 300);
+        market_state.sync_liquidity_score(0, 100);
+
+        // 300 of the 400 total score.
+        assert_eq!(market_state.liquidity_reward_share(300), 750);
+        market_state.liquidity_reward_pool -= 750;
+        market_state.sync_liquidity_score(300, 0);
+
+        // Whoever is paid later gets the same share per unit of score.
+        assert_eq!(market_state.liquidity_reward_share(100), 250);
+        // A score the total doesn't cover can never take more than the pool.
+        assert_eq!(market_state.liquidity_reward_share(1_000), 250);
+    }
+
+    /// Three users with scores 200, 100 and 100 against a pool of 800; the first opts out.
+    fn opt_out_distribution(policy: OptOutPolicy) -> (MarketState, [UserState; 3]) {
+        let mut market_state = test_market_state();
+        market_state.opt_out_policy = policy;
+        market_state.liquidity_reward_pool = 800;
+        let mut users = [200, 100, 100].map(|score| {
+            let mut user = empty_user_state(Pubkey::new_unique());
+            user.liquidity_score = score;
+            market_state.sync_liquidity_score(0, score);
+            user
+        });
+        users[0].rewards_opt_out = true;
+        (market_state, users)
+    }
+
+    #[test]
+    fn opted_out_share_goes_to_the_treasury() {
+        let (mut market_state, mut users) = opt_out_distribution(OptOutPolicy::Treasury);
+        let fees_before = market_state.total_fees_collected;
+
+        let opted_out = market_state.distribute_liquidity_reward(&mut users[0], 0).unwrap();
+        assert_eq!(opted_out.amount, 400);
+        assert!(opted_out.redirected);
+        assert_eq!(users[0].claimable_rewards, 0);
+        assert_eq!(users[0].liquidity_score, 0);
+        assert_eq!(market_state.total_fees_collected, fees_before + 400);
+
+        // Everyone else still gets only their own share of the original pool.
+        for user in &mut users[1..] {
+            let paid = market_state.distribute_liquidity_reward(user, 0).unwrap();
+            assert_eq!((paid.amount, paid.redirected), (200, false));
+            assert_eq!(user.claimable_rewards, 200);
+        }
+        assert_eq!(market_state.liquidity_reward_pool, 0);
+        assert_eq!(market_state.outstanding_claims, 400);
+        assert_eq!(market_state.total_liquidity_rewards_distributed, 400);
+    }
+
+    #[test]
+    fn opted_out_share_is_spread_pro_rata() {
+        let (mut market_state, mut users) = opt_out_distribution(OptOutPolicy::ProRata);
+        let fees_before = market_state.total_fees_collected;
+
+        let opted_out = market_state.distribute_liquidity_reward(&mut users[0], 0).unwrap();
+        assert_eq!(opted_out.amount, 400);
+        assert!(opted_out.redirected);
+        assert_eq!(users[0].claimable_rewards, 0);
+        assert_eq!(market_state.total_fees_collected, fees_before);
+        assert_eq!(market_state.liquidity_reward_pool, 800);
+
+        // The two remaining scores split the whole pool.
+        for user in &mut users[1..] {
+            let paid = market_state.distribute_liquidity_reward(user, 0).unwrap();
+            assert_eq!((paid.amount, paid.redirected), (400, false));
+            assert_eq!(user.claimable_rewards, 400);
+        }
+        assert_eq!(market_state.liquidity_reward_pool, 0);
+        assert_eq!(market_state.outstanding_claims, 800);
+
+        // Opting back in takes effect on the next distribution.
+        users[0].rewards_opt_out = false;
+        users[0].liquidity_score = 50;
+        market_state.liquidity_reward_pool = 100;
+        market_state.sync_liquidity_score(0, 50);
+        let paid = market_state.distribute_liquidity_reward(&mut users[0], 0).unwrap();
+        assert_eq!((paid.amount, paid.redirected), (100, false));
+        assert_eq!(users[0].claimable_rewards, 100);
+    }
+
+    #[test]
+    fn market_stats_size_matches_borsh() {
+        let market_stats = MarketStats {
+            market: Pubkey::new_unique(),
+            volume_buckets: [u64::MAX; VOLUME_BUCKETS],
+            last_bucket_hour: i64::MAX,
+            volume_24h: u64::MAX,
+            fill_count: u64::MAX,
+            total_fill_volume: u64::MAX,
+            avg_fill_size: u64::MAX,
+            place_count: u64::MAX,
+            cancel_count: u64::MAX,
+            cancel_to_fill_bps: u64::MAX,
+            last_update_ts: i64::MAX,
+            rejections: [u64::MAX; REJECTION_REASONS],
+        };
+        assert_eq!(serialized_len(&market_stats), MarketStats::SIZE);
+    }
+
+    #[test]
+    fn market_stats_volume_window_drops_old_hours() {
+        let mut market_stats = MarketStats {
+            market: Pubkey::new_unique(),
+            volume_buckets: [0; VOLUME_BUCKETS],
+            last_bucket_hour: 0,
+            volume_24h: 0,
+            fill_count: 0,
+            total_fill_volume: 0,
+            avg_fill_size: 0,
+            place_count: 0,
+            cancel_count: 0,
+            cancel_to_fill_bps: 0,
+            last_update_ts: 0,
+            rejections: [0; REJECTION_REASONS],
+        };
+        let hour = VOLUME_BUCKET_SECS;
+
+        market_stats.record_fill(100, 10 * hour);
+        market_stats.record_fill(50, 20 * hour);
+        assert_eq!(market_stats.volume_24h, 150);
+        assert_eq!(market_stats.avg_fill_size, 75);
+
+        // The first fill ages out of the window, the second is still inside it.
+        market_stats.record_cancel(34 * hour);
+        assert_eq!(market_stats.volume_24h, 50);
+        assert_eq!(market_stats.cancel_to_fill_bps, 5_000);
+
+        // A long gap clears everything.
+        market_stats.record_place(100 * hour);
+        assert_eq!(market_stats.volume_24h, 0);
+        assert_eq!(market_stats.total_fill_volume, 150);
+    }
+
+    #[test]
+    fn rejections_are_counted_by_reason() {
+        assert_eq!(
+            RejectionReason::for_error(FeeError::OpenOrderLimitReached),
+            Some(RejectionReason::NoSlot)
+        );
+        assert_eq!(
+            RejectionReason::for_error(FeeError::SlippageExceeded),
+            Some(RejectionReason::RiskLimit)
+        );
+        assert_eq!(RejectionReason::for_error(FeeError::Unauthorized), None);
+
+        let mut market_stats = MarketStats {
+            market: Pubkey::new_unique(),
+            volume_buckets: [0; VOLUME_BUCKETS],
+            last_bucket_hour: 0,
+            volume_24h: 0,
+            fill_count: 0,
+            total_fill_volume: 0,
+            avg_fill_size: 0,
+            place_count: 0,
+            cancel_count: 0,
+            cancel_to_fill_bps: 0,
+            last_update_ts: 0,
+            rejections: [u64::MAX - 1, 0, 0, 0],
+        };
+        market_stats.record_rejections(RejectionReason::NoSlot, 3, 10);
+        market_stats.record_rejections(RejectionReason::NoSlot, 2, 20);
+        market_stats.record_rejections(RejectionReason::Expired, 5, 30);
+        assert_eq!(market_stats.rejections, [u64::MAX, 5, 0, 0]);
+        assert_eq!(market_stats.place_count, 0);
+        assert_eq!(market_stats.last_update_ts, 30);
+    }
+
+    #[test]
+    fn cancel_credits_liquidity_for_time_live() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        let idx = user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 10, 0, 1_000))
+            .unwrap();
+        assert_eq!(idx, 0);
+
+        let canceled = user_state
+            .cancel_order(0, &test_market_state(), &FixedClock(1_030))
+            .unwrap();
+        assert_eq!(canceled, 10);
+        assert_eq!(user_state.liquidity_score, 300);
+        assert_eq!(user_state.orders[0], Order::default());
+
+        // The slot is free again, so a second cancel fails.
+        assert!(user_state.cancel_order(0, &test_market_state(), &FixedClock(1_040)).is_err());
+    }
+
+    #[test]
+    fn insert_order_fails_when_all_slots_are_used() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        for _ in 0..MAX_ORDERS {
+            user_state
+                .insert_order(fixed_order(OrderSide::Ask, 100, 1, 0, 0))
+                .unwrap();
+        }
+        assert!(user_state
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1, 0, 0))
+            .is_err());
+    }
+
+    #[test]
+    fn max_open_orders_stays_within_allocated_slots() {
+        assert!(validate_max_open_orders(0).is_err());
+        assert!(validate_max_open_orders(1).is_ok());
+        assert!(validate_max_open_orders(MAX_ORDERS as u8).is_ok());
+        assert!(validate_max_open_orders(MAX_ORDERS as u8 + 1).is_err());
+
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1, 0, 0))
+            .unwrap();
+        user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 1, 0, 0))
+            .unwrap();
+        user_state.cancel_order(0, &test_market_state(), &FixedClock(0)).unwrap();
+        assert_eq!(user_state.open_order_count(), 1);
+
+        // Reusing a slot still hands out a fresh id.
+        let index = user_state
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1, 0, 0))
+            .unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(user_state.orders[index].order_id, 3);
+    }
+
+    #[test]
+    fn partial_then_full_fill_updates_both_users_and_market() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 30_000, 0, 1_000))
+            .unwrap();
+
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 20_000,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(1_010),
+        )
+        .unwrap();
+        assert_eq!(fill.trade_size, 20_000);
+        assert_eq!(fill.taker_fee, 10);
+        assert_eq!(fill.maker_rebate, 4);
+        assert!(!fill.fully_filled);
+        assert_eq!(maker.orders[0].size_remaining, 10_000);
+        assert_eq!(fill.maker_size_remaining, 10_000);
+        assert_eq!(fill.maker_order_id, 1);
+
+        // Asking for more than remains only fills what is left and frees the slot.
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: u64::MAX,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(1_020),
+        )
+        .unwrap();
+        assert_eq!(fill.trade_size, 10_000);
+        assert!(fill.fully_filled);
+        assert_eq!(fill.maker_size_remaining, 0);
+        assert_eq!(fill.maker_order_id, 1);
+        assert_eq!(maker.orders[0], Order::default());
+        // 30_000 rested for the first 10s, the remaining 10_000 for the next 10s.
+        assert_eq!(maker.liquidity_score, 10 * 30_000 + 10 * 10_000);
+        assert_eq!(market_state.total_liquidity_score, maker.liquidity_score);
+
+        assert_eq!(maker.maker_volume, 30_000);
+        assert_eq!(taker.taker_volume, 30_000);
+        assert_eq!(taker.taker_fees_paid, 15);
+        assert_eq!(maker.maker_rebates_earned, 6);
+        assert_eq!(market_state.total_fees_collected, 9);
+        assert_eq!(market_state.gross_fees_charged, 15);
+        assert_eq!(market_state.net_fees_retained, 9);
+    }
+
+    #[test]
+    fn whole_order_fills_score_and_clear_the_slot() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 30_000, 0, 1_000))
+            .unwrap();
+        maker.require_whole_order(0, 30_000).unwrap();
+
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 30_000,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(1_020),
+        )
+        .unwrap();
+        assert!(fill.fully_filled);
+        assert_eq!(fill.trade_size, 30_000);
+        assert_eq!(fill.maker_size_remaining, 0);
+        assert_eq!(fill.maker_order_id, 1);
+        assert_eq!(fill.maker_side, OrderSide::Ask);
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(maker.liquidity_score, 20 * 30_000);
+        assert_eq!(market_state.total_liquidity_score, maker.liquidity_score);
+        assert_eq!(maker.maker_volume, 30_000);
+        assert_eq!(taker.taker_volume, 30_000);
+    }
+
+    #[test]
+    fn fill_rejects_expired_orders() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Bid, 100, 10, 1_050, 1_000))
+            .unwrap();
+
+        let result = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 10,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(1_051),
+        );
+        assert!(result.is_err());
+        assert_eq!(maker.orders[0].size_remaining, 10);
+    }
+
+    #[test]
+    fn price_improvement_rebate_is_capped_by_net_fee() {
+        let mut market_state = test_market_state();
+        market_state.price_improvement_rebate_bps = 10_000;
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 10_000, 0, 0))
+            .unwrap();
+
+        // Taker would have paid up to 110: 10 per unit of improvement.
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 10_000,
+                taker_limit_price: 110,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(0),
+        )
+        .unwrap();
+        assert_eq!(fill.taker_rebate, 3); // the whole net fee (5 - 2)
+        assert_eq!(fill.net_fee, 0);
+        assert_eq!(taker.claimable_rebates, 3);
+        assert_eq!(market_state.outstanding_claims, 3);
+    }
+
+    #[test]
+    fn worst_price_bounds_fee_inclusive_price() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 10_000, 10, 0, 0))
+            .unwrap();
+
+        // 10_000 plus the 5 bps taker fee is 10_005 per unit.
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 10,
+            taker_limit_price: 0,
+            worst_price: 10_004,
+            book_imbalance_bps: 0,
+        };
+        let result =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0));
+        assert!(result.is_err());
+        assert_eq!(maker.orders[0].size_remaining, 10);
+
+        let params = FillParams {
+            worst_price: 10_005,
+            ..params
+        };
+        let result =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn cancel_flicker_penalizes_the_next_epoch() {
+        let mut market_state = test_market_state();
+        market_state.penalty_cancel_to_fill_bps = 20_000; // 2 cancels per fill
+        market_state.penalty_min_cancels = 3;
+        market_state.penalty_rebate_haircut_bps = 5_000;
+        assert!(!market_state.cancel_penalty_triggered(2, 0));
+        assert!(!market_state.cancel_penalty_triggered(4, 2));
+        assert!(market_state.cancel_penalty_triggered(5, 2));
+
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker.epoch_cancels = 5;
+        maker.epoch_fills = 2;
+        market_state.current_epoch = 1;
+
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 40_000, 0, 0))
+            .unwrap();
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 40_000,
+            ..FillParams::default()
+        };
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        let penalty = fill.maker_penalty.unwrap();
+        assert_eq!((penalty.offending_epoch, penalty.penalty_epoch), (0, 1));
+        // 8 rebate halved; the market keeps the other half.
+        assert_eq!(fill.maker_rebate, 4);
+        assert_eq!(fill.maker_rebate_haircut, 4);
+        assert_eq!(fill.net_fee, 20 - 4);
+        assert_eq!(maker.epoch_fills, 1);
+
+        // The penalty lasts exactly one epoch.
+        market_state.current_epoch = 2;
+        assert_eq!(maker.sync_activity_epoch(&market_state), None);
+        assert!(!maker.is_penalized(2));
+    }
+
+    #[test]
+    fn stale_activity_does_not_penalize() {
+        let mut market_state = test_market_state();
+        market_state.penalty_cancel_to_fill_bps = 1;
+        market_state.current_epoch = 5;
+
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state.activity_epoch = 3;
+        user_state.epoch_cancels = 100;
+        assert_eq!(user_state.sync_activity_epoch(&market_state), None);
+        assert_eq!(user_state.activity_epoch, 5);
+        assert_eq!(user_state.epoch_cancels, 0);
+    }
+
+    #[test]
+    fn reward_programs_credit_within_budget_once_per_epoch() {
+        let mut reward_program = RewardProgram {
+            market: Pubkey::new_unique(),
+            sponsor: Pubkey::new_unique(),
+            name: [u8::MAX; 32],
+            reward_mint: Pubkey::new_unique(),
+            start_ts: 100,
+            end_ts: 200,
+            budget: 150,
+            epoch_reward_pool: 200,
+            distributed: 0,
+            eligibility: RewardEligibility::MinLiquidityScore { min: 10 },
+            position_count: u64::MAX,
+            pool_epoch: Some(u64::MAX),
+            epoch_total_score: 0,
+        };
+        let mut position = RewardPosition {
+            reward_program: Pubkey::new_unique(),
+            user: Pubkey::new_unique(),
+            claimable: 0,
+            total_earned: 0,
+            last_distributed_epoch: Some(u64::MAX),
+        };
+        assert_eq!(serialized_len(&reward_program), RewardProgram::SIZE);
+        assert_eq!(serialized_len(&position), RewardPosition::SIZE);
+        position.last_distributed_epoch = None;
+        reward_program.pool_epoch = None;
+
+        let mut user_state = empty_user_state(reward_program.market);
+        user_state.liquidity_score = 50;
+
+        // Outside the window.
+        assert!(reward_program
+            .credit(&mut position, &user_state, 0, 100, 200)
+            .is_err());
+
+        assert_eq!(
+            reward_program.credit(&mut position, &user_state, 0, 100, 150).unwrap(),
+            100
+        );
+        assert!(reward_program
+            .credit(&mut position, &user_state, 0, 100, 150)
+            .is_err());
+        // Later users of the epoch share against the total as of its first distribution,
+        // capped by what is left of the budget; the score is not consumed.
+        let mut other = RewardPosition { last_distributed_epoch: None, ..position };
+        assert_eq!(
+            reward_program.credit(&mut other, &user_state, 0, 400, 150).unwrap(),
+            50
+        );
+        assert_eq!(reward_program.epoch_total_score, 100);
+        assert_eq!(position.claimable, 100);
+        assert_eq!(reward_program.distributed, 150);
+        assert_eq!(user_state.liquidity_score, 50);
+
+        // The next epoch takes a fresh total.
+        reward_program.budget = 200;
+        assert_eq!(
+            reward_program.credit(&mut position, &user_state, 1, 400, 150).unwrap(),
+            25
+        );
+        assert_eq!(reward_program.pool_epoch, Some(1));
+
+        user_state.liquidity_score = 9;
+        assert_eq!(
+            reward_program.credit(&mut position, &user_state, 2, 100, 150).unwrap(),
+            0
+        );
+
+        // Unclaimed: only the sponsor can close it, and only after the program ends.
+        let (user, sponsor) = (position.user, reward_program.sponsor);
+        assert!(!position.is_closable_by(&user, &reward_program, i64::MAX));
+        assert!(!position.is_closable_by(&sponsor, &reward_program, 199));
+        assert!(position.is_closable_by(&sponsor, &reward_program, 200));
+        position.claimable = 0;
+        assert!(position.is_closable_by(&user, &reward_program, 150));
+        assert!(!position.is_closable_by(&Pubkey::new_unique(), &reward_program, i64::MAX));
+    }
+
+    #[test]
+    fn full_export_page_fits_in_return_data() {
+        let page = UserStatsPage {
+            start: Pubkey::new_unique(),
+            next: Pubkey::new_unique(),
+            total: u64::MAX,
+            users: vec![UserStatsSummary::from(&max_user_state()); MAX_EXPORT_PAGE],
+        };
+        assert_eq!(serialized_len(&page.users[0]), UserStatsSummary::SIZE);
+        assert!(serialized_len(&page) <= MAX_RETURN_DATA);
+
+        let entry = UserIndexEntry {
+            market: Pubkey::new_unique(),
+            user: Pubkey::new_unique(),
+            user_state: Pubkey::new_unique(),
+            next: Pubkey::new_unique(),
+        };
+        assert_eq!(serialized_len(&entry), UserIndexEntry::SIZE);
+
+        let record = RegistrationRecord {
+            market: Pubkey::new_unique(),
+            user: Pubkey::new_unique(),
+            payer: Pubkey::new_unique(),
+            first_registered_ts: i64::MAX,
+        };
+        assert_eq!(serialized_len(&record), RegistrationRecord::SIZE);
+    }
+
+    #[test]
+    fn user_index_links_each_live_user_once() {
+        let mut market_state = test_market_state();
+        let market = Pubkey::new_unique();
+        let users: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let mut entries: Vec<UserIndexEntry> = users
+            .iter()
+            .map(|&user| {
+                let mut entry = UserIndexEntry {
+                    market: Pubkey::default(),
+                    user: Pubkey::default(),
+                    user_state: Pubkey::default(),
+                    next: Pubkey::default(),
+                };
+                entry.append(&mut market_state, market, user, Pubkey::new_unique());
+                entry
+            })
+            .collect();
+        let walk = |market_state: &MarketState, entries: &[UserIndexEntry]| {
+            let mut order = vec![];
+            let mut cursor = market_state.user_index_head;
+            while let Some(entry) = entries.iter().find(|entry| entry.user == cursor) {
+                order.push(entry.user);
+                cursor = entry.next;
+            }
+            order
+        };
+        // Newest first.
+        assert_eq!(walk(&market_state, &entries), [users[2], users[1], users[0]]);
+
+        // The middle entry needs the one linking to it.
+        let middle = entries[1].clone();
+        assert!(middle.unlink(&mut market_state, None).is_err());
+        assert!(middle
+            .unlink(&mut market_state, Some(&mut entries[0].clone()))
+            .is_err());
+        middle.unlink(&mut market_state, Some(&mut entries[2])).unwrap();
+        entries.remove(1);
+        assert_eq!(walk(&market_state, &entries), [users[2], users[0]]);
+
+        // The head needs nothing, and re-registering puts the user back once.
+        let head = entries.pop().unwrap();
+        head.unlink(&mut market_state, None).unwrap();
+        assert_eq!(walk(&market_state, &entries), [users[0]]);
+        let mut entry = head.clone();
+        entry.append(&mut market_state, market, users[2], Pubkey::new_unique());
+        entries.push(entry);
+        assert_eq!(walk(&market_state, &entries), [users[2], users[0]]);
+    }
+
+    #[test]
+    fn market_status_drives_allowed_instructions() {
+        let mut market_state = test_market_state();
+        assert!(market_state.require_allowed(ProgramInstruction::PlaceOrder).is_ok());
+        assert!(market_state.require_allowed(ProgramInstruction::CloseMarket).is_err());
+
+        market_state.transition_to(MarketStatus::Paused).unwrap();
+        assert!(market_state.require_allowed(ProgramInstruction::FillOrder).is_err());
+        assert!(market_state.require_allowed(ProgramInstruction::CancelOrder).is_ok());
+
+        market_state.transition_to(MarketStatus::SunsetPending).unwrap();
+        assert!(market_state.require_allowed(ProgramInstruction::PlaceOrder).is_err());
+        assert!(market_state.require_allowed(ProgramInstruction::FillOrder).is_ok());
+        assert!(market_state.require_allowed(ProgramInstruction::CloseMarket).is_ok());
+
+        market_state.transition_to(MarketStatus::Closed).unwrap();
+        assert!(market_state.require_allowed(ProgramInstruction::CancelOrder).is_err());
+        // Closed is terminal.
+        assert!(market_state.transition_to(MarketStatus::Active).is_err());
+        assert_eq!(market_state.status, MarketStatus::Closed);
+    }
+
+    #[test]
+    fn side_incentive_boosts_one_side_within_its_window() {
+        let incentive = SideIncentive {
+            side: OrderSide::Ask,
+            multiplier_bps: 20_000,
+            starts_at: 50,
+            expires_at: 100,
+        };
+        assert_eq!(incentive.window(OrderSide::Ask, 0, 130), Some((50, 100)));
+        assert_eq!(incentive.window(OrderSide::Ask, 60, 70), Some((60, 70)));
+        assert_eq!(incentive.window(OrderSide::Bid, 0, 130), None);
+        assert_eq!(incentive.window(OrderSide::Ask, 100, 130), None);
+        assert_eq!(incentive.window(OrderSide::Ask, 0, 50), None);
+        assert_eq!(SideIncentive::default().window(OrderSide::Bid, 0, 100), None);
+        assert_eq!(incentive.boost(50), 100);
+
+        let market_state = MarketState {
+            side_incentive: incentive,
+            ..test_market_state()
+        };
+        let ask = fixed_order(OrderSide::Ask, 100, 10, 0, 0);
+
+        // One accrual across the whole window: only its 50s are doubled.
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state.insert_order(ask).unwrap();
+        user_state.cancel_order(0, &market_state, &FixedClock(130)).unwrap();
+        assert_eq!(user_state.liquidity_score, (80 + 2 * 50) * 10);
+
+        // Checkpointing inside the window splits the same time the same way.
+        let mut checkpointed = empty_user_state(Pubkey::new_unique());
+        checkpointed.insert_order(ask).unwrap();
+        checkpointed.accrue_open_orders(&market_state, 70);
+        assert_eq!(checkpointed.liquidity_score, (50 + 2 * 20) * 10);
+        checkpointed.cancel_order(0, &market_state, &FixedClock(130)).unwrap();
+        assert_eq!(checkpointed.liquidity_score, user_state.liquidity_score);
+
+        // The boost moves pool share toward the ask, but the pool pays out no more.
+        let bid_score = 130 * 10;
+        let rewards = MarketState {
+            liquidity_reward_pool: 1_000,
+            total_liquidity_score: user_state.liquidity_score + bid_score,
+            ..test_market_state()
+        };
+        let ask_reward = rewards.liquidity_reward_share(user_state.liquidity_score);
+        let bid_reward = rewards.liquidity_reward_share(bid_score);
+        assert!(ask_reward > bid_reward);
+        assert!(ask_reward + bid_reward <= 1_000);
+    }
+
+    #[test]
+    fn banded_score_only_counts_near_touch_time() {
+        let mut market_state = test_market_state();
+        market_state.liquidity_band_bps = 100; // 1%
+        market_state.push_reference_snapshot(10_000, 100);
+        market_state.push_reference_snapshot(12_000, 200);
+        market_state.push_reference_snapshot(10_050, 300);
+
+        // In band at 100..200 and from 300 on, out of band at 200..300.
+        let order = fixed_order(OrderSide::Bid, 10_000, 10, 0, 0);
+        assert_eq!(market_state.in_band_secs(&order, 0, 400), 100 + 100);
+        assert_eq!(market_state.in_band_secs(&order, 150, 250), 50);
+        assert_eq!(market_state.in_band_secs(&order, 400, 300), 0);
+
+        // A mid peg always tracks the reference.
+        let pegged = Order {
+            price_mode: PriceMode::MidPeg,
+            ..order
+        };
+        assert_eq!(market_state.in_band_secs(&pegged, 0, 400), 300);
+
+        // No band => all resting time counts.
+        market_state.liquidity_band_bps = 0;
+        assert_eq!(market_state.in_band_secs(&order, 0, 400), 400);
+
+        // The history is a ring: the oldest snapshot is overwritten, and reported.
+        for i in 0..REFERENCE_HISTORY as i64 {
+            let overwritten = market_state.push_reference_snapshot(1, 1_000 + i);
+            // The three snapshots above go once the five empty slots are used up.
+            let expected = (i as usize).checked_sub(5).map(|k| [100, 200, 300][k]);
+            assert_eq!(overwritten.map(|snapshot| snapshot.ts), expected);
+        }
+        assert!(market_state
+            .reference_history
+            .iter()
+            .all(|snapshot| snapshot.price == 1));
+    }
+
+    #[test]
+    fn reference_history_overflow_only_drops_unchecked_time() {
+        let mut market_state = test_market_state();
+        market_state.liquidity_band_bps = 100;
+        let order = fixed_order(OrderSide::Bid, 10_000, 10, 0, 0);
+        let mut cranked = empty_user_state(Pubkey::new_unique());
+        cranked.insert_order(order).unwrap();
+        let mut idle = empty_user_state(Pubkey::new_unique());
+        idle.insert_order(order).unwrap();
+
+        // In band throughout; one snapshot every 100s from 0, overflowing the ring.
+        let updates = REFERENCE_HISTORY as i64 + 2;
+        for i in 0..updates {
+            market_state.push_reference_snapshot(10_000, i * 100);
+            if i == REFERENCE_HISTORY as i64 / 2 {
+                cranked.accrue_open_orders(&market_state, i * 100);
+            }
+        }
+        let now = updates * 100;
+        cranked.accrue_open_orders(&market_state, now);
+        idle.accrue_open_orders(&market_state, now);
+
+        // Checkpointed within the history, every second counts.
+        assert_eq!(cranked.liquidity_score, now as u64 * 10);
+        // Not, only the time since the oldest kept snapshot does.
+        let oldest_kept = (updates - REFERENCE_HISTORY as i64) * 100;
+        assert_eq!(idle.liquidity_score, (now - oldest_kept) as u64 * 10);
+    }
+
+    #[test]
+    fn partial_fills_checkpoint_the_score_at_the_rested_size() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 40, 0, 0))
+            .unwrap();
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        let fill = |fill_size| FillParams {
+            maker_order_index: 0,
+            fill_size,
+            taker_limit_price: 0,
+            worst_price: 0,
+            book_imbalance_bps: 0,
+        };
+
+        // 40 rest for 100s, then 10 for another 100s.
+        execute_fill(&mut market_state, &mut maker, &mut taker, fill(30), &FixedClock(100))
+            .unwrap();
+        assert_eq!(maker.liquidity_score, 40 * 100);
+        assert_eq!(maker.orders[0].scored_until, 100);
+        execute_fill(&mut market_state, &mut maker, &mut taker, fill(10), &FixedClock(200))
+            .unwrap();
+        assert_eq!(maker.liquidity_score, 40 * 100 + 10 * 100);
+        assert_eq!(market_state.total_liquidity_score, maker.liquidity_score);
+    }
+
+    #[test]
+    fn accrual_checkpoints_are_not_double_counted() {
+        let mut market_state = test_market_state();
+        market_state.liquidity_band_bps = 100;
+        market_state.push_reference_snapshot(10_000, 0);
+
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state
+            .insert_order(fixed_order(OrderSide::Ask, 10_000, 10, 0, 0))
+            .unwrap();
+        assert_eq!(user_state.accrue_open_orders(&market_state, 50), 500);
+        assert_eq!(user_state.accrue_open_orders(&market_state, 50), 0);
+
+        user_state.cancel_order(0, &market_state, &FixedClock(80)).unwrap();
+        assert_eq!(user_state.liquidity_score, 800);
+    }
+
+    #[test]
+    fn first_fill_bonus_is_limited_by_count_window_and_budget() {
+        let mut market_state = test_market_state();
+        market_state.total_fees_collected = 1_000;
+        market_state.set_promotion_budget(150).unwrap();
+        assert_eq!(market_state.total_fees_collected, 850);
+        market_state.first_fill_bonus = FirstFillBonus {
+            max_fills: 2,
+            window_secs: 100,
+            discount_bps: 5_000,
+        };
+
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.registered_ts = 1;
+        for _ in 0..3 {
+            maker
+                .insert_order(fixed_order(OrderSide::Ask, 100, 200_000, 0, 0))
+                .unwrap();
+        }
+        let mut fill = |index: u8, now: i64| {
+            execute_fill(
+                &mut market_state,
+                &mut maker,
+                &mut taker,
+                FillParams {
+                    maker_order_index: index,
+                    fill_size: 200_000,
+                    taker_limit_price: 0,
+                    worst_price: 0,
+                    book_imbalance_bps: 0,
+                },
+                &FixedClock(now),
+            )
+            .unwrap()
+        };
+
+        // Half of each 100 fee (5 bps of 200_000) is discounted for the first two fills.
+        assert_eq!(fill(0, 10).taker_bonus_discount, 50);
+        assert_eq!(fill(1, 20).taker_bonus_discount, 50);
+        assert_eq!(fill(2, 30).taker_bonus_discount, 0); // max_fills used up
+        assert_eq!(taker.taker_fees_paid, 3 * 100 - 2 * 50);
+        assert_eq!(taker.first_fill_ts, 10);
+        assert_eq!(market_state.promotion_budget, 50);
+        assert_eq!(market_state.promotion_spent, 100);
+
+        let bonus = market_state.first_fill_bonus;
+        let mut newcomer = empty_user_state(Pubkey::new_unique());
+        newcomer.registered_ts = 1;
+        assert_eq!(bonus.discount(&newcomer, 1_000, 30, 101).unwrap(), 30);
+        assert_eq!(bonus.discount(&newcomer, 1_000, 30, 102).unwrap(), 0);
+        newcomer.registered_ts = 0;
+        assert_eq!(bonus.discount(&newcomer, 1_000, 30, 1).unwrap(), 0);
+
+        // Lowering the budget hands the unspent part back to the treasury.
+        market_state.set_promotion_budget(0).unwrap();
+        assert_eq!(market_state.total_fees_collected, 850 + 50 + 3 * (100 - 40));
+    }
+
+    #[test]
+    fn first_fill_discount_lowers_the_fee_charged() {
+        let mut market_state = test_market_state();
+        market_state.total_fees_collected = 1_000;
+        market_state.set_promotion_budget(100).unwrap();
+        market_state.first_fill_bonus = FirstFillBonus {
+            max_fills: 1,
+            window_secs: 100,
+            discount_bps: 5_000,
+        };
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.registered_ts = 1;
+        // At the full 5 bps the taker pays 20_010 per unit, at the discounted 3 bps 20_006.
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 200_000,
+            worst_price: 20_006,
+            ..FillParams::default()
+        };
+
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 20_000, 200_000, 0, 0))
+            .unwrap();
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(10))
+                .unwrap();
+        assert_eq!((fill.taker_fee, fill.taker_bonus_discount), (50, 50));
+        assert_eq!(taker.taker_fees_paid, 50);
+        assert_eq!(market_state.gross_fees_charged, 100);
+
+        // The bonus is used up, so the same bound now fails at the full fee.
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 20_000, 200_000, 0, 0))
+            .unwrap();
+        let err = execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(20))
+            .unwrap_err();
+        assert_eq!(
+            error_code(&err),
+            FeeError::SlippageExceeded as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+    }
+
+    fn empty_top_of_book() -> TopOfBook {
+        TopOfBook {
+            market: Pubkey::new_unique(),
+            bids: [PriceLevel::default(); MAX_PRICE_LEVELS],
+            asks: [PriceLevel::default(); MAX_PRICE_LEVELS],
+            bid_len: 0,
+            ask_len: 0,
+            truncated: false,
+            next_level_id: 0,
+            untracked_orders: 0,
+        }
+    }
+
+    #[test]
+    fn top_of_book_size_matches_borsh() {
+        let top_of_book = TopOfBook {
+            bids: [PriceLevel {
+                price: u64::MAX,
+                size: u64::MAX,
+                order_count: u32::MAX,
+                id: u64::MAX,
+            }; MAX_PRICE_LEVELS],
+            bid_len: u8::MAX,
+            ask_len: u8::MAX,
+            truncated: true,
+            next_level_id: u64::MAX,
+            untracked_orders: u32::MAX,
+            ..empty_top_of_book()
+        };
+        assert_eq!(serialized_len(&top_of_book), TopOfBook::SIZE);
+        assert!(8 + TopOfBook::SIZE <= MAX_INIT_SPACE);
+    }
+
+    #[test]
+    fn top_of_book_tracks_best_prices() {
+        let mut book = empty_top_of_book();
+        book.add(OrderSide::Bid, 99, 10);
+        let bid = book.add(OrderSide::Bid, 100, 5);
+        assert_eq!(book.add(OrderSide::Bid, 100, 5), bid); // same level
+        let far_ask = book.add(OrderSide::Ask, 103, 1);
+        let near_ask = book.add(OrderSide::Ask, 102, 1);
+        assert_eq!((book.best_bid(), book.best_ask()), (100, 102));
+
+        // A partial fill keeps the level, closing its last order frees it.
+        book.remove(OrderSide::Bid, 100, 5, true, bid);
+        assert_eq!(book.best_bid(), 100);
+        book.remove(OrderSide::Bid, 100, 5, true, bid);
+        assert_eq!(book.best_bid(), 99);
+        book.remove(OrderSide::Ask, 102, 1, true, near_ask);
+        assert_eq!(book.best_ask(), 103);
+
+        // Freed trailing levels shrink the used prefix.
+        book.remove(OrderSide::Ask, 103, 1, true, far_ask);
+        assert_eq!((book.ask_len, book.best_ask()), (0, 0));
+        assert!(!book.truncated);
+    }
+
+    #[test]
+    fn top_of_book_compacts_lazily_and_keeps_best_levels() {
+        let mut book = empty_top_of_book();
+        for price in 1..=MAX_PRICE_LEVELS as u64 {
+            assert_eq!(book.add(OrderSide::Bid, price, 1), price); // fresh level ids
+        }
+        // Freeing a middle level leaves a hole until the next append needs room.
+        book.remove(OrderSide::Bid, 5, 1, true, 5);
+        assert_eq!(book.bid_len as usize, MAX_PRICE_LEVELS);
+        book.add(OrderSide::Bid, 1_000, 1);
+        assert_eq!(book.bid_len as usize, MAX_PRICE_LEVELS);
+        assert_eq!(book.bids[4].price, 6); // order kept, hole closed
+        assert_eq!(book.best_bid(), 1_000);
+        assert!(!book.truncated);
+
+        // Full: a better price evicts the worst level, a worse one is dropped.
+        book.add(OrderSide::Bid, 2_000, 1);
+        assert!(book.truncated);
+        assert_eq!(book.best_bid(), 2_000);
+        assert!(book.bids.iter().all(|level| level.price != 1));
+        assert_eq!(book.add(OrderSide::Bid, 1, 1), 0);
+        assert!(book.bids.iter().all(|level| level.price != 1));
+        assert_eq!(book.untracked_orders, 2);
+    }
+
+    #[test]
+    fn top_of_book_keeps_dropped_orders_off_readded_levels() {
+        let mut book = empty_top_of_book();
+        let ids: Vec<u64> = (1..=MAX_PRICE_LEVELS as u64)
+            .map(|i| book.add(OrderSide::Ask, i * 10, 5))
+            .collect();
+        // A better ask pushes out the worst level, 160, and its order.
+        book.add(OrderSide::Ask, 1, 5);
+        assert_eq!((book.best_ask(), book.untracked_orders), (1, 1));
+
+        // Once there is room, a new order rests at 160 again, on a new level.
+        book.remove(OrderSide::Ask, 10, 5, true, ids[0]);
+        let readded = book.add(OrderSide::Ask, 160, 7);
+        assert_ne!(readded, ids[15]);
+
+        // The dropped order's partial fill and cancel leave the new level alone, and
+        // once it is gone nothing is untracked anymore.
+        book.remove(OrderSide::Ask, 160, 2, false, ids[15]);
+        book.remove(OrderSide::Ask, 160, 3, true, ids[15]);
+        let level = book.asks.iter().find(|level| level.price == 160).unwrap();
+        assert_eq!((level.size, level.order_count), (7, 1));
+        assert_eq!(book.untracked_orders, 0);
+        assert!(!book.truncated);
+
+        book.remove(OrderSide::Ask, 160, 7, true, readded);
+        assert!(book.asks.iter().all(|level| level.price != 160));
+    }
+
+    #[test]
+    fn expired_orders_are_found_for_the_crank() {
+        let mut user = empty_user_state(Pubkey::new_unique());
+        user.insert_order(fixed_order(OrderSide::Bid, 100, 10, 50, 0)).unwrap();
+        user.insert_order(fixed_order(OrderSide::Bid, 100, 10, 0, 0)).unwrap(); // no expiry
+        user.insert_order(fixed_order(OrderSide::Ask, 101, 10, 60, 0)).unwrap();
+        assert!(user.expired_orders(50).is_empty());
+        assert_eq!(user.expired_orders(51), vec![0]);
+        assert_eq!(user.expired_orders(61), vec![0, 2]);
+    }
+
+    fn empty_open_order_index() -> OpenOrderIndex {
+        OpenOrderIndex {
+            market: Pubkey::new_unique(),
+            len: 0,
+            unindexed_orders: 0,
+            entries: [OpenOrderEntry::default(); MAX_INDEXED_ORDERS],
+        }
+    }
+
+    fn open_order_entry(user: Pubkey, order_id: u64, size: u64) -> OpenOrderEntry {
+        OpenOrderEntry {
+            user,
+            order_id,
+            order_index: 0,
+            side: OrderSide::Bid,
+            price_mode: PriceMode::Fixed,
+            price: 100,
+            size,
+            expiry_timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn open_order_index_size_matches_borsh() {
+        let index = OpenOrderIndex {
+            len: u16::MAX,
+            unindexed_orders: u32::MAX,
+            entries: [OpenOrderEntry {
+                order_id: u64::MAX,
+                order_index: u8::MAX,
+                price_mode: PriceMode::PrimaryPeg { offset_bps: u16::MAX },
+                price: u64::MAX,
+                expiry_timestamp: i64::MAX,
+                ..open_order_entry(Pubkey::new_unique(), 0, u64::MAX)
+            }; MAX_INDEXED_ORDERS],
+            ..empty_open_order_index()
+        };
+        assert_eq!(serialized_len(&index), OpenOrderIndex::SIZE);
+        assert!(8 + OpenOrderIndex::SIZE <= MAX_INIT_SPACE);
+    }
+
+    #[test]
+    fn open_order_index_follows_fills_and_cancels() {
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut index = empty_open_order_index();
+        index.insert(open_order_entry(alice, 1, 10), 0);
+        index.insert(open_order_entry(bob, 1, 20), 0);
+        index.insert(open_order_entry(alice, 2, 30), 0);
+
+        // Partial fill shrinks in place; a cancel swap-removes.
+        index.update(&bob, 1, 5);
+        assert_eq!(index.entries[1].size, 5);
+        index.update(&alice, 1, 0);
+        assert_eq!(index.len, 2);
+        assert_eq!(index.entries[0], open_order_entry(alice, 2, 30));
+        assert_eq!(index.entries[2], OpenOrderEntry::default());
+    }
+
+    #[test]
+    fn full_open_order_index_counts_unlisted_orders() {
+        let mut index = empty_open_order_index();
+        let mut user = Pubkey::default();
+        for order_id in 1..=MAX_INDEXED_ORDERS as u64 + 1 {
+            user = Pubkey::new_unique();
+            index.insert(open_order_entry(user, order_id, 1), 0);
+        }
+        assert_eq!(index.len as usize, MAX_INDEXED_ORDERS);
+        assert_eq!(index.unindexed_orders, 1);
+
+        // Closing the unlisted order settles the count; partial fills of it change nothing.
+        index.update(&user, MAX_INDEXED_ORDERS as u64 + 1, 0);
+        assert_eq!(index.unindexed_orders, 0);
+        index.update(&user, MAX_INDEXED_ORDERS as u64 + 1, 0);
+        assert_eq!(index.unindexed_orders, 0);
+    }
+
+    #[test]
+    fn open_order_index_caps_makers_and_keeps_the_best_prices() {
+        let mut index = empty_open_order_index();
+        let maker = Pubkey::new_unique();
+        for order_id in 1..=MAX_INDEXED_ORDERS_PER_USER as u64 + 1 {
+            index.insert(open_order_entry(maker, order_id, 1), 0);
+        }
+        assert_eq!(index.len as usize, MAX_INDEXED_ORDERS_PER_USER);
+        assert_eq!(index.unindexed_orders, 1);
+
+        // Fill up with bids at 100, one of them at 90 and one expiring at 50.
+        let (low, expiring) = (Pubkey::new_unique(), Pubkey::new_unique());
+        index.insert(
+            OpenOrderEntry {
+                price: 90,
+                ..open_order_entry(low, 1, 1)
+            },
+            0,
+        );
+        index.insert(
+            OpenOrderEntry {
+                expiry_timestamp: 50,
+                ..open_order_entry(expiring, 1, 1)
+            },
+            0,
+        );
+        while (index.len as usize) < MAX_INDEXED_ORDERS {
+            index.insert(open_order_entry(Pubkey::new_unique(), 1, 1), 0);
+        }
+
+        // Full at 50: a bid no better than the worst listed one stays out.
+        let newcomer = Pubkey::new_unique();
+        index.insert(
+            OpenOrderEntry {
+                price: 90,
+                ..open_order_entry(newcomer, 1, 1)
+            },
+            50,
+        );
+        assert_eq!(index.unindexed_orders, 2);
+        // After 50 the expired entry is pruned to make room.
+        index.insert(open_order_entry(newcomer, 2, 1), 51);
+        assert!(index.entries.iter().all(|entry| entry.user != expiring));
+        assert!(index.entries.iter().any(|entry| entry.user == newcomer));
+        assert_eq!(index.unindexed_orders, 3);
+        // A better bid pushes out the worst one, at 90.
+        index.insert(
+            OpenOrderEntry {
+                price: 101,
+                ..open_order_entry(newcomer, 3, 1)
+            },
+            51,
+        );
+        assert!(index.entries.iter().all(|entry| entry.user != low));
+        assert_eq!(index.len as usize, MAX_INDEXED_ORDERS);
+        assert_eq!(index.unindexed_orders, 4);
+    }
+
+    fn recovery_committee(members: &[Pubkey], threshold: u8) -> RecoveryCommittee {
+        let mut committee = RecoveryCommittee {
+            member_count: members.len() as u8,
+            threshold,
+            ..RecoveryCommittee::default()
+        };
+        committee.members[..members.len()].copy_from_slice(members);
+        committee
+    }
+
+    #[test]
+    fn recovery_committee_validation() {
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert!(RecoveryCommittee::default().validate().is_ok());
+        assert!(recovery_committee(&[a, b], 2).validate().is_ok());
+        assert!(recovery_committee(&[a, b], 3).validate().is_err());
+        assert!(recovery_committee(&[a, a], 1).validate().is_err());
+        assert!(recovery_committee(&[a, Pubkey::default()], 1).validate().is_err());
+        // Members without a threshold would be a committee that can never act.
+        assert!(recovery_committee(&[a], 0).validate().is_err());
+    }
+
+    #[test]
+    fn authority_recovery_waits_for_threshold_timelock_and_challenge() {
+        let (a, b, c) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let new_authority = Pubkey::new_unique();
+        let mut market_state = test_market_state();
+        market_state.recovery_committee = recovery_committee(&[a, b, c], 2);
+
+        assert!(market_state
+            .propose_recovery(&Pubkey::new_unique(), new_authority, 0)
+            .is_err());
+        market_state.propose_recovery(&a, new_authority, 0).unwrap();
+        assert!(market_state.propose_recovery(&b, new_authority, 0).is_err());
+        let late = RECOVERY_TIMELOCK_SECS + RECOVERY_CHALLENGE_SECS;
+        assert!(market_state.execute_recovery(late).is_err()); // 1 of 2 approvals
+
+        // Approved near the end of the timelock: the challenge period still applies.
+        let approved = RECOVERY_TIMELOCK_SECS - 1;
+        market_state.approve_recovery(&b, approved).unwrap();
+        assert_eq!(market_state.pending_recovery.approved_ts, approved);
+        market_state.approve_recovery(&c, approved + 10).unwrap();
+        assert_eq!(market_state.pending_recovery.approved_ts, approved);
+        assert!(market_state.execute_recovery(RECOVERY_TIMELOCK_SECS).is_err());
+        let ready = approved + RECOVERY_CHALLENGE_SECS;
+        market_state.execute_recovery(ready).unwrap();
+        assert_eq!(market_state.authority, new_authority);
+        assert!(!market_state.pending_recovery.is_pending());
+    }
+
+    #[test]
+    fn committee_can_reject_or_outwait_a_bogus_recovery() {
+        let (a, b, c) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let bogus = Pubkey::new_unique();
+        let mut market_state = test_market_state();
+        market_state.recovery_committee = recovery_committee(&[a, b, c], 2);
+
+        // With 2 of 3 needed, the second rejection leaves too few members to approve.
+        market_state.propose_recovery(&a, bogus, 0).unwrap();
+        assert!(!market_state.reject_recovery(&b, 10).unwrap());
+        assert!(market_state.reject_recovery(&c, 20).unwrap());
+        assert!(!market_state.pending_recovery.is_pending());
+
+        // A proposal nobody else approves lapses when its timelock ends.
+        market_state.propose_recovery(&a, bogus, 100).unwrap();
+        let lapsed = 100 + RECOVERY_TIMELOCK_SECS + 1;
+        let new_authority = Pubkey::new_unique();
+        assert!(market_state
+            .propose_recovery(&b, new_authority, lapsed - 1)
+            .is_err());
+        let err = market_state.approve_recovery(&c, lapsed).unwrap_err();
+        assert_eq!(
+            error_code(&err),
+            FeeError::RecoveryLapsed as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+        market_state.propose_recovery(&b, new_authority, lapsed).unwrap();
+        assert_eq!(market_state.pending_recovery.new_authority, new_authority);
+        assert_eq!(market_state.pending_recovery.approvals, 0b010);
+
+        // Once approved, only the current authority can still stop it.
+        market_state.approve_recovery(&c, lapsed).unwrap();
+        assert!(market_state.reject_recovery(&a, lapsed).is_err());
+    }
+
+    #[test]
+    fn markets_without_a_committee_cannot_be_recovered() {
+        let mut market_state = test_market_state();
+        assert!(market_state
+            .propose_recovery(&Pubkey::default(), Pubkey::new_unique(), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn event_cursor_chains_events_in_order() {
+        let mut cursor = EventCursor {
+            market: Pubkey::new_unique(),
+            event_seq: 0,
+            last_slot: 0,
+            rolling_hash: [0; 32],
+        };
+        assert_eq!(serialized_len(&cursor), EventCursor::SIZE);
+        let canceled = |event_seq| OrderCanceled {
+            user: Pubkey::default(),
+            order_index: 0,
+            canceled_size: 1,
+            event_seq,
+        };
+
+        let first = canceled(cursor.next_seq());
+        cursor.record(&first, 10);
+        let second = canceled(cursor.next_seq());
+        cursor.record(&second, 11);
+        assert_eq!((first.event_seq, second.event_seq), (1, 2));
+        assert_eq!((cursor.event_seq, cursor.last_slot), (2, 11));
+
+        // An indexer replaying the same logged events lands on the same hash; a gap does not.
+        use anchor_lang::Event;
+        let replayed = hashv(&[&[0; 32], &first.data()]).to_bytes();
+        assert_eq!(hashv(&[&replayed, &second.data()]).to_bytes(), cursor.rolling_hash);
+        let skipped = hashv(&[&[0; 32], &second.data()]).to_bytes();
+        assert_ne!(skipped, cursor.rolling_hash);
+    }
+
+    #[test]
+    fn audit_checks_owner_discriminator_and_layout() {
+        let mut data = vec![0u8; 8 + EventCursor::SIZE];
+        data[..8].copy_from_slice(&EventCursor::DISCRIMINATOR);
+        assert_eq!(
+            audit_account(&crate::ID, &data),
+            (Some(AccountKind::EventCursor), AccountAuditStatus::Healthy)
+        );
+        assert_eq!(
+            audit_account(&Pubkey::new_unique(), &data),
+            (None, AccountAuditStatus::WrongOwner)
+        );
+
+        // Neither the current layout nor one a migration covers.
+        data.truncate(8 + EventCursor::SIZE - 8);
+        assert_eq!(
+            audit_account(&crate::ID, &data).1,
+            AccountAuditStatus::SizeMismatch {
+                expected: (8 + EventCursor::SIZE) as u32,
+                actual: EventCursor::SIZE as u32,
+            }
+        );
+
+        data[0] ^= 0xff;
+        assert_eq!(
+            audit_account(&crate::ID, &data),
+            (None, AccountAuditStatus::UnknownDiscriminator)
+        );
+        assert_eq!(
+            audit_account(&crate::ID, &[]),
+            (None, AccountAuditStatus::UnknownDiscriminator)
+        );
+
+        // Discriminators must be distinct for the audit to identify types at all.
+        for (i, a) in AccountKind::ALL.iter().enumerate() {
+            for b in &AccountKind::ALL[i + 1..] {
+                assert_ne!(a.layout().0, b.layout().0);
+            }
+        }
+    }
+
+    #[test]
+    fn skew_rebate_favors_the_thin_side() {
+        let skew = SkewRebate {
+            max_bonus_bps: 5_000,
+            max_haircut_bps: 2_000,
+        };
+        // Fully bid-heavy: asks are thin.
+        assert_eq!(skew.adjustment(OrderSide::Ask, 10_000, 200).unwrap(), 100);
+        assert_eq!(skew.adjustment(OrderSide::Bid, 10_000, 200).unwrap(), -40);
+        // Half as lopsided toward asks: half the adjustment, sides swapped.
+        assert_eq!(skew.adjustment(OrderSide::Bid, -5_000, 200).unwrap(), 50);
+        assert_eq!(skew.adjustment(OrderSide::Ask, -5_000, 200).unwrap(), -20);
+        assert_eq!(skew.adjustment(OrderSide::Ask, 0, 200).unwrap(), 0);
+
+        let summary = OrderBookSummary {
+            bid_size: 30,
+            ask_size: 10,
+            ..OrderBookSummary::default()
+        };
+        assert_eq!(summary.imbalance_bps(), 5_000);
+        assert_eq!(OrderBookSummary::default().imbalance_bps(), 0);
+    }
+
+    #[test]
+    fn fills_do_exactly_what_was_quoted() {
+        fn fill_as_quoted(
+            market_state: &mut MarketState,
+            maker: &mut UserState,
+            taker: &mut UserState,
+            fill_size: u64,
+        ) -> FillQuote {
+            let params = FillParams {
+                maker_order_index: 0,
+                fill_size,
+                taker_limit_price: 101,
+                worst_price: 0,
+                book_imbalance_bps: 10_000,
+            };
+            let quote = quote_fill(market_state, maker, taker, params, 10).unwrap();
+            let fill = execute_fill(market_state, maker, taker, params, &FixedClock(10)).unwrap();
+            assert_eq!(
+                (fill.trade_size, fill.execution_price, fill.notional),
+                (quote.trade_size, quote.execution_price, quote.notional)
+            );
+            assert_eq!(fill.taker_fee, quote.fees.taker_fee);
+            assert_eq!(fill.maker_rebate, quote.fees.maker_rebate);
+            assert_eq!(fill.referral_reward, quote.fees.referral_reward);
+            assert_eq!(fill.net_fee, quote.net_fee);
+            assert_eq!(fill.taker_rebate, quote.taker_rebate);
+            assert_eq!(fill.maker_rebate_haircut, quote.rebate_haircut);
+            assert_eq!(fill.maker_skew_adjustment, quote.skew_adjustment);
+            assert_eq!(fill.maker_promo_rebate, quote.promo_rebate);
+            assert_eq!(fill.taker_bonus_discount, quote.bonus_discount);
+            quote
+        }
+
+        let mut market_state = test_market_state();
+        market_state.referral_rate = 1;
+        market_state.current_epoch = 3;
+        market_state.penalty_rebate_haircut_bps = 5_000;
+        market_state.skew_rebate = SkewRebate {
+            max_bonus_bps: 10_000,
+            max_haircut_bps: 0,
+        };
+        market_state.price_improvement_rebate_bps = 1;
+        market_state.total_fees_collected = 10_000;
+        market_state.set_promotion_budget(1_000).unwrap();
+        market_state.promo_maker_rebate_bps = 5;
+        market_state.first_fill_bonus = FirstFillBonus {
+            max_fills: 1,
+            window_secs: 100,
+            discount_bps: 5_000,
+        };
+
+        // A penalized maker's promotional order, taken by a referred newcomer.
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        maker.activity_epoch = 3;
+        maker.penalty_epoch = 3;
+        let promo_reserve = market_state.reserve_promotion(400_000, 0).unwrap();
+        maker
+            .insert_order(Order {
+                promo_reserve,
+                ..fixed_order(OrderSide::Ask, 100, 400_000, 0, 0)
+            })
+            .unwrap();
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.referrer = Some(Pubkey::new_unique());
+        taker.registered_ts = 1;
+
+        let quote = fill_as_quoted(&mut market_state, &mut maker, &mut taker, 300_000);
+        assert_eq!(quote.fees.referral_reward, 30);
+        assert_eq!(quote.rebate_haircut, 30);
+        assert_eq!(quote.skew_adjustment, 30);
+        assert_eq!(quote.taker_rebate, 30);
+        assert_eq!(quote.promo_rebate, 150);
+        assert_eq!(quote.bonus_discount, 75);
+
+        // With the epoch's promotion cap binding, part of the reserve goes back instead.
+        market_state.promotion_epoch_cap = 20;
+        let quote = fill_as_quoted(&mut market_state, &mut maker, &mut taker, 100_000);
+        assert_eq!((quote.promo_rebate, quote.promo_released), (20, 30));
+        assert_eq!(market_state.promotion_reserved, 0);
+    }
+
+    #[test]
+    fn skew_bonus_is_paid_from_net_fee() {
+        let mut market_state = test_market_state();
+        market_state.skew_rebate = SkewRebate {
+            max_bonus_bps: 10_000,
+            max_haircut_bps: 0,
+        };
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1_000_000, 0, 0))
+            .unwrap();
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 1_000_000,
+            book_imbalance_bps: 10_000,
+            ..FillParams::default()
+        };
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        // A 2x rebate on the thin side, taken from the 300 the market would have kept.
+        assert_eq!(fill.maker_skew_adjustment, 200);
+        assert_eq!(fill.maker_rebate, 400);
+        assert_eq!(fill.net_fee, 100);
+    }
+
+    #[test]
+    fn open_order_index_summary_measures_imbalance() {
+        let user = Pubkey::new_unique();
+        let mut index = empty_open_order_index();
+        index.insert(open_order_entry(user, 1, 30), 0);
+        index.insert(
+            OpenOrderEntry {
+                side: OrderSide::Ask,
+                price: 101,
+                ..open_order_entry(user, 2, 10)
+            },
+            0,
+        );
+        let summary = index.summary(0).unwrap();
+        assert_eq!((summary.bid_count, summary.ask_count), (1, 1));
+        assert_eq!((summary.best_bid, summary.best_ask), (100, 101));
+        assert_eq!(summary.imbalance_bps(), 5_000);
+    }
+
+    #[test]
+    fn skew_imbalance_ignores_far_off_and_expired_size() {
+        let (user, spoofer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ask = |order_id, price, size| OpenOrderEntry {
+            side: OrderSide::Ask,
+            price,
+            ..open_order_entry(user, order_id, size)
+        };
+        let mut index = empty_open_order_index();
+        index.insert(open_order_entry(user, 1, 30), 0);
+        index.insert(ask(2, 1_000, 30), 0);
+        // A huge bid far below the touch and another about to expire.
+        index.insert(
+            OpenOrderEntry {
+                price: 50,
+                ..open_order_entry(spoofer, 1, 1_000_000)
+            },
+            0,
+        );
+        index.insert(
+            OpenOrderEntry {
+                expiry_timestamp: 10,
+                ..open_order_entry(spoofer, 2, 1_000_000)
+            },
+            0,
+        );
+        // Within 50 bps of the best ask (1_000) counts, beyond it does not.
+        index.insert(ask(3, 1_005, 10), 0);
+        index.insert(ask(4, 1_006, 1_000_000), 0);
+
+        let summary = index.banded_summary(11).unwrap();
+        assert_eq!((summary.bid_size, summary.ask_size), (30, 40));
+        assert_eq!(summary.bid_count, 2); // counts still include every live order
+
+        let mut market_state = test_market_state();
+        market_state.skew_rebate = SkewRebate {
+            max_bonus_bps: 10_000,
+            max_haircut_bps: 0,
+        };
+        assert_eq!(
+            market_state.book_imbalance_bps(&index, 11).unwrap(),
+            summary.imbalance_bps()
+        );
+        // While the bid is live it sits at the touch, so it counts.
+        assert!(market_state.book_imbalance_bps(&index, 10).unwrap() > 9_000);
+    }
+
+    #[test]
+    fn promotional_orders_reserve_and_draw_their_budget() {
+        let mut market_state = test_market_state();
+        market_state.total_fees_collected = 1_000;
+        market_state.set_promotion_budget(800).unwrap();
+        market_state.promo_maker_rebate_bps = 5; // above the 5 bps taker fee: maker nets more
+
+        // 1_000_000 at 5 bps would reserve 500; one maker holds at most a quarter, 200.
+        assert_eq!(market_state.reserve_promotion(1_000_000, 0).unwrap(), 200);
+        assert_eq!(market_state.reserve_promotion(1_000_000, 200).unwrap(), 0);
+        // Other makers take the rest, then nothing is left to reserve.
+        assert_eq!(market_state.reserve_promotion(400_000, 0).unwrap(), 200);
+        assert_eq!(market_state.reserve_promotion(400_000, 150).unwrap(), 50);
+        let promo_reserve = market_state.reserve_promotion(400_000, 0).unwrap();
+        assert_eq!(promo_reserve, 200);
+        assert_eq!(market_state.available_promotion_budget(), 150);
+        assert!(market_state.reserve_promotion(400_000, 0).is_err());
+        assert_eq!(market_state.reserve_promotion(300_000, 0).unwrap(), 150);
+        assert!(market_state.reserve_promotion(2_000, 0).is_err());
+        // The budget cannot be pulled back under what open orders were promised.
+        assert!(market_state.set_promotion_budget(799).is_err());
+
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(Order {
+                promo_reserve,
+                ..fixed_order(OrderSide::Ask, 100, 400_000, 0, 0)
+            })
+            .unwrap();
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 100_000,
+            ..FillParams::default()
+        };
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.maker_promo_rebate, 50);
+        assert_eq!(fill.maker_rebate, 20 + 50);
+        assert_eq!(fill.taker_fee, 50);
+        assert_eq!(maker.orders[0].promo_reserve, 150);
+        assert_eq!(maker.promo_reserved(), 150);
+        assert_eq!(market_state.promotion_budget, 750);
+        assert_eq!(market_state.promotion_reserved, 750);
+
+        // Canceling hands the rest of the reserve back.
+        market_state.release_promotion(maker.orders[0].promo_reserve);
+        assert_eq!(market_state.available_promotion_budget(), 150);
+    }
+
+    fn valid_market_params() -> MarketParams {
+        MarketParams {
+            fees: FeeParameters {
+                maker_rebate_rate: 2,
+                taker_fee_rate: 5,
+                referral_rate: 1,
+            },
+            referral_basis: ReferralBasis::FillSize,
+            fee_tiers: vec![FeeTier {
+                min_volume: 1_000,
+                taker_fee_rate: 4,
+                maker_rebate_rate: 2,
+            }],
+            price_decimals: 6,
+            max_open_orders: MAX_ORDERS as u8,
+            referral_cap_bps: 10_000,
+            oracle_authority: Pubkey::new_unique(),
+            max_reference_age_secs: 60,
+            recovery_committee: None,
+            fee_precision: FeePrecision::Bps,
+            min_fill_notional: 1_000,
+            default_order_ttl_secs: 60,
+            max_order_ttl_secs: 3_600,
+            skew_rebate: SkewRebate {
+                max_bonus_bps: 1_000,
+                max_haircut_bps: 1_000,
+            },
+            promo_maker_rebate_bps: 1,
+            promotion_epoch_cap: 0,
+        }
+    }
+
+    #[test]
+    fn validate_market_params_reports_every_issue() {
+        assert_eq!(
+            valid_market_params().validate(),
+            MarketParamsValidation {
+                valid: true,
+                issues: vec![],
+            }
+        );
+
+        let code = |error: FeeError| error as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+        let params = MarketParams {
+            price_decimals: MAX_PRICE_DECIMALS + 1,
+            max_open_orders: 0,
+            fee_tiers: vec![FeeTier {
+                min_volume: 1_000,
+                taker_fee_rate: 6, // a tier may not charge more than the base rate
+                maker_rebate_rate: 2,
+            }],
+            default_order_ttl_secs: 7_200, // outlives the maximum
+            promo_maker_rebate_bps: BPS_DENOMINATOR + 1,
+            ..valid_market_params()
+        };
+        assert!(params.require_valid().is_err());
+        let validation = params.validate();
+        assert!(!validation.valid);
+        assert_eq!(
+            validation.issues,
+            vec![
+                MarketParamIssue {
+                    param: MarketParam::FeeTiers,
+                    error_code: code(FeeError::InvalidFeeTiers),
+                },
+                MarketParamIssue {
+                    param: MarketParam::PriceDecimals,
+                    error_code: code(FeeError::InvalidPriceDecimals),
+                },
+                MarketParamIssue {
+                    param: MarketParam::MaxOpenOrders,
+                    error_code: code(FeeError::InvalidMaxOpenOrders),
+                },
+                MarketParamIssue {
+                    param: MarketParam::OrderTtl,
+                    error_code: code(FeeError::InvalidOrderTtl),
+                },
+                MarketParamIssue {
+                    param: MarketParam::PromoMakerRebate,
+                    error_code: code(FeeError::InvalidFeeConfiguration),
+                },
+            ]
+        );
+
+        // Tier rates are checked in the bundle's precision: 20_000 is over 100% in bps.
+        let tiers = vec![FeeTier {
+            min_volume: 1_000,
+            taker_fee_rate: 20_000,
+            maker_rebate_rate: 20,
+        }];
+        let pips = MarketParams {
+            fees: FeeParameters {
+                maker_rebate_rate: 20,
+                taker_fee_rate: 50_000,
+                referral_rate: 10,
+            },
+            fee_tiers: tiers.clone(),
+            fee_precision: FeePrecision::Pips,
+            ..valid_market_params()
+        };
+        assert!(pips.require_valid().is_ok());
+        let bps = MarketParams {
+            fee_tiers: tiers,
+            ..valid_market_params()
+        };
+        assert_eq!(bps.validate().issues[0].param, MarketParam::FeeTiers);
+    }
+
+    #[test]
+    fn fills_sample_a_fresh_oracle_price() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 20, 0, 0))
+            .unwrap();
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 10,
+            ..FillParams::default()
+        };
+
+        // No oracle published yet.
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!((fill.oracle_price, fill.oracle_confidence), (0, 0));
+
+        market_state.reference_price = 101;
+        market_state.reference_confidence = 2;
+        market_state.reference_price_ts = 100;
+        market_state.max_reference_age_secs = 30;
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(120))
+                .unwrap();
+        assert_eq!((fill.oracle_price, fill.oracle_confidence), (101, 2));
+        assert_eq!(market_state.oracle_sample(131), (0, 0)); // stale
+    }
+
+    #[test]
+    fn only_empty_markets_are_closable() {
+        let mut market_state = test_market_state();
+        assert!(market_state.is_closable());
 
-// ----------------------------------
-// ORDER STRUCT
-// ----------------------------------
+        market_state.user_count = 1;
+        assert!(!market_state.is_closable());
+        market_state.user_count = 0;
+        market_state.outstanding_claims = 1;
+        assert!(!market_state.is_closable());
+        market_state.outstanding_claims = 0;
+        market_state.total_fees_collected = 1;
+        assert!(!market_state.is_closable());
+        market_state.total_fees_collected = 0;
+        market_state.promotion_budget = 1;
+        assert!(!market_state.is_closable());
+        market_state.promotion_budget = 0;
+        market_state.liquidity_reward_pool = 1;
+        assert!(!market_state.is_closable());
+        market_state.liquidity_reward_pool = 0;
 
-pub const MAX_ORDERS: usize = 5;
+        // Reward programs and checkpoints have to be closed first.
+        market_state.add_auxiliary_account().unwrap();
+        assert!(!market_state.is_closable());
+    }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
-pub struct Order {
-    pub side: OrderSide,
-    pub price: u64,
-    pub size_remaining: u64,
-    pub creation_timestamp: i64,
-    pub expiry_timestamp: i64,
-}
+    #[test]
+    fn rent_reclaim_waits_for_idle_empty_account() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        assert!(!user_state.is_rent_reclaimable(i64::MAX));
 
-impl Order {
-    // side (enum) as 1 byte
-    pub const SIZE: usize =
-          1  // side
-        + 8  // price
-        + 8  // size_remaining
-        + 8  // creation_timestamp
-        + 8; // expiry_timestamp
-}
+        user_state.reclaim_requested_ts = 100;
+        assert!(!user_state.is_rent_reclaimable(100 + RENT_RECLAIM_DELAY_SECS - 1));
+        assert!(user_state.is_rent_reclaimable(100 + RENT_RECLAIM_DELAY_SECS));
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
-pub enum OrderSide {
-    Bid,
-    Ask,
-}
+        // Activity after the request restarts the delay.
+        user_state.last_activity_ts = 500;
+        assert!(!user_state.is_rent_reclaimable(100 + RENT_RECLAIM_DELAY_SECS));
+        assert!(user_state.is_rent_reclaimable(500 + RENT_RECLAIM_DELAY_SECS));
 
-impl Default for OrderSide {
-    fn default() -> Self {
-        OrderSide::Bid
+        let now = 500 + RENT_RECLAIM_DELAY_SECS;
+        user_state.claimable_rewards = 1;
+        assert!(!user_state.is_rent_reclaimable(now));
+        user_state.claimable_rewards = 0;
+        user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 1, 0, 0))
+            .unwrap();
+        assert!(!user_state.is_rent_reclaimable(now));
     }
-}
 
-// ----------------------------------
-// ERRORS
-// ----------------------------------
+    #[test]
+    fn market_health_summarizes_market() {
+        let mut market_state = test_market_state();
+        market_state.total_fees_collected = 10;
+        market_state.outstanding_claims = 12;
+        market_state.reference_price_ts = 100;
+        market_state.max_reference_age_secs = 60;
+        let global_config = GlobalConfig {
+            authority: Pubkey::new_unique(),
+            disabled_instructions: ProgramInstruction::FillOrder.mask(),
+        };
+        let mut market_health = MarketHealth {
+            market: Pubkey::new_unique(),
+            treasury_balance: 0,
+            outstanding_liabilities: 0,
+            liabilities_covered: true,
+            open_orders: 0,
+            current_epoch: 0,
+            last_epoch_roll_ts: 0,
+            disabled_instructions: 0,
+            market_status: MarketStatus::Uninitialized,
+            reference_price_ts: 0,
+            oracle_stale: false,
+            last_update_ts: 0,
+        };
+        market_health.record_order_opened();
+        market_health.record_order_opened();
+        market_health.record_order_closed();
+        market_health.refresh(&market_state, &global_config, 161);
 
-#[error_code]
-pub enum FeeError {
-    #[msg("Overflow or underflow detected.")]
-    Overflow,
-    #[msg("Configuration leads to negative net fee.")]
-    NegativeFee,
-    #[msg("Unauthorized operation.")]
-    Unauthorized,
-    #[msg("No open orders found.")]
-    NoOpenOrders,
-    #[msg("Invalid fee configuration.")]
-    InvalidFeeConfiguration,
-    #[msg("Insufficient funds.")]
-    InsufficientFunds,
-    #[msg("No free slot to place a new order.")]
-    NoFreeOrderSlot,
-    #[msg("Invalid order index.")]
-    InvalidOrderIndex,
-    #[msg("Order is expired.")]
-    OrderExpired,
-}
+        assert_eq!(market_health.open_orders, 1);
+        assert!(!market_health.liabilities_covered);
+        assert!(market_health.oracle_stale);
+        assert_eq!(
+            market_health.disabled_instructions,
+            ProgramInstruction::FillOrder.mask()
+        );
+        assert_eq!(serialized_len(&market_health), MarketHealth::SIZE);
+    }
 
-// ----------------------------------
-// EVENTS
-// ----------------------------------
+    #[test]
+    fn large_fill_threshold_uses_notional() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Bid, 250, 40, 0, 0))
+            .unwrap();
 
-#[event]
-pub struct FeeParametersUpdated {
-    pub maker_rebate_bps: u16,
-    pub taker_fee_bps: u16,
-    pub referral_bps: u16,
-}
+        let params = FillParams {
+            maker_order_index: 0,
+            fill_size: 40,
+            ..FillParams::default()
+        };
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.notional, 10_000);
+        assert_eq!(fill.maker_side, OrderSide::Bid);
 
-#[event]
-pub struct OrderPlaced {
-    pub user: Pubkey,
-    pub side: OrderSide,
-    pub price: u64,
-    pub size: u64,
-    pub expiry_timestamp: i64,
-}
+        // Disabled by default, inclusive once set.
+        assert!(!market_state.is_large_fill(fill.notional));
+        market_state.large_fill_notional = 10_000;
+        assert!(market_state.is_large_fill(fill.notional));
+        market_state.large_fill_notional = 10_001;
+        assert!(!market_state.is_large_fill(fill.notional));
+    }
 
-#[event]
-pub struct OrderCanceled {
-    pub user: Pubkey,
-    pub order_index: u8,
-    pub canceled_size: u64,
-}
+    #[test]
+    fn fills_below_min_notional_are_rejected() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Bid, 250, 40, 0, 0))
+            .unwrap();
+        market_state.min_fill_notional = 5_000;
 
-#[event]
-pub struct OrderFilled {
-    pub maker: Pubkey,
-    pub taker: Pubkey,
-    pub trade_size: u64,
-    pub maker_rebate: u64,
-    pub taker_fee: u64,
-    pub referral_reward: u64,
-}
+        // 19 * 250 = 4_750 quote units.
+        let dust = FillParams {
+            maker_order_index: 0,
+            fill_size: 19,
+            ..FillParams::default()
+        };
+        let below_floor =
+            FeeError::FillBelowMinNotional as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+        let err = execute_fill(&mut market_state, &mut maker, &mut taker, dust, &FixedClock(0))
+            .unwrap_err();
+        assert_eq!(error_code(&err), below_floor);
+        assert_eq!(maker.orders[0].size_remaining, 40);
 
-#[event]
-pub struct FeesWithdrawn {
-    pub authority: Pubkey,
-    pub amount: u64,
-}
+        let params = FillParams {
+            fill_size: 37,
+            ..dust
+        };
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, params, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.notional, 9_250);
 
-#[event]
-pub struct LiquidityRewardsDistributed {
-    pub user: Pubkey,
-    pub distributed_amount: u64,
+        // The 3 left are under the floor, but taking all of them closes the order out.
+        let err = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                fill_size: 2,
+                ..dust
+            },
+            &FixedClock(0),
+        )
+        .unwrap_err();
+        assert_eq!(error_code(&err), below_floor);
+        let fill =
+            execute_fill(&mut market_state, &mut maker, &mut taker, dust, &FixedClock(0))
+                .unwrap();
+        assert_eq!(fill.notional, 750);
+        assert_eq!(maker.orders[0].size_remaining, 0);
+
+        // Orders that could never fill at the floor are not placed.
+        let err = market_state.require_min_order_notional(19, 250).unwrap_err();
+        assert_eq!(
+            error_code(&err),
+            FeeError::OrderBelowMinNotional as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+        assert!(market_state.require_min_order_notional(20, 250).is_ok());
+    }
+
+    #[test]
+    fn top_level_only_markets_reject_cpi_callers() {
+        use anchor_lang::solana_program::instruction::BorrowedInstruction;
+
+        let check = |market_state: &MarketState, stack_height: usize, program_id: Pubkey| {
+            let instruction = BorrowedInstruction {
+                program_id: &program_id,
+                accounts: vec![],
+                data: &[],
+            };
+            let mut data = sysvar::instructions::construct_instructions_data(&[instruction]);
+            let mut lamports = 0;
+            let key = sysvar::instructions::ID;
+            let owner = sysvar::ID;
+            let instructions = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &owner,
+                false,
+                0,
+            );
+            market_state.require_caller_allowed_at(stack_height, &instructions)
+        };
+        let top_level = TRANSACTION_LEVEL_STACK_HEIGHT;
+        let rejected = |result: Result<()>| {
+            error_code(&result.unwrap_err())
+                == FeeError::CpiNotAllowed as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        };
+
+        let mut market_state = test_market_state();
+        assert!(check(&market_state, top_level + 1, Pubkey::new_unique()).is_ok());
+        market_state.top_level_only = true;
+        assert!(check(&market_state, top_level, crate::ID).is_ok());
+        // Called through CPI, the top-level instruction belongs to the caller. Either check
+        // rejects it on its own.
+        assert!(rejected(check(&market_state, top_level + 1, Pubkey::new_unique())));
+        assert!(rejected(check(&market_state, top_level, Pubkey::new_unique())));
+        // Re-entered through another program from one of this program's own instructions.
+        assert!(rejected(check(&market_state, top_level + 1, crate::ID)));
+    }
+
+    #[test]
+    fn notional_is_scaled_by_price_decimals() {
+        // 2.5 quote per base unit with 6 decimals.
+        assert_eq!(notional_value(40, 2_500_000, 6).unwrap(), 100);
+        assert_eq!(notional_value(1, 999_999, 6).unwrap(), 0);
+        assert_eq!(notional_value(40, 250, 0).unwrap(), 10_000);
+
+        assert_eq!(price_scale(MAX_PRICE_DECIMALS).unwrap(), 10u64.pow(18));
+        assert!(price_scale(MAX_PRICE_DECIMALS + 1).is_err());
+
+        // Scaling can bring an oversized product back in range, but not past u64.
+        assert_eq!(notional_value(u64::MAX, 1_000, 3).unwrap(), u64::MAX);
+        assert!(notional_value(u64::MAX, 1_001, 3).is_err());
+        assert!(notional_value(u64::MAX, u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn taker_effective_price_rounds_against_taker() {
+        let bps = FeePrecision::Bps;
+        assert_eq!(taker_effective_price(OrderSide::Ask, 101, 5, bps).unwrap(), 102);
+        assert_eq!(taker_effective_price(OrderSide::Bid, 101, 5, bps).unwrap(), 100);
+        assert_eq!(taker_effective_price(OrderSide::Bid, 101, 0, bps).unwrap(), 101);
+        // 50 pips is half a bps.
+        let pips = FeePrecision::Pips;
+        assert_eq!(taker_effective_price(OrderSide::Ask, 20_000, 50, pips).unwrap(), 20_001);
+    }
+
+    #[test]
+    fn maker_fills_are_attributed_to_strategy_tags() {
+        let mut market_state = test_market_state();
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(Order {
+                strategy_id: 42,
+                ..fixed_order(OrderSide::Ask, 100, 10_000, 0, 0)
+            })
+            .unwrap();
+
+        let fill = execute_fill(
+            &mut market_state,
+            &mut maker,
+            &mut taker,
+            FillParams {
+                maker_order_index: 0,
+                fill_size: 10_000,
+                taker_limit_price: 0,
+                worst_price: 0,
+                book_imbalance_bps: 0,
+            },
+            &FixedClock(0),
+        )
+        .unwrap();
+        assert_eq!(fill.maker_strategy_id, 42);
+
+        let stats = maker.strategy_stats[0];
+        assert_eq!(stats.strategy_id, 42);
+        assert_eq!(stats.volume, 10_000);
+        assert_eq!(stats.rebates_earned, 2);
+        assert_eq!(stats.fill_count, 1);
+    }
+
+    #[test]
+    fn strategy_table_is_bounded() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        for strategy_id in 1..=MAX_STRATEGY_TAGS as u16 {
+            user_state
+                .insert_order(Order {
+                    strategy_id,
+                    ..fixed_order(OrderSide::Bid, 100, 1, 0, 0)
+                })
+                .unwrap();
+        }
+        user_state.cancel_order(0, &test_market_state(), &FixedClock(0)).unwrap();
+
+        // A known tag still fits, a new one does not.
+        assert!(user_state
+            .insert_order(Order {
+                strategy_id: 1,
+                ..fixed_order(OrderSide::Bid, 100, 1, 0, 0)
+            })
+            .is_ok());
+        user_state.cancel_order(0, &test_market_state(), &FixedClock(0)).unwrap();
+        assert!(user_state
+            .insert_order(Order {
+                strategy_id: 99,
+                ..fixed_order(OrderSide::Bid, 100, 1, 0, 0)
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn roll_epoch_waits_for_duration() {
+        let mut market_state = test_market_state();
+        assert!(market_state.roll_epoch(&FixedClock(i64::MAX)).is_err());
+
+        market_state.epoch_duration_secs = 100;
+        assert!(market_state.roll_epoch(&FixedClock(99)).is_err());
+        market_state.roll_epoch(&FixedClock(100)).unwrap();
+        assert_eq!(market_state.current_epoch, 1);
+        assert_eq!(market_state.epoch_start_ts, 100);
+    }
+
+    #[test]
+    fn duplicate_claim_keys_are_rejected_within_history() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        assert!(user_state.record_claim_key(0).is_err());
+
+        user_state.record_claim_key(7).unwrap();
+        assert!(user_state.record_claim_key(7).is_err());
+
+        // Once enough newer claims push it out of the history, the key is forgotten.
+        for key in 100..100 + CLAIM_KEY_HISTORY as u64 {
+            user_state.record_claim_key(key).unwrap();
+        }
+        user_state.record_claim_key(7).unwrap();
+    }
+
+    #[test]
+    fn attestation_size_and_digest() {
+        let mut user_state = max_user_state();
+        let mut attestation = VolumeAttestation {
+            user: Pubkey::default(),
+            market: Pubkey::default(),
+            maker_volume: 0,
+            taker_volume: 0,
+            maker_rebates_earned: 0,
+            taker_fees_paid: 0,
+            taker_rebates_earned: 0,
+            issued_at: 0,
+            digest: [0; 32],
+        };
+        attestation.record(&user_state, &FixedClock(i64::MAX));
+        assert_eq!(serialized_len(&attestation), VolumeAttestation::SIZE);
+        assert_eq!(attestation.digest, attestation.compute_digest());
+
+        // Any change to the stats changes the digest.
+        let before = attestation.digest;
+        user_state.taker_volume -= 1;
+        attestation.record(&user_state, &FixedClock(i64::MAX));
+        assert_ne!(attestation.digest, before);
+    }
+
+    #[test]
+    fn book_summary_aggregates_resting_orders() {
+        let market = Pubkey::new_unique();
+        let mut first = empty_user_state(market);
+        let mut second = empty_user_state(market);
+        first.insert_order(fixed_order(OrderSide::Bid, 99, 10, 0, 0)).unwrap();
+        first.insert_order(fixed_order(OrderSide::Ask, 103, 5, 0, 0)).unwrap();
+        second.insert_order(fixed_order(OrderSide::Bid, 100, 7, 0, 0)).unwrap();
+        second.insert_order(fixed_order(OrderSide::Ask, 101, 1, 0, 0)).unwrap();
+
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let summary =
+            OrderBookSummary::build(market, &[(a, first.clone()), (b, second.clone())]).unwrap();
+        assert_eq!(summary.order_count, 4);
+        assert_eq!((summary.best_bid, summary.best_ask), (100, 101));
+        assert_eq!((summary.bid_size, summary.ask_size), (17, 6));
+
+        // Retagging an order's strategy changes the hash.
+        let mut retagged = first.clone();
+        retagged.orders[0].strategy_id = 7;
+        let retagged =
+            OrderBookSummary::build(market, &[(a, retagged), (b, second.clone())]).unwrap();
+        assert_ne!(retagged.orders_hash, summary.orders_hash);
+
+        // Orders are keyed by the UserState address, not the authority stored in it.
+        let mut reowned = first.clone();
+        reowned.authority = Pubkey::new_unique();
+        let reowned =
+            OrderBookSummary::build(market, &[(a, reowned), (b, second.clone())]).unwrap();
+        assert_eq!(reowned.orders_hash, summary.orders_hash);
+        let moved =
+            OrderBookSummary::build(market, &[(b, first.clone()), (b, second.clone())]).unwrap();
+        assert_ne!(moved.orders_hash, summary.orders_hash);
+
+        // Same book, different account order: same aggregates, different hash.
+        let swapped = OrderBookSummary::build(market, &[(b, second), (a, first)]).unwrap();
+        assert_eq!(swapped.best_bid, summary.best_bid);
+        assert_ne!(swapped.orders_hash, summary.orders_hash);
+    }
+
+    #[test]
+    fn pda_helpers_match_seed_literals() {
+        let (market, user) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            pda::user_state_address(&market, &user),
+            Pubkey::find_program_address(&[b"user_state", market.as_ref(), user.as_ref()], &ID)
+        );
+        assert_eq!(
+            pda::global_config_address(),
+            Pubkey::find_program_address(&[b"global_config"], &ID)
+        );
+        assert_ne!(
+            pda::book_checkpoint_address(&market, 0),
+            pda::book_checkpoint_address(&market, 1)
+        );
+    }
+
+    #[test]
+    fn imported_users_keep_tier_status() {
+        let mut market_state = test_market_state();
+        market_state.go_live_ts = 1_000;
+        market_state.fee_tier_count = 1;
+        market_state.fee_tiers[0] = tier(50_000, 3, 2);
+        assert!(!market_state.is_live(999));
+        assert!(market_state.is_live(1_000));
+
+        let referrer = Pubkey::new_unique();
+        let mut user_state = max_user_state();
+        user_state.initialize(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Some(referrer),
+            DEFAULT_MAX_OPEN_ORDERS as usize,
+        );
+        user_state.import_legacy_stats(&LegacyUserStats {
+            maker_volume: 20_000,
+            taker_volume: 30_000,
+            liquidity_score: 7,
+            referrer: Some(referrer),
+            ..LegacyUserStats::default()
+        });
+
+        assert_eq!(user_state.liquidity_score, 7);
+        assert_eq!(user_state.claimable_rebates, 0);
+        assert_eq!(
+            user_state.orders,
+            vec![Order::default(); DEFAULT_MAX_OPEN_ORDERS as usize]
+        );
+        assert_eq!(user_state.referrer, Some(referrer));
+        assert_eq!(
+            market_state.tier_for_volume(user_state.total_volume()).tier,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn stake_account_size_and_compounding() {
+        let mut stake_account = StakeAccount {
+            user: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            staked_amount: 0,
+            total_compounded: 0,
+            last_stake_ts: i64::MAX,
+            unstaking_amount: 0,
+            unstake_available_ts: i64::MAX,
+        };
+        stake_account.compound(7, &FixedClock(100)).unwrap();
+        stake_account.compound(3, &FixedClock(200)).unwrap();
+        assert_eq!(stake_account.staked_amount, 10);
+        assert_eq!(stake_account.total_compounded, 10);
+        assert_eq!(stake_account.last_stake_ts, 200);
+
+        stake_account.staked_amount = u64::MAX;
+        assert!(stake_account.compound(1, &FixedClock(300)).is_err());
+        assert_eq!(serialized_len(&stake_account), StakeAccount::SIZE);
+    }
+
+    #[test]
+    fn unstaked_funds_wait_out_the_cooldown() {
+        let mut stake_account = StakeAccount {
+            user: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            staked_amount: 10,
+            total_compounded: 10,
+            last_stake_ts: 0,
+            unstaking_amount: 0,
+            unstake_available_ts: 0,
+        };
+        assert!(stake_account.request_unstake(11, &FixedClock(0)).is_err());
+        assert!(stake_account.withdraw_unstaked(&FixedClock(0)).is_err());
+
+        stake_account.request_unstake(4, &FixedClock(100)).unwrap();
+        assert_eq!(stake_account.staked_amount, 6);
+        assert_eq!(stake_account.unstake_available_ts, 100 + UNSTAKE_COOLDOWN_SECS);
+        assert!(stake_account
+            .withdraw_unstaked(&FixedClock(99 + UNSTAKE_COOLDOWN_SECS))
+            .is_err());
+
+        // A second request restarts the cooldown for everything still unstaking.
+        stake_account.request_unstake(1, &FixedClock(200)).unwrap();
+        assert!(stake_account
+            .withdraw_unstaked(&FixedClock(100 + UNSTAKE_COOLDOWN_SECS))
+            .is_err());
+        let clock = FixedClock(200 + UNSTAKE_COOLDOWN_SECS);
+        assert_eq!(stake_account.withdraw_unstaked(&clock).unwrap(), 5);
+        assert_eq!(stake_account.unstaking_amount, 0);
+        assert!(stake_account.withdraw_unstaked(&clock).is_err());
+    }
+
+    #[test]
+    fn stake_lifts_the_fee_tier() {
+        let mut market_state = test_market_state();
+        market_state.fee_tiers[0] = tier(1_000, 4, 2);
+        market_state.fee_tier_count = 1;
+        let mut taker = empty_user_state(Pubkey::new_unique());
+        taker.taker_volume = 500;
+
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee_rate, 5);
+
+        // 5 staked count as 500 volume, reaching the tier.
+        taker.staked_amount = 5;
+        assert_eq!(taker.tier_volume(), 1_000);
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee_rate, 4);
+
+        // Unstaking drops back to the base rate.
+        taker.staked_amount = 4;
+        let fees = market_state.effective_fees(0, &taker, 1_000_000).unwrap();
+        assert_eq!(fees.taker_fee_rate, 5);
+    }
+
+    #[test]
+    fn book_checkpoint_size_and_hash() {
+        let mut checkpoint = BookCheckpoint {
+            market: Pubkey::new_unique(),
+            epoch: u64::MAX,
+            user_count: 3,
+            summary: OrderBookSummary {
+                order_count: u32::MAX,
+                best_bid: u64::MAX,
+                ..OrderBookSummary::default()
+            },
+            taken_at: 0,
+            book_hash: [0; 32],
+            user_set_version: u64::MAX,
+            event_seq: u64::MAX,
+            last_user: Pubkey::new_unique(),
+        };
+        assert!(!checkpoint.is_final());
+        checkpoint.finalize(&FixedClock(1));
+        assert!(checkpoint.is_final());
+        assert_eq!(serialized_len(&checkpoint), BookCheckpoint::SIZE);
+        assert_eq!(checkpoint.book_hash, checkpoint.compute_hash());
+    }
+
+    #[test]
+    fn book_checkpoints_build_the_same_summary_across_batches() {
+        let market = Pubkey::new_unique();
+        let mut users: Vec<(Pubkey, UserState)> = (0..5)
+            .map(|i| {
+                let mut user_state = empty_user_state(market);
+                let side = if i % 2 == 0 { OrderSide::Bid } else { OrderSide::Ask };
+                user_state
+                    .insert_order(fixed_order(side, 100 + i, 10 + i, 0, 0))
+                    .unwrap();
+                (Pubkey::new_unique(), user_state)
+            })
+            .collect();
+        users.sort_by_key(|(address, _)| *address);
+        let whole = OrderBookSummary::build(market, &users).unwrap();
+
+        // Each batch picks up the summary the checkpoint carried over from the last one.
+        let mut batched = OrderBookSummary::new(market);
+        for batch in users.chunks(2) {
+            for (address, user_state) in batch {
+                batched.add_user(*address, user_state).unwrap();
+            }
+        }
+        assert_eq!(batched, whole);
+
+        // Any order event or user change since the build started invalidates it.
+        let mut market_state = test_market_state();
+        let mut checkpoint = BookCheckpoint {
+            market,
+            epoch: 0,
+            user_count: 0,
+            summary: OrderBookSummary::default(),
+            taken_at: 0,
+            book_hash: [0; 32],
+            user_set_version: 0,
+            event_seq: 0,
+            last_user: Pubkey::default(),
+        };
+        checkpoint.restart(market_state.user_set_version, 7);
+        assert_eq!(checkpoint.summary, OrderBookSummary::new(market));
+        checkpoint.require_current(&market_state, 7).unwrap();
+        assert_eq!(
+            error_code(&checkpoint.require_current(&market_state, 8).unwrap_err()),
+            FeeError::BookChanged as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+        market_state.add_user().unwrap();
+        assert!(checkpoint.require_current(&market_state, 7).is_err());
+    }
+
+    fn empty_referral_checkpoint() -> ReferralCheckpoint {
+        ReferralCheckpoint {
+            market: Pubkey::new_unique(),
+            epoch: 0,
+            user_count: 0,
+            registered_users: 0,
+            taken_at: 0,
+            root: [0; 32],
+            user_set_version: 0,
+            last_user: Pubkey::default(),
+            frontier: [[0; 32]; REFERRAL_TREE_DEPTH],
+        }
+    }
+
+    /// Users of a market sorted by `UserState` address, with their leaves.
+    fn referral_users(count: usize) -> (Vec<ReferralLeaf>, Vec<[u8; 32]>) {
+        let mut users: Vec<ReferralLeaf> = (0..count)
+            .map(|_| {
+                let address = Pubkey::new_unique();
+                ReferralLeaf::new(address, &empty_user_state(Pubkey::new_unique()))
+            })
+            .collect();
+        users.sort_by_key(|leaf| leaf.user_state);
+        let leaves = users.iter().map(ReferralLeaf::hash).collect();
+        (users, leaves)
+    }
+
+    #[test]
+    fn referral_graph_proofs_verify_against_the_root() {
+        let referrer = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let mut users: Vec<UserState> =
+            (0..3).map(|_| empty_user_state(Pubkey::new_unique())).collect();
+        users[0].referrer = Some(referrer);
+        users[1].referrer_commitment = referral_commitment(&referrer, &[7; 32]);
+        let leaves: Vec<[u8; 32]> =
+            users.iter().map(|user| referral_leaf(address, user)).collect();
+
+        // No referral, plain and confidential referrals all get distinct leaves.
+        assert_ne!(leaves[0], leaves[1]);
+        assert_ne!(leaves[1], leaves[2]);
+        assert_eq!(referral_merkle_root(&[]), [0; 32]);
+        assert_eq!(referral_merkle_root(&leaves[..1]), leaves[0]);
+
+        // Three leaves: ((0, 1), 2).
+        let root = referral_merkle_root(&leaves);
+        let pair = referral_node(&leaves[0], &leaves[1]);
+        assert_eq!(referral_proof(&leaves, 0), vec![leaves[1], leaves[2]]);
+        assert_eq!(referral_proof(&leaves, 2), vec![pair]);
+        assert!(verify_referral_proof(leaves[0], 0, 3, &[leaves[1], leaves[2]], root));
+        assert!(verify_referral_proof(leaves[2], 2, 3, &[pair], root));
+
+        // Siblings are positional: a leaf can't claim another leaf's place.
+        assert!(!verify_referral_proof(leaves[1], 0, 3, &[leaves[0], leaves[2]], root));
+        assert!(!verify_referral_proof(leaves[2], 1, 3, &[pair], root));
+        assert!(!verify_referral_proof(leaves[2], 3, 3, &[pair], root));
+
+        // A referral the user didn't have has no leaf in the tree.
+        let mut forged = users[2].clone();
+        forged.referrer = Some(referrer);
+        assert!(!verify_referral_proof(referral_leaf(address, &forged), 2, 3, &[pair], root));
+
+        // Every leaf of every tree shape proves against its root.
+        let (_, leaves) = referral_users(7);
+        for count in 1..=leaves.len() {
+            let root = referral_merkle_root(&leaves[..count]);
+            for index in 0..count {
+                let proof = referral_proof(&leaves[..count], index);
+                let (index, count) = (index as u32, count as u32);
+                assert!(verify_referral_proof(leaves[index as usize], index, count, &proof, root));
+            }
+        }
+    }
+
+    #[test]
+    fn referral_checkpoints_build_the_same_root_across_batches() {
+        let (_, leaves) = referral_users(13);
+        let mut checkpoint = empty_referral_checkpoint();
+        assert_eq!(checkpoint.frontier_root(), [0; 32]);
+
+        // Whatever the batch boundaries, the frontier matches the tree over every leaf so far.
+        for (pushed, leaf) in leaves.iter().enumerate() {
+            checkpoint.push_leaf(*leaf).unwrap();
+            assert_eq!(checkpoint.user_count as usize, pushed + 1);
+            assert_eq!(checkpoint.frontier_root(), referral_merkle_root(&leaves[..=pushed]));
+        }
+
+        // Users joining or leaving mid-build invalidate it until restarted.
+        let mut market_state = test_market_state();
+        checkpoint.restart(market_state.user_set_version);
+        checkpoint.require_current(&market_state).unwrap();
+        assert_eq!(checkpoint.user_count, 0);
+        assert_eq!(checkpoint.frontier_root(), [0; 32]);
+
+        let changed =
+            FeeError::ReferralGraphChanged as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+        market_state.add_user().unwrap();
+        let err = checkpoint.require_current(&market_state).unwrap_err();
+        assert_eq!(error_code(&err), changed);
+        checkpoint.restart(market_state.user_set_version);
+        market_state.remove_user().unwrap();
+        let err = checkpoint.require_current(&market_state).unwrap_err();
+        assert_eq!(error_code(&err), changed);
+        assert_eq!(market_state.user_count, 0);
+        assert!(market_state.remove_user().is_err());
+
+        checkpoint.frontier = [[u8::MAX; 32]; REFERRAL_TREE_DEPTH];
+        checkpoint.last_user = Pubkey::new_unique();
+        assert_eq!(serialized_len(&checkpoint), ReferralCheckpoint::SIZE);
+        assert!(8 + ReferralCheckpoint::SIZE <= MAX_INIT_SPACE);
+    }
+
+    #[test]
+    fn referral_exclusion_needs_adjacent_neighbors() {
+        let (users, leaves) = referral_users(5);
+        let mut checkpoint = empty_referral_checkpoint();
+        for leaf in &leaves {
+            checkpoint.push_leaf(*leaf).unwrap();
+        }
+        let proven = |index: usize| ReferralLeafProof {
+            leaf: users[index],
+            index: index as u32,
+            proof: referral_proof(&leaves, index),
+        };
+        let (below, above) = (proven(1), proven(2));
+        let between = Pubkey::new_from_array({
+            // Unique keys differ in their leading bytes; maxing out the rest lands between.
+            let mut bytes = users[1].user_state.to_bytes();
+            bytes[8..].fill(u8::MAX);
+            bytes
+        });
+        assert!(users[1].user_state < between && between < users[2].user_state);
+
+        // Nothing proves against a checkpoint still being built.
+        assert!(!verify_referral_exclusion(&between, Some(&below), Some(&above), &checkpoint));
+        checkpoint.root = checkpoint.frontier_root();
+        checkpoint.taken_at = 1;
+        assert!(verify_referral_exclusion(&between, Some(&below), Some(&above), &checkpoint));
+
+        // A user in the tree can't be excluded, nor can neighbors with a gap between them.
+        let member = users[2].user_state;
+        assert!(!verify_referral_exclusion(&member, Some(&below), Some(&above), &checkpoint));
+        let gap = proven(3);
+        assert!(!verify_referral_exclusion(&between, Some(&below), Some(&gap), &checkpoint));
+
+        // Past either end, only the first or last leaf will do.
+        let (first, last) = (proven(0), proven(4));
+        let lowest = Pubkey::default();
+        assert!(lowest < users[0].user_state);
+        assert!(verify_referral_exclusion(&lowest, None, Some(&first), &checkpoint));
+        assert!(!verify_referral_exclusion(&lowest, None, Some(&below), &checkpoint));
+        let highest = Pubkey::new_from_array([u8::MAX; 32]);
+        assert!(verify_referral_exclusion(&highest, Some(&last), None, &checkpoint));
+        assert!(!verify_referral_exclusion(&highest, Some(&above), None, &checkpoint));
+        assert!(!verify_referral_exclusion(&highest, None, None, &checkpoint));
+
+        // A forged leaf fails its proof.
+        let mut forged = below.clone();
+        forged.leaf.kind = 1;
+        assert!(!verify_referral_exclusion(&between, Some(&forged), Some(&above), &checkpoint));
+
+        // Nobody is in an empty tree.
+        let mut empty = empty_referral_checkpoint();
+        empty.taken_at = 1;
+        assert!(verify_referral_exclusion(&between, None, None, &empty));
+    }
+
+    #[test]
+    fn user_state_size_matches_borsh() {
+        assert_eq!(serialized_len(&max_user_state()), UserState::space(MAX_ORDERS));
+        assert!(8 + UserState::space(MAX_ORDERS) <= MAX_INIT_SPACE);
+
+        // Without a referrer the account serializes shorter, never longer.
+        let mut user_state = max_user_state();
+        user_state.referrer = None;
+        assert!(serialized_len(&user_state) < UserState::space(MAX_ORDERS));
+
+        // Fewer slots, proportionally smaller.
+        user_state.referrer = Some(Pubkey::new_unique());
+        user_state.orders.truncate(1);
+        assert_eq!(serialized_len(&user_state), UserState::space(1));
+    }
+
+    #[test]
+    fn exact_fills_must_take_the_whole_order() {
+        let mut maker = empty_user_state(Pubkey::new_unique());
+        maker
+            .insert_order(fixed_order(OrderSide::Ask, 100, 40, 0, 0))
+            .unwrap();
+        let mismatch = FeeError::FillSizeMismatch as u32 + anchor_lang::error::ERROR_CODE_OFFSET;
+
+        maker.require_whole_order(0, 40).unwrap();
+        for (order_index, fill_size) in [(0, 39), (0, 41), (1, 0), (1, 40), (u8::MAX, 40)] {
+            let error = maker.require_whole_order(order_index, fill_size).unwrap_err();
+            assert_eq!(error_code(&error), mismatch);
+        }
+    }
+
+    #[test]
+    fn order_slots_resize_without_dropping_open_orders() {
+        let mut user_state = empty_user_state(Pubkey::new_unique());
+        user_state.resize_order_slots(2).unwrap();
+        user_state
+            .insert_order(fixed_order(OrderSide::Ask, 100, 1, 0, 0))
+            .unwrap();
+        let index = user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 1, 0, 0))
+            .unwrap();
+        assert!(user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 1, 0, 0))
+            .is_err());
+
+        // The second slot is in use, so it can't be dropped.
+        assert!(user_state.resize_order_slots(1).is_err());
+        assert_eq!(user_state.orders.len(), 2);
+
+        user_state.cancel_order(index as u8, &test_market_state(), &FixedClock(0)).unwrap();
+        user_state.resize_order_slots(1).unwrap();
+        assert_eq!(user_state.orders.len(), 1);
+
+        // Growing adds free slots.
+        user_state.resize_order_slots(3).unwrap();
+        user_state
+            .insert_order(fixed_order(OrderSide::Bid, 100, 1, 0, 0))
+            .unwrap();
+        assert_eq!(user_state.open_order_count(), 2);
+    }
+
+    #[test]
+    fn audit_accepts_user_states_of_any_slot_count() {
+        let user_state = |order_slots: usize| {
+            let mut data = UserState::DISCRIMINATOR.to_vec();
+            data.resize(8 + UserState::space(order_slots), 0);
+            data
+        };
+        for order_slots in [1, DEFAULT_MAX_OPEN_ORDERS as usize, MAX_ORDERS] {
+            assert_eq!(
+                audit_account(&crate::ID, &user_state(order_slots)).1,
+                AccountAuditStatus::Healthy
+            );
+        }
+        let mut odd = user_state(2);
+        odd.push(0);
+        assert!(matches!(
+            audit_account(&crate::ID, &odd).1,
+            AccountAuditStatus::SizeMismatch { .. }
+        ));
+        assert!(matches!(
+            audit_account(&crate::ID, &user_state(MAX_ORDERS + 1)).1,
+            AccountAuditStatus::SizeMismatch { .. }
+        ));
+    }
+
+    fn legacy_account<T: AnchorSerialize>(discriminator: &[u8], legacy: &T, len: usize) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        legacy.serialize(&mut data).unwrap();
+        assert!(data.len() <= len);
+        data.resize(len, 0);
+        data
+    }
+
+    #[test]
+    fn legacy_market_states_migrate_to_bps_markets() {
+        let legacy = LegacyMarketState {
+            authority: Pubkey::new_unique(),
+            maker_rebate_bps: 2,
+            taker_fee_bps: 5,
+            referral_bps: 1,
+            total_fees_collected: 1_000,
+            total_liquidity_rewards_distributed: 400,
+        };
+        let len = 8 + LegacyMarketState::SIZE;
+        let data = legacy_account(&MarketState::DISCRIMINATOR, &legacy, len);
+        assert_eq!(
+            audit_account(&crate::ID, &data),
+            (Some(AccountKind::MarketState), AccountAuditStatus::Legacy)
+        );
+        assert_eq!(LegacyMarketState::load(&crate::ID, &data).unwrap(), legacy);
+        assert_eq!(
+            error_code(&LegacyMarketState::load(&Pubkey::new_unique(), &data).unwrap_err()),
+            FeeError::NotLegacyAccount as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+
+        // What the handler does after growing the account: zeroes past the discriminator,
+        // which must still read as a `MarketState`, rebuilt from the legacy fields.
+        let mut data = MarketState::DISCRIMINATOR.to_vec();
+        data.resize(8 + MarketState::SIZE, 0);
+        let mut market_state = MarketState::try_deserialize(&mut &data[..]).unwrap();
+        market_state.initialize(legacy.authority, &legacy.params(), 0, 100).unwrap();
+        assert_eq!(market_state.authority, legacy.authority);
+        assert_eq!(market_state.fee_precision, FeePrecision::Bps);
+        assert_eq!(market_state.status, MarketStatus::Active);
+        assert_eq!(market_state.maker_rebate_rate, 2);
+        assert_eq!(market_state.taker_fee_rate, 5);
+        assert_eq!(market_state.referral_rate, 1);
+        assert_eq!(market_state.go_live_ts, 100);
+
+        // A migrated market is no longer legacy, and can't be migrated twice.
+        let mut migrated = vec![];
+        market_state.try_serialize(&mut migrated).unwrap();
+        migrated.resize(8 + MarketState::SIZE, 0);
+        assert_eq!(audit_account(&crate::ID, &migrated).1, AccountAuditStatus::Healthy);
+        assert!(LegacyMarketState::load(&crate::ID, &migrated).is_err());
+    }
+
+    #[test]
+    fn legacy_user_states_decode_with_and_without_a_referrer() {
+        let order = |size_remaining| LegacyOrder {
+            side: OrderSide::Ask,
+            price: 100,
+            size_remaining,
+            creation_timestamp: 1,
+            expiry_timestamp: 0,
+        };
+        let mut legacy = LegacyUserState {
+            authority: Pubkey::new_unique(),
+            maker_volume: 10,
+            taker_volume: 20,
+            maker_rebates_earned: 3,
+            taker_fees_paid: 4,
+            liquidity_score: 5,
+            referrer: None,
+            orders: [order(7), order(0), order(9), order(0), order(0)],
+        };
+        for referrer in [None, Some(Pubkey::new_unique())] {
+            legacy.referrer = referrer;
+            let len = 8 + LegacyUserState::SIZE;
+            let data = legacy_account(&UserState::DISCRIMINATOR, &legacy, len);
+            assert_eq!(
+                audit_account(&crate::ID, &data),
+                (Some(AccountKind::UserState), AccountAuditStatus::Legacy)
+            );
+            let loaded = LegacyUserState::load(&crate::ID, &data).unwrap();
+            assert_eq!(loaded, legacy);
+            assert_eq!(loaded.open_order_count(), 2);
+
+            let mut user_state = empty_user_state(Pubkey::new_unique());
+            user_state.import_legacy_stats(&loaded.stats());
+            assert_eq!(user_state.maker_volume, 10);
+            assert_eq!(user_state.taker_fees_paid, 4);
+            assert_eq!(user_state.liquidity_score, 5);
+        }
+
+        let mut current = UserState::DISCRIMINATOR.to_vec();
+        current.resize(8 + UserState::space(DEFAULT_MAX_OPEN_ORDERS as usize), 0);
+        assert_eq!(
+            error_code(&LegacyUserState::load(&crate::ID, &current).unwrap_err()),
+            FeeError::NotLegacyAccount as u32 + anchor_lang::error::ERROR_CODE_OFFSET
+        );
+    }
 }
@@ -0,0 +1,32 @@
+import BN from "bn.js";
+import { formatAmount, parseAmount } from "../client/amounts";
+
+describe("Client amount helpers", () => {
+  const roundTrip = (raw: string, decimals: number, locale: string) => {
+    const text = formatAmount(new BN(raw), decimals, locale);
+    assert.equal(parseAmount(text, decimals, locale).toString(), raw, text);
+    return text;
+  };
+
+  it("Groups the whole part by the locale's rules", () => {
+    assert.equal(roundTrip("123456789012", 6, "en-US"), "123,456.789012");
+    assert.equal(roundTrip("123456789012", 6, "de-DE"), "123.456,789012");
+    // Indian grouping: thousands, then every two digits.
+    assert.equal(roundTrip("123456700", 2, "en-IN"), "12,34,567");
+  });
+
+  it("Round-trips amounts past float precision", () => {
+    for (const locale of ["en-US", "de-DE", "en-IN", "fr-FR"]) {
+      roundTrip("18446744073709551615", 9, locale); // u64::MAX
+      roundTrip("-250000001", 8, locale);
+      roundTrip("5", 6, locale);
+      roundTrip("0", 0, locale);
+    }
+  });
+
+  it("Rejects malformed input and excess precision", () => {
+    assert.throws(() => parseAmount("1.2.3", 6, "en-US"), /not an amount/);
+    assert.throws(() => parseAmount("abc", 6, "en-US"), /not an amount/);
+    assert.throws(() => parseAmount("1.0000001", 6, "en-US"), /more than 6 decimals/);
+  });
+});